@@ -16,6 +16,7 @@ const MADT_STRUCT_SIZE: usize = core::mem::size_of::<MADT>();
 
 const STRUCT_TYPE_GICC: u8 = 0x0B;
 const STRUCT_TYPE_GICD: u8 = 0x0C;
+const STRUCT_TYPE_GICR: u8 = 0x0E;
 const STRUCT_TYPE_ITS: u8 = 0x0F;
 
 const GICC_FLAGS_ENABLED: u32 = 1;
@@ -59,11 +60,18 @@ pub struct GicCpuInterfaceStructure {
 }
 
 /// The iterator to get MPIDR which is enabled(`GICC_FLAGS_ENABLED` is enabled)
+#[derive(Clone)]
 pub struct GicCpuInterfaceStructureList {
     pointer: usize,
     limit: usize,
 }
 
+/// The iterator to get (DiscoveryRangeBaseAddress, DiscoveryRangeLength) of GICR structures
+pub struct GicRedistributorStructureList {
+    pointer: usize,
+    limit: usize,
+}
+
 pub struct GicInterruptTranslationServiceStructureList {
     pointer: usize,
     limit: usize,
@@ -94,6 +102,16 @@ impl MADT {
         return None;
     }
 
+    pub fn get_gic_redistributor_list(&self) -> GicRedistributorStructureList {
+        let length = self.length as usize - MADT_STRUCT_SIZE;
+        let pointer = self as *const _ as usize + MADT_STRUCT_SIZE;
+
+        GicRedistributorStructureList {
+            pointer,
+            limit: pointer + length,
+        }
+    }
+
     pub fn get_gic_its_list(&self) -> GicInterruptTranslationServiceStructureList {
         let length = self.length as usize - MADT_STRUCT_SIZE;
         let pointer = self as *const _ as usize + MADT_STRUCT_SIZE;
@@ -131,6 +149,27 @@ impl Iterator for GicCpuInterfaceStructureList {
     }
 }
 
+impl Iterator for GicRedistributorStructureList {
+    type Item = (usize, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pointer >= self.limit {
+            return None;
+        }
+        let record_base = self.pointer;
+        let record_type = unsafe { *(record_base as *const u8) };
+        let record_length = unsafe { *((record_base + 1) as *const u8) };
+
+        self.pointer += record_length as usize;
+        match record_type {
+            STRUCT_TYPE_GICR => Some((
+                unsafe { core::ptr::read_unaligned((record_base + 4) as *const u64) } as usize,
+                unsafe { core::ptr::read_unaligned((record_base + 12) as *const u32) } as usize,
+            )),
+            _ => self.next(),
+        }
+    }
+}
+
 impl Iterator for GicInterruptTranslationServiceStructureList {
     type Item = usize;
     fn next(&mut self) -> Option<Self::Item> {
@@ -45,6 +45,7 @@ pub const CPTR_EL2_FPEN_NO_TRAP: u64 = 0b11 << CPTR_EL2_FPEN_BITS_OFFSET;
 pub const CPTR_EL2_ZEN_BITS_OFFSET: u64 = 16;
 pub const CPTR_EL2_ZEN: u64 = 0b11 << CPTR_EL2_ZEN_BITS_OFFSET;
 pub const CPTR_EL2_ZEN_NO_TRAP: u64 = 0b11 << CPTR_EL2_ZEN_BITS_OFFSET;
+pub const CPTR_EL2_TZ_WITHOUT_E2H: u64 = 1 << 8;
 //pub const CPTR_EL2_RES1: u64 = 0b11111111 | (1 << 9) | (0b11 << 12);
 
 /* TCR_EL2 */
@@ -107,6 +108,8 @@ pub const VTCR_EL2_SL2_BIT_OFFSET: u64 = 33;
 pub const VTCR_EL2_SL2: u64 = 1 << VTCR_EL2_SL2_BIT_OFFSET;
 pub const VTCR_EL2_RES1: u64 = 1 << 31;
 pub const VTCR_EL2_HWU_BITS_OFFSET: u64 = 25;
+pub const VTCR_EL2_HD: u64 = 1 << 22;
+pub const VTCR_EL2_HA: u64 = 1 << 21;
 pub const VTCR_EL2_PS_BITS_OFFSET: u64 = 16;
 pub const VTCR_EL2_PS: u64 = 0b111 << VTCR_EL2_PS_BITS_OFFSET;
 pub const VTCR_EL2_TG0_BITS_OFFSET: u64 = 14;
@@ -126,10 +129,68 @@ pub const SPSR_EL2_M_EL0T: u64 = 0b0000;
 /* ID_AA64PFR0_EL1 */
 pub const ID_AA64PFR0_EL1_SVE: u64 = 0b1111 << 32;
 pub const ID_AA64PFR0_EL1_GIC: u64 = 0b1111 << 24;
+pub const ID_AA64PFR0_EL1_FP: u64 = 0b1111 << 16;
+pub const ID_AA64PFR0_EL1_FP_NOT_IMPLEMENTED: u64 = 0b1111 << 16;
+
+/* ID_AA64DFR0_EL1 */
+pub const ID_AA64DFR0_EL1_WRPS_BITS_OFFSET: u64 = 20;
+pub const ID_AA64DFR0_EL1_WRPS: u64 = 0b1111 << ID_AA64DFR0_EL1_WRPS_BITS_OFFSET;
+pub const ID_AA64DFR0_EL1_BRPS_BITS_OFFSET: u64 = 12;
+pub const ID_AA64DFR0_EL1_BRPS: u64 = 0b1111 << ID_AA64DFR0_EL1_BRPS_BITS_OFFSET;
+
+/* ID_AA64ISAR1_EL1 */
+pub const ID_AA64ISAR1_EL1_GPI: u64 = 0b1111 << 28;
+pub const ID_AA64ISAR1_EL1_GPA: u64 = 0b1111 << 24;
+pub const ID_AA64ISAR1_EL1_API: u64 = 0b1111 << 8;
+pub const ID_AA64ISAR1_EL1_APA: u64 = 0b1111 << 4;
+
+/* ID_AA64ISAR2_EL1 */
+pub const ID_AA64ISAR2_EL1_APA3: u64 = 0b1111 << 12;
+pub const ID_AA64ISAR2_EL1_GPA3: u64 = 0b1111 << 8;
+
+/* ICC_SRE_EL2 */
+pub const ICC_SRE_EL2_SRE: u64 = 1 << 0;
+
+/* ICC_CTLR_EL1 */
+pub const ICC_CTLR_EL1_PRI_BITS_BITS_OFFSET: u64 = 8;
+pub const ICC_CTLR_EL1_PRI_BITS: u64 = 0b111 << ICC_CTLR_EL1_PRI_BITS_BITS_OFFSET;
 
 /* ID_AA64MMFR0_EL1 */
 pub const ID_AA64MMFR0_EL1_PARANGE: u64 = 0b1111;
 
+/* ID_AA64MMFR1_EL1 */
+pub const ID_AA64MMFR1_EL1_HAFDBS: u64 = 0b1111;
+
+/* ESR_EL2 (ISS of Data Abort) */
+pub const ESR_EL2_ISS_ISV: u64 = 1 << 24;
+pub const ESR_EL2_ISS_SAS_BITS_OFFSET: u64 = 22;
+pub const ESR_EL2_ISS_SAS: u64 = 0b11 << ESR_EL2_ISS_SAS_BITS_OFFSET;
+pub const ESR_EL2_ISS_SSE: u64 = 1 << 21;
+pub const ESR_EL2_ISS_SRT_BITS_OFFSET: u64 = 16;
+pub const ESR_EL2_ISS_SRT: u64 = 0b11111 << ESR_EL2_ISS_SRT_BITS_OFFSET;
+pub const ESR_EL2_ISS_SF: u64 = 1 << 15;
+pub const ESR_EL2_ISS_AR: u64 = 1 << 14;
+pub const ESR_EL2_ISS_FNV: u64 = 1 << 10;
+pub const ESR_EL2_ISS_CM: u64 = 1 << 8;
+pub const ESR_EL2_ISS_S1PTW: u64 = 1 << 7;
+pub const ESR_EL2_ISS_WNR: u64 = 1 << 6;
+/// DFSC of Data Abort and IFSC of Instruction Abort
+pub const ESR_EL2_ISS_FSC: u64 = 0b111111;
+/// Translation fault of level 0 ~ 3, the lowest two bits are the level
+pub const ESR_EL2_ISS_FSC_TRANSLATION_FAULT: u64 = 0b000100;
+
+/* HPFAR_EL2 */
+pub const HPFAR_EL2_FIPA_BITS_OFFSET: u64 = 4;
+pub const HPFAR_EL2_FIPA: u64 = ((1 << 40) - 1) << HPFAR_EL2_FIPA_BITS_OFFSET;
+/// HPFAR_EL2.FIPA is the fault intermediate physical address shifted right by this value
+pub const HPFAR_EL2_FIPA_ADDRESS_SHIFT: u64 = 12;
+
+/* CTR_EL0 */
+pub const CTR_EL0_ERG_BITS_OFFSET: u64 = 20;
+pub const CTR_EL0_ERG: u64 = 0b1111 << CTR_EL0_ERG_BITS_OFFSET;
+pub const CTR_EL0_DMINLINE_BITS_OFFSET: u64 = 16;
+pub const CTR_EL0_DMINLINE: u64 = 0b1111 << CTR_EL0_DMINLINE_BITS_OFFSET;
+
 /* ZCR_EL2 */
 pub const MAX_ZCR_EL2_LEN: u64 = 0x1ff;
 
@@ -175,7 +236,7 @@ pub fn secure_monitor_call(
             inout("x15") * x15,
             inout("x16") * x16,
             inout("x17") * x17,
-            clobber_abi("C")
+            /* SIMD&FP registers are preserved by the callee in SMCCC 1.2 */
         )
     };
 }
@@ -476,6 +537,13 @@ pub fn get_hpfar_el2() -> u64 {
     return hpfar_el2;
 }
 
+/// Get the page address of the fault intermediate physical address from HPFAR_EL2
+#[inline(always)]
+pub fn convert_hpfar_el2_to_ipa(hpfar_el2: u64) -> usize {
+    (((hpfar_el2 & HPFAR_EL2_FIPA) >> HPFAR_EL2_FIPA_BITS_OFFSET) << HPFAR_EL2_FIPA_ADDRESS_SHIFT)
+        as usize
+}
+
 #[inline(always)]
 pub fn get_spsr_el2() -> u64 {
     let spsr_el2: u64;
@@ -519,6 +587,13 @@ pub fn set_sp_el1(sp_el1: u64) {
     unsafe { asm!("msr sp_el1, {:x}", in(reg) sp_el1) };
 }
 
+#[inline(always)]
+pub fn get_ctr_el0() -> u64 {
+    let ctr_el0: u64;
+    unsafe { asm!("mrs {:x}, ctr_el0", out(reg) ctr_el0) };
+    return ctr_el0;
+}
+
 #[inline(always)]
 pub fn get_id_aa64mmfr0_el1() -> u64 {
     let id_aa64mmfr0_el1: u64;
@@ -526,6 +601,13 @@ pub fn get_id_aa64mmfr0_el1() -> u64 {
     return id_aa64mmfr0_el1;
 }
 
+#[inline(always)]
+pub fn get_id_aa64mmfr1_el1() -> u64 {
+    let id_aa64mmfr1_el1: u64;
+    unsafe { asm!("mrs {:x}, id_aa64mmfr1_el1", out(reg) id_aa64mmfr1_el1) };
+    return id_aa64mmfr1_el1;
+}
+
 #[inline(always)]
 pub fn get_id_aa64pfr0_el1() -> u64 {
     let id_aa64pfr0_el1: u64;
@@ -533,6 +615,35 @@ pub fn get_id_aa64pfr0_el1() -> u64 {
     return id_aa64pfr0_el1;
 }
 
+#[inline(always)]
+pub fn get_id_aa64dfr0_el1() -> u64 {
+    let id_aa64dfr0_el1: u64;
+    unsafe { asm!("mrs {:x}, id_aa64dfr0_el1", out(reg) id_aa64dfr0_el1) };
+    return id_aa64dfr0_el1;
+}
+
+#[inline(always)]
+pub fn get_id_aa64isar1_el1() -> u64 {
+    let id_aa64isar1_el1: u64;
+    unsafe { asm!("mrs {:x}, id_aa64isar1_el1", out(reg) id_aa64isar1_el1) };
+    return id_aa64isar1_el1;
+}
+
+#[inline(always)]
+pub fn get_id_aa64isar2_el1() -> u64 {
+    let id_aa64isar2_el1: u64;
+    /* ID_AA64ISAR2_EL1 */
+    unsafe { asm!("mrs {:x}, S3_0_C0_C6_2", out(reg) id_aa64isar2_el1) };
+    return id_aa64isar2_el1;
+}
+
+#[inline(always)]
+pub fn get_icc_sre_el2() -> u64 {
+    let icc_sre_el2: u64;
+    unsafe { asm!("mrs {:x}, icc_sre_el2", out(reg) icc_sre_el2) };
+    return icc_sre_el2;
+}
+
 #[inline(always)]
 pub fn get_mpidr_el1() -> u64 {
     let mpidr_el1: u64;
@@ -571,6 +682,20 @@ pub fn flush_tlb_el1() {
     };
 }
 
+/// Invalidate the stage 1 and stage 2 TLB entries of the current VMID on all CPUs
+#[inline(always)]
+pub fn flush_tlb_vmalls12e1is() {
+    unsafe {
+        asm!(
+            "
+            dsb ishst
+            tlbi vmalls12e1is
+            dsb ish
+            isb"
+        )
+    };
+}
+
 #[inline(always)]
 pub fn dsb() {
     unsafe { asm!("dsb sy") }
@@ -596,6 +721,21 @@ pub fn invalidate_data_cache(virtual_address: usize) {
     unsafe { asm!("DC IVAC, {:x}", in(reg) virtual_address) };
 }
 
+#[inline(always)]
+pub fn clean_data_cache(virtual_address: usize) {
+    unsafe { asm!("DC CVAC, {:x}", in(reg) virtual_address) };
+}
+
+#[inline(always)]
+pub fn clean_data_cache_to_pou(virtual_address: usize) {
+    unsafe { asm!("DC CVAU, {:x}", in(reg) virtual_address) };
+}
+
+#[inline(always)]
+pub fn invalidate_instruction_cache(virtual_address: usize) {
+    unsafe { asm!("IC IVAU, {:x}", in(reg) virtual_address) };
+}
+
 #[inline(always)]
 pub fn send_event_all() {
     unsafe { asm!("SEV") };
@@ -0,0 +1,51 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! CRC-32 (IEEE 802.3)
+//!
+
+const CRC32_POLYNOMIAL: u32 = 0xEDB88320; /* Reversed 0x04C11DB7 */
+
+const CRC32_TABLE: [u32; 256] = create_crc32_table();
+
+const fn create_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Update CRC-32 with `data`
+///
+/// To calculate CRC-32 of divided data, pass the result of the previous call as `crc`.
+/// For the first call, `crc` must be 0.
+pub fn update_crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data {
+        crc = CRC32_TABLE[((crc ^ (*b as u32)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Calculate CRC-32 of `data`
+pub fn calculate_crc32(data: &[u8]) -> u32 {
+    update_crc32(0, data)
+}
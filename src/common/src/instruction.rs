@@ -0,0 +1,967 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! A64 Load/Store Instruction Decoder
+//!
+//! This module decodes the load/store instructions which may access the trapped memory.
+//! The decoder is pure and does not depend on any architecture specific code,
+//! therefore it can be used on the host (`cargo test` in `common`).
+//!
+//! ARM DDI 0487G.a ID011921 C4.1.4 Loads and Stores
+//!
+
+use crate::bitmask;
+
+pub const REGISTER_NUMBER_XZR: u8 = 31;
+
+/// Access size of 128bit (Only for SIMD&FP registers)
+pub const ACCESS_SIZE_128BIT: u8 = 0b100;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressingMode {
+    /// No write back (unsigned immediate, unscaled immediate, register offset, no offset)
+    Offset,
+    /// The base register is updated after the access, the value is the byte offset to add
+    PostIndex(i64),
+    /// The base register is updated before the access, the value is the byte offset to add
+    PreIndex(i64),
+    /// Unprivileged access (LDTR/STTR), no write back
+    Unprivileged,
+    /// PC-relative literal, no base register
+    Literal,
+}
+
+/// Single register access (LDR/STR/LDUR/STUR/LDTR/STTR/LDAR/STLR/LDAPR and SIMD&FP variants)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RegisterAccess {
+    pub target_register: u8,
+    pub base_register: u8,
+    /// log2 of the access bytes ([`ACCESS_SIZE_128BIT`] is used for 128bit SIMD&FP access)
+    pub size: u8,
+    pub is_sign_extend_required: bool,
+    /// The width of the target register, false means Wn (Ignored for SIMD&FP registers)
+    pub is_64bit_register: bool,
+    pub addressing_mode: AddressingMode,
+}
+
+/// Pair access (LDP/STP/LDNP/STNP/LDPSW and SIMD&FP variants)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PairAccess {
+    pub target_register_1: u8,
+    pub target_register_2: u8,
+    pub base_register: u8,
+    /// log2 of the access bytes of each register
+    pub size: u8,
+    pub is_sign_extend_required: bool,
+    pub is_64bit_register: bool,
+    pub addressing_mode: AddressingMode,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AtomicOperation {
+    Add,
+    Clear,
+    ExclusiveOr,
+    Set,
+    SignedMax,
+    SignedMin,
+    UnsignedMax,
+    UnsignedMin,
+    Swap,
+}
+
+/// Atomic memory operation (LD<OP>/SWP)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AtomicAccess {
+    pub operation: AtomicOperation,
+    pub source_register: u8,
+    pub target_register: u8,
+    pub size: u8,
+    pub is_acquire: bool,
+    pub is_release: bool,
+}
+
+/// Compare and swap (CAS/CASP)
+///
+/// For CASP, `source_register` and `target_register` are the first registers of each pair.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompareAndSwapAccess {
+    pub source_register: u8,
+    pub target_register: u8,
+    /// log2 of the access bytes of each register
+    pub size: u8,
+    pub is_pair: bool,
+    pub is_acquire: bool,
+    pub is_release: bool,
+}
+
+/// Exclusive access (LDXR/LDAXR/LDXP/LDAXP/STXR/STLXR/STXP/STLXP)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExclusiveAccess {
+    /// The register to write the status (Only for store)
+    pub status_register: u8,
+    pub target_register_1: u8,
+    /// Some for the pair access
+    pub target_register_2: Option<u8>,
+    /// log2 of the access bytes of each register
+    pub size: u8,
+    /// Acquire (load) or Release (store) semantics
+    pub is_ordered: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum A64MemoryInstruction {
+    Load(RegisterAccess),
+    Store(RegisterAccess),
+    LoadPair(PairAccess),
+    StorePair(PairAccess),
+    SimdLoad(RegisterAccess),
+    SimdStore(RegisterAccess),
+    SimdLoadPair(PairAccess),
+    SimdStorePair(PairAccess),
+    /// PRFM/PRFUM, nothing to access
+    Prefetch,
+    AtomicMemoryOperation(AtomicAccess),
+    CompareAndSwap(CompareAndSwapAccess),
+    LoadExclusive(ExclusiveAccess),
+    StoreExclusive(ExclusiveAccess),
+    /// LDAR/LDLAR/LDAPR
+    LoadAcquire(RegisterAccess),
+    /// STLR/STLLR
+    StoreRelease(RegisterAccess),
+}
+
+impl TryFrom<u32> for A64MemoryInstruction {
+    type Error = ();
+    fn try_from(instruction: u32) -> Result<Self, Self::Error> {
+        /* op0 is bits[31:28], op1 is bit[26](V) */
+        if (((instruction & bitmask!(28, 25)) >> 25) & 0b0101) != 0b0100 {
+            /* Not Load/Store Instruction */
+            return Err(());
+        }
+        match (instruction & bitmask!(29, 28)) >> 28 {
+            0b00 => decode_exclusive(instruction),
+            0b01 => decode_literal(instruction),
+            0b10 => decode_pair(instruction),
+            0b11 => decode_register(instruction),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl A64MemoryInstruction {
+    /// Check whether the instruction writes the memory
+    pub const fn is_store(&self) -> bool {
+        matches!(
+            self,
+            Self::Store(_)
+                | Self::StorePair(_)
+                | Self::SimdStore(_)
+                | Self::SimdStorePair(_)
+                | Self::AtomicMemoryOperation(_)
+                | Self::CompareAndSwap(_)
+                | Self::StoreExclusive(_)
+                | Self::StoreRelease(_)
+        )
+    }
+}
+
+#[inline(always)]
+const fn is_simd(instruction: u32) -> bool {
+    (instruction & (1 << 26)) != 0
+}
+
+#[inline(always)]
+const fn get_rt(instruction: u32) -> u8 {
+    (instruction & bitmask!(4, 0)) as u8
+}
+
+#[inline(always)]
+const fn get_rn(instruction: u32) -> u8 {
+    ((instruction & bitmask!(9, 5)) >> 5) as u8
+}
+
+#[inline(always)]
+const fn get_rt2(instruction: u32) -> u8 {
+    ((instruction & bitmask!(14, 10)) >> 10) as u8
+}
+
+#[inline(always)]
+const fn get_rs(instruction: u32) -> u8 {
+    ((instruction & bitmask!(20, 16)) >> 16) as u8
+}
+
+/// Sign extend the lower `bits` bits of `data`
+const fn sign_extend(data: u32, bits: u32) -> i64 {
+    ((data as i64) << (64 - bits)) >> (64 - bits)
+}
+
+/// Decode Load/Store Exclusive, Load-Acquire/Store-Release and Compare and Swap
+fn decode_exclusive(instruction: u32) -> Result<A64MemoryInstruction, ()> {
+    if is_simd(instruction) || (instruction & (1 << 24)) != 0 {
+        /* Advanced SIMD load/store multiple structures and others */
+        return Err(());
+    }
+    let size = (instruction >> 30) as u8;
+    let o2 = (instruction & (1 << 23)) != 0;
+    let is_load = (instruction & (1 << 22)) != 0;
+    let o1 = (instruction & (1 << 21)) != 0;
+    let o0 = (instruction & (1 << 15)) != 0;
+
+    if o1 {
+        if o2 || (size & 0b10) == 0 {
+            /* o2 == 1: CAS, o2 == 0 && size == 0x: CASP */
+            if get_rt2(instruction) != 0b11111 {
+                return Err(());
+            }
+            let is_pair = !o2;
+            if is_pair && ((get_rs(instruction) & 1) != 0 || (get_rt(instruction) & 1) != 0) {
+                return Err(());
+            }
+            return Ok(A64MemoryInstruction::CompareAndSwap(CompareAndSwapAccess {
+                source_register: get_rs(instruction),
+                target_register: get_rt(instruction),
+                /* CASP: sz is bit[30] */
+                size: if is_pair { size | 0b10 } else { size },
+                is_pair,
+                is_acquire: is_load,
+                is_release: o0,
+            }));
+        }
+        /* LDXP/LDAXP/STXP/STLXP: sz is bit[30] */
+        let access = ExclusiveAccess {
+            status_register: get_rs(instruction),
+            target_register_1: get_rt(instruction),
+            target_register_2: Some(get_rt2(instruction)),
+            size,
+            is_ordered: o0,
+        };
+        return Ok(if is_load {
+            A64MemoryInstruction::LoadExclusive(access)
+        } else {
+            A64MemoryInstruction::StoreExclusive(access)
+        });
+    }
+
+    if o2 {
+        /* LDAR/LDLAR/STLR/STLLR */
+        let access = RegisterAccess {
+            target_register: get_rt(instruction),
+            base_register: get_rn(instruction),
+            size,
+            is_sign_extend_required: false,
+            is_64bit_register: size == 0b11,
+            addressing_mode: AddressingMode::Offset,
+        };
+        return Ok(if is_load {
+            A64MemoryInstruction::LoadAcquire(access)
+        } else {
+            A64MemoryInstruction::StoreRelease(access)
+        });
+    }
+
+    let access = ExclusiveAccess {
+        status_register: get_rs(instruction),
+        target_register_1: get_rt(instruction),
+        target_register_2: None,
+        size,
+        is_ordered: o0,
+    };
+    Ok(if is_load {
+        A64MemoryInstruction::LoadExclusive(access)
+    } else {
+        A64MemoryInstruction::StoreExclusive(access)
+    })
+}
+
+/// Decode Load Register (literal)
+fn decode_literal(instruction: u32) -> Result<A64MemoryInstruction, ()> {
+    if (instruction & (1 << 24)) != 0 {
+        /* LDAPR/STLR (unscaled immediate) and Memory Copy/Set are not supported */
+        return Err(());
+    }
+    let opc = (instruction >> 30) as u8;
+    let mut access = RegisterAccess {
+        target_register: get_rt(instruction),
+        base_register: 0,
+        size: 0,
+        is_sign_extend_required: false,
+        is_64bit_register: false,
+        addressing_mode: AddressingMode::Literal,
+    };
+    if is_simd(instruction) {
+        access.size = match opc {
+            0b00 => 0b10,
+            0b01 => 0b11,
+            0b10 => ACCESS_SIZE_128BIT,
+            _ => return Err(()),
+        };
+        access.is_64bit_register = true;
+        return Ok(A64MemoryInstruction::SimdLoad(access));
+    }
+    match opc {
+        0b00 => {
+            access.size = 0b10;
+        }
+        0b01 => {
+            access.size = 0b11;
+            access.is_64bit_register = true;
+        }
+        0b10 => {
+            /* LDRSW */
+            access.size = 0b10;
+            access.is_sign_extend_required = true;
+            access.is_64bit_register = true;
+        }
+        _ => return Ok(A64MemoryInstruction::Prefetch),
+    }
+    Ok(A64MemoryInstruction::Load(access))
+}
+
+/// Decode Load/Store Register Pair
+fn decode_pair(instruction: u32) -> Result<A64MemoryInstruction, ()> {
+    let opc = (instruction >> 30) as u8;
+    let is_load = (instruction & (1 << 22)) != 0;
+    let imm7 = (instruction & bitmask!(21, 15)) >> 15;
+
+    let (size, is_sign_extend_required) = if is_simd(instruction) {
+        match opc {
+            0b00 => (0b10, false),
+            0b01 => (0b11, false),
+            0b10 => (ACCESS_SIZE_128BIT, false),
+            _ => return Err(()),
+        }
+    } else {
+        match opc {
+            0b00 => (0b10, false),
+            /* LDPSW (STGP is not supported) */
+            0b01 if is_load && (instruction & bitmask!(24, 23)) != 0 => (0b10, true),
+            0b10 => (0b11, false),
+            _ => return Err(()),
+        }
+    };
+    let offset = sign_extend(imm7, 7) << size;
+    let addressing_mode = match (instruction & bitmask!(24, 23)) >> 23 {
+        0b00 => AddressingMode::Offset, /* Non-temporal */
+        0b01 => AddressingMode::PostIndex(offset),
+        0b10 => AddressingMode::Offset,
+        0b11 => AddressingMode::PreIndex(offset),
+        _ => unreachable!(),
+    };
+    let access = PairAccess {
+        target_register_1: get_rt(instruction),
+        target_register_2: get_rt2(instruction),
+        base_register: get_rn(instruction),
+        size,
+        is_sign_extend_required,
+        is_64bit_register: opc != 0b00,
+        addressing_mode,
+    };
+    Ok(match (is_simd(instruction), is_load) {
+        (false, true) => A64MemoryInstruction::LoadPair(access),
+        (false, false) => A64MemoryInstruction::StorePair(access),
+        (true, true) => A64MemoryInstruction::SimdLoadPair(access),
+        (true, false) => A64MemoryInstruction::SimdStorePair(access),
+    })
+}
+
+/// Decode Load/Store Register (immediate, register offset and atomic memory operations)
+fn decode_register(instruction: u32) -> Result<A64MemoryInstruction, ()> {
+    let size = (instruction >> 30) as u8;
+    let opc = ((instruction & bitmask!(23, 22)) >> 22) as u8;
+    let op4 = ((instruction & bitmask!(11, 10)) >> 10) as u8;
+
+    let addressing_mode = if (instruction & (1 << 24)) != 0 {
+        /* Unsigned immediate */
+        AddressingMode::Offset
+    } else if (instruction & (1 << 21)) != 0 {
+        match op4 {
+            0b00 => return decode_atomic(instruction),
+            0b10 => AddressingMode::Offset, /* Register offset */
+            _ => return Err(()),            /* LDRAA/LDRAB */
+        }
+    } else {
+        let offset = sign_extend((instruction & bitmask!(20, 12)) >> 12, 9);
+        match op4 {
+            0b00 => AddressingMode::Offset, /* Unscaled */
+            0b01 => AddressingMode::PostIndex(offset),
+            0b10 => AddressingMode::Unprivileged,
+            0b11 => AddressingMode::PreIndex(offset),
+            _ => unreachable!(),
+        }
+    };
+    let mut access = RegisterAccess {
+        target_register: get_rt(instruction),
+        base_register: get_rn(instruction),
+        size,
+        is_sign_extend_required: false,
+        is_64bit_register: size == 0b11,
+        addressing_mode,
+    };
+
+    if is_simd(instruction) {
+        if (opc & 0b10) != 0 {
+            if size != 0b00 || addressing_mode == AddressingMode::Unprivileged {
+                return Err(());
+            }
+            access.size = ACCESS_SIZE_128BIT;
+        } else if addressing_mode == AddressingMode::Unprivileged {
+            return Err(());
+        }
+        access.is_64bit_register = true;
+        return Ok(if (opc & 1) != 0 {
+            A64MemoryInstruction::SimdLoad(access)
+        } else {
+            A64MemoryInstruction::SimdStore(access)
+        });
+    }
+
+    match opc {
+        0b00 => Ok(A64MemoryInstruction::Store(access)),
+        0b01 => Ok(A64MemoryInstruction::Load(access)),
+        0b10 => {
+            if size == 0b11 {
+                /* PRFM/PRFUM */
+                return if addressing_mode == AddressingMode::Offset {
+                    Ok(A64MemoryInstruction::Prefetch)
+                } else {
+                    Err(())
+                };
+            }
+            /* LDRSB/LDRSH/LDRSW (64bit) */
+            access.is_sign_extend_required = true;
+            access.is_64bit_register = true;
+            Ok(A64MemoryInstruction::Load(access))
+        }
+        0b11 => {
+            if (size & 0b10) != 0 {
+                return Err(());
+            }
+            /* LDRSB/LDRSH (32bit) */
+            access.is_sign_extend_required = true;
+            access.is_64bit_register = false;
+            Ok(A64MemoryInstruction::Load(access))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Decode Atomic Memory Operations (including LDAPR)
+fn decode_atomic(instruction: u32) -> Result<A64MemoryInstruction, ()> {
+    if is_simd(instruction) {
+        return Err(());
+    }
+    let size = (instruction >> 30) as u8;
+    let is_acquire = (instruction & (1 << 23)) != 0;
+    let is_release = (instruction & (1 << 22)) != 0;
+    let o3 = (instruction & (1 << 15)) != 0;
+    let opc = ((instruction & bitmask!(14, 12)) >> 12) as u8;
+
+    let operation = match (o3, opc) {
+        (false, 0b000) => AtomicOperation::Add,
+        (false, 0b001) => AtomicOperation::Clear,
+        (false, 0b010) => AtomicOperation::ExclusiveOr,
+        (false, 0b011) => AtomicOperation::Set,
+        (false, 0b100) => AtomicOperation::SignedMax,
+        (false, 0b101) => AtomicOperation::SignedMin,
+        (false, 0b110) => AtomicOperation::UnsignedMax,
+        (false, 0b111) => AtomicOperation::UnsignedMin,
+        (true, 0b000) => AtomicOperation::Swap,
+        (true, 0b100) => {
+            if !is_acquire || is_release || get_rs(instruction) != 0b11111 {
+                return Err(());
+            }
+            /* LDAPR */
+            return Ok(A64MemoryInstruction::LoadAcquire(RegisterAccess {
+                target_register: get_rt(instruction),
+                base_register: get_rn(instruction),
+                size,
+                is_sign_extend_required: false,
+                is_64bit_register: size == 0b11,
+                addressing_mode: AddressingMode::Offset,
+            }));
+        }
+        _ => return Err(()),
+    };
+    Ok(A64MemoryInstruction::AtomicMemoryOperation(AtomicAccess {
+        operation,
+        source_register: get_rs(instruction),
+        target_register: get_rt(instruction),
+        size,
+        is_acquire,
+        is_release,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    //! The encodings are taken from ARM DDI 0487G.a and checked with the assembler.
+
+    use super::*;
+
+    fn register(
+        target_register: u8,
+        base_register: u8,
+        size: u8,
+        is_sign_extend_required: bool,
+        is_64bit_register: bool,
+        addressing_mode: AddressingMode,
+    ) -> RegisterAccess {
+        RegisterAccess {
+            target_register,
+            base_register,
+            size,
+            is_sign_extend_required,
+            is_64bit_register,
+            addressing_mode,
+        }
+    }
+
+    fn pair(
+        target_register_1: u8,
+        target_register_2: u8,
+        base_register: u8,
+        size: u8,
+        is_sign_extend_required: bool,
+        is_64bit_register: bool,
+        addressing_mode: AddressingMode,
+    ) -> PairAccess {
+        PairAccess {
+            target_register_1,
+            target_register_2,
+            base_register,
+            size,
+            is_sign_extend_required,
+            is_64bit_register,
+            addressing_mode,
+        }
+    }
+
+    fn atomic(
+        operation: AtomicOperation,
+        source_register: u8,
+        target_register: u8,
+        size: u8,
+        is_acquire: bool,
+        is_release: bool,
+    ) -> AtomicAccess {
+        AtomicAccess {
+            operation,
+            source_register,
+            target_register,
+            size,
+            is_acquire,
+            is_release,
+        }
+    }
+
+    fn exclusive(
+        status_register: u8,
+        target_register_1: u8,
+        target_register_2: Option<u8>,
+        size: u8,
+        is_ordered: bool,
+    ) -> ExclusiveAccess {
+        ExclusiveAccess {
+            status_register,
+            target_register_1,
+            target_register_2,
+            size,
+            is_ordered,
+        }
+    }
+
+    fn check(table: &[(u32, &str, A64MemoryInstruction)]) {
+        for (instruction, assembly, expected) in table {
+            assert_eq!(
+                A64MemoryInstruction::try_from(*instruction),
+                Ok(*expected),
+                "{:#010X}: {}",
+                instruction,
+                assembly
+            );
+        }
+    }
+
+    #[test]
+    fn load_store_register() {
+        use A64MemoryInstruction::{Load, Prefetch, Store};
+        use AddressingMode::*;
+        check(&[
+            (
+                0xF9400441,
+                "ldr x1, [x2, #8]",
+                Load(register(1, 2, 3, false, true, Offset)),
+            ),
+            (
+                0xB85FCC83,
+                "ldr w3, [x4, #-4]!",
+                Load(register(3, 4, 2, false, false, PreIndex(-4))),
+            ),
+            (
+                0xF84104C5,
+                "ldr x5, [x6], #16",
+                Load(register(5, 6, 3, false, true, PostIndex(16))),
+            ),
+            (
+                0xB85FF107,
+                "ldur w7, [x8, #-1]",
+                Load(register(7, 8, 2, false, false, Offset)),
+            ),
+            (
+                0xF8408949,
+                "ldtr x9, [x10, #8]",
+                Load(register(9, 10, 3, false, true, Unprivileged)),
+            ),
+            (
+                0xF86D698B,
+                "ldr x11, [x12, x13]",
+                Load(register(11, 12, 3, false, true, Offset)),
+            ),
+            (
+                0xB870D9EE,
+                "ldr w14, [x15, w16, sxtw #2]",
+                Load(register(14, 15, 2, false, false, Offset)),
+            ),
+            (
+                0x58000211,
+                "ldr x17, #0x40",
+                Load(register(17, 0, 3, false, true, Literal)),
+            ),
+            (
+                0x39400020,
+                "ldrb w0, [x1]",
+                Load(register(0, 1, 0, false, false, Offset)),
+            ),
+            (
+                0x79400462,
+                "ldrh w2, [x3, #2]",
+                Load(register(2, 3, 1, false, false, Offset)),
+            ),
+            (
+                0x398000A4,
+                "ldrsb x4, [x5]",
+                Load(register(4, 5, 0, true, true, Offset)),
+            ),
+            (
+                0x79C000E6,
+                "ldrsh w6, [x7]",
+                Load(register(6, 7, 1, true, false, Offset)),
+            ),
+            (
+                0xB9800528,
+                "ldrsw x8, [x9, #4]",
+                Load(register(8, 9, 2, true, true, Offset)),
+            ),
+            (
+                0x98FFFFCA,
+                "ldrsw x10, #-8",
+                Load(register(10, 0, 2, true, true, Literal)),
+            ),
+            (
+                0xF9000441,
+                "str x1, [x2, #8]",
+                Store(register(1, 2, 3, false, true, Offset)),
+            ),
+            (
+                0x381FF483,
+                "strb w3, [x4], #-1",
+                Store(register(3, 4, 0, false, false, PostIndex(-1))),
+            ),
+            (
+                0x781FEFE5,
+                "strh w5, [sp, #-2]!",
+                Store(register(5, 31, 1, false, false, PreIndex(-2))),
+            ),
+            (
+                0xB82878E6,
+                "str w6, [x7, x8, lsl #2]",
+                Store(register(6, 7, 2, false, false, Offset)),
+            ),
+            (
+                0xB8000949,
+                "sttr w9, [x10]",
+                Store(register(9, 10, 2, false, false, Unprivileged)),
+            ),
+            (0xF9800000, "prfm pldl1keep, [x0]", Prefetch),
+        ]);
+    }
+
+    #[test]
+    fn load_store_pair() {
+        use A64MemoryInstruction::{LoadPair, StorePair};
+        use AddressingMode::*;
+        check(&[
+            (
+                0xA9410861,
+                "ldp x1, x2, [x3, #16]",
+                LoadPair(pair(1, 2, 3, 3, false, true, Offset)),
+            ),
+            (
+                0x28C114C4,
+                "ldp w4, w5, [x6], #8",
+                LoadPair(pair(4, 5, 6, 2, false, false, PostIndex(8))),
+            ),
+            (
+                0xA9BF7BFD,
+                "stp x29, x30, [sp, #-16]!",
+                StorePair(pair(29, 30, 31, 3, false, true, PreIndex(-16))),
+            ),
+            (
+                0x69402127,
+                "ldpsw x7, x8, [x9]",
+                LoadPair(pair(7, 8, 9, 2, true, true, Offset)),
+            ),
+            (
+                0xA8402D8A,
+                "ldnp x10, x11, [x12]",
+                LoadPair(pair(10, 11, 12, 3, false, true, Offset)),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn simd_load_store() {
+        use A64MemoryInstruction::{SimdLoad, SimdLoadPair, SimdStore, SimdStorePair};
+        use AddressingMode::*;
+        const Q: u8 = ACCESS_SIZE_128BIT;
+        check(&[
+            (
+                0x3DC00420,
+                "ldr q0, [x1, #16]",
+                SimdLoad(register(0, 1, Q, false, true, Offset)),
+            ),
+            (
+                0xFC008462,
+                "str d2, [x3], #8",
+                SimdStore(register(2, 3, 3, false, true, PostIndex(8))),
+            ),
+            (
+                0xBC5FCCA4,
+                "ldr s4, [x5, #-4]!",
+                SimdLoad(register(4, 5, 2, false, true, PreIndex(-4))),
+            ),
+            (
+                0x3D4000E6,
+                "ldr b6, [x7]",
+                SimdLoad(register(6, 7, 0, false, true, Offset)),
+            ),
+            (
+                0x7D000528,
+                "str h8, [x9, #2]",
+                SimdStore(register(8, 9, 1, false, true, Offset)),
+            ),
+            (
+                0x9C00010A,
+                "ldr q10, #0x20",
+                SimdLoad(register(10, 0, Q, false, true, Literal)),
+            ),
+            (
+                0x3CED798B,
+                "ldr q11, [x12, x13, lsl #4]",
+                SimdLoad(register(11, 12, Q, false, true, Offset)),
+            ),
+            (
+                0xAD410440,
+                "ldp q0, q1, [x2, #32]",
+                SimdLoadPair(pair(0, 1, 2, Q, false, true, Offset)),
+            ),
+            (
+                0x6DBF10A3,
+                "stp d3, d4, [x5, #-16]!",
+                SimdStorePair(pair(3, 4, 5, 3, false, true, PreIndex(-16))),
+            ),
+            (
+                0x2CC11D06,
+                "ldp s6, s7, [x8], #8",
+                SimdLoadPair(pair(6, 7, 8, 2, false, false, PostIndex(8))),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn atomic_memory_operation() {
+        use A64MemoryInstruction::{AtomicMemoryOperation, CompareAndSwap, LoadAcquire};
+        use AtomicOperation::*;
+        check(&[
+            (
+                0xF8200041,
+                "ldadd x0, x1, [x2]",
+                AtomicMemoryOperation(atomic(Add, 0, 1, 3, false, false)),
+            ),
+            (
+                0xB8E300A4,
+                "ldaddal w3, w4, [x5]",
+                AtomicMemoryOperation(atomic(Add, 3, 4, 2, true, true)),
+            ),
+            (
+                0x38261107,
+                "ldclrb w6, w7, [x8]",
+                AtomicMemoryOperation(atomic(Clear, 6, 7, 0, false, false)),
+            ),
+            (
+                0x7829216A,
+                "ldeorh w9, w10, [x11]",
+                AtomicMemoryOperation(atomic(ExclusiveOr, 9, 10, 1, false, false)),
+            ),
+            (
+                0xF86C31CD,
+                "ldsetl x12, x13, [x14]",
+                AtomicMemoryOperation(atomic(Set, 12, 13, 3, false, true)),
+            ),
+            (
+                0xB8AF4230,
+                "ldsmaxa w15, w16, [x17]",
+                AtomicMemoryOperation(atomic(SignedMax, 15, 16, 2, true, false)),
+            ),
+            (
+                0xF8327293,
+                "ldumin x18, x19, [x20]",
+                AtomicMemoryOperation(atomic(UnsignedMin, 18, 19, 3, false, false)),
+            ),
+            (
+                0xF8F582F6,
+                "swpal x21, x22, [x23]",
+                AtomicMemoryOperation(atomic(Swap, 21, 22, 3, true, true)),
+            ),
+            (
+                0xC8A07C41,
+                "cas x0, x1, [x2]",
+                CompareAndSwap(CompareAndSwapAccess {
+                    source_register: 0,
+                    target_register: 1,
+                    size: 3,
+                    is_pair: false,
+                    is_acquire: false,
+                    is_release: false,
+                }),
+            ),
+            (
+                0x88E3FCA4,
+                "casal w3, w4, [x5]",
+                CompareAndSwap(CompareAndSwapAccess {
+                    source_register: 3,
+                    target_register: 4,
+                    size: 2,
+                    is_pair: false,
+                    is_acquire: true,
+                    is_release: true,
+                }),
+            ),
+            (
+                0x48267D48,
+                "casp x6, x7, x8, x9, [x10]",
+                CompareAndSwap(CompareAndSwapAccess {
+                    source_register: 6,
+                    target_register: 8,
+                    size: 3,
+                    is_pair: true,
+                    is_acquire: false,
+                    is_release: false,
+                }),
+            ),
+            (
+                0xF8BFC18B,
+                "ldapr x11, [x12]",
+                LoadAcquire(register(11, 12, 3, false, true, AddressingMode::Offset)),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn exclusive_and_ordered() {
+        use A64MemoryInstruction::*;
+        use AddressingMode::Offset;
+        check(&[
+            (
+                0xC85F7C20,
+                "ldxr x0, [x1]",
+                LoadExclusive(exclusive(31, 0, None, 3, false)),
+            ),
+            (
+                0x885FFC62,
+                "ldaxr w2, [x3]",
+                LoadExclusive(exclusive(31, 2, None, 2, true)),
+            ),
+            (
+                0xC8047CC5,
+                "stxr w4, x5, [x6]",
+                StoreExclusive(exclusive(4, 5, None, 3, false)),
+            ),
+            (
+                0x0807FD28,
+                "stlxrb w7, w8, [x9]",
+                StoreExclusive(exclusive(7, 8, None, 0, true)),
+            ),
+            (
+                0xC87F2D8A,
+                "ldxp x10, x11, [x12]",
+                LoadExclusive(exclusive(31, 10, Some(11), 3, false)),
+            ),
+            (
+                0xC82DBE0E,
+                "stlxp w13, x14, x15, [x16]",
+                StoreExclusive(exclusive(13, 14, Some(15), 3, true)),
+            ),
+            (
+                0xC8DFFE51,
+                "ldar x17, [x18]",
+                LoadAcquire(register(17, 18, 3, false, true, Offset)),
+            ),
+            (
+                0x889FFE93,
+                "stlr w19, [x20]",
+                StoreRelease(register(19, 20, 2, false, false, Offset)),
+            ),
+            (
+                0x08DF7ED5,
+                "ldlarb w21, [x22]",
+                LoadAcquire(register(21, 22, 0, false, false, Offset)),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn unsupported() {
+        for (instruction, assembly) in [
+            (0x8B020020u32, "add x0, x1, x2"),
+            (0xF8200420, "ldraa x0, [x1]"),
+            (0x4C407020, "ld1 {v0.16b}, [x1]"),
+        ] {
+            assert_eq!(
+                A64MemoryInstruction::try_from(instruction),
+                Err(()),
+                "{:#010X}: {}",
+                instruction,
+                assembly
+            );
+        }
+    }
+
+    #[test]
+    fn is_store() {
+        for (instruction, expected) in [
+            (0xF9400441u32, false), /* ldr x1, [x2, #8] */
+            (0xF9000441, true),     /* str x1, [x2, #8] */
+            (0xA9BF7BFD, true),     /* stp x29, x30, [sp, #-16]! */
+            (0x3DC00420, false),    /* ldr q0, [x1, #16] */
+            (0xF8200041, true),     /* ldadd x0, x1, [x2] */
+            (0xC8A07C41, true),     /* cas x0, x1, [x2] */
+            (0xC85F7C20, false),    /* ldxr x0, [x1] */
+            (0xC8047CC5, true),     /* stxr w4, x5, [x6] */
+            (0x889FFE93, true),     /* stlr w19, [x20] */
+        ] {
+            assert_eq!(
+                A64MemoryInstruction::try_from(instruction).map(|i| i.is_store()),
+                Ok(expected),
+                "{:#010X}",
+                instruction
+            );
+        }
+    }
+}
@@ -5,24 +5,30 @@
 // This software is released under the MIT License.
 // http://opensource.org/licenses/mit-license.php
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(let_chains)]
 
 pub mod acpi;
+#[cfg(target_arch = "aarch64")]
 pub mod cpu;
+pub mod crc32;
+pub mod instruction;
 #[cfg(feature = "advanced_memory_manager")]
 pub mod memory_allocator;
+#[cfg(target_arch = "aarch64")]
 pub mod paging;
 pub mod serial_port;
+#[cfg(target_arch = "aarch64")]
 pub mod smmu;
+pub mod snapshot_file;
 pub mod spin_flag;
-#[cfg(not(feature = "advanced_memory_manager"))]
+#[cfg(all(target_arch = "aarch64", not(feature = "advanced_memory_manager")))]
 pub mod stack_memory_allocator;
 
 #[cfg(feature = "advanced_memory_manager")]
 pub use memory_allocator::MemoryAllocator;
 
-#[cfg(not(feature = "advanced_memory_manager"))]
+#[cfg(all(target_arch = "aarch64", not(feature = "advanced_memory_manager")))]
 pub use stack_memory_allocator::MemoryAllocator;
 
 use crate::serial_port::SerialPortInfo;
@@ -35,6 +41,8 @@ pub const HYPERVISOR_HASH_INFO: Option<&'static str> = if let Some(s) = option_e
 pub const COMPILER_INFO: Option<&'static str> =if let Some(s) = option_env!("RUSTC_VERSION") && s.len() != 0 {Some(s)}else{None};
 /// The path of hypervisor_kernel
 pub const HYPERVISOR_PATH: &'static str = "\\EFI\\BOOT\\hypervisor_kernel";
+/// The path of the snapshot file of fast restore
+pub const SNAPSHOT_FILE_PATH: &'static str = "\\EFI\\BOOT\\hypervisor_snapshot";
 /// The path of hypervisor_kernel of tftp
 pub const HYPERVISOR_TFTP_PATH: &'static str = "/uefi/hypervisor_kernel";
 /// The path of payload uefi application
@@ -103,4 +111,8 @@ pub struct SystemInformation {
     pub ecam_info: Option<EcamInfo>,
     pub smmu_v3_base_address: Option<usize>,
     pub exit_boot_service_address: usize,
+    /// MPIDR_EL1 of each CPU node in DTB, it is used when ACPI is not available
+    pub dtb_cpu_list: Option<*const [u64]>,
+    /// The snapshot file loaded by hypervisor_bootloader, hypervisor_kernel sets None if it is not used
+    pub snapshot_image: Option<(usize /* address */, usize /* size */)>,
 }
@@ -20,6 +20,7 @@ pub const PAGE_TABLE_SIZE: usize = 0x1000;
 
 pub const PAGE_DESCRIPTORS_UPPER_ATTRIBUTES_OFFSET: u64 = 50;
 pub const PAGE_DESCRIPTORS_CONTIGUOUS: u64 = 1 << 52;
+pub const PAGE_DESCRIPTORS_DBM: u64 = 1 << 51;
 pub const PAGE_DESCRIPTORS_NX_BIT_OFFSET: u64 = 54;
 
 pub const PAGE_DESCRIPTORS_NT: u64 = 1 << 16;
@@ -28,6 +29,7 @@ pub const PAGE_DESCRIPTORS_AF: u64 = 1 << PAGE_DESCRIPTORS_AF_BIT_OFFSET;
 pub const PAGE_DESCRIPTORS_SH_BITS_OFFSET: u64 = 8;
 pub const PAGE_DESCRIPTORS_SH_INNER_SHAREABLE: u64 = 0b11 << PAGE_DESCRIPTORS_SH_BITS_OFFSET;
 pub const PAGE_DESCRIPTORS_AP_BITS_OFFSET: u64 = 6;
+pub const PAGE_DESCRIPTORS_S2AP_WRITE: u64 = 1 << (PAGE_DESCRIPTORS_AP_BITS_OFFSET + 1);
 
 pub const MEMORY_PERMISSION_READABLE_BIT: u8 = 0;
 pub const MEMORY_PERMISSION_WRITABLE_BIT: u8 = 1;
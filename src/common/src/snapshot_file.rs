@@ -0,0 +1,207 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Snapshot File Format of Fast Restore
+//!
+//! The snapshot is stored into one file, all offsets are from the top of the file.
+//!
+//! | Contents                                               |
+//! |--------------------------------------------------------|
+//! | [`SnapshotFileHeader`]                                 |
+//! | [`SnapshotFileRegion`] x `number_of_regions`           |
+//! | Context (The registers, defined by hypervisor_kernel)  |
+//! | [`SnapshotFileChunk`] table of each region             |
+//! | Compressed data of each chunk                          |
+//!
+//! Each region is divided into chunks of `chunk_pages` pages(the last one may be smaller).
+//! The header, the region table and the context are protected by [`SnapshotFileHeader::crc32`],
+//! and each chunk data is protected by [`SnapshotFileChunk::crc32`].
+//!
+
+use crate::crc32::{calculate_crc32, update_crc32};
+use crate::PAGE_SIZE;
+
+use core::mem::{align_of, size_of};
+
+pub const SNAPSHOT_FILE_MAGIC: [u8; 8] = *b"MVSNAPSH";
+/// The version of the file format, must be changed when the layout including the context is changed
+pub const SNAPSHOT_FILE_VERSION: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotFileHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub header_size: u32,
+    pub file_size: u64,
+    pub chunk_pages: u64,
+    pub number_of_regions: u64,
+    pub region_table_offset: u64,
+    pub context_offset: u64,
+    pub context_size: u64,
+    /// CRC-32 of the header(this field is treated as zero), the region table, and the context
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotFileRegion {
+    pub memory_start: u64,
+    pub number_of_pages: u64,
+    /// The offset of [`SnapshotFileChunk`] array of this region
+    pub chunk_table_offset: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotFileChunk {
+    pub data_offset: u64,
+    pub data_size: u64,
+    /// CRC-32 of the compressed data
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+impl SnapshotFileRegion {
+    pub const fn get_number_of_chunks(&self, chunk_pages: u64) -> usize {
+        ((self.number_of_pages + chunk_pages - 1) / chunk_pages) as usize
+    }
+}
+
+/// Check if `image[offset..(offset + size)]` is available for `T`
+fn is_valid_range<T>(image: &[u8], offset: u64, size: u64) -> bool {
+    let Some(end) = offset.checked_add(size) else {
+        return false;
+    };
+    end <= image.len() as u64
+        && ((image.as_ptr() as usize + offset as usize) % align_of::<T>()) == 0
+}
+
+fn get_slice<T>(image: &[u8], offset: u64, length: usize) -> &[T] {
+    unsafe { core::slice::from_raw_parts(image.as_ptr().add(offset as usize) as *const T, length) }
+}
+
+/// Get the header of the snapshot file
+///
+/// # Result
+/// If `image` is too short or the header is not supported, returns Err(())
+pub fn get_header(image: &[u8]) -> Result<&SnapshotFileHeader, ()> {
+    if !is_valid_range::<SnapshotFileHeader>(image, 0, size_of::<SnapshotFileHeader>() as u64) {
+        return Err(());
+    }
+    let header = &get_slice::<SnapshotFileHeader>(image, 0, 1)[0];
+    if header.magic != SNAPSHOT_FILE_MAGIC
+        || header.version != SNAPSHOT_FILE_VERSION
+        || header.header_size as usize != size_of::<SnapshotFileHeader>()
+        || header.chunk_pages == 0
+        || header.chunk_pages > u32::MAX as u64
+    {
+        return Err(());
+    }
+    return Ok(header);
+}
+
+/// Get the region table, `image` must be validated by [`validate_snapshot_image`]
+pub fn get_regions(image: &[u8]) -> &[SnapshotFileRegion] {
+    let header = get_header(image).unwrap();
+    get_slice(
+        image,
+        header.region_table_offset,
+        header.number_of_regions as usize,
+    )
+}
+
+/// Get the chunk table of `region`, `image` must be validated by [`validate_snapshot_image`]
+pub fn get_chunks<'a>(image: &'a [u8], region: &SnapshotFileRegion) -> &'a [SnapshotFileChunk] {
+    let header = get_header(image).unwrap();
+    get_slice(
+        image,
+        region.chunk_table_offset,
+        region.get_number_of_chunks(header.chunk_pages),
+    )
+}
+
+/// Get the context, `image` must be validated by [`validate_snapshot_image`]
+pub fn get_context(image: &[u8]) -> &[u8] {
+    let header = get_header(image).unwrap();
+    &image
+        [(header.context_offset as usize)..((header.context_offset + header.context_size) as usize)]
+}
+
+/// Calculate [`SnapshotFileHeader::crc32`]
+///
+/// The header, the region table, and the context must be in `image`.
+pub fn calculate_header_crc32(image: &[u8]) -> u32 {
+    let mut header = *get_header(image).unwrap();
+    header.crc32 = 0;
+    let header_bytes = unsafe {
+        core::slice::from_raw_parts(
+            &header as *const _ as *const u8,
+            size_of::<SnapshotFileHeader>(),
+        )
+    };
+    let crc = calculate_crc32(header_bytes);
+    let crc = update_crc32(
+        crc,
+        &image[(header.region_table_offset as usize)
+            ..(header.region_table_offset as usize
+                + header.number_of_regions as usize * size_of::<SnapshotFileRegion>())],
+    );
+    update_crc32(crc, get_context(image))
+}
+
+/// Check the structure of the snapshot file
+///
+/// This checks the header, CRC-32 of the header, and the ranges of all tables and chunks.
+/// CRC-32 of each chunk is not checked.
+///
+/// # Result
+/// If `image` is valid, returns Ok(()), otherwise Err(())
+pub fn validate_snapshot_image(image: &[u8]) -> Result<(), ()> {
+    let header = get_header(image)?;
+    if header.file_size != image.len() as u64
+        || !is_valid_range::<SnapshotFileRegion>(
+            image,
+            header.region_table_offset,
+            header
+                .number_of_regions
+                .checked_mul(size_of::<SnapshotFileRegion>() as u64)
+                .ok_or(())?,
+        )
+        || !is_valid_range::<u8>(image, header.context_offset, header.context_size)
+    {
+        return Err(());
+    }
+    if calculate_header_crc32(image) != header.crc32 {
+        return Err(());
+    }
+    for region in get_regions(image) {
+        if region.number_of_pages == 0
+            || region
+                .number_of_pages
+                .checked_mul(PAGE_SIZE as u64)
+                .and_then(|size| region.memory_start.checked_add(size))
+                .is_none()
+            || !is_valid_range::<SnapshotFileChunk>(
+                image,
+                region.chunk_table_offset,
+                (region.get_number_of_chunks(header.chunk_pages) * size_of::<SnapshotFileChunk>())
+                    as u64,
+            )
+        {
+            return Err(());
+        }
+        for chunk in get_chunks(image, region) {
+            if !is_valid_range::<u8>(image, chunk.data_offset, chunk.data_size) {
+                return Err(());
+            }
+        }
+    }
+    return Ok(());
+}
@@ -540,6 +540,25 @@ impl DtbNodeNameSearchHolder {
         return Ok(result);
     }
 
+    /// Search the next node named `node_name` in the node which made this holder
+    ///
+    /// Unlike [`Self::search_next_device_by_node_name`], this does not search the nodes after
+    /// the end of the node. After this returns Ok(None), this must not be called again.
+    pub fn search_next_child_by_node_name(
+        &mut self,
+        node_name: &[u8],
+        dtb: &DtbAnalyser,
+    ) -> Result<Option<DtbNode>, ()> {
+        let result =
+            self.node
+                .__search_device_by_node_name(node_name, dtb, &mut self.pointer, false)?;
+        if let Some(t) = &result {
+            self.pointer = t.base_pointer;
+            DtbNode::skip_to_end_of_node(&mut self.pointer)?;
+        }
+        return Ok(result);
+    }
+
     pub fn search_next_device_by_compatible(
         &mut self,
         compatible_devices: &[&[u8]],
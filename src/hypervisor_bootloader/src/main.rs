@@ -48,6 +48,9 @@ static mut MEMORY_ALLOCATOR: MaybeUninit<MemoryAllocator> = MaybeUninit::uninit(
 
 #[cfg(feature = "tftp")]
 static mut PXE_PROTOCOL: *const pxe::EfiPxeBaseCodeProtocol = core::ptr::null();
+/// True if hypervisor_kernel imported the snapshot file, el1_main requests to restore it
+#[cfg(all(feature = "fast_restore", not(feature = "tftp")))]
+static mut IS_SNAPSHOT_IMPORTED: bool = false;
 
 #[no_mangle]
 extern "C" fn efi_main(image_handle: EfiHandle, system_table: *mut EfiSystemTable) {
@@ -122,10 +125,21 @@ extern "C" fn efi_main(image_handle: EfiHandle, system_table: *mut EfiSystemTabl
     #[cfg(not(feature = "smmu"))]
     let smmu_v3_base_address = None;
 
+    /* The CPUs are listed by MADT if ACPI is available */
+    let dtb_cpu_list = if unsafe { ACPI_20_TABLE_ADDRESS }.is_none() {
+        unsafe { DTB_ADDRESS }.and_then(detect_cpu_list_from_dtb)
+    } else {
+        None
+    };
+
     /* Stack for BSP */
     let stack_address = allocate_memory(STACK_PAGES, None).expect("Failed to alloc stack")
         + (STACK_PAGES << PAGE_SHIFT);
     let memory_save_list = create_memory_save_list();
+    #[cfg(all(feature = "fast_restore", not(feature = "tftp")))]
+    let snapshot_image = load_snapshot_file(memory_save_list);
+    #[cfg(not(all(feature = "fast_restore", not(feature = "tftp"))))]
+    let snapshot_image = None;
 
     println!("Call the hypervisor(Entry Point: {:#X})", entry_point);
     let mut system_info = SystemInformation {
@@ -139,8 +153,14 @@ extern "C" fn efi_main(image_handle: EfiHandle, system_table: *mut EfiSystemTabl
         exit_boot_service_address: unsafe {
             (*(*SYSTEM_TABLE).efi_boot_services).exit_boot_services
         } as usize,
+        dtb_cpu_list: dtb_cpu_list.map(|l| l as *const [u64]),
+        snapshot_image,
     };
     unsafe { (transmute::<usize, HypervisorKernelMainType>(entry_point))(&mut system_info) };
+    #[cfg(all(feature = "fast_restore", not(feature = "tftp")))]
+    unsafe {
+        IS_SNAPSHOT_IMPORTED = system_info.snapshot_image.is_some()
+    };
 
     /* Do not call allocate_memory/free_memory from here */
 
@@ -185,6 +205,65 @@ fn detect_acpi_and_dtb() {
     }
 }
 
+/// Call `f` with MPIDR_EL1 of each cpu node under "/cpus" of DTB
+///
+/// The nodes are not filtered by "status" because a disabled CPU can be started by PSCI CPU_ON.
+fn for_each_cpu_in_dtb(dtb_analyser: &dtb::DtbAnalyser, mut f: impl FnMut(u64)) -> Result<(), ()> {
+    let Some(cpus) = dtb_analyser
+        .get_root_node()
+        .get_search_holder()?
+        .search_next_device_by_node_name(b"cpus", dtb_analyser)? else {
+        return Err(());
+    };
+    let mut search_holder = cpus.get_search_holder()?;
+    while let Some(cpu) = search_holder.search_next_child_by_node_name(b"cpu", dtb_analyser)? {
+        /* "reg" of cpu nodes is MPIDR_EL1 */
+        f(cpu.get_offset() as u64);
+    }
+    return Ok(());
+}
+
+/// Make the list of MPIDR_EL1 of all CPUs from DTB
+///
+/// The list is allocated from the memory pool, and hypervisor_kernel uses it to track the power
+/// state of each CPU when MADT is not available.
+///
+/// # Arguments
+/// * `dtb_address` - The address of DTB
+///
+/// # Result
+/// If the cpu nodes are found, returns Some(list), otherwise None
+fn detect_cpu_list_from_dtb(dtb_address: usize) -> Option<&'static [u64]> {
+    let Ok(dtb_analyser) = dtb::DtbAnalyser::new(dtb_address) else {
+        println!("Invalid DTB");
+        return None;
+    };
+    let mut number_of_cpus = 0;
+    if for_each_cpu_in_dtb(&dtb_analyser, |_| number_of_cpus += 1).is_err() || number_of_cpus == 0 {
+        println!("Failed to find the cpu nodes in the DTB");
+        return None;
+    }
+    let list = unsafe {
+        core::slice::from_raw_parts_mut(
+            allocate_memory(
+                common::paging::page_align_up(number_of_cpus * core::mem::size_of::<u64>())
+                    >> PAGE_SHIFT,
+                None,
+            )
+            .expect("Failed to allocate memory for CPU list") as *mut u64,
+            number_of_cpus,
+        )
+    };
+    let mut index = 0;
+    for_each_cpu_in_dtb(&dtb_analyser, |mpidr| {
+        list[index] = mpidr;
+        index += 1;
+    })
+    .expect("Failed to analysis the DTB");
+    pr_debug!("CPUs in the DTB: {:#X?}", list);
+    return Some(list);
+}
+
 /// Allocate memory and setup [`MEMORY_ALLOCATOR`]
 ///
 /// This function allocates [`ALLOC_SIZE`] and then, set it into [`MEMORY_ALLOCATOR`]
@@ -656,6 +735,148 @@ fn create_memory_save_list() -> &'static mut [MemorySaveListEntry] {
     return list;
 }
 
+/// Load the snapshot file of fast restore from [`common::SNAPSHOT_FILE_PATH`]
+///
+/// This bootloader does not create the file because the boot snapshot is taken at
+/// ExitBootServices, after this bootloader exits and the file services of UEFI are gone.
+/// The file is written by the guest OS with the snapshot exported by hypervisor_kernel,
+/// `tools/snapshot_exporter` is the reference implementation for Linux.
+/// The file is loaded into the memory pool and validated by
+/// [`common::snapshot_file::validate_snapshot_image`].
+/// Each saved region must be covered by `memory_save_list` because the memory map may be
+/// changed from the boot which exported the snapshot.
+///
+/// # Arguments
+/// * `memory_save_list` - The list made by [`create_memory_save_list`]
+///
+/// # Result
+/// If the file is loaded and valid, returns Some((address, size)), otherwise None
+#[cfg(all(feature = "fast_restore", not(feature = "tftp")))]
+fn load_snapshot_file(memory_save_list: &[MemorySaveListEntry]) -> Option<(usize, usize)> {
+    use common::snapshot_file::SnapshotFileHeader;
+
+    let image_handle = unsafe { IMAGE_HANDLE };
+    let b_s = unsafe { (*SYSTEM_TABLE).efi_boot_services };
+    let root_protocol = file::open_root_dir(image_handle, b_s).expect("Failed to open the volume");
+    let mut file_name_utf16: [u16; SNAPSHOT_FILE_PATH.len() + 1] =
+        [0; SNAPSHOT_FILE_PATH.len() + 1];
+
+    for (i, e) in SNAPSHOT_FILE_PATH.encode_utf16().enumerate() {
+        file_name_utf16[i] = e;
+    }
+    let Ok(snapshot_protocol) = file::open_file(root_protocol, &file_name_utf16) else {
+        pr_debug!("The snapshot file is not found");
+        if let Err(e) = file::close_file(root_protocol) {
+            println!("Failed to clone the RootProtocol: {:?}", e);
+        }
+        return None;
+    };
+
+    let is_covered_by_memory_save_list = |start: usize, number_of_pages: usize| -> bool {
+        let end = start + (number_of_pages << PAGE_SHIFT);
+        let mut address = start;
+        while address < end {
+            let Some(e) = memory_save_list
+                .iter()
+                .take_while(|e| !(e.memory_start == 0 && e.num_of_pages == 0))
+                .find(|e| {
+                    (e.memory_start..(e.memory_start + ((e.num_of_pages as usize) << PAGE_SHIFT)))
+                        .contains(&address)
+                })
+            else {
+                return false;
+            };
+            address = e.memory_start + ((e.num_of_pages as usize) << PAGE_SHIFT);
+        }
+        true
+    };
+
+    let result = (|| {
+        let mut header: MaybeUninit<SnapshotFileHeader> = MaybeUninit::uninit();
+        const HEADER_SIZE: usize = core::mem::size_of::<SnapshotFileHeader>();
+        let read_size = file::read(
+            snapshot_protocol,
+            header.as_mut_ptr() as *mut u8,
+            HEADER_SIZE,
+        )
+        .or(Err("Failed to read the header"))?;
+        if read_size != HEADER_SIZE {
+            return Err("The file is too short");
+        }
+        let header = snapshot_file::get_header(unsafe {
+            core::slice::from_raw_parts(header.as_ptr() as *const u8, HEADER_SIZE)
+        })
+        .or(Err("The header is not supported"))?;
+        let file_size = header.file_size as usize;
+        let pages = (file_size + PAGE_SIZE - 1) >> PAGE_SHIFT;
+        let image_address =
+            allocate_memory(pages, None).or(Err("Failed to allocate memory for the file"))?;
+        let image = unsafe { core::slice::from_raw_parts(image_address as *const u8, file_size) };
+
+        let result = (|| {
+            file::seek(snapshot_protocol, 0).or(Err("Failed to seek"))?;
+            let read_size = file::read(snapshot_protocol, image_address as *mut u8, file_size)
+                .or(Err("Failed to read the file"))?;
+            if read_size != file_size {
+                return Err("The file is too short");
+            }
+            snapshot_file::validate_snapshot_image(image).or(Err("The file is broken"))?;
+            for r in snapshot_file::get_regions(image) {
+                if !is_covered_by_memory_save_list(
+                    r.memory_start as usize,
+                    r.number_of_pages as usize,
+                ) {
+                    pr_debug!("{:#X?} is not in the memory save list", r);
+                    return Err("The memory map is changed");
+                }
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            if let Err(e) = free_memory(image_address, pages) {
+                println!("Failed to free memory for the file: {:?}", e);
+            }
+        }
+        result.and(Ok((image_address, file_size)))
+    })();
+
+    if let Err(e) = file::close_file(snapshot_protocol) {
+        println!("Failed to clone the SnapshotProtocol: {:?}", e);
+    }
+    if let Err(e) = file::close_file(root_protocol) {
+        println!("Failed to clone the RootProtocol: {:?}", e);
+    }
+    match result {
+        Ok(image) => {
+            println!("Loaded the snapshot file({} Bytes)", image.1);
+            Some(image)
+        }
+        Err(e) => {
+            println!("Ignore the snapshot file: {}", e);
+            None
+        }
+    }
+}
+
+/// Request hypervisor_kernel to restore the boot snapshot imported from the snapshot file
+///
+/// This calls the snapshot operation of hypervisor_kernel
+/// (`HVC_SNAPSHOT_CALL`(0xFFF2), Restore(1), the name "boot").
+/// On success, this function does not return and the environment saved in the file is resumed.
+/// The memory areas in the snapshot, including UEFI boot services data, are overwritten.
+#[cfg(all(feature = "fast_restore", not(feature = "tftp")))]
+fn restore_snapshot_file() {
+    let name = u64::from_le_bytes(*b"boot\0\0\0\0");
+    let result: i64;
+    println!("Restore the snapshot file.");
+    unsafe {
+        asm!("hvc #0xFFF2",
+            inout("x0") 1u64 => result, inout("x1") name => _, inout("x2") 0u64 => _,
+            out("x3") _, out("x4") _)
+    };
+    println!("Failed to restore the snapshot file: {}", result);
+}
+
 #[allow(dead_code)]
 fn dump_memory_map() {
     let b_s = unsafe { (*SYSTEM_TABLE).efi_boot_services };
@@ -939,6 +1160,11 @@ extern "C" fn el1_main() -> ! {
     assert_eq!(get_current_el() >> 2, 1, "Failed to jump to EL1");
     println!("Hello,world! from EL1");
 
+    #[cfg(all(feature = "fast_restore", not(feature = "tftp")))]
+    if unsafe { IS_SNAPSHOT_IMPORTED } {
+        restore_snapshot_file();
+    }
+
     #[cfg(feature = "tftp")]
     let status = run_payload();
     #[cfg(not(feature = "tftp"))]
@@ -8,7 +8,10 @@
 //! ACPI Table Protection from store access
 //!
 
-use crate::memory_hook::{add_memory_store_hook_handler, StoreAccessHandlerEntry, StoreHookResult};
+use crate::memory_hook::{
+    add_memory_store_hook_handler, StoreAccessHandlerEntry, StoreHookResult,
+    MEMORY_HOOK_PRIORITY_HIGHEST,
+};
 use crate::paging::add_memory_access_trap;
 use crate::StoredRegisters;
 
@@ -74,12 +77,14 @@ fn register_acpi_table(table_address: usize, table_length: Option<u32>) {
 
     add_memory_access_trap(aligned_table_address, aligned_table_length, true, false)
         .expect("Failed to add memory trap");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    let mut entry = StoreAccessHandlerEntry::new(
         table_address,
         table_length as usize,
         acpi_table_store_handler,
-    ))
-    .expect("Failed to add ACPI table store handler");
+    );
+    /* Cancel the store access before other handlers see it */
+    entry.set_priority(MEMORY_HOOK_PRIORITY_HIGHEST);
+    add_memory_store_hook_handler(entry).expect("Failed to add ACPI table store handler");
     println!(
         "Protect {}({:#X}~{:#X}) from store access",
         core::str::from_utf8(unsafe { &*(table_address as *const [u8; 4]) }).unwrap_or("????"),
@@ -93,6 +98,7 @@ pub fn acpi_table_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     _data: u64,
+    _context: usize,
 ) -> Result<StoreHookResult, ()> {
     Ok(StoreHookResult::Cancel)
 }
@@ -14,19 +14,28 @@ use crate::memory_hook::{
     StoreAccessHandlerEntry, StoreHookResult,
 };
 use crate::pci::{get_configuration_space_data, get_ecam_target_address};
-use crate::{paging, StoredRegisters};
+use crate::{allocate_memory, paging, StoredRegisters};
 
 use common::{bitmask, PAGE_SIZE, STAGE_2_PAGE_MASK, STAGE_2_PAGE_SIZE};
 
 pub const VENDOR_ID: u16 = 0x8086;
 pub const DEVICE_ID: u16 = 0x1533;
 
-static mut EEPROM_BLOCK_BASE: u32 = 0;
-static mut EEPROM_BLOCK_END: u32 = 0;
-static mut IS_64BIT_BAR: bool = false;
-static mut CURRENT_MEMORY_BAR: usize = 0;
-static mut CURRENT_EXPANSION_ROM_BAR: usize = 0;
-static mut FLBAR_SIZE: usize = 1024 * 64;
+/// The state of each I210 device
+///
+/// The address of this struct is passed to the memory hook handlers as the context.
+struct I210Device {
+    eeprom_block_base: u32,
+    eeprom_block_end: u32,
+    is_64bit_bar: bool,
+    current_memory_bar: usize,
+    current_expansion_rom_bar: usize,
+    flbar_size: usize,
+}
+
+fn get_device(context: usize) -> &'static mut I210Device {
+    unsafe { &mut *(context as *mut I210Device) }
+}
 
 const FLASH_SECURITY_REGISTERS_BASE: usize = 0x12000;
 const EEWR: usize = 0x12018;
@@ -67,12 +76,26 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
     } else {
         println!("32bit BAR Mode");
     }
-    unsafe { IS_64BIT_BAR = is_64bit_bar };
 
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    assert!(core::mem::size_of::<I210Device>() <= PAGE_SIZE);
+    let context = allocate_memory(1, None).expect("Failed to allocate memory for I210");
+    unsafe {
+        *(context as *mut I210Device) = I210Device {
+            eeprom_block_base: 0,
+            eeprom_block_end: 0,
+            is_64bit_bar,
+            current_memory_bar: 0,
+            current_expansion_rom_bar: 0,
+            flbar_size: 1024 * 64,
+        }
+    };
+    let i210 = get_device(context);
+
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         get_ecam_target_address(ecam_address, bus, device, function) + 0x10,
         4 * 2,
         i210_pci_bar_address_store_handler,
+        context,
     ))
     .expect("Failed to add the handler for memory bar");
     paging::add_memory_access_trap(
@@ -91,8 +114,8 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
             0
         });
     println!("I210 Base Address Register: {:#X}", memory_bar);
-    unsafe { CURRENT_MEMORY_BAR = memory_bar };
-    setup_memory_trap(memory_bar);
+    i210.current_memory_bar = memory_bar;
+    setup_memory_trap(context, memory_bar);
     println!("Add I210 Ethernet Controller BAR Handler");
 
     /* Expansion ROM */
@@ -117,12 +140,14 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
             "Expansion ROM: {:#X}, FLBAR_SIZE: {:#X}",
             expansion_rom_bar, flbar_size
         );
-        unsafe { FLBAR_SIZE = flbar_size };
-        setup_expansion_rom_memory_trap(expansion_rom_bar);
-        add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+        i210.flbar_size = flbar_size;
+        i210.current_expansion_rom_bar = expansion_rom_bar;
+        setup_expansion_rom_memory_trap(context, expansion_rom_bar);
+        add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
             get_ecam_target_address(ecam_address, bus, device, function) + 0x30,
             4,
             i210_pci_expansion_rom_bar_address_store_handler,
+            context,
         ))
         .expect("Failed to add the handler for expansion rom bar");
     } else {
@@ -139,13 +164,11 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
         eeprom_block_end & bitmask!(10, 0),
         (eeprom_block_base & bitmask!(22, 12)) >> 12
     );
-    unsafe {
-        EEPROM_BLOCK_BASE = eeprom_block_base;
-        EEPROM_BLOCK_END = eeprom_block_end;
-    }
+    i210.eeprom_block_base = eeprom_block_base;
+    i210.eeprom_block_end = eeprom_block_end;
 }
 
-fn setup_memory_trap(new_memory_bar: usize) {
+fn setup_memory_trap(context: usize, new_memory_bar: usize) {
     pr_debug!("I210 Base Address Register: {:#X}", new_memory_bar);
     paging::map_address(
         new_memory_bar,
@@ -178,6 +201,7 @@ fn setup_memory_trap(new_memory_bar: usize) {
     for e in &I210_LOAD_HANDLERS {
         let mut e = e.clone();
         e.set_target_address(e.get_target_address() + new_memory_bar);
+        e.set_context(context);
         add_memory_load_hook_handler(e).expect("Failed to set up the load handler");
     }
 
@@ -185,11 +209,12 @@ fn setup_memory_trap(new_memory_bar: usize) {
     for e in &I210_STORE_HANDLERS {
         let mut e = e.clone();
         e.set_target_address(e.get_target_address() + new_memory_bar);
+        e.set_context(context);
         add_memory_store_hook_handler(e).expect("Failed to set up the store handler");
     }
 }
 
-fn remove_memory_trap(bar_address: usize) {
+fn remove_memory_trap(context: usize, bar_address: usize) {
     pr_debug!("Remove I210 Base Address Register Trap: {:#X}", bar_address);
 
     /* Remove the trap of registers' area */
@@ -208,6 +233,7 @@ fn remove_memory_trap(bar_address: usize) {
     for e in &I210_LOAD_HANDLERS {
         let mut e = e.clone();
         e.set_target_address(e.get_target_address() + bar_address);
+        e.set_context(context);
         remove_memory_load_hook_handler(e).expect("Failed to remove the load handler");
     }
 
@@ -215,28 +241,33 @@ fn remove_memory_trap(bar_address: usize) {
     for e in &I210_STORE_HANDLERS {
         let mut e = e.clone();
         e.set_target_address(e.get_target_address() + bar_address);
+        e.set_context(context);
         remove_memory_store_hook_handler(e).expect("Failed to remove the store handler");
     }
 }
 
-fn setup_expansion_rom_memory_trap(expansion_rom_bar: usize) {
-    paging::add_memory_access_trap(expansion_rom_bar, unsafe { FLBAR_SIZE }, true, false)
+fn setup_expansion_rom_memory_trap(context: usize, expansion_rom_bar: usize) {
+    let flbar_size = get_device(context).flbar_size;
+    paging::add_memory_access_trap(expansion_rom_bar, flbar_size, true, false)
         .expect("Failed to add the trap for Expansion ROM");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         expansion_rom_bar,
-        unsafe { FLBAR_SIZE },
+        flbar_size,
         i210_expansion_rom_store_handler,
+        context,
     ))
     .expect("Failed to add the handler for Expansion ROM");
 }
 
-fn remove_expansion_rom_memory_trap(expansion_rom_bar: usize) {
-    paging::remove_memory_access_trap(expansion_rom_bar, unsafe { FLBAR_SIZE })
+fn remove_expansion_rom_memory_trap(context: usize, expansion_rom_bar: usize) {
+    let flbar_size = get_device(context).flbar_size;
+    paging::remove_memory_access_trap(expansion_rom_bar, flbar_size)
         .expect("Failed to add the trap for Expansion ROM");
-    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         expansion_rom_bar,
-        unsafe { FLBAR_SIZE },
+        flbar_size,
         i210_expansion_rom_store_handler,
+        context,
     ))
     .expect("Failed to add the handler for Expansion ROM");
 }
@@ -246,21 +277,23 @@ fn i210_pci_bar_address_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let i210 = get_device(context);
     let offset = accessing_memory_address & 0xFFF;
     if offset == 0x10 {
         pr_debug!("Writing I210 BAR0: {:#X}", data);
     } else {
         pr_debug!("Writing I210 BAR1: {:#X}", data);
     }
-    if offset != 0x10 || !unsafe { IS_64BIT_BAR } {
+    if offset != 0x10 || !i210.is_64bit_bar {
         let new_bar = ((if offset == 0x10 {
             data & bitmask!(31, 17)
         } else {
             assert_eq!(offset, 0x14);
             (unsafe { *((accessing_memory_address - 0x04) as *const u32) } & bitmask!(31, 17))
                 as u64
-        }) | (if unsafe { IS_64BIT_BAR } {
+        }) | (if i210.is_64bit_bar {
             assert_eq!(offset, 0x14);
             data
         } else {
@@ -268,12 +301,12 @@ fn i210_pci_bar_address_store_handler(
         })) as usize;
         pr_debug!(
             "Change I210 BAR: {:#X} => {:#X}",
-            unsafe { CURRENT_MEMORY_BAR },
+            i210.current_memory_bar,
             new_bar
         );
-        remove_memory_trap(unsafe { CURRENT_MEMORY_BAR });
-        setup_memory_trap(new_bar);
-        unsafe { CURRENT_MEMORY_BAR = new_bar };
+        remove_memory_trap(context, i210.current_memory_bar);
+        setup_memory_trap(context, new_bar);
+        i210.current_memory_bar = new_bar;
     }
     return Ok(StoreHookResult::PassThrough);
 }
@@ -283,15 +316,18 @@ fn i210_pci_expansion_rom_bar_address_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let i210 = get_device(context);
     let new_expansion_rom_bar = (data & bitmask!(31, 11)) as usize;
     pr_debug!(
         "Change I210 Expansion ROM BAR: {:#X} => {:#X}",
-        unsafe { CURRENT_EXPANSION_ROM_BAR },
+        i210.current_expansion_rom_bar,
         new_expansion_rom_bar
     );
-    remove_expansion_rom_memory_trap(unsafe { CURRENT_EXPANSION_ROM_BAR });
-    setup_expansion_rom_memory_trap(new_expansion_rom_bar);
+    remove_expansion_rom_memory_trap(context, i210.current_expansion_rom_bar);
+    setup_expansion_rom_memory_trap(context, new_expansion_rom_bar);
+    i210.current_expansion_rom_bar = new_expansion_rom_bar;
     return Ok(StoreHookResult::PassThrough);
 }
 
@@ -301,6 +337,7 @@ fn i210_eeprom_write_register_load_handler(
     _access_size: u8,
     _is_64bit_register: bool,
     _is_sign_extend_required: bool,
+    _context: usize,
 ) -> Result<LoadHookResult, ()> {
     pr_debug!("EEPROM Write Register Load Access");
     let data: u64 = 1 << 1;
@@ -313,13 +350,15 @@ fn i210_eeprom_write_register_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let i210 = get_device(context);
     println!("EEPROM Write Register Store Access");
     let address = ((data & bitmask!(12, 2)) >> 2) as u32;
     pr_debug!("EEPROM Address: {:#X}, Data: {:#X}", address, data >> 16);
-    let eeprom_1st_block_end = unsafe { EEPROM_BLOCK_END & bitmask!(10, 0) };
-    let eeprom_1st_block_start = unsafe { EEPROM_BLOCK_BASE & bitmask!(10, 0) };
-    let eeprom_2nd_block_start = unsafe { (EEPROM_BLOCK_BASE & bitmask!(22, 12)) >> 12 };
+    let eeprom_1st_block_end = i210.eeprom_block_end & bitmask!(10, 0);
+    let eeprom_1st_block_start = i210.eeprom_block_base & bitmask!(10, 0);
+    let eeprom_2nd_block_start = (i210.eeprom_block_base & bitmask!(22, 12)) >> 12;
     if eeprom_1st_block_end != 0
         && (eeprom_1st_block_start..=eeprom_1st_block_end).contains(&address)
     {
@@ -335,10 +374,11 @@ fn i210_i_nvm_data_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
     println!(
         "iNVM Data Register Store Access: Offset: {:#X}, Data: {:#X}",
-        accessing_memory_address - unsafe { CURRENT_MEMORY_BAR } - I_NVM_DATA,
+        accessing_memory_address - get_device(context).current_memory_bar - I_NVM_DATA,
         data
     );
 
@@ -350,10 +390,11 @@ fn i210_i_flash_burst_registers_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
     println!(
         "iNVM Flash Burst Registers Store Access: Register: {}, Data: {:#X}",
-        if accessing_memory_address - unsafe { CURRENT_MEMORY_BAR } == FLSWCTL {
+        if accessing_memory_address - get_device(context).current_memory_bar == FLSWCTL {
             "FLSWCTL"
         } else {
             "FLSWDATA"
@@ -369,6 +410,7 @@ fn i210_expansion_rom_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     _data: u64,
+    _context: usize,
 ) -> Result<StoreHookResult, ()> {
     println!("i210 Expansion ROM Store Access");
     return Ok(StoreHookResult::Cancel);
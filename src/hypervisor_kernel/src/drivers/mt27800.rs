@@ -14,14 +14,24 @@ use crate::memory_hook::{
     LoadAccessHandlerEntry, LoadHookResult, StoreAccessHandlerEntry, StoreHookResult,
 };
 use crate::pci::{get_configuration_space_data, get_ecam_target_address};
-use crate::{paging, StoredRegisters};
+use crate::{allocate_memory, paging, StoredRegisters};
 
-use common::{bitmask, STAGE_2_PAGE_MASK, STAGE_2_PAGE_SIZE};
+use common::{bitmask, PAGE_SIZE, STAGE_2_PAGE_MASK, STAGE_2_PAGE_SIZE};
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-static mut CURRENT_EXPANSION_ROM_BAR: usize = 0;
-static mut EXPANSION_ROM_SIZE: usize = 0;
+/// The state of each MT27800 device
+///
+/// The address of this struct is passed to the memory hook handlers as the context.
+struct Mt27800Device {
+    current_expansion_rom_bar: usize,
+    expansion_rom_size: usize,
+    is_write_canceled: AtomicBool,
+}
+
+fn get_device(context: usize) -> &'static mut Mt27800Device {
+    unsafe { &mut *(context as *mut Mt27800Device) }
+}
 
 pub const VENDOR_ID: u16 = 0x15b3;
 pub const DEVICE_ID: u16 = 0x1017;
@@ -41,16 +51,29 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
     )
     .expect("Failed to setup memory trap.");
 
-    add_memory_load_hook_handler(LoadAccessHandlerEntry::new(
+    assert!(core::mem::size_of::<Mt27800Device>() <= PAGE_SIZE);
+    let context = allocate_memory(1, None).expect("Failed to allocate memory for MT27800");
+    unsafe {
+        *(context as *mut Mt27800Device) = Mt27800Device {
+            current_expansion_rom_bar: 0,
+            expansion_rom_size: 0,
+            is_write_canceled: AtomicBool::new(false),
+        }
+    };
+    let mt27800 = get_device(context);
+
+    add_memory_load_hook_handler(LoadAccessHandlerEntry::new_with_context(
         get_ecam_target_address(ecam_address, bus, device, function) + 0xD0,
         4 * 2,
         mt27800_address_and_data_load_handler,
+        context,
     ))
     .expect("Failed to add the handler for PCI configuration space");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         get_ecam_target_address(ecam_address, bus, device, function) + 0xD0,
         4 * 2,
         mt27800_address_and_data_store_handler,
+        context,
     ))
     .expect("Failed to add the handler for PCI configuration space");
 
@@ -69,12 +92,14 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
             true,
         )
         .expect("Failed to map Expansion ROM");
-        unsafe { EXPANSION_ROM_SIZE = 1024 * 1024 };
-        setup_expansion_rom_memory_trap(expansion_rom_bar);
-        add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+        mt27800.expansion_rom_size = 1024 * 1024;
+        mt27800.current_expansion_rom_bar = expansion_rom_bar;
+        setup_expansion_rom_memory_trap(context, expansion_rom_bar);
+        add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
             get_ecam_target_address(ecam_address, bus, device, function) + 0x30,
             4,
             mt27800_pci_expansion_rom_bar_address_store_handler,
+            context,
         ))
         .expect("Failed to add the handler for expansion rom bar");
     } else {
@@ -82,32 +107,36 @@ pub fn setup_device(ecam_address: usize, bus: u8, device: u8, function: u8) {
     }
 }
 
-fn setup_expansion_rom_memory_trap(expansion_rom_bar: usize) {
+fn setup_expansion_rom_memory_trap(context: usize, expansion_rom_bar: usize) {
     let aligned_bar = expansion_rom_bar & STAGE_2_PAGE_MASK;
-    let aligned_size = ((unsafe { EXPANSION_ROM_SIZE } + (expansion_rom_bar - aligned_bar) - 1)
-        & STAGE_2_PAGE_MASK)
-        + STAGE_2_PAGE_SIZE;
+    let aligned_size =
+        ((get_device(context).expansion_rom_size + (expansion_rom_bar - aligned_bar) - 1)
+            & STAGE_2_PAGE_MASK)
+            + STAGE_2_PAGE_SIZE;
     paging::add_memory_access_trap(aligned_bar, aligned_size, true, false)
         .expect("Failed to add the trap for Expansion ROM");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         aligned_bar,
         aligned_size,
         mt27800_expansion_rom_store_handler,
+        context,
     ))
     .expect("Failed to add the handler for Expansion ROM");
 }
 
-fn remove_expansion_rom_memory_trap(expansion_rom_bar: usize) {
+fn remove_expansion_rom_memory_trap(context: usize, expansion_rom_bar: usize) {
     let aligned_bar = expansion_rom_bar & STAGE_2_PAGE_MASK;
-    let aligned_size = ((unsafe { EXPANSION_ROM_SIZE } + (expansion_rom_bar - aligned_bar) - 1)
-        & STAGE_2_PAGE_MASK)
-        + STAGE_2_PAGE_SIZE;
+    let aligned_size =
+        ((get_device(context).expansion_rom_size + (expansion_rom_bar - aligned_bar) - 1)
+            & STAGE_2_PAGE_MASK)
+            + STAGE_2_PAGE_SIZE;
     paging::remove_memory_access_trap(aligned_bar, aligned_size)
         .expect("Failed to add the trap for Expansion ROM");
-    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new(
-        expansion_rom_bar,
+    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
+        aligned_bar,
         aligned_size,
         mt27800_expansion_rom_store_handler,
+        context,
     ))
     .expect("Failed to add the handler for Expansion ROM");
 }
@@ -117,15 +146,18 @@ fn mt27800_pci_expansion_rom_bar_address_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let mt27800 = get_device(context);
     let new_expansion_rom_bar = (data & bitmask!(31, 11)) as usize;
     pr_debug!(
         "Change MT27800 Expansion ROM BAR: {:#X} => {:#X}",
-        unsafe { CURRENT_EXPANSION_ROM_BAR },
+        mt27800.current_expansion_rom_bar,
         new_expansion_rom_bar
     );
-    remove_expansion_rom_memory_trap(unsafe { CURRENT_EXPANSION_ROM_BAR });
-    setup_expansion_rom_memory_trap(new_expansion_rom_bar);
+    remove_expansion_rom_memory_trap(context, mt27800.current_expansion_rom_bar);
+    setup_expansion_rom_memory_trap(context, new_expansion_rom_bar);
+    mt27800.current_expansion_rom_bar = new_expansion_rom_bar;
     return Ok(StoreHookResult::PassThrough);
 }
 
@@ -134,25 +166,29 @@ fn mt27800_expansion_rom_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     _data: u64,
+    _context: usize,
 ) -> Result<StoreHookResult, ()> {
     pr_debug!("MT27800 Expansion ROM Store Access");
     return Ok(StoreHookResult::Cancel);
 }
 
-static IS_WRITE_CANCELED: AtomicBool = AtomicBool::new(false);
-
 fn mt27800_address_and_data_load_handler(
     accessing_memory_address: usize,
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     _is_64bit_register: bool,
     _is_sign_extend_required: bool,
+    context: usize,
 ) -> Result<LoadHookResult, ()> {
     pr_debug!(
         "MT27800 PCI Configuration Space Address/Data Store: Address: {:#X}",
         accessing_memory_address
     );
-    if (accessing_memory_address & 0b100 == 0) && IS_WRITE_CANCELED.load(Ordering::Relaxed) {
+    if (accessing_memory_address & 0b100 == 0)
+        && get_device(context)
+            .is_write_canceled
+            .load(Ordering::Relaxed)
+    {
         return Ok(LoadHookResult::Data(0));
     }
     return Ok(LoadHookResult::PassThrough);
@@ -163,21 +199,23 @@ fn mt27800_address_and_data_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let is_write_canceled = &get_device(context).is_write_canceled;
     pr_debug!(
         "MT27800 PCI Configuration Space Address/Data Store: Address: {:#X}, Data: {:#X}",
         accessing_memory_address,
         data
     );
     if accessing_memory_address & 0b100 != 0 {
-        IS_WRITE_CANCELED.store(true, Ordering::Relaxed);
+        is_write_canceled.store(true, Ordering::Relaxed);
         return Ok(StoreHookResult::Cancel);
     } else if accessing_memory_address & 0b100 == 0 {
         if (data & (1 << 31)) != 0 {
-            IS_WRITE_CANCELED.store(true, Ordering::Relaxed);
+            is_write_canceled.store(true, Ordering::Relaxed);
             return Ok(StoreHookResult::Cancel);
         } else {
-            IS_WRITE_CANCELED.store(false, Ordering::Relaxed);
+            is_write_canceled.store(false, Ordering::Relaxed);
         }
     }
     return Ok(StoreHookResult::PassThrough);
@@ -9,26 +9,40 @@
 //! A64 Instructions' Emulator
 //!
 
+mod atomic;
+mod exclusive;
 mod load;
 mod store;
 
 pub use load::read_memory;
 pub use store::write_memory;
 
-use crate::{handler_panic, paging::map_address, StoredRegisters};
+use crate::memory_hook::{
+    memory_load_hook_handler, memory_store_hook_handler, LoadHookResult, StoreHookResult,
+};
+use crate::{paging::map_address, StoredRegisters};
 
 use common::cpu::{
-    convert_virtual_address_to_intermediate_physical_address_el0_read,
+    advance_elr_el2, convert_virtual_address_to_intermediate_physical_address_el0_read,
     convert_virtual_address_to_intermediate_physical_address_el1_read,
     convert_virtual_address_to_intermediate_physical_address_el1_write,
     convert_virtual_address_to_physical_address_el2_read,
-    convert_virtual_address_to_physical_address_el2_write, SPSR_EL2_M, SPSR_EL2_M_EL0T,
+    convert_virtual_address_to_physical_address_el2_write, ESR_EL2_ISS_AR, ESR_EL2_ISS_CM,
+    ESR_EL2_ISS_FNV, ESR_EL2_ISS_ISV, ESR_EL2_ISS_SAS, ESR_EL2_ISS_SAS_BITS_OFFSET, ESR_EL2_ISS_SF,
+    ESR_EL2_ISS_SRT, ESR_EL2_ISS_SRT_BITS_OFFSET, ESR_EL2_ISS_SSE, ESR_EL2_ISS_WNR, SPSR_EL2_M,
+    SPSR_EL2_M_EL0T,
 };
-use common::{bitmask, PAGE_MASK, PAGE_SIZE};
-
-use core::arch::asm;
+use common::instruction::{
+    A64MemoryInstruction, AddressingMode, RegisterAccess, REGISTER_NUMBER_XZR,
+};
+use common::spin_flag::SpinLockFlag;
+use common::{PAGE_MASK, PAGE_SIZE};
 
-const REGISTER_NUMBER_XZR: u8 = 31;
+/// The lock to serialize emulated memory accesses which must be observed atomically
+///
+/// Emulated atomic/exclusive instructions and emulated stores (which may clear exclusive monitors)
+/// take this lock.
+static EMULATED_MEMORY_ACCESS_LOCK: SpinLockFlag = SpinLockFlag::new();
 
 #[allow(unused_variables)]
 pub fn data_abort_handler(
@@ -39,37 +53,10 @@ pub fn data_abort_handler(
     hpfar: u64,
     spsr: u64,
 ) -> Result<(), ()> {
-    #[cfg(debug_assertions)]
-    if (esr & (1 << 24)) != 0 {
-        let sas = (esr >> 22) & 0b11;
-        match sas {
-            0b00 => println!("Byte(8Bits) Access"),
-            0b01 => println!("HalfWord(16Bits) Access"),
-            0b10 => println!("Word(32Bits) Access"),
-            0b11 => println!("DoubleWord(64Bits) Access"),
-            _ => unreachable!(),
-        }
-        if (esr & (1 << 21)) != 0 {
-            println!("Sign Extension is required.");
-        }
-        let srt = (esr >> 16) & 0b11111;
-        println!("SRT: {}", srt);
-        if (esr & (1 << 15)) != 0 {
-            println!("64Bit Register");
-        } else {
-            println!("32Bit Register");
-        }
-        if (esr & (1 << 10)) != 0 {
-            panic!("FAR is not valid");
-        }
-        if (esr & (1 << 6)) != 0 {
-            println!("Write Access");
-        } else {
-            println!("Read Access");
-        }
-    } else {
-        println!("No Valid Instruction Syndrome Information.");
+    if (esr & ESR_EL2_ISS_ISV) != 0 && (esr & (ESR_EL2_ISS_FNV | ESR_EL2_ISS_CM)) == 0 {
+        return emulate_instruction_with_syndrome(s_r, esr, far);
     }
+    pr_debug!("No Valid Instruction Syndrome Information.");
 
     let instruction_intermediate_physical_address = if (spsr & SPSR_EL2_M) == SPSR_EL2_M_EL0T {
         pr_debug!("Access from EL0");
@@ -90,130 +77,127 @@ pub fn data_abort_handler(
     emulate_instruction(s_r, target_instruction, elr, far, hpfar)
 }
 
+/// Emulate the load/store instruction by the instruction syndrome of ESR_EL2
+///
+/// ESR_EL2.ISV is set only for the single general-purpose register access without write back,
+/// therefore fetching and decoding the target instruction can be skipped.
+fn emulate_instruction_with_syndrome(
+    s_r: &mut StoredRegisters,
+    esr: u64,
+    far: u64,
+) -> Result<(), ()> {
+    let is_store = (esr & ESR_EL2_ISS_WNR) != 0;
+    let access = RegisterAccess {
+        target_register: ((esr & ESR_EL2_ISS_SRT) >> ESR_EL2_ISS_SRT_BITS_OFFSET) as u8,
+        base_register: 0, /* Unknown, but not used */
+        size: ((esr & ESR_EL2_ISS_SAS) >> ESR_EL2_ISS_SAS_BITS_OFFSET) as u8,
+        is_sign_extend_required: (esr & ESR_EL2_ISS_SSE) != 0,
+        is_64bit_register: (esr & ESR_EL2_ISS_SF) != 0,
+        addressing_mode: AddressingMode::Offset,
+    };
+    pr_debug!(
+        "Instruction Syndrome: {}, {:?}",
+        if is_store { "Write" } else { "Read" },
+        access
+    );
+
+    if is_store {
+        let intermediate_physical_address = faulting_va_to_ipa_store(far)?;
+        if (esr & ESR_EL2_ISS_AR) != 0 {
+            exclusive::emulate_store_release_register(s_r, &access, intermediate_physical_address)?;
+        } else {
+            store::emulate_store_register(s_r, &access, intermediate_physical_address)?;
+        }
+    } else {
+        let intermediate_physical_address = faulting_va_to_ipa_load(far)?;
+        if (esr & ESR_EL2_ISS_AR) != 0 {
+            exclusive::emulate_load_acquire_register(s_r, &access, intermediate_physical_address)?;
+        } else {
+            load::emulate_load_register(s_r, &access, intermediate_physical_address)?;
+        }
+    }
+    advance_elr_el2();
+    return Ok(());
+}
+
 fn emulate_instruction(
     s_r: &mut StoredRegisters,
     target_instruction: u32,
     _elr: u64,
     far: u64,
-    hpfar: u64,
+    _hpfar: u64,
 ) -> Result<(), ()> {
-    /* ARM DDI 0487G.a ID011921 C4-280 */
-    let op0 = ((target_instruction & bitmask!(28, 25)) >> 25) as u8;
-    if (op0 & 0b0101) != 0b0100 {
-        handler_panic!(s_r, "Not Load/Store Instruction: {:#X}", target_instruction);
+    let Ok(instruction) = A64MemoryInstruction::try_from(target_instruction) else {
+        println!("Unknown Instruction: {:#X}", target_instruction);
+        return Err(());
+    };
+    pr_debug!("{:?}", instruction);
+    if instruction == A64MemoryInstruction::Prefetch {
+        pr_debug!("Prefetch Memory Signals.");
+        advance_elr_el2();
+        return Ok(());
     }
-    let op1 = (op0 >> 1) & 1;
-    let op0 = ((target_instruction & bitmask!(31, 28)) >> 28) as u8;
-    let op2 = ((target_instruction & bitmask!(24, 23)) >> 23) as u8;
-    let op3 = ((target_instruction & bitmask!(21, 16)) >> 16) as u8;
-    let op4 = ((target_instruction & bitmask!(11, 10)) >> 10) as u8;
-    pr_debug!(
-        "op0: {:#b}, op1: {:#b}, op2: {:#b}, op3: {:#b}, op4: {:#b}",
-        op0,
-        op1,
-        op2,
-        op3,
-        op4
-    );
-    let op0_half_bottom = op0 & 0b11;
-    if op0_half_bottom == 0b11 {
-        pr_debug!("Load/Store Register");
-        if (op2 & 0b10) != 0 {
-            /* unsigned immediate (No post|pre indexing) */
-            if op1 != 0 {
-                /* V */
-                handler_panic!(s_r, "SIMD is not supported: {:#X}", target_instruction);
-            }
-            let opc = ((target_instruction & bitmask!(23, 22)) >> 22) as u8;
-            return if opc == 0b00 {
-                pr_debug!("STR(unsigned immediate)");
-                store::emulate_unsigned_immediate_store_register(
-                    s_r,
-                    target_instruction,
-                    far,
-                    hpfar,
-                )
-            } else {
-                pr_debug!("LDR(unsigned immediate)");
-                load::emulate_unsigned_immediate_load_register(s_r, target_instruction, far, hpfar)
-            };
-        } else if (op3 & 0b100000) != 0 {
-            match op4 {
-                0b00 => {
-                    println!("Atomic Operation")
-                }
-                0b10 => {
-                    pr_debug!("Load/Store Register Offset");
-                    if op1 != 0 {
-                        /* V */
-                        handler_panic!(s_r, "SIMD is not supported: {:#X}", target_instruction);
-                    }
-                    let opc = ((target_instruction & bitmask!(23, 22)) >> 22) as u8;
-                    return if opc == 0b00 {
-                        /* when implements SIMD, opc of SIMD-STR may not be 0b00 */
-                        pr_debug!("STR Register Offset");
-                        store::emulate_store_register_register_offset(
-                            s_r,
-                            target_instruction,
-                            far,
-                            hpfar,
-                        )
-                    } else {
-                        pr_debug!("LDR Register Offset");
-                        load::emulate_load_register_register_offset(
-                            s_r,
-                            target_instruction,
-                            far,
-                            hpfar,
-                        )
-                    };
-                }
-                _ => {
-                    println!("Load/Store pac")
-                }
-            }
-        } else {
-            if op1 != 0 {
-                /* V */
-                handler_panic!(s_r, "SIMD is not supported: {:#X}", target_instruction);
-            }
-            let opc = ((target_instruction & bitmask!(23, 22)) >> 22) as u8;
-            return if opc == 0b00 {
-                /* when implements SIMD, opc of SIMD-STR may not be 0b00 */
-                pr_debug!("STR");
-                store::emulate_store_register(s_r, target_instruction, far, hpfar)
-            } else {
-                pr_debug!("LDR");
-                load::emulate_load_register(s_r, target_instruction, far, hpfar)
-            };
+    let intermediate_physical_address = if instruction.is_store() {
+        faulting_va_to_ipa_store(far)?
+    } else {
+        faulting_va_to_ipa_load(far)?
+    };
+
+    match instruction {
+        A64MemoryInstruction::Load(access) => {
+            load::emulate_load_register(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
         }
-    } else if op0_half_bottom == 0b10 {
-        pr_debug!("Load/Store Register Pair");
-        if op1 != 0 {
-            /* V */
-            handler_panic!(s_r, "SIMD is not supported: {:#X}", target_instruction);
+        A64MemoryInstruction::Store(access) => {
+            store::emulate_store_register(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
         }
-        return if (target_instruction & (1 << 22)) != 0 {
-            pr_debug!("LDP");
-            load::emulate_load_pair(s_r, target_instruction, far, hpfar)
-        } else {
-            pr_debug!("STP");
-            store::emulate_store_pair(s_r, target_instruction, far, hpfar)
-        };
-    } else if op0_half_bottom == 0b01 {
-        {
-            if (op2 & (1 << 1)) == 0 {
-                pr_debug!("Load Register Literal");
-                if op1 != 0 {
-                    /* V */
-                    handler_panic!(s_r, "SIMD is not supported: {:#X}", target_instruction);
-                }
-                return load::emulate_literal_load_register(s_r, target_instruction, far, hpfar);
-            }
+        A64MemoryInstruction::LoadPair(access) => {
+            load::emulate_load_pair(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
+        }
+        A64MemoryInstruction::StorePair(access) => {
+            store::emulate_store_pair(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
+        }
+        A64MemoryInstruction::SimdLoad(access) => {
+            load::emulate_simd_load_register(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
         }
+        A64MemoryInstruction::SimdStore(access) => {
+            store::emulate_simd_store_register(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
+        }
+        A64MemoryInstruction::SimdLoadPair(access) => {
+            load::emulate_simd_load_pair(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
+        }
+        A64MemoryInstruction::SimdStorePair(access) => {
+            store::emulate_simd_store_pair(s_r, &access, intermediate_physical_address)?;
+            write_back_base_register(s_r, access.base_register, access.addressing_mode);
+        }
+        A64MemoryInstruction::AtomicMemoryOperation(access) => {
+            atomic::emulate_atomic_memory_operation(s_r, &access, intermediate_physical_address)?
+        }
+        A64MemoryInstruction::CompareAndSwap(access) => {
+            atomic::emulate_compare_and_swap(s_r, &access, intermediate_physical_address)?
+        }
+        A64MemoryInstruction::LoadExclusive(access) => {
+            exclusive::emulate_load_exclusive(s_r, &access, intermediate_physical_address)?
+        }
+        A64MemoryInstruction::StoreExclusive(access) => {
+            exclusive::emulate_store_exclusive(s_r, &access, intermediate_physical_address)?
+        }
+        A64MemoryInstruction::LoadAcquire(access) => {
+            exclusive::emulate_load_acquire_register(s_r, &access, intermediate_physical_address)?
+        }
+        A64MemoryInstruction::StoreRelease(access) => {
+            exclusive::emulate_store_release_register(s_r, &access, intermediate_physical_address)?
+        }
+        A64MemoryInstruction::Prefetch => unreachable!(),
     }
-    println!("Unknown Instruction: {:#X}", target_instruction);
-    Err(())
+    advance_elr_el2();
+    return Ok(());
 }
 
 fn faulting_va_to_ipa_load(far: u64) -> Result<usize, ()> {
@@ -271,24 +255,128 @@ fn get_register_reference_mut(s_r: &mut StoredRegisters, index: u8) -> &mut u64
     }
 }
 
-fn write_back_index_register_imm9(base_register: &mut u64, imm9_u32: u32) {
-    unsafe {
-        asm!("
-            sbfx {imm9}, {imm9}, #0, #9
-            add  {base_reg}, {base_reg}, {imm9}
-            ",
-            imm9 = inout(reg) (imm9_u32 as u64) => _ ,
-            base_reg = inout(reg) *base_register)
+fn read_register(s_r: &mut StoredRegisters, register_number: u8) -> u64 {
+    if register_number == REGISTER_NUMBER_XZR {
+        0
+    } else {
+        *get_register_reference_mut(s_r, register_number)
+    }
+}
+
+fn write_register(s_r: &mut StoredRegisters, register_number: u8, data: u64) {
+    if register_number != REGISTER_NUMBER_XZR {
+        *get_register_reference_mut(s_r, register_number) = data;
+    }
+}
+
+const fn size_to_mask(size: u8) -> u64 {
+    if size == 0b11 {
+        u64::MAX
+    } else {
+        (1 << (8 << size)) - 1
+    }
+}
+
+/// Load data from `intermediate_physical_address` through memory hook handlers
+///
+/// The returned data is zero-extended.
+fn load_from_address_with_hook(
+    s_r: &mut StoredRegisters,
+    intermediate_physical_address: usize,
+    size: u8,
+) -> Result<u64, ()> {
+    let virtual_address = get_virtual_address_to_access_ipa(intermediate_physical_address, false)?;
+    let data = match memory_load_hook_handler(
+        intermediate_physical_address,
+        s_r,
+        size,
+        size == 0b11,
+        false,
+    )? {
+        LoadHookResult::PassThrough | LoadHookResult::Continue => {
+            load::_read_memory(virtual_address, size)
+        }
+        LoadHookResult::Data(d) => d,
     };
+    Ok(data & size_to_mask(size))
 }
 
-fn write_back_index_register_imm7(base_register: &mut u64, imm7_u32: u32) {
-    unsafe {
-        asm!("
-            sbfx {imm7}, {imm7}, #0, #7
-            add  {base_reg}, {base_reg}, {imm7}
-            ",
-        imm7 = inout(reg) (imm7_u32 as u64) => _ ,
-        base_reg = inout(reg) *base_register)
+/// Store data into `intermediate_physical_address` through memory hook handlers
+///
+/// The exclusive monitors observing the address are cleared.
+/// The caller must hold [`EMULATED_MEMORY_ACCESS_LOCK`].
+fn store_into_address_with_hook(
+    s_r: &mut StoredRegisters,
+    intermediate_physical_address: usize,
+    size: u8,
+    data: u64,
+) -> Result<(), ()> {
+    let virtual_address = get_virtual_address_to_access_ipa(intermediate_physical_address, true)?;
+    let data = match memory_store_hook_handler(intermediate_physical_address, s_r, size, data)? {
+        StoreHookResult::PassThrough | StoreHookResult::Continue => data,
+        StoreHookResult::AlternativeData(d) => d,
+        StoreHookResult::Cancel => {
+            pr_debug!("The store instruction is cancelled.");
+            return Ok(());
+        }
+    };
+    exclusive::clear_exclusive_monitors(intermediate_physical_address, size);
+    store::_write_memory(virtual_address, size, data);
+    Ok(())
+}
+
+/// Access the `index`-th SIMD&FP register by `instruction`, `index` must be less than 32
+macro_rules! access_simd_register {
+    ($instruction:literal, $index:expr, $pointer:expr) => {
+        access_simd_register!(
+            $instruction,
+            $index,
+            $pointer,
+            [
+                0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22,
+                23, 24, 25, 26, 27, 28, 29, 30, 31
+            ]
+        )
+    };
+    ($instruction:literal, $index:expr, $pointer:expr, [$($n:literal),*]) => {
+        match $index {
+            $($n => unsafe {
+                core::arch::asm!(concat!($instruction, " q", $n, ", [{:x}]"), in(reg) $pointer)
+            },)*
+            _ => unreachable!(),
+        }
     };
 }
+
+/// Read the SIMD&FP register of EL1/EL0
+///
+/// The hypervisor is built for `aarch64-unknown-none-softfloat` and the compiler never uses SIMD&FP registers,
+/// therefore the registers keep the values of EL1/EL0 while the handlers are running.
+pub fn read_simd_register(index: u8) -> u128 {
+    let mut value: u128 = 0;
+    access_simd_register!("str", index, &mut value as *mut u128);
+    value
+}
+
+/// Write the SIMD&FP register of EL1/EL0
+///
+/// Like the load instructions, the bits above 128bit of the SVE register are cleared.
+pub fn write_simd_register(index: u8, value: u128) {
+    access_simd_register!("ldr", index, &value as *const u128);
+}
+
+/// Update the base register of the pre/post indexed access
+fn write_back_base_register(
+    s_r: &mut StoredRegisters,
+    base_register: u8,
+    addressing_mode: AddressingMode,
+) {
+    match addressing_mode {
+        AddressingMode::PreIndex(offset) | AddressingMode::PostIndex(offset) => {
+            pr_debug!("Post/Pre Indexed");
+            let base_register = get_register_reference_mut(s_r, base_register);
+            *base_register = base_register.wrapping_add(offset as u64);
+        }
+        AddressingMode::Offset | AddressingMode::Unprivileged | AddressingMode::Literal => {}
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! A64 Atomic Instructions' Emulator
+//!
+//! Supported: ldadd, ldclr, ldeor, ldset, ldsmax, ldsmin, ldumax, ldumin, swp, cas, casp
+//!
+//! The read-modify-write sequence is serialized by [`EMULATED_MEMORY_ACCESS_LOCK`],
+//! therefore the emulated atomic operations are atomic for each other.
+//!
+
+use super::{
+    load_from_address_with_hook, read_register, size_to_mask, store_into_address_with_hook,
+    write_register, EMULATED_MEMORY_ACCESS_LOCK,
+};
+
+use crate::StoredRegisters;
+
+use common::instruction::{AtomicAccess, AtomicOperation, CompareAndSwapAccess};
+use common::STAGE_2_PAGE_SHIFT;
+
+use core::sync::atomic::{fence, Ordering};
+
+pub fn emulate_atomic_memory_operation(
+    s_r: &mut StoredRegisters,
+    access: &AtomicAccess,
+    intermediate_physical_address: usize,
+) -> Result<(), ()> {
+    let size = access.size;
+    pr_debug!(
+        "{:?}: [{:#X}](IPA), R{}, R{}(Size: {:#b})",
+        access.operation,
+        intermediate_physical_address,
+        access.source_register,
+        access.target_register,
+        size
+    );
+
+    let operand = read_register(s_r, access.source_register) & size_to_mask(size);
+    if access.is_release {
+        fence(Ordering::Release);
+    }
+    EMULATED_MEMORY_ACCESS_LOCK.lock();
+    let result =
+        load_from_address_with_hook(s_r, intermediate_physical_address, size).and_then(|data| {
+            let new_data = match access.operation {
+                AtomicOperation::Add => data.wrapping_add(operand),
+                AtomicOperation::Clear => data & !operand,
+                AtomicOperation::ExclusiveOr => data ^ operand,
+                AtomicOperation::Set => data | operand,
+                AtomicOperation::SignedMax => {
+                    if sign_extend(data, size) >= sign_extend(operand, size) {
+                        data
+                    } else {
+                        operand
+                    }
+                }
+                AtomicOperation::SignedMin => {
+                    if sign_extend(data, size) <= sign_extend(operand, size) {
+                        data
+                    } else {
+                        operand
+                    }
+                }
+                AtomicOperation::UnsignedMax => data.max(operand),
+                AtomicOperation::UnsignedMin => data.min(operand),
+                AtomicOperation::Swap => operand,
+            } & size_to_mask(size);
+            store_into_address_with_hook(s_r, intermediate_physical_address, size, new_data)?;
+            Ok(data)
+        });
+    EMULATED_MEMORY_ACCESS_LOCK.unlock();
+    let data = result?;
+    if access.is_acquire {
+        fence(Ordering::Acquire);
+    }
+
+    pr_debug!("Old Data: {:#X}", data);
+    write_register(s_r, access.target_register, data);
+    return Ok(());
+}
+
+pub fn emulate_compare_and_swap(
+    s_r: &mut StoredRegisters,
+    access: &CompareAndSwapAccess,
+    intermediate_physical_address: usize,
+) -> Result<(), ()> {
+    let size = access.size;
+    let source_register = access.source_register;
+    let target_register = access.target_register;
+    let mask = size_to_mask(size);
+
+    if access.is_release {
+        fence(Ordering::Release);
+    }
+    if access.is_pair {
+        if (intermediate_physical_address >> STAGE_2_PAGE_SHIFT)
+            != ((intermediate_physical_address + ((1 << size) * 2) - 1) >> STAGE_2_PAGE_SHIFT)
+        {
+            println!("CASP alignment error.");
+            return Err(());
+        }
+        pr_debug!(
+            "CASP: [{:#X}](IPA), R{}, R{}(Size: {:#b})",
+            intermediate_physical_address,
+            source_register,
+            target_register,
+            size
+        );
+        let compare_1 = read_register(s_r, source_register) & mask;
+        let compare_2 = read_register(s_r, source_register + 1) & mask;
+        let new_data_1 = read_register(s_r, target_register) & mask;
+        let new_data_2 = read_register(s_r, target_register + 1) & mask;
+        let second_address = intermediate_physical_address + (1 << size);
+
+        EMULATED_MEMORY_ACCESS_LOCK.lock();
+        let result = (|| {
+            let data_1 = load_from_address_with_hook(s_r, intermediate_physical_address, size)?;
+            let data_2 = load_from_address_with_hook(s_r, second_address, size)?;
+            if data_1 == compare_1 && data_2 == compare_2 {
+                store_into_address_with_hook(s_r, intermediate_physical_address, size, new_data_1)?;
+                store_into_address_with_hook(s_r, second_address, size, new_data_2)?;
+            }
+            Ok((data_1, data_2))
+        })();
+        EMULATED_MEMORY_ACCESS_LOCK.unlock();
+        let (data_1, data_2) = result?;
+
+        pr_debug!("Old Data: {:#X}, {:#X}", data_1, data_2);
+        write_register(s_r, source_register, data_1);
+        write_register(s_r, source_register + 1, data_2);
+    } else {
+        pr_debug!(
+            "CAS: [{:#X}](IPA), R{}, R{}(Size: {:#b})",
+            intermediate_physical_address,
+            source_register,
+            target_register,
+            size
+        );
+        let compare = read_register(s_r, source_register) & mask;
+        let new_data = read_register(s_r, target_register) & mask;
+
+        EMULATED_MEMORY_ACCESS_LOCK.lock();
+        let result = load_from_address_with_hook(s_r, intermediate_physical_address, size)
+            .and_then(|data| {
+                if data == compare {
+                    store_into_address_with_hook(
+                        s_r,
+                        intermediate_physical_address,
+                        size,
+                        new_data,
+                    )?;
+                }
+                Ok(data)
+            });
+        EMULATED_MEMORY_ACCESS_LOCK.unlock();
+        let data = result?;
+
+        pr_debug!("Old Data: {:#X}", data);
+        write_register(s_r, source_register, data);
+    }
+    if access.is_acquire {
+        fence(Ordering::Acquire);
+    }
+    return Ok(());
+}
+
+const fn sign_extend(data: u64, size: u8) -> i64 {
+    let shift = 64 - (8 << size);
+    ((data << shift) as i64) >> shift
+}
@@ -0,0 +1,280 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! A64 Load/Store Exclusive and Ordered Instructions' Emulator
+//!
+//! Supported: ldxr, ldaxr, ldxp, ldaxp, stxr, stlxr, stxp, stlxp, ldar, ldlar, ldapr, stlr, stllr
+//!
+//! The exclusive monitor is modelled in software:
+//! emulated LDXR marks the exclusive reservation granule for the current CPU,
+//! and emulated STXR succeeds only if the mark is still valid.
+//! Every emulated store clears the marks of all CPUs on the same granule.
+//! Because the trapped page is accessed only via this emulator, this is sufficient to detect
+//! the conflict. (If the trap is removed before STXR, real STXR fails and the guest retries.)
+//!
+
+use super::{
+    load_from_address_with_hook, read_register, store_into_address_with_hook, write_register,
+    EMULATED_MEMORY_ACCESS_LOCK,
+};
+
+use crate::StoredRegisters;
+
+use common::cpu::{get_ctr_el0, get_mpidr_el1, CTR_EL0_ERG, CTR_EL0_ERG_BITS_OFFSET};
+use common::instruction::{ExclusiveAccess, RegisterAccess};
+
+use core::sync::atomic::{fence, Ordering};
+
+const MAX_NUMBER_OF_EXCLUSIVE_MONITORS: usize = 256;
+/// Used when CTR_EL0.ERG is 0 (no information is available)
+const MAX_EXCLUSIVE_RESERVATION_GRANULE_SIZE: usize = 2048;
+
+#[derive(Clone, Copy)]
+struct ExclusiveMonitor {
+    mpidr: u64,
+    /// The address aligned by the exclusive reservation granule
+    address: usize,
+}
+
+static mut EXCLUSIVE_MONITOR_LIST: [Option<ExclusiveMonitor>; MAX_NUMBER_OF_EXCLUSIVE_MONITORS] =
+    [None; MAX_NUMBER_OF_EXCLUSIVE_MONITORS];
+static mut NUMBER_OF_ACTIVE_EXCLUSIVE_MONITORS: usize = 0;
+
+pub fn emulate_load_exclusive(
+    s_r: &mut StoredRegisters,
+    access: &ExclusiveAccess,
+    intermediate_physical_load_address: usize,
+) -> Result<(), ()> {
+    let size = access.size;
+    pr_debug!(
+        "LD{}X{}: R{}{:?} <= [{:#X}](IPA)(Size: {:#b})",
+        if access.is_ordered { "A" } else { "" },
+        if access.target_register_2.is_some() {
+            "P"
+        } else {
+            "R"
+        },
+        access.target_register_1,
+        access.target_register_2,
+        intermediate_physical_load_address,
+        size
+    );
+
+    EMULATED_MEMORY_ACCESS_LOCK.lock();
+    set_exclusive_monitor(intermediate_physical_load_address);
+    let result = load_from_address_with_hook(s_r, intermediate_physical_load_address, size)
+        .and_then(|data| {
+            if access.target_register_2.is_some() {
+                let data_2 = load_from_address_with_hook(
+                    s_r,
+                    intermediate_physical_load_address + (1 << size),
+                    size,
+                )?;
+                Ok((data, data_2))
+            } else {
+                Ok((data, 0))
+            }
+        });
+    EMULATED_MEMORY_ACCESS_LOCK.unlock();
+    let (data, data_2) = result?;
+
+    pr_debug!("Data: {:#X}, {:#X}", data, data_2);
+    write_register(s_r, access.target_register_1, data);
+    if let Some(target_register_2) = access.target_register_2 {
+        write_register(s_r, target_register_2, data_2);
+    }
+    if access.is_ordered {
+        fence(Ordering::Acquire);
+    }
+    return Ok(());
+}
+
+pub fn emulate_store_exclusive(
+    s_r: &mut StoredRegisters,
+    access: &ExclusiveAccess,
+    intermediate_physical_store_address: usize,
+) -> Result<(), ()> {
+    let size = access.size;
+    pr_debug!(
+        "ST{}X{}: [{:#X}](IPA) <= R{}{:?}(Size: {:#b}), Status: W{}",
+        if access.is_ordered { "L" } else { "" },
+        if access.target_register_2.is_some() {
+            "P"
+        } else {
+            "R"
+        },
+        intermediate_physical_store_address,
+        access.target_register_1,
+        access.target_register_2,
+        size,
+        access.status_register
+    );
+
+    let data = read_register(s_r, access.target_register_1);
+    let data_2 = access
+        .target_register_2
+        .map(|target_register_2| read_register(s_r, target_register_2));
+    if access.is_ordered {
+        fence(Ordering::Release);
+    }
+
+    EMULATED_MEMORY_ACCESS_LOCK.lock();
+    let result = if check_and_clear_exclusive_monitor(intermediate_physical_store_address) {
+        store_into_address_with_hook(s_r, intermediate_physical_store_address, size, data).and_then(
+            |_| {
+                if let Some(data_2) = data_2 {
+                    store_into_address_with_hook(
+                        s_r,
+                        intermediate_physical_store_address + (1 << size),
+                        size,
+                        data_2,
+                    )?;
+                }
+                Ok(0)
+            },
+        )
+    } else {
+        Ok(1)
+    };
+    EMULATED_MEMORY_ACCESS_LOCK.unlock();
+    let status = result?;
+
+    pr_debug!("Status: {}", status);
+    write_register(s_r, access.status_register, status);
+    return Ok(());
+}
+
+/// Emulate LDAR, LDLAR and LDAPR
+pub fn emulate_load_acquire_register(
+    s_r: &mut StoredRegisters,
+    access: &RegisterAccess,
+    intermediate_physical_load_address: usize,
+) -> Result<(), ()> {
+    pr_debug!(
+        "R{} <= [{:#X}](IPA)(Acquire)(Size: {:#b})",
+        access.target_register,
+        intermediate_physical_load_address,
+        access.size
+    );
+    let data = load_from_address_with_hook(s_r, intermediate_physical_load_address, access.size)?;
+    pr_debug!("Data: {:#X}", data);
+    write_register(s_r, access.target_register, data);
+    fence(Ordering::Acquire);
+    return Ok(());
+}
+
+/// Emulate STLR and STLLR
+pub fn emulate_store_release_register(
+    s_r: &mut StoredRegisters,
+    access: &RegisterAccess,
+    intermediate_physical_store_address: usize,
+) -> Result<(), ()> {
+    pr_debug!(
+        "[{:#X}](IPA) <= R{}(Release)(Size: {:#b})",
+        intermediate_physical_store_address,
+        access.target_register,
+        access.size
+    );
+    let data = read_register(s_r, access.target_register);
+    fence(Ordering::Release);
+    EMULATED_MEMORY_ACCESS_LOCK.lock();
+    let result =
+        store_into_address_with_hook(s_r, intermediate_physical_store_address, access.size, data);
+    EMULATED_MEMORY_ACCESS_LOCK.unlock();
+    return result;
+}
+
+/// Clear the exclusive monitors of all CPUs watching the granule including the given range
+///
+/// The caller must hold [`EMULATED_MEMORY_ACCESS_LOCK`].
+///
+/// # Arguments
+/// * `address` - the start address of the store access
+/// * `size` - log2 of the access bytes
+pub fn clear_exclusive_monitors(address: usize, size: u8) {
+    if unsafe { NUMBER_OF_ACTIVE_EXCLUSIVE_MONITORS } == 0 {
+        return;
+    }
+    let granule_mask = !(get_exclusive_reservation_granule_size() - 1);
+    let start_address = address & granule_mask;
+    let end_address = (address + (1 << size) - 1) & granule_mask;
+    for e in unsafe { &mut EXCLUSIVE_MONITOR_LIST } {
+        if let Some(monitor) = e {
+            if (start_address..=end_address).contains(&monitor.address) {
+                pr_debug!(
+                    "Clear the exclusive monitor of {:#X}(Address: {:#X})",
+                    monitor.mpidr,
+                    monitor.address
+                );
+                *e = None;
+                unsafe { NUMBER_OF_ACTIVE_EXCLUSIVE_MONITORS -= 1 };
+            }
+        }
+    }
+}
+
+/// Mark the granule including `address` as exclusive access for the current CPU
+///
+/// If there is no space to add the monitor, one of the other monitors is discarded.
+/// (It only makes the corresponding STXR fail, and it is allowed by the architecture.)
+fn set_exclusive_monitor(address: usize) {
+    let mpidr = get_mpidr_el1();
+    let new_monitor = ExclusiveMonitor {
+        mpidr,
+        address: address & !(get_exclusive_reservation_granule_size() - 1),
+    };
+    let mut empty_entry = None;
+    for e in unsafe { &mut EXCLUSIVE_MONITOR_LIST } {
+        match e {
+            Some(monitor) if monitor.mpidr == mpidr => {
+                *monitor = new_monitor;
+                return;
+            }
+            None if empty_entry.is_none() => {
+                empty_entry = Some(e);
+            }
+            _ => {}
+        }
+    }
+    if let Some(e) = empty_entry {
+        *e = Some(new_monitor);
+        unsafe { NUMBER_OF_ACTIVE_EXCLUSIVE_MONITORS += 1 };
+    } else {
+        println!("Exclusive monitor list is full, discard the first entry.");
+        unsafe { EXCLUSIVE_MONITOR_LIST[0] = Some(new_monitor) };
+    }
+}
+
+/// Check whether the current CPU has the exclusive monitor of the granule including `address`
+///
+/// The monitor of the current CPU is cleared regardless of the result.
+fn check_and_clear_exclusive_monitor(address: usize) -> bool {
+    let mpidr = get_mpidr_el1();
+    let address = address & !(get_exclusive_reservation_granule_size() - 1);
+    for e in unsafe { &mut EXCLUSIVE_MONITOR_LIST } {
+        if let Some(monitor) = e {
+            if monitor.mpidr == mpidr {
+                let result = monitor.address == address;
+                *e = None;
+                unsafe { NUMBER_OF_ACTIVE_EXCLUSIVE_MONITORS -= 1 };
+                return result;
+            }
+        }
+    }
+    return false;
+}
+
+fn get_exclusive_reservation_granule_size() -> usize {
+    let erg = (get_ctr_el0() & CTR_EL0_ERG) >> CTR_EL0_ERG_BITS_OFFSET;
+    if erg == 0 {
+        MAX_EXCLUSIVE_RESERVATION_GRANULE_SIZE
+    } else {
+        /* ERG is log2 of the number of words */
+        4 << erg
+    }
+}
@@ -8,238 +8,136 @@
 //!
 //! A64 Load Instructions' Emulator
 //!
-//! Supported: ldr, ldp (except Atomic, SIMD)
+//! Supported: ldr, ldp (including SIMD&FP registers) (except Atomic and Exclusive)
 //!
 
-use super::{
-    faulting_va_to_ipa_load, get_register_reference_mut, get_virtual_address_to_access_ipa,
-    write_back_index_register_imm7, write_back_index_register_imm9, REGISTER_NUMBER_XZR,
-};
+use super::{get_register_reference_mut, get_virtual_address_to_access_ipa, write_simd_register};
 
 use crate::memory_hook::{memory_load_hook_handler, LoadHookResult};
 use crate::StoredRegisters;
 
-use common::{bitmask, cpu::advance_elr_el2, STAGE_2_PAGE_SHIFT};
+use common::instruction::{PairAccess, RegisterAccess, ACCESS_SIZE_128BIT, REGISTER_NUMBER_XZR};
+use common::STAGE_2_PAGE_SHIFT;
 
 pub fn emulate_load_register(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &RegisterAccess,
+    intermediate_physical_load_address: usize,
 ) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_load_address = faulting_va_to_ipa_load(far)?;
-    //let op2 = ((target_instruction & bitmask!(24, 23)) >> 23) as u8;
-    //let op3 = ((target_instruction & bitmask!(21, 16)) >> 16) as u8;
-    let op4 = ((target_instruction & bitmask!(11, 10)) >> 10) as u8;
-    /* sf is usable only if sse is true */
-    let sf = (target_instruction & (1 << 22)) == 0;
-    let sse = (target_instruction & (1 << 23)) != 0;
-
     pr_debug!(
-        "{}{} <= [{:#X}](IPA)(Sign Extend: {})({})",
-        if sse && !sf { 'W' } else { 'X' },
-        target_register,
+        "{}{} <= [{:#X}](IPA)(Size: {:#b}, Sign Extend: {})({:?})",
+        if access.is_64bit_register { 'X' } else { 'W' },
+        access.target_register,
         intermediate_physical_load_address,
-        sse,
-        match op4 {
-            0b00 => "Unscaled",
-            0b01 => "PostIndexed",
-            0b10 => "Unprivileged",
-            0b11 => "PreIndexed",
-            _ => unreachable!(),
-        }
+        access.size,
+        access.is_sign_extend_required,
+        access.addressing_mode
     );
-    if op4 == 0b10 {
-        unimplemented!("UnPrivileged Access is not implemented...");
-    }
-
-    let size = (target_instruction >> 30) as u8;
-    if size == 0b11 && op4 == 0b00 && sse && !sf {
-        pr_debug!("Prefetch Memory Unscaled Signals.");
-        return Ok(());
-    }
-    pr_debug!("Size: {:#b}", size);
     load_from_address_and_store_into_register(
         s_r,
         intermediate_physical_load_address,
-        target_register,
-        size,
-        sf,
-        sse,
-    )?;
-    if (op4 & 1) != 0 {
-        pr_debug!("Post/Pre Indexed");
-        let imm9 = (target_instruction & bitmask!(20, 12)) >> 12;
-        let base_register =
-            get_register_reference_mut(s_r, ((target_instruction & bitmask!(9, 5)) >> 5) as u8);
-        write_back_index_register_imm9(base_register, imm9);
-    }
-
-    advance_elr_el2();
-    return Ok(());
+        access.target_register,
+        access.size,
+        access.is_64bit_register,
+        access.is_sign_extend_required,
+    )
 }
 
-pub fn emulate_unsigned_immediate_load_register(
+pub fn emulate_load_pair(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &PairAccess,
+    intermediate_physical_load_address: usize,
 ) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_load_address = faulting_va_to_ipa_load(far)?;
-
-    /* sf is usable only if sse is true */
-    let sf = (target_instruction & (1 << 22)) == 0;
-    let sse = (target_instruction & (1 << 23)) != 0;
-    let size = (target_instruction >> 30) as u8;
+    pr_debug!(
+        "{}{}, {}{}(+{}) <= [{:#X}](IPA)(Sign Extend: {})({:?})",
+        if access.is_64bit_register { 'X' } else { 'W' },
+        access.target_register_1,
+        if access.is_64bit_register { 'X' } else { 'W' },
+        access.target_register_2,
+        1 << access.size,
+        intermediate_physical_load_address,
+        access.is_sign_extend_required,
+        access.addressing_mode
+    );
 
-    pr_debug!("Size: {:#b}", size);
-    if size == 0b11 && sse && !sf {
-        pr_debug!("Prefetch Memory Immediate Signals.");
-        return Ok(());
+    if (intermediate_physical_load_address >> STAGE_2_PAGE_SHIFT)
+        != ((intermediate_physical_load_address + ((1 << access.size) * 2) - 1)
+            >> STAGE_2_PAGE_SHIFT)
+    {
+        println!("LDP alignment error.");
+        return Err(());
     }
     load_from_address_and_store_into_register(
         s_r,
         intermediate_physical_load_address,
-        target_register,
-        size,
-        sf,
-        sse,
+        access.target_register_1,
+        access.size,
+        access.is_64bit_register,
+        access.is_sign_extend_required,
     )?;
-    advance_elr_el2();
-    return Ok(());
-}
-
-pub fn emulate_load_register_register_offset(
-    s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
-) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_load_address = faulting_va_to_ipa_load(far)?;
-
-    /* sf is usable only if sse is true */
-    let sf = (target_instruction & (1 << 22)) == 0;
-    let sse = (target_instruction & (1 << 23)) != 0;
-    let size = (target_instruction >> 30) as u8;
-    if size == 0b11 && sse {
-        pr_debug!("Prefetch Memory Register Signals.");
-        return Ok(());
-    }
-
-    pr_debug!("Size: {:#b}", size);
     load_from_address_and_store_into_register(
         s_r,
-        intermediate_physical_load_address,
-        target_register,
-        size,
-        sf,
-        sse,
-    )?;
-    advance_elr_el2();
-    return Ok(());
+        intermediate_physical_load_address + (1 << access.size),
+        access.target_register_2,
+        access.size,
+        access.is_64bit_register,
+        access.is_sign_extend_required,
+    )
 }
 
-pub fn emulate_literal_load_register(
+pub fn emulate_simd_load_register(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &RegisterAccess,
+    intermediate_physical_load_address: usize,
 ) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_load_address = faulting_va_to_ipa_load(far)?;
-
-    let opc = (target_instruction >> 30) as u8;
-    /* sf is usable only if sse is true */
-    let sf = opc == 0b01 || opc == 0b10;
-    let sse = opc == 0b10;
-    let size = if opc == 0b01 { 0b11 } else { 0b10 };
-
-    pr_debug!("Size: {:#b}", size);
-    if opc == 0b11 {
-        pr_debug!("Prefetch Memory Literal Signals");
-        return Ok(());
-    }
-    load_from_address_and_store_into_register(
+    pr_debug!(
+        "V{} <= [{:#X}](IPA)(Size: {:#b})({:?})",
+        access.target_register,
+        intermediate_physical_load_address,
+        access.size,
+        access.addressing_mode
+    );
+    load_from_address_and_store_into_simd_register(
         s_r,
         intermediate_physical_load_address,
-        target_register,
-        size,
-        sf,
-        sse,
-    )?;
-    advance_elr_el2();
-    return Ok(());
+        access.target_register,
+        access.size,
+    )
 }
 
-pub fn emulate_load_pair(
+pub fn emulate_simd_load_pair(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &PairAccess,
+    intermediate_physical_load_address: usize,
 ) -> Result<(), ()> {
-    let op2 = ((target_instruction & bitmask!(24, 23)) >> 23) as u8;
-    let opc = (target_instruction >> 30) as u8;
-    let sf = (opc & (1 << 1)) != 0;
-    let sse = (opc & 1) != 0;
-    let is_pre_or_post_indexed = (op2 & 1) != 0;
-    let intermediate_physical_load_address = faulting_va_to_ipa_load(far)?;
-    let target_register_1 = (target_instruction & bitmask!(4, 0)) as u8;
-    let target_register_2 = ((target_instruction & bitmask!(14, 10)) >> 10) as u8;
-
     pr_debug!(
-        "{}{}, {}{}(+{}) <= [{:#X}](IPA)(Sign Extend: {})({})",
-        if sf { 'X' } else { 'W' },
-        target_register_1,
-        if sf { 'X' } else { 'W' },
-        target_register_2,
-        if sf { 8 } else { 4 },
+        "V{}, V{}(+{}) <= [{:#X}](IPA)({:?})",
+        access.target_register_1,
+        access.target_register_2,
+        1 << access.size,
         intermediate_physical_load_address,
-        sse,
-        match op2 {
-            0b00 => "NonAllocate", /* It means the memory area is unlikely to access repeatedly */
-            0b01 => "PostIndexed",
-            0b10 => "Offset",
-            0b11 => "PreIndexed",
-            _ => unreachable!(),
-        }
+        access.addressing_mode
     );
 
     if (intermediate_physical_load_address >> STAGE_2_PAGE_SHIFT)
-        != ((intermediate_physical_load_address + ((if sf { 8 } else { 4 }) * 2) - 1)
+        != ((intermediate_physical_load_address + ((1 << access.size) * 2) - 1)
             >> STAGE_2_PAGE_SHIFT)
     {
         println!("LDP alignment error.");
         return Err(());
     }
-    load_from_address_and_store_into_register(
+    load_from_address_and_store_into_simd_register(
         s_r,
         intermediate_physical_load_address,
-        target_register_1,
-        if sf { 0b11 } else { 0b10 },
-        sf,
-        sse,
+        access.target_register_1,
+        access.size,
     )?;
-    load_from_address_and_store_into_register(
+    load_from_address_and_store_into_simd_register(
         s_r,
-        intermediate_physical_load_address + if sf { 8 } else { 4 },
-        target_register_2,
-        if sf { 0b11 } else { 0b10 },
-        sf,
-        sse,
-    )?;
-
-    if is_pre_or_post_indexed {
-        pr_debug!("Post/Pre Indexed");
-        let imm7 = (target_instruction & bitmask!(21, 15)) >> 15;
-        let base_register =
-            get_register_reference_mut(s_r, ((target_instruction & bitmask!(9, 5)) >> 5) as u8);
-        write_back_index_register_imm7(base_register, imm7);
-    }
-    advance_elr_el2();
-    return Ok(());
+        intermediate_physical_load_address + (1 << access.size),
+        access.target_register_2,
+        access.size,
+    )
 }
 
 fn load_from_address_and_store_into_register(
@@ -247,34 +145,37 @@ fn load_from_address_and_store_into_register(
     intermediate_physical_load_address: usize,
     target_register: u8,
     size: u8,
-    sf: bool, /* sf is usable only if sse is true */
-    sse: bool,
+    is_64bit_register: bool,
+    is_sign_extend_required: bool,
 ) -> Result<(), ()> {
-    let sf = !sse || sf;
-
-    pr_debug!(
-        "{}{} <= [{:#X}](IPA)(Sign Extend: {})",
-        if sf { 'X' } else { 'W' },
-        target_register,
-        intermediate_physical_load_address,
-        sse
-    );
     let virtual_address_to_load =
         get_virtual_address_to_access_ipa(intermediate_physical_load_address, false)?;
 
-    if !sf && size == 0b11 {
+    if !is_64bit_register && size == 0b11 {
         println!("Invalid Instruction: Loading a 64bit data into the 32bit register.");
         return Err(());
     }
 
-    let hook_result =
-        memory_load_hook_handler(intermediate_physical_load_address, s_r, size, sf, sse)?;
+    let hook_result = memory_load_hook_handler(
+        intermediate_physical_load_address,
+        s_r,
+        size,
+        is_64bit_register,
+        is_sign_extend_required,
+    )?;
     let data = match hook_result {
-        LoadHookResult::PassThrough => {
-            if sse {
-                unimplemented!();
+        LoadHookResult::PassThrough | LoadHookResult::Continue => {
+            let data = _read_memory(virtual_address_to_load, size);
+            if is_sign_extend_required {
+                let shift = 64 - (8 << size);
+                let data = (((data << shift) as i64) >> shift) as u64;
+                if is_64bit_register {
+                    data
+                } else {
+                    data & (u32::MAX as u64)
+                }
             } else {
-                _read_memory(virtual_address_to_load, size)
+                data
             }
         }
         LoadHookResult::Data(d) => d,
@@ -287,7 +188,47 @@ fn load_from_address_and_store_into_register(
     return Ok(());
 }
 
-fn _read_memory(load_virtual_address: usize, access_size: u8) -> u64 {
+/// Load data into the SIMD&FP register
+///
+/// 128bit access is divided into two 64bit accesses for memory hook handlers.
+/// The upper bits of the target register are cleared.
+fn load_from_address_and_store_into_simd_register(
+    s_r: &mut StoredRegisters,
+    intermediate_physical_load_address: usize,
+    target_register: u8,
+    size: u8,
+) -> Result<(), ()> {
+    let mut data: u128 = 0;
+    let (access_size, number_of_accesses) = if size == ACCESS_SIZE_128BIT {
+        (0b11, 2)
+    } else {
+        (size, 1)
+    };
+
+    for i in 0..number_of_accesses {
+        let address = intermediate_physical_load_address + (i << access_size);
+        let virtual_address_to_load = get_virtual_address_to_access_ipa(address, false)?;
+        let hook_result = memory_load_hook_handler(address, s_r, access_size, true, false)?;
+        let d = match hook_result {
+            LoadHookResult::PassThrough | LoadHookResult::Continue => {
+                _read_memory(virtual_address_to_load, access_size)
+            }
+            LoadHookResult::Data(d) => d,
+        };
+        let d = if access_size == 0b11 {
+            d
+        } else {
+            d & ((1 << (8 << access_size)) - 1)
+        };
+        data |= (d as u128) << (64 * i);
+    }
+
+    pr_debug!("Data: {:#X}", data);
+    write_simd_register(target_register, data);
+    return Ok(());
+}
+
+pub fn _read_memory(load_virtual_address: usize, access_size: u8) -> u64 {
     use core::ptr::read_volatile;
     match access_size {
         0b00 => unsafe { read_volatile(load_virtual_address as *const u8) as u64 },
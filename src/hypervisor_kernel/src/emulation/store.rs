@@ -8,175 +8,131 @@
 //!
 //! A64 Store Instructions' Emulator
 //!
-//! Supported: str, stp (except Atomic, SIMD)
+//! Supported: str, stp (including SIMD&FP registers) (except Atomic and Exclusive)
 //!
 
+use super::exclusive::clear_exclusive_monitors;
 use super::{
-    faulting_va_to_ipa_store, get_register_reference_mut, get_virtual_address_to_access_ipa,
-    write_back_index_register_imm7, write_back_index_register_imm9, REGISTER_NUMBER_XZR,
+    get_register_reference_mut, get_virtual_address_to_access_ipa, read_simd_register,
+    EMULATED_MEMORY_ACCESS_LOCK,
 };
 
 use crate::memory_hook::{memory_store_hook_handler, StoreHookResult};
-use crate::{handler_panic, StoredRegisters};
+use crate::StoredRegisters;
 
-use common::{bitmask, cpu::advance_elr_el2, STAGE_2_PAGE_SHIFT};
+use common::instruction::{PairAccess, RegisterAccess, ACCESS_SIZE_128BIT, REGISTER_NUMBER_XZR};
+use common::STAGE_2_PAGE_SHIFT;
 
 pub fn emulate_store_register(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &RegisterAccess,
+    intermediate_physical_store_address: usize,
 ) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_store_address = faulting_va_to_ipa_store(far)?;
-    //let op2 = ((target_instruction & bitmask!(24, 23)) >> 23) as u8;
-    //let op3 = ((target_instruction & bitmask!(21, 16)) >> 16) as u8;
-    let op4 = ((target_instruction & bitmask!(11, 10)) >> 10) as u8;
-    let size = (target_instruction >> 30) as u8;
-
     pr_debug!(
-        "[{:#X}](IPA) <= R{}({})",
+        "[{:#X}](IPA) <= R{}(Size: {:#b})({:?})",
         intermediate_physical_store_address,
-        target_register,
-        match op4 {
-            0b00 => "Unscaled",
-            0b01 => "PostIndexed",
-            0b10 => "Unprivileged",
-            0b11 => "PreIndexed",
-            _ => unreachable!(),
-        }
+        access.target_register,
+        access.size,
+        access.addressing_mode
     );
-    if op4 == 0b10 {
-        unimplemented!("UnPrivileged Access is not implemented...");
-    }
-
-    if ((target_instruction >> 26) & 1) != 0 {
-        /* V */
-        handler_panic!(s_r, "SIMD is not supported: {:#X}", target_instruction);
-    }
-
-    pr_debug!("Size: {:#b}", size);
     store_register_into_address(
         s_r,
         intermediate_physical_store_address,
-        target_register,
-        size,
-    )?;
-    if (op4 & 1) != 0 {
-        pr_debug!("Post/Pre Indexed");
-        let imm9 = (target_instruction & bitmask!(20, 12)) >> 12;
-        let base_register =
-            get_register_reference_mut(s_r, ((target_instruction & bitmask!(9, 5)) >> 5) as u8);
-        write_back_index_register_imm9(base_register, imm9);
-    }
-
-    advance_elr_el2();
-    return Ok(());
+        access.target_register,
+        access.size,
+    )
 }
 
-pub fn emulate_unsigned_immediate_store_register(
+pub fn emulate_store_pair(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &PairAccess,
+    intermediate_physical_store_address: usize,
 ) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_store_address = faulting_va_to_ipa_store(far)?;
-    let size = (target_instruction >> 30) as u8;
+    pr_debug!(
+        "[{:#X}](IPA) <= {}{}, {}{}(+{})({:?})",
+        intermediate_physical_store_address,
+        if access.is_64bit_register { 'X' } else { 'W' },
+        access.target_register_1,
+        if access.is_64bit_register { 'X' } else { 'W' },
+        access.target_register_2,
+        1 << access.size,
+        access.addressing_mode
+    );
+
+    if (intermediate_physical_store_address >> STAGE_2_PAGE_SHIFT)
+        != ((intermediate_physical_store_address + ((1 << access.size) * 2) - 1)
+            >> STAGE_2_PAGE_SHIFT)
+    {
+        println!("STP alignment error.");
+        return Err(());
+    }
     store_register_into_address(
         s_r,
         intermediate_physical_store_address,
-        target_register,
-        size,
+        access.target_register_1,
+        access.size,
     )?;
-    advance_elr_el2();
-    return Ok(());
+    store_register_into_address(
+        s_r,
+        intermediate_physical_store_address + (1 << access.size),
+        access.target_register_2,
+        access.size,
+    )
 }
 
-pub fn emulate_store_register_register_offset(
+pub fn emulate_simd_store_register(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &RegisterAccess,
+    intermediate_physical_store_address: usize,
 ) -> Result<(), ()> {
-    let target_register = (target_instruction & bitmask!(4, 0)) as u8;
-    let intermediate_physical_store_address = faulting_va_to_ipa_store(far)?;
-    let size = (target_instruction >> 30) as u8;
-    store_register_into_address(
+    pr_debug!(
+        "[{:#X}](IPA) <= V{}(Size: {:#b})({:?})",
+        intermediate_physical_store_address,
+        access.target_register,
+        access.size,
+        access.addressing_mode
+    );
+    store_simd_register_into_address(
         s_r,
         intermediate_physical_store_address,
-        target_register,
-        size,
-    )?;
-    advance_elr_el2();
-    return Ok(());
+        access.target_register,
+        access.size,
+    )
 }
 
-pub fn emulate_store_pair(
+pub fn emulate_simd_store_pair(
     s_r: &mut StoredRegisters,
-    target_instruction: u32,
-    far: u64,
-    _hpfar: u64,
+    access: &PairAccess,
+    intermediate_physical_store_address: usize,
 ) -> Result<(), ()> {
-    let op2 = ((target_instruction & bitmask!(24, 23)) >> 23) as u8;
-    let opc = (target_instruction >> 30) as u8;
-    let sf = (opc & (1 << 1)) != 0;
-    let sse = (opc & 1) != 0;
-    let is_pre_or_post_indexed = (op2 & 1) != 0;
-    let intermediate_physical_store_address = faulting_va_to_ipa_store(far)?;
-    let target_register_1 = (target_instruction & bitmask!(4, 0)) as u8;
-    let target_register_2 = ((target_instruction & bitmask!(14, 10)) >> 10) as u8;
-
     pr_debug!(
-        "[{:#X}](IPA) <= {}{}, {}{}(+{}) (Sign Extend: {})({})",
+        "[{:#X}](IPA) <= V{}, V{}(+{})({:?})",
         intermediate_physical_store_address,
-        if sf { 'X' } else { 'W' },
-        target_register_1,
-        if sf { 'X' } else { 'W' },
-        target_register_2,
-        if sf { 8 } else { 4 },
-        sse,
-        match op2 {
-            0b00 => "NonAllocate", /* It means the memory area is unlikely to access repeatedly */
-            0b01 => "PostIndexed",
-            0b10 => "Offset",
-            0b11 => "PreIndexed",
-            _ => unreachable!(),
-        }
+        access.target_register_1,
+        access.target_register_2,
+        1 << access.size,
+        access.addressing_mode
     );
 
     if (intermediate_physical_store_address >> STAGE_2_PAGE_SHIFT)
-        != ((intermediate_physical_store_address + ((if sf { 8 } else { 4 }) * 2) - 1)
+        != ((intermediate_physical_store_address + ((1 << access.size) * 2) - 1)
             >> STAGE_2_PAGE_SHIFT)
     {
         println!("STP alignment error.");
         return Err(());
     }
-    if sse {
-        unimplemented!();
-    }
-    store_register_into_address(
+    store_simd_register_into_address(
         s_r,
         intermediate_physical_store_address,
-        target_register_1,
-        if sf { 0b11 } else { 0b10 },
+        access.target_register_1,
+        access.size,
     )?;
-    store_register_into_address(
+    store_simd_register_into_address(
         s_r,
-        intermediate_physical_store_address + if sf { 8 } else { 4 },
-        target_register_2,
-        if sf { 0b11 } else { 0b10 },
-    )?;
-
-    if is_pre_or_post_indexed {
-        pr_debug!("Post/Pre Indexed");
-        let imm7 = (target_instruction & bitmask!(21, 15)) >> 15;
-        let base_register =
-            get_register_reference_mut(s_r, ((target_instruction & bitmask!(9, 5)) >> 5) as u8);
-        write_back_index_register_imm7(base_register, imm7);
-    }
-    advance_elr_el2();
-    return Ok(());
+        intermediate_physical_store_address + (1 << access.size),
+        access.target_register_2,
+        access.size,
+    )
 }
 
 fn store_register_into_address(
@@ -209,7 +165,7 @@ fn store_register_into_address(
     let hook_result =
         memory_store_hook_handler(intermediate_physical_store_address, s_r, size, reg_data)?;
     let data = match hook_result {
-        StoreHookResult::PassThrough => reg_data,
+        StoreHookResult::PassThrough | StoreHookResult::Continue => reg_data,
         StoreHookResult::AlternativeData(d) => d,
         StoreHookResult::Cancel => {
             pr_debug!("The store instruction is cancelled.");
@@ -218,7 +174,48 @@ fn store_register_into_address(
     };
 
     pr_debug!("Data: {:#X}", data);
+    EMULATED_MEMORY_ACCESS_LOCK.lock();
+    clear_exclusive_monitors(intermediate_physical_store_address, size);
     _write_memory(virtual_address_to_store, size, data);
+    EMULATED_MEMORY_ACCESS_LOCK.unlock();
+    return Ok(());
+}
+
+/// Store the SIMD&FP register into memory
+///
+/// 128bit access is divided into two 64bit accesses for memory hook handlers.
+fn store_simd_register_into_address(
+    s_r: &mut StoredRegisters,
+    intermediate_physical_store_address: usize,
+    target_register: u8,
+    size: u8,
+) -> Result<(), ()> {
+    let reg_data = read_simd_register(target_register);
+    let (access_size, number_of_accesses) = if size == ACCESS_SIZE_128BIT {
+        (0b11, 2)
+    } else {
+        (size, 1)
+    };
+
+    for i in 0..number_of_accesses {
+        let address = intermediate_physical_store_address + (i << access_size);
+        let virtual_address_to_store = get_virtual_address_to_access_ipa(address, true)?;
+        let reg_data = (reg_data >> (64 * i)) as u64;
+        let hook_result = memory_store_hook_handler(address, s_r, access_size, reg_data)?;
+        let data = match hook_result {
+            StoreHookResult::PassThrough | StoreHookResult::Continue => reg_data,
+            StoreHookResult::AlternativeData(d) => d,
+            StoreHookResult::Cancel => {
+                pr_debug!("The store instruction is cancelled.");
+                continue;
+            }
+        };
+        pr_debug!("Data: {:#X}", data);
+        EMULATED_MEMORY_ACCESS_LOCK.lock();
+        clear_exclusive_monitors(address, access_size);
+        _write_memory(virtual_address_to_store, access_size, data);
+        EMULATED_MEMORY_ACCESS_LOCK.unlock();
+    }
     return Ok(());
 }
 
@@ -5,14 +5,28 @@
 // This software is released under the MIT License.
 // http://opensource.org/licenses/mit-license.php
 
+mod compression;
+mod context;
+mod dirty_page;
+mod snapshot;
+
+pub use self::dirty_page::init_dirty_page_tracking;
+
+use self::snapshot::{
+    delete_snapshot, find_snapshot, get_snapshot_by_index, import_snapshot, take_snapshot,
+    Snapshot, SnapshotError, SnapshotName, BOOT_SNAPSHOT_NAME, SNAPSHOT_NAME_LENGTH,
+};
+
 use crate::{
     allocate_memory, free_memory,
-    gic::restore_gic,
-    multi_core::{power_off_cpu, NUMBER_OF_RUNNING_AP, STACK_TO_FREE_LATER},
+    gic::{restore_gic, restore_gic_state, save_gic, save_gic_state},
+    guest_breakpoint::{add_breakpoint, restore_write_protection},
+    multi_core::{is_any_ap_running, power_off_cpu, STACK_TO_FREE_LATER},
     paging::{
-        add_memory_access_trap, map_address, remake_stage2_page_table, remove_memory_access_trap,
+        add_memory_access_trap, get_number_of_stage2_table_pages, map_address,
+        remove_memory_access_trap,
     },
-    psci::PsciReturnCode,
+    psci::{call_psci_function, PsciFunctionId, PsciReturnCode},
     smmu::restore_smmu_status,
     StoredRegisters, BSP_MPIDR,
 };
@@ -22,68 +36,219 @@ use common::{
     PAGE_SIZE, STACK_PAGES,
 };
 
-use core::mem::MaybeUninit;
-use core::ptr::copy_nonoverlapping;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::cmp::min;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 static IS_RESTORE_NEEDED: AtomicBool = AtomicBool::new(false);
+static IS_MEMORY_RESTORE_READY: AtomicBool = AtomicBool::new(false);
+static NUMBER_OF_RESTORE_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+static NEXT_RESTORE_CHUNK: AtomicUsize = AtomicUsize::new(0);
+static NUMBER_OF_RESTORED_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+static IS_SNAPSHOT_CORRUPTED: AtomicBool = AtomicBool::new(false);
+/// True if only the dirty pages are restored in the current restore process
+static IS_INCREMENTAL_RESTORE: AtomicBool = AtomicBool::new(false);
 static mut MEMORY_SAVE_LIST: MaybeUninit<&'static mut [MemorySaveListEntry]> =
     MaybeUninit::uninit();
-static mut SAVED_SYSTEM_REGISTERS: MaybeUninit<SavedRegisters> = MaybeUninit::uninit();
-static mut SAVED_REGISTERS: MaybeUninit<StoredRegisters> = MaybeUninit::uninit();
-static mut ORIGINAL_VTTBR_EL2: u64 = 0;
-
-pub const HVC_EXIT_BOOT_SERVICE_TRAP: u16 = 0xFFF0;
-pub const HVC_AFTER_EXIT_BOOT_SERVICE_TRAP: u16 = 0xFFF1;
-
-struct SavedRegisters {
-    cpacr_el1: u64,
-    ttbr0_el1: u64,
-    /*ttbr1_el1: u64,*/
-    tcr_el1: u64,
-    mair_el1: u64,
-    sctlr_el1: u64,
-    vbar_el1: u64,
-    spsr_el2: u64,
-    elr_el2: u64,
-    sp_el1: u64,
+/// The snapshot which [`restore_main`] restores, this is set before [`IS_RESTORE_NEEDED`] is set
+static mut RESTORE_TARGET_SNAPSHOT: Option<&'static Snapshot> = None;
+/// All memory areas given to the guest, this is used to take snapshots by [`HVC_SNAPSHOT_CALL`]
+static mut GUEST_MEMORY_AREA_LIST: &[GuestMemoryArea] = &[];
+/// True while the store accesses to the on-demand save areas are trapped
+static IS_ON_DEMAND_SAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+/// True if the firmware has handed over GIC to the guest OS
+///
+/// The snapshots are taken by the guest only before ExitBootServices because the state of
+/// the devices and the command queues of ITS configured by the guest OS is not saved.
+static IS_AFTER_EXIT_BOOT_SERVICES: AtomicBool = AtomicBool::new(false);
+
+/// The action when the saved data is broken
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum CorruptionPolicy {
+    /// Do not restore the environment and perform the real system reset
+    SystemReset,
+    /// Log the error and restore the remaining data
+    Continue,
+}
+
+const CORRUPTION_POLICY: CorruptionPolicy = if cfg!(feature = "fast_restore_ignore_corruption") {
+    CorruptionPolicy::Continue
+} else {
+    CorruptionPolicy::SystemReset
+};
+
+/// The immediate value of HVC to operate snapshots from the guest
+///
+/// x0 is [`SnapshotOperation`], and the result is returned by x0 as [`SnapshotCallStatus`].
+/// The snapshot name(up to 16 bytes, padded by zero) is passed by x1 and x2 in little endian.
+pub const HVC_SNAPSHOT_CALL: u16 = 0xFFF2;
+
+/// The operation of [`HVC_SNAPSHOT_CALL`]
+///
+/// If edit this enum, you must adjust TryFrom
+#[repr(u64)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum SnapshotOperation {
+    /// Take the snapshot named by x1 and x2.
+    /// Only BSP can take it while all APs are powered off.
+    /// x1 becomes 0 after taking the snapshot, and 1 after restoring it.
+    Take = 0,
+    /// Restore the snapshot named by x1 and x2, this does not return on success
+    Restore = 1,
+    /// Get the information of the x1-th snapshot,
+    /// the name is returned by x1 and x2, and the compressed size is returned by x3
+    List = 2,
+    /// Delete the snapshot named by x1 and x2, the boot snapshot cannot be deleted
+    Delete = 3,
+    /// Write the snapshot named by x1 and x2 into the guest buffer in the snapshot file format.
+    /// x3 is the page-aligned address of the buffer, x4 is its size,
+    /// and x5 is the offset in the file to start writing.
+    /// The file size is returned by x1, and the number of written bytes is returned by x2.
+    /// The guest OS can save the file as [`common::SNAPSHOT_FILE_PATH`] to restore it at next boot,
+    /// `tools/snapshot_exporter` is the reference implementation.
+    Export = 4,
+}
+
+impl TryFrom<u64> for SnapshotOperation {
+    type Error = ();
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            x if x == SnapshotOperation::Take as u64 => Ok(SnapshotOperation::Take),
+            x if x == SnapshotOperation::Restore as u64 => Ok(SnapshotOperation::Restore),
+            x if x == SnapshotOperation::List as u64 => Ok(SnapshotOperation::List),
+            x if x == SnapshotOperation::Delete as u64 => Ok(SnapshotOperation::Delete),
+            x if x == SnapshotOperation::Export as u64 => Ok(SnapshotOperation::Export),
+            _ => Err(()),
+        }
+    }
+}
+
+#[repr(i64)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum SnapshotCallStatus {
+    Success = 0,
+    NotSupported = -1,
+    InvalidParameters = -2,
+    Denied = -3,
+    NotFound = -4,
+    AlreadyExists = -5,
+    NoMemory = -6,
+}
+
+impl From<SnapshotError> for SnapshotCallStatus {
+    fn from(e: SnapshotError) -> Self {
+        match e {
+            SnapshotError::AlreadyExists => SnapshotCallStatus::AlreadyExists,
+            SnapshotError::NotFound => SnapshotCallStatus::NotFound,
+            SnapshotError::NotEnoughMemory => SnapshotCallStatus::NoMemory,
+            SnapshotError::InvalidImage => SnapshotCallStatus::InvalidParameters,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct GuestMemoryArea {
+    start_address: usize,
+    size: usize,
+    /// If true, the area is saved only the pages written until ExitBootServices
+    is_on_demand_save: bool,
 }
 
 pub fn add_memory_save_list(list: *mut [MemorySaveListEntry]) {
     unsafe { MEMORY_SAVE_LIST.write(&mut *list) };
 }
 
+/// Trap the store access to the on-demand save areas
+///
+/// The traps are added into the current stage 2 page table, and removed
+/// when the area is saved or ExitBootServices is completed.
+/// The areas are copied into [`GUEST_MEMORY_AREA_LIST`] because the entries of
+/// [`MEMORY_SAVE_LIST`] will be overwritten by [`add_memory_area_to_memory_save_list`].
+/// If the boot snapshot is already imported by [`import_boot_snapshot`], no trap is added.
 pub fn create_memory_trap_for_save_memory() {
-    unsafe { ORIGINAL_VTTBR_EL2 = cpu::get_vttbr_el2() };
-    let page_table = remake_stage2_page_table().expect("Failed to remake page table.");
-    cpu::set_vttbr_el2(page_table as u64);
+    let is_boot_snapshot_imported = find_snapshot(&BOOT_SNAPSHOT_NAME).is_some();
     let list = unsafe { MEMORY_SAVE_LIST.assume_init_read() };
-    for e in list {
-        if e.num_of_pages == 0 && e.memory_start == 0 {
-            break;
-        }
-        if e.saved_address == MEMORY_SAVE_ADDRESS_ONDEMAND_FLAG {
+    let number_of_areas = list
+        .iter()
+        .take_while(|e| !(e.num_of_pages == 0 && e.memory_start == 0))
+        .count();
+    if number_of_areas == 0 {
+        return;
+    }
+    let area_list_pages =
+        ((number_of_areas * size_of::<GuestMemoryArea>()) + PAGE_SIZE - 1) >> PAGE_SHIFT;
+    let area_list_address =
+        allocate_memory(area_list_pages, None).expect("Failed to allocate memory");
+    let area_list = unsafe {
+        core::slice::from_raw_parts_mut(area_list_address as *mut GuestMemoryArea, number_of_areas)
+    };
+
+    for (e, area) in list.iter().zip(area_list.iter_mut()) {
+        *area = GuestMemoryArea {
+            start_address: e.memory_start,
+            size: (e.num_of_pages as usize) << PAGE_SHIFT,
+            is_on_demand_save: e.saved_address == MEMORY_SAVE_ADDRESS_ONDEMAND_FLAG,
+        };
+        if area.is_on_demand_save && !is_boot_snapshot_imported {
             /* OnDemand Save */
-            add_memory_access_trap(
-                e.memory_start,
-                (e.num_of_pages as usize) << PAGE_SHIFT,
-                true,
-                false,
-            )
-            .expect("Failed to add memory trap");
+            add_memory_access_trap(area.start_address, area.size, true, false)
+                .expect("Failed to add memory trap");
+            IS_ON_DEMAND_SAVE_ENABLED.store(true, Ordering::Relaxed);
         }
     }
+    unsafe { GUEST_MEMORY_AREA_LIST = area_list };
+}
+
+/// Remove the traps of all on-demand save areas
+///
+/// After this function, the pages which are not saved will not be restored by the boot snapshot.
+fn remove_memory_trap_for_save_memory() {
+    if !IS_ON_DEMAND_SAVE_ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    for area in unsafe { GUEST_MEMORY_AREA_LIST }
+        .iter()
+        .filter(|a| a.is_on_demand_save)
+    {
+        remove_memory_access_trap(area.start_address, area.size)
+            .expect("Failed to remove memory trap");
+        restore_write_protection(area.start_address, area.size);
+    }
 }
 
 #[inline(always)]
-pub fn check_memory_access_for_memory_save_list(ec: u8, far_el2: u64) -> bool {
-    if unsafe { ORIGINAL_VTTBR_EL2 != 0 } && ec == crate::EC_DATA_ABORT {
-        add_memory_area_to_memory_save_list(far_el2);
-        return true;
+pub fn check_memory_access_for_memory_save_list(
+    ec: u8,
+    esr_el2: u64,
+    far_el2: u64,
+    hpfar_el2: u64,
+) -> bool {
+    if ec != crate::EC_DATA_ABORT {
+        return false;
+    }
+    if IS_ON_DEMAND_SAVE_ENABLED.load(Ordering::Relaxed) {
+        return add_memory_area_to_memory_save_list(get_fault_ipa(esr_el2, far_el2, hpfar_el2));
+    }
+    if dirty_page::is_software_tracking_enabled() {
+        return dirty_page::handle_write_fault(get_fault_ipa(esr_el2, far_el2, hpfar_el2));
     }
     return false;
 }
 
+/// Get the intermediate physical address of the stage 2 fault from HPFAR_EL2
+///
+/// If the fault occurred on the stage 1 translation table walk(ESR_EL2.S1PTW == 1),
+/// the address is the page of the stage 1 translation table, not the page of FAR_EL2.
+/// Therefore, the address translation of EL1 must not be used.
+fn get_fault_ipa(esr_el2: u64, far_el2: u64, hpfar_el2: u64) -> usize {
+    let page_address = cpu::convert_hpfar_el2_to_ipa(hpfar_el2);
+    if (esr_el2 & cpu::ESR_EL2_ISS_S1PTW) != 0 {
+        page_address
+    } else {
+        page_address | ((far_el2 as usize) & !PAGE_MASK)
+    }
+}
+
 fn compress_memory_save_list(list: &mut [MemorySaveListEntry]) -> Option<usize> {
     for i in 0..list.len() {
         if (list[i].memory_start == 0 && list[i].num_of_pages == 0)
@@ -115,17 +280,44 @@ fn compress_memory_save_list(list: &mut [MemorySaveListEntry]) -> Option<usize>
     return None;
 }
 
+/// Check if the page is in the on-demand save areas and not added into the memory save list yet
+fn is_on_demand_save_required(page_address: usize) -> bool {
+    let is_on_demand_save_area = unsafe { GUEST_MEMORY_AREA_LIST }.iter().any(|a| {
+        a.is_on_demand_save && (a.start_address..(a.start_address + a.size)).contains(&page_address)
+    });
+    if !is_on_demand_save_area {
+        return false;
+    }
+    for e in unsafe { MEMORY_SAVE_LIST.assume_init_read() }.iter() {
+        if e.num_of_pages == 0 && e.memory_start == 0 {
+            break;
+        }
+        if e.saved_address != MEMORY_SAVE_ADDRESS_ONDEMAND_FLAG
+            && (e.memory_start..(e.memory_start + ((e.num_of_pages as usize) << PAGE_SHIFT)))
+                .contains(&page_address)
+        {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// Add the page of the fault address into the memory save list and remove its trap
+///
+/// # Arguments
+/// * `fault_ipa` - The intermediate physical address of the store access
+///
+/// # Result
+/// If the fault address is not in the on-demand save areas, or already saved, returns false.
+/// In that case, the abort must be handled by the other handlers.
 #[inline(never)]
-fn add_memory_area_to_memory_save_list(far_el2: u64) {
-    let fault_address =
-        cpu::convert_virtual_address_to_intermediate_physical_address_el1_read(far_el2 as usize)
-            .unwrap_or_else(|_| {
-                cpu::convert_virtual_address_to_intermediate_physical_address_el1_write(
-                    far_el2 as usize,
-                )
-                .unwrap_or_else(|_| panic!("Failed to convert FAR_EL2({:#X})", far_el2))
-            })
-            & PAGE_MASK;
+fn add_memory_area_to_memory_save_list(fault_ipa: usize) -> bool {
+    let fault_address = fault_ipa & PAGE_MASK;
+
+    if !is_on_demand_save_required(fault_address) {
+        /* The page is not the target or already added, the abort was caused by another trap */
+        return false;
+    }
 
     pr_debug!("Fault Address: {:#X}", fault_address);
     let mut available_entry: Option<*mut MemorySaveListEntry> = None;
@@ -182,134 +374,108 @@ fn add_memory_area_to_memory_save_list(far_el2: u64) {
         }
     }
     remove_memory_access_trap(fault_address, PAGE_SIZE).expect("Failed to remove memory trap");
+    restore_write_protection(fault_address, PAGE_SIZE);
+    return true;
 }
 
-fn save_memory(list: &mut [MemorySaveListEntry]) {
-    for e in list {
-        if e.num_of_pages == 0 && e.memory_start == 0 {
-            break;
-        }
-        if e.saved_address != usize::MAX {
-            let allocated_memory =
-                allocate_memory(e.num_of_pages as usize, None).expect("Failed to allocate memory");
-            unsafe {
-                copy_nonoverlapping(
-                    e.memory_start as *const u8,
-                    allocated_memory as *mut u8,
-                    (e.num_of_pages << PAGE_SHIFT) as usize,
-                )
-            };
-            e.saved_address = allocated_memory;
-        }
+/// Re-add the memory trap of fast restore into the page after other module removed its trap
+///
+/// This is called by [`crate::guest_breakpoint`] when the write protection of the page is removed.
+pub fn restore_memory_trap(page_address: usize) {
+    if IS_ON_DEMAND_SAVE_ENABLED.load(Ordering::Relaxed) && is_on_demand_save_required(page_address)
+    {
+        add_memory_access_trap(page_address, PAGE_SIZE, true, false)
+            .expect("Failed to add memory trap");
     }
+    dirty_page::restore_write_trap(page_address);
 }
 
-fn restore_memory(list: &[MemorySaveListEntry]) {
-    for e in list {
-        if e.num_of_pages == 0 && e.memory_start == 0 {
-            break;
+/// Copy the saved memory chunk by chunk until no chunk remains
+///
+/// This is called by BSP and APs in parallel after [`IS_MEMORY_RESTORE_READY`] became true.
+/// Each chunk is taken from [`NEXT_RESTORE_CHUNK`], and [`NUMBER_OF_RESTORED_CHUNKS`] is
+/// increased after the copy is finished.
+fn restore_memory_chunks(snapshot: &Snapshot) {
+    let number_of_chunks = NUMBER_OF_RESTORE_CHUNKS.load(Ordering::Relaxed);
+    let page_filter: Option<&dyn Fn(usize) -> bool> =
+        if IS_INCREMENTAL_RESTORE.load(Ordering::Relaxed) {
+            Some(&dirty_page::is_page_dirty)
+        } else {
+            None
+        };
+    loop {
+        let chunk = NEXT_RESTORE_CHUNK.fetch_add(1, Ordering::Relaxed);
+        if chunk >= number_of_chunks {
+            return;
         }
-        if e.saved_address != MEMORY_SAVE_ADDRESS_ONDEMAND_FLAG {
-            pr_debug!("Restore {:#X} from {:#X}", e.memory_start, e.saved_address);
-            //if cpu::convert_virtual_address_to_physical_address_el2_write(e.memory_start).is_err() {
-            map_address(
-                e.memory_start,
-                e.memory_start,
-                (e.num_of_pages << PAGE_SHIFT) as usize,
-                true,
-                true,
-                false,
-                false,
-            )
-            .expect("Failed to map memory");
-            //}
-            unsafe {
-                copy_nonoverlapping(
-                    e.saved_address as *const u8,
-                    e.memory_start as *mut u8,
-                    (e.num_of_pages << PAGE_SHIFT) as usize,
-                )
-            };
+        if !snapshot.restore_chunk(
+            chunk,
+            CORRUPTION_POLICY == CorruptionPolicy::SystemReset,
+            page_filter,
+        ) {
+            IS_SNAPSHOT_CORRUPTED.store(true, Ordering::Relaxed);
         }
+        NUMBER_OF_RESTORED_CHUNKS.fetch_add(1, Ordering::Release);
     }
 }
 
-static mut ORIGINAL_INSTRUCTION: u32 = 0;
-pub fn save_original_instruction_and_insert_hvc(address: usize, hvc_number: u16) {
-    if cpu::convert_virtual_address_to_physical_address_el2_write(address & PAGE_MASK).is_err() {
-        map_address(
-            address & PAGE_MASK,
-            address & PAGE_MASK,
-            PAGE_SIZE,
-            true,
-            true,
-            false,
-            false,
-        )
-        .expect("Failed to map memory");
-    }
-    let hvc_instruction = 0b11010100000 << 21 | (hvc_number as u32) << 5 | 0b00010;
-    unsafe {
-        ORIGINAL_INSTRUCTION = *(address as usize as *const u32);
-        *(address as usize as *mut u32) = hvc_instruction;
-    }
-    cpu::flush_tlb_el1();
-    cpu::clear_instruction_cache_all();
-}
+/// The entry point of ExitBootServices, this is used to re-arm the trap when the call failed
+static mut EXIT_BOOT_SERVICE_ADDRESS: usize = 0;
 
 pub fn add_trap_to_exit_boot_service(address: usize) {
     assert_ne!(address, 0);
-    save_original_instruction_and_insert_hvc(address, HVC_EXIT_BOOT_SERVICE_TRAP);
+    unsafe { EXIT_BOOT_SERVICE_ADDRESS = address };
+    add_breakpoint(address, exit_boot_service_trap_main, 0)
+        .expect("Failed to add the breakpoint into ExitBootServices");
 }
 
-pub fn exit_boot_service_trap_main(regs: &mut StoredRegisters, elr: u64) {
-    if unsafe { ORIGINAL_INSTRUCTION } == 0 {
+fn exit_boot_service_trap_main(regs: &mut StoredRegisters, _address: usize, _context: usize) {
+    let Ok(return_address) =
+        cpu::convert_virtual_address_to_intermediate_physical_address_el1_read(regs.x30 as usize) else {
+        println!(
+            "Failed to convert the return address of ExitBootServices({:#X}).",
+            regs.x30
+        );
+        add_trap_to_exit_boot_service(unsafe { EXIT_BOOT_SERVICE_ADDRESS });
         return;
-    }
-    assert_ne!(elr, 0);
-    let hvc_address = elr as usize - cpu::AA64_INSTRUCTION_SIZE;
-    unsafe { *(hvc_address as *mut u32) = ORIGINAL_INSTRUCTION };
-    cpu::set_elr_el2(hvc_address as u64);
-    save_original_instruction_and_insert_hvc(regs.x30 as usize, HVC_AFTER_EXIT_BOOT_SERVICE_TRAP);
+    };
+    add_breakpoint(return_address, after_exit_boot_service_trap_main, 0)
+        .expect("Failed to add the breakpoint into the return address of ExitBootServices");
 }
 
-pub fn after_exit_boot_service_trap_main(regs: &mut StoredRegisters, elr: u64) {
-    if unsafe { ORIGINAL_INSTRUCTION } == 0 {
-        return;
-    }
-    assert_ne!(elr, 0);
-    let hvc_address = elr as usize - cpu::AA64_INSTRUCTION_SIZE;
-    unsafe {
-        *(hvc_address as *mut u32) = ORIGINAL_INSTRUCTION;
-        ORIGINAL_INSTRUCTION = 0;
-    }
-    cpu::set_elr_el2(hvc_address as u64);
-    cpu::flush_tlb_el1();
-    cpu::clear_instruction_cache_all();
+fn after_exit_boot_service_trap_main(regs: &mut StoredRegisters, _address: usize, _context: usize) {
     pr_debug!("ExitBootServiceStatus: {:#X}", regs.x0);
     if regs.x0 != 0 {
-        panic!("ExitBootService is failed(TODO: reset trap point and continue...)");
+        /*
+            ExitBootServices fails when the memory map key is stale,
+            the payload will get the memory map and call it again.
+        */
+        println!(
+            "ExitBootServices is failed({:#X}), wait for the next call.",
+            regs.x0
+        );
+        add_trap_to_exit_boot_service(unsafe { EXIT_BOOT_SERVICE_ADDRESS });
+        return;
     }
 
     /* Save current status */
-    save_memory(unsafe { MEMORY_SAVE_LIST.assume_init_read() });
-    /* Store registers */
-    let r = SavedRegisters {
-        cpacr_el1: cpu::get_cpacr_el1(),
-        ttbr0_el1: cpu::get_ttbr0_el1(),
-        tcr_el1: cpu::get_tcr_el1(),
-        mair_el1: cpu::get_mair_el1(),
-        sctlr_el1: cpu::get_sctlr_el1(),
-        vbar_el1: cpu::get_vbar_el1(),
-        spsr_el2: cpu::get_spsr_el2(),
-        elr_el2: cpu::get_elr_el2(),
-        sp_el1: cpu::get_sp_el1(),
-    };
-    unsafe { SAVED_SYSTEM_REGISTERS.write(r) };
-    unsafe { SAVED_REGISTERS.write(regs.clone()) };
-    cpu::set_vttbr_el2(unsafe { ORIGINAL_VTTBR_EL2 }); /* TODO: free old page table */
-    unsafe { ORIGINAL_VTTBR_EL2 = 0 };
-    pr_debug!("Remove page table for memory save");
+    if let Some(acpi_rsdp) = unsafe { crate::ACPI_RSDP } {
+        save_gic(acpi_rsdp);
+    }
+    let areas = unsafe { MEMORY_SAVE_LIST.assume_init_read() }
+        .iter()
+        .take_while(|e| !(e.num_of_pages == 0 && e.memory_start == 0))
+        .filter(|e| e.saved_address != MEMORY_SAVE_ADDRESS_ONDEMAND_FLAG)
+        .map(|e| (e.memory_start, e.num_of_pages as usize));
+    let boot_snapshot = take_snapshot(BOOT_SNAPSHOT_NAME, areas, regs.clone(), None)
+        .unwrap_or_else(|err| panic!("Failed to take the boot snapshot: {:?}", err));
+    IS_AFTER_EXIT_BOOT_SERVICES.store(true, Ordering::Relaxed);
+    remove_memory_trap_for_save_memory();
+    pr_debug!(
+        "Remove traps for memory save(Stage 2 Table: {} Pages)",
+        get_number_of_stage2_table_pages()
+    );
+    dirty_page::start_dirty_page_tracking(boot_snapshot);
 }
 
 /// If you disable all entries of Stage2 Page Table,
@@ -337,7 +503,7 @@ fn modify_all_enable_bit_of_stage2_top_level_entries(is_enabled: bool) {
         )
     };
     if is_enabled {
-        for e in table {
+        for e in table.iter_mut().filter(|e| **e != 0) {
             *e |= 1;
         }
     } else {
@@ -350,7 +516,18 @@ fn modify_all_enable_bit_of_stage2_top_level_entries(is_enabled: bool) {
 /// This function will be called when the guest OS requested power off or reboot
 pub fn enter_restore_process() -> ! {
     pr_debug!("Fast Restore is requested.");
+    let Some(snapshot) = find_snapshot(&BOOT_SNAPSHOT_NAME) else {
+        panic!("The boot snapshot is not taken yet");
+    };
+    start_restore_process(snapshot)
+}
+
+/// Stop all CPUs and restore `snapshot`
+///
+/// The interrupt controller and SMMU are reinitialized in the same way as the boot snapshot.
+fn start_restore_process(snapshot: &'static Snapshot) -> ! {
     cpu::local_irq_fiq_save();
+    unsafe { RESTORE_TARGET_SNAPSHOT = Some(snapshot) };
     IS_RESTORE_NEEDED.store(true, Ordering::SeqCst);
 
     modify_all_enable_bit_of_stage2_top_level_entries(false);
@@ -370,6 +547,201 @@ pub fn enter_restore_process() -> ! {
     restore_main()
 }
 
+/// Handle [`HVC_SNAPSHOT_CALL`]
+///
+/// # Arguments
+/// * `regs` - The registers of the guest, the result is written into them
+pub fn snapshot_call_main(regs: &mut StoredRegisters) {
+    fn name_from_registers(x1: u64, x2: u64) -> SnapshotName {
+        let mut name = [0u8; SNAPSHOT_NAME_LENGTH];
+        name[0..8].copy_from_slice(&x1.to_le_bytes());
+        name[8..16].copy_from_slice(&x2.to_le_bytes());
+        name
+    }
+
+    let name = name_from_registers(regs.x1, regs.x2);
+    let status = match SnapshotOperation::try_from(regs.x0) {
+        Ok(SnapshotOperation::Take) => take_snapshot_by_guest(name, regs),
+        Ok(SnapshotOperation::Restore) => match find_snapshot(&name) {
+            Some(s) => {
+                println!("Restore the snapshot requested by the guest.");
+                start_restore_process(s)
+            }
+            None => SnapshotCallStatus::NotFound,
+        },
+        Ok(SnapshotOperation::List) => match get_snapshot_by_index(regs.x1 as usize) {
+            Some(s) => {
+                regs.x1 = u64::from_le_bytes(s.name[0..8].try_into().unwrap());
+                regs.x2 = u64::from_le_bytes(s.name[8..16].try_into().unwrap());
+                regs.x3 = s.size as u64;
+                SnapshotCallStatus::Success
+            }
+            None => SnapshotCallStatus::NotFound,
+        },
+        Ok(SnapshotOperation::Delete) => {
+            if name == BOOT_SNAPSHOT_NAME {
+                SnapshotCallStatus::Denied
+            } else if !is_guest_running_on_bsp_only() {
+                /* Other CPUs may be using the snapshot by other operations */
+                println!("Snapshots can be deleted only when the guest is running on BSP only.");
+                SnapshotCallStatus::Denied
+            } else {
+                if find_snapshot(&name)
+                    .map(dirty_page::is_tracking)
+                    .unwrap_or(false)
+                {
+                    dirty_page::stop_dirty_page_tracking();
+                }
+                delete_snapshot(&name)
+                    .map(|_| SnapshotCallStatus::Success)
+                    .unwrap_or_else(SnapshotCallStatus::from)
+            }
+        }
+        Ok(SnapshotOperation::Export) => export_snapshot_by_guest(name, regs),
+        Err(_) => {
+            println!("Unknown snapshot operation: {:#X}", regs.x0);
+            SnapshotCallStatus::NotSupported
+        }
+    };
+    regs.x0 = status as i64 as u64;
+}
+
+fn is_guest_running_on_bsp_only() -> bool {
+    (unsafe { BSP_MPIDR }) == cpu::get_mpidr_el1() && !is_any_ap_running()
+}
+
+/// Take the snapshot of all guest memory, the registers, and GIC
+///
+/// The guest must be running only on BSP, otherwise the state of APs will be lost.
+/// The snapshot can be taken only before ExitBootServices, see [`IS_AFTER_EXIT_BOOT_SERVICES`].
+/// While the on-demand save is enabled, the on-demand save areas are saved entirely and
+/// their traps are kept to make the boot snapshot at ExitBootServices.
+fn take_snapshot_by_guest(name: SnapshotName, regs: &mut StoredRegisters) -> SnapshotCallStatus {
+    if name == [0; SNAPSHOT_NAME_LENGTH] {
+        return SnapshotCallStatus::InvalidParameters;
+    }
+    if !is_guest_running_on_bsp_only() {
+        println!("Snapshots can be taken only when the guest is running on BSP only.");
+        return SnapshotCallStatus::Denied;
+    }
+    if IS_AFTER_EXIT_BOOT_SERVICES.load(Ordering::Relaxed) {
+        println!("Snapshots can be taken only before ExitBootServices.");
+        return SnapshotCallStatus::Denied;
+    }
+    let Some(gic_state) = unsafe { crate::ACPI_RSDP }.and_then(save_gic_state) else {
+        println!("Failed to save GIC.");
+        return SnapshotCallStatus::NotSupported;
+    };
+    let areas = unsafe { GUEST_MEMORY_AREA_LIST }
+        .iter()
+        .map(|a| (a.start_address, a.size >> PAGE_SHIFT));
+    for (start_address, number_of_pages) in areas.clone() {
+        map_address(
+            start_address,
+            start_address,
+            number_of_pages << PAGE_SHIFT,
+            true,
+            true,
+            false,
+            false,
+        )
+        .expect("Failed to map memory");
+    }
+
+    /* The guest can distinguish the return from the restore by x1 */
+    let mut saved_registers = regs.clone();
+    saved_registers.x0 = SnapshotCallStatus::Success as i64 as u64;
+    saved_registers.x1 = 1;
+    match take_snapshot(name, areas, saved_registers, Some(gic_state)) {
+        Ok(_) => {
+            regs.x1 = 0;
+            SnapshotCallStatus::Success
+        }
+        Err(e) => SnapshotCallStatus::from(e),
+    }
+}
+
+/// Write the part of the snapshot file into the guest buffer
+///
+/// The buffer must be in one of the guest memory areas.
+/// The guest can read the whole file by calling this with the increasing offset
+/// until the number of written bytes becomes zero.
+/// The written pages are marked as dirty to be restored by the next restore process.
+fn export_snapshot_by_guest(name: SnapshotName, regs: &mut StoredRegisters) -> SnapshotCallStatus {
+    let Some(snapshot) = find_snapshot(&name) else {
+        return SnapshotCallStatus::NotFound;
+    };
+    let buffer_address = regs.x3 as usize;
+    let offset = regs.x5 as usize;
+    let file_size = snapshot.get_file_size();
+    regs.x1 = file_size as u64;
+    regs.x2 = 0;
+    if (buffer_address & !PAGE_MASK) != 0 || offset > file_size {
+        return SnapshotCallStatus::InvalidParameters;
+    }
+    let write_size = min(regs.x4 as usize, file_size - offset);
+    if write_size == 0 {
+        return SnapshotCallStatus::Success;
+    }
+    let is_guest_memory = unsafe { GUEST_MEMORY_AREA_LIST }.iter().any(|a| {
+        a.start_address <= buffer_address
+            && buffer_address
+                .checked_add(write_size)
+                .map(|end| end <= a.start_address + a.size)
+                .unwrap_or(false)
+    });
+    if !is_guest_memory {
+        return SnapshotCallStatus::InvalidParameters;
+    }
+    if IS_ON_DEMAND_SAVE_ENABLED.load(Ordering::Relaxed) {
+        return SnapshotCallStatus::Denied;
+    }
+    map_address(
+        buffer_address,
+        buffer_address,
+        (write_size + PAGE_SIZE - 1) & PAGE_MASK,
+        true,
+        true,
+        false,
+        false,
+    )
+    .expect("Failed to map memory");
+    let written_size = snapshot.export(offset, unsafe {
+        core::slice::from_raw_parts_mut(buffer_address as *mut u8, write_size)
+    });
+    dirty_page::mark_dirty_pages(buffer_address, written_size);
+    regs.x2 = written_size as u64;
+    return SnapshotCallStatus::Success;
+}
+
+/// Import the snapshot file loaded by hypervisor_bootloader as the boot snapshot
+///
+/// This must be called after [`add_memory_save_list`].
+/// If succeeded, the on-demand save is not needed, and the guest must request
+/// the restore by [`HVC_SNAPSHOT_CALL`] instead of calling ExitBootServices.
+///
+/// # Arguments
+/// * `image_address` - The address of the image allocated from the memory pool
+/// * `image_size` - The size of the image
+///
+/// # Result
+/// If the image is imported, returns true. Otherwise the image is freed and returns false.
+pub fn import_boot_snapshot(image_address: usize, image_size: usize) -> bool {
+    match import_snapshot(BOOT_SNAPSHOT_NAME, image_address, image_size) {
+        Ok(s) => {
+            println!(
+                "Imported the boot snapshot({} KiB) from the snapshot file.",
+                s.size >> 10
+            );
+            true
+        }
+        Err(e) => {
+            println!("Failed to import the snapshot file: {:?}", e);
+            false
+        }
+    }
+}
+
 #[inline(always)]
 pub fn perform_restore_if_needed() {
     if IS_RESTORE_NEEDED.load(Ordering::Relaxed) {
@@ -381,7 +753,14 @@ pub fn perform_restore_if_needed() {
 fn restore_main() -> ! {
     cpu::local_irq_fiq_save();
     if unsafe { BSP_MPIDR } != cpu::get_mpidr_el1() {
-        pr_debug!("This CPU(MPIDR: {:#X}) is not BSP, currently perform CPU_OFF(TODO: use AP to copy memory)", cpu::get_mpidr_el1());
+        pr_debug!(
+            "This CPU(MPIDR: {:#X}) is not BSP, copy memory and perform CPU_OFF",
+            cpu::get_mpidr_el1()
+        );
+        while !IS_MEMORY_RESTORE_READY.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        restore_memory_chunks(unsafe { RESTORE_TARGET_SNAPSHOT.unwrap() });
         let result = power_off_cpu();
         panic!(
             "Failed to call CPU_OFF: {:#X?}",
@@ -389,17 +768,60 @@ fn restore_main() -> ! {
         );
     }
     println!("BSP entered the restore process.");
+
+    /* Restore memory with APs */
+    pr_debug!("Restore the memory");
+    let snapshot = unsafe { RESTORE_TARGET_SNAPSHOT.unwrap() };
+    let number_of_chunks = snapshot.prepare_restore();
+    let is_incremental_restore = dirty_page::is_tracking(snapshot);
+    if is_incremental_restore {
+        println!(
+            "Restore only dirty pages({} pages).",
+            dirty_page::collect_dirty_pages()
+        );
+    }
+    IS_INCREMENTAL_RESTORE.store(is_incremental_restore, Ordering::Relaxed);
+    NUMBER_OF_RESTORE_CHUNKS.store(number_of_chunks, Ordering::Relaxed);
+    IS_MEMORY_RESTORE_READY.store(true, Ordering::Release);
+    restore_memory_chunks(snapshot);
+    while NUMBER_OF_RESTORED_CHUNKS.load(Ordering::Acquire) != number_of_chunks {
+        core::hint::spin_loop();
+    }
+    if IS_SNAPSHOT_CORRUPTED.load(Ordering::Relaxed) {
+        if CORRUPTION_POLICY == CorruptionPolicy::SystemReset {
+            println!("The snapshot is corrupted, perform the system reset instead of restoring.");
+            let result = call_psci_function(PsciFunctionId::SystemReset, 0, 0, 0);
+            panic!(
+                "Failed to call SYSTEM_RESET: {:#X?}",
+                PsciReturnCode::try_from(result as i32)
+            );
+        }
+        println!("The snapshot is corrupted, continue the restore process.");
+        IS_SNAPSHOT_CORRUPTED.store(false, Ordering::Relaxed);
+    }
+    println!("Restored the memory({} chunks).", number_of_chunks);
+
     println!("Wait until all APs are powered off...");
     cpu::dsb();
     cpu::isb();
-    while NUMBER_OF_RUNNING_AP.load(Ordering::Relaxed) != 0 {
+    while is_any_ap_running() {
         core::hint::spin_loop();
     }
     println!("All APs are powered off.");
+    IS_MEMORY_RESTORE_READY.store(false, Ordering::Relaxed);
+    NEXT_RESTORE_CHUNK.store(0, Ordering::Relaxed);
+    NUMBER_OF_RESTORED_CHUNKS.store(0, Ordering::Relaxed);
 
     modify_all_enable_bit_of_stage2_top_level_entries(true);
     /* Now, we can call add_memory_access_trap/remove_memory_access_trap */
 
+    if is_incremental_restore {
+        dirty_page::rearm_dirty_page_tracking();
+    } else if !IS_ON_DEMAND_SAVE_ENABLED.load(Ordering::Relaxed) {
+        /* The tracking will be started with the boot snapshot at ExitBootServices */
+        dirty_page::start_dirty_page_tracking(snapshot);
+    }
+
     /* Free last one AP's stack if needed */
     let old_stack = STACK_TO_FREE_LATER.load(Ordering::Relaxed);
     if old_stack != 0 {
@@ -410,34 +832,29 @@ fn restore_main() -> ! {
     }
 
     /* Restore GIC */
-    if let Some(acpi_rsdp) = unsafe { crate::ACPI_RSDP } {
-        restore_gic(acpi_rsdp);
+    match &snapshot.gic_state {
+        Some(gic_state) => restore_gic_state(gic_state),
+        None => restore_gic(),
     }
+    /* Only the snapshots taken at ExitBootServices(the boot snapshot) have no GIC state */
+    IS_AFTER_EXIT_BOOT_SERVICES.store(snapshot.gic_state.is_none(), Ordering::Relaxed);
 
     #[cfg(feature = "smmu")]
     restore_smmu_status();
 
     /* Restore saved registers */
-    let saved_registers = unsafe { SAVED_SYSTEM_REGISTERS.assume_init_read() };
-    cpu::set_cpacr_el1(saved_registers.cpacr_el1);
-    cpu::set_ttbr0_el1(saved_registers.ttbr0_el1);
-    cpu::set_tcr_el1(saved_registers.tcr_el1);
-    cpu::set_mair_el1(saved_registers.mair_el1);
-    cpu::set_sctlr_el1(saved_registers.sctlr_el1);
-    cpu::set_vbar_el1(saved_registers.vbar_el1);
-    cpu::set_spsr_el2(saved_registers.spsr_el2);
-    cpu::set_elr_el2(saved_registers.elr_el2);
-    cpu::set_sp_el1(saved_registers.sp_el1);
-    cpu::set_cntp_ctl_el0(0);
-
-    /* Restore memory */
-    pr_debug!("Restore the memory");
-    restore_memory(unsafe { MEMORY_SAVE_LIST.assume_init_read() });
+    snapshot.system_registers.restore();
+
+    println!(
+        "Stage 2 Table: {} Pages",
+        get_number_of_stage2_table_pages()
+    );
 
     cpu::flush_tlb_el1();
     cpu::clear_instruction_cache_all();
     pr_debug!("ERET");
     IS_RESTORE_NEEDED.store(false, Ordering::SeqCst);
+    let registers = &snapshot.registers as *const StoredRegisters as usize;
     unsafe {
         core::arch::asm!("
             ldp x30, xzr, [x0, #( 15 * 16)]
@@ -456,6 +873,6 @@ fn restore_main() -> ! {
             ldp  x4,  x5, [x0, #(  2 * 16)]
             ldp  x2,  x3, [x0, #(  1 * 16)]
             ldp  x0,  x1, [x0, #(  0 * 16)]
-            eret", in("x0") SAVED_REGISTERS.as_ptr() as usize, options(noreturn))
+            eret", in("x0") registers, options(noreturn))
     }
 }
@@ -0,0 +1,290 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Page Compression for Fast Restore
+//!
+//! The pages are compressed one by one, and each page is stored as a 16bit header followed by the data.
+//!
+//! | Header            | Data                                  |
+//! |-------------------|---------------------------------------|
+//! | 0                 | None (All bytes of the page are zero) |
+//! | [`PAGE_STORED_RAW`] | The page itself (PAGE_SIZE bytes)     |
+//! | Others            | LZ4 block which has `Header` bytes    |
+//!
+
+use common::PAGE_SIZE;
+
+use core::mem::size_of;
+
+const PAGE_ZERO: u16 = 0;
+const PAGE_STORED_RAW: u16 = u16::MAX;
+
+/* LZ4 block format parameters */
+const MIN_MATCH: usize = 4;
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const HASH_TABLE_BITS: usize = 10;
+
+/// Get the size of the buffer which can store compressed `pages` in the worst case
+pub const fn get_max_compressed_size(pages: usize) -> usize {
+    pages * (size_of::<u16>() + PAGE_SIZE)
+}
+
+/// Compress pages
+///
+/// # Arguments
+/// * `source` - The pages to compress, the length must be the multiple of PAGE_SIZE
+/// * `destination` - The buffer to store the compressed data,
+///   the length must be larger than [`get_max_compressed_size`]
+///
+/// # Result
+/// Returns (the size of compressed data, the number of zero pages)
+pub fn compress_pages(source: &[u8], destination: &mut [u8]) -> (usize, usize) {
+    assert_eq!(source.len() % PAGE_SIZE, 0);
+    assert!(destination.len() >= get_max_compressed_size(source.len() / PAGE_SIZE));
+    let mut output = 0;
+    let mut number_of_zero_pages = 0;
+
+    for page in source.chunks_exact(PAGE_SIZE) {
+        let data_area = output + size_of::<u16>();
+        let header = if is_zero_page(page) {
+            number_of_zero_pages += 1;
+            PAGE_ZERO
+        } else if let Some(size) = lz4_compress_block(
+            page,
+            &mut destination[data_area..(data_area + PAGE_SIZE - 1)],
+        ) {
+            size as u16
+        } else {
+            destination[data_area..(data_area + PAGE_SIZE)].copy_from_slice(page);
+            PAGE_STORED_RAW
+        };
+        destination[output..data_area].copy_from_slice(&header.to_le_bytes());
+        output = data_area
+            + match header {
+                PAGE_ZERO => 0,
+                PAGE_STORED_RAW => PAGE_SIZE,
+                size => size as usize,
+            };
+    }
+    return (output, number_of_zero_pages);
+}
+
+/// Decompress pages compressed by [`compress_pages`]
+///
+/// # Arguments
+/// * `source` - The compressed data
+/// * `destination` - The pages to store, the length must be same as the original one
+/// * `page_filter` - If Some, only the pages whose index is accepted by the filter are written
+///
+/// # Result
+/// If the data is broken, returns Err(())
+pub fn decompress_pages(
+    source: &[u8],
+    destination: &mut [u8],
+    page_filter: Option<&dyn Fn(usize) -> bool>,
+) -> Result<(), ()> {
+    let mut input = 0;
+    for (index, page) in destination.chunks_exact_mut(PAGE_SIZE).enumerate() {
+        let header = u16::from_le_bytes([
+            *source.get(input).ok_or(())?,
+            *source.get(input + 1).ok_or(())?,
+        ]);
+        input += size_of::<u16>();
+        let is_required = page_filter.map(|f| f(index)).unwrap_or(true);
+        match header {
+            PAGE_ZERO => {
+                if is_required {
+                    page.fill(0)
+                }
+            }
+            PAGE_STORED_RAW => {
+                let data = source.get(input..(input + PAGE_SIZE)).ok_or(())?;
+                if is_required {
+                    page.copy_from_slice(data);
+                }
+                input += PAGE_SIZE;
+            }
+            size => {
+                let size = size as usize;
+                let data = source.get(input..(input + size)).ok_or(())?;
+                if is_required && lz4_decompress_block(data, page)? != PAGE_SIZE {
+                    return Err(());
+                }
+                input += size;
+            }
+        }
+    }
+    if input != source.len() {
+        return Err(());
+    }
+    return Ok(());
+}
+
+fn is_zero_page(page: &[u8]) -> bool {
+    let (prefix, words, suffix) = unsafe { page.align_to::<u64>() };
+    prefix.iter().all(|b| *b == 0)
+        && words.iter().all(|w| *w == 0)
+        && suffix.iter().all(|b| *b == 0)
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes([
+        data[position],
+        data[position + 1],
+        data[position + 2],
+        data[position + 3],
+    ])
+}
+
+const fn calculate_hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_TABLE_BITS)) as usize
+}
+
+/// Compress `source` into LZ4 block format
+///
+/// # Result
+/// If the compressed data does not fit in `destination`, returns None
+fn lz4_compress_block(source: &[u8], destination: &mut [u8]) -> Option<usize> {
+    assert!(source.len() <= u16::MAX as usize);
+    let mut hash_table = [0u16; 1 << HASH_TABLE_BITS];
+    let mut output = 0;
+    let mut anchor = 0;
+    let mut position = 0;
+
+    if source.len() > MF_LIMIT {
+        let match_limit = source.len() - MF_LIMIT;
+        let extend_limit = source.len() - LAST_LITERALS;
+        while position < match_limit {
+            let sequence = read_u32(source, position);
+            let hash = calculate_hash(sequence);
+            let candidate = hash_table[hash] as usize;
+            hash_table[hash] = position as u16;
+            if candidate < position && read_u32(source, candidate) == sequence {
+                let mut match_length = MIN_MATCH;
+                while position + match_length < extend_limit
+                    && source[candidate + match_length] == source[position + match_length]
+                {
+                    match_length += 1;
+                }
+                output = lz4_write_sequence(
+                    destination,
+                    output,
+                    &source[anchor..position],
+                    Some(((position - candidate) as u16, match_length)),
+                )?;
+                position += match_length;
+                anchor = position;
+            } else {
+                position += 1;
+            }
+        }
+    }
+    lz4_write_sequence(destination, output, &source[anchor..], None)
+}
+
+fn lz4_write_sequence(
+    destination: &mut [u8],
+    mut output: usize,
+    literals: &[u8],
+    match_info: Option<(u16, usize)>,
+) -> Option<usize> {
+    let mut push = |output: &mut usize, data: u8| -> Option<()> {
+        *destination.get_mut(*output)? = data;
+        *output += 1;
+        Some(())
+    };
+    let literal_length = literals.len();
+    let match_length_code = match_info.map(|(_, l)| l - MIN_MATCH).unwrap_or(0);
+    push(
+        &mut output,
+        ((literal_length.min(15) as u8) << 4) | (match_length_code.min(15) as u8),
+    )?;
+    if literal_length >= 15 {
+        let mut remaining = literal_length - 15;
+        while remaining >= 255 {
+            push(&mut output, 255)?;
+            remaining -= 255;
+        }
+        push(&mut output, remaining as u8)?;
+    }
+    for l in literals {
+        push(&mut output, *l)?;
+    }
+    if let Some((offset, _)) = match_info {
+        for b in offset.to_le_bytes() {
+            push(&mut output, b)?;
+        }
+        if match_length_code >= 15 {
+            let mut remaining = match_length_code - 15;
+            while remaining >= 255 {
+                push(&mut output, 255)?;
+                remaining -= 255;
+            }
+            push(&mut output, remaining as u8)?;
+        }
+    }
+    return Some(output);
+}
+
+/// Decompress LZ4 block
+///
+/// # Result
+/// Returns the size of decompressed data, if the data is broken, returns Err(())
+fn lz4_decompress_block(source: &[u8], destination: &mut [u8]) -> Result<usize, ()> {
+    let mut input = 0;
+    let mut output = 0;
+    let read_length = |input: &mut usize| -> Result<usize, ()> {
+        let mut length = 0;
+        loop {
+            let l = *source.get(*input).ok_or(())?;
+            *input += 1;
+            length += l as usize;
+            if l != 255 {
+                return Ok(length);
+            }
+        }
+    };
+
+    loop {
+        let token = *source.get(input).ok_or(())?;
+        input += 1;
+        let mut literal_length = (token >> 4) as usize;
+        if literal_length == 15 {
+            literal_length += read_length(&mut input)?;
+        }
+        destination
+            .get_mut(output..(output + literal_length))
+            .ok_or(())?
+            .copy_from_slice(source.get(input..(input + literal_length)).ok_or(())?);
+        input += literal_length;
+        output += literal_length;
+        if input == source.len() {
+            return Ok(output);
+        }
+
+        let offset = u16::from_le_bytes([
+            *source.get(input).ok_or(())?,
+            *source.get(input + 1).ok_or(())?,
+        ]) as usize;
+        input += 2;
+        let mut match_length = (token & 0xF) as usize;
+        if match_length == 15 {
+            match_length += read_length(&mut input)?;
+        }
+        match_length += MIN_MATCH;
+        if offset == 0 || offset > output || output + match_length > destination.len() {
+            return Err(());
+        }
+        /* The area may overlap, copy byte by byte */
+        for i in output..(output + match_length) {
+            destination[i] = destination[i - offset];
+        }
+        output += match_length;
+    }
+}
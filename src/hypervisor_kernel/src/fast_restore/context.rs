@@ -0,0 +1,652 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! EL1/EL0 Context of Fast Restore
+//!
+//! The optional parts(GIC CPU interface, pointer authentication keys, FP/SIMD, and SVE) are
+//! saved only when ID_AA64*_EL1 shows they are implemented, and restored only when they were saved
+//! and are implemented on the current CPU.
+//!
+
+use common::cpu;
+
+use core::arch::asm;
+
+macro_rules! read_system_register {
+    ($name:expr) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {:x}, ", $name), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! write_system_register {
+    ($name:expr, $value:expr) => {
+        unsafe { asm!(concat!("msr ", $name, ", {:x}"), in(reg) $value) }
+    };
+}
+
+/// Access the `index`-th register of `prefix` `index` `suffix`, `index` must be less than 16
+macro_rules! read_indexed_system_register {
+    ($prefix:literal, $index:expr, $suffix:literal) => {
+        read_indexed_system_register!(
+            $prefix, $index, $suffix, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        )
+    };
+    ($prefix:literal, $index:expr, $suffix:literal, [$($n:literal),*]) => {
+        match $index {
+            $($n => read_system_register!(concat!($prefix, $n, $suffix)),)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+macro_rules! write_indexed_system_register {
+    ($prefix:literal, $index:expr, $suffix:literal, $value:expr) => {
+        write_indexed_system_register!(
+            $prefix, $index, $suffix, $value, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        )
+    };
+    ($prefix:literal, $index:expr, $suffix:literal, $value:expr, [$($n:literal),*]) => {
+        match $index {
+            $($n => write_system_register!(concat!($prefix, $n, $suffix), $value),)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+const SAVED_FEATURE_GIC_CPU_INTERFACE: u64 = 1 << 0;
+const SAVED_FEATURE_POINTER_AUTHENTICATION: u64 = 1 << 1;
+const SAVED_FEATURE_FP: u64 = 1 << 2;
+const SAVED_FEATURE_SVE: u64 = 1 << 3;
+
+const MAX_NUMBER_OF_BREAKPOINTS: usize = 16;
+const MAX_NUMBER_OF_WATCHPOINTS: usize = 16;
+const MAX_NUMBER_OF_ICC_AP1R: usize = 4;
+/// The maximum vector length of SVE in bytes
+const MAX_SVE_VECTOR_LENGTH: usize = 2048 / 8;
+const NUMBER_OF_SVE_PREDICATE_REGISTERS: usize = 16;
+
+fn get_available_features() -> u64 {
+    let id_aa64pfr0_el1 = cpu::get_id_aa64pfr0_el1();
+    let id_aa64isar1_el1 = cpu::get_id_aa64isar1_el1();
+    let id_aa64isar2_el1 = cpu::get_id_aa64isar2_el1();
+    let mut features = 0;
+
+    if (id_aa64pfr0_el1 & cpu::ID_AA64PFR0_EL1_GIC) != 0
+        && (cpu::get_icc_sre_el2() & cpu::ICC_SRE_EL2_SRE) != 0
+    {
+        features |= SAVED_FEATURE_GIC_CPU_INTERFACE;
+    }
+    if (id_aa64isar1_el1
+        & (cpu::ID_AA64ISAR1_EL1_APA
+            | cpu::ID_AA64ISAR1_EL1_API
+            | cpu::ID_AA64ISAR1_EL1_GPA
+            | cpu::ID_AA64ISAR1_EL1_GPI))
+        != 0
+        || (id_aa64isar2_el1 & (cpu::ID_AA64ISAR2_EL1_APA3 | cpu::ID_AA64ISAR2_EL1_GPA3)) != 0
+    {
+        features |= SAVED_FEATURE_POINTER_AUTHENTICATION;
+    }
+    if (id_aa64pfr0_el1 & cpu::ID_AA64PFR0_EL1_FP) != cpu::ID_AA64PFR0_EL1_FP_NOT_IMPLEMENTED {
+        features |= SAVED_FEATURE_FP;
+        if (id_aa64pfr0_el1 & cpu::ID_AA64PFR0_EL1_SVE) != 0 {
+            features |= SAVED_FEATURE_SVE;
+        }
+    }
+    return features;
+}
+
+/// The GIC CPU interface registers of the Non-secure Group 1
+///
+/// Group 0 registers are not saved because they may be owned by the secure world.
+#[repr(C)]
+struct GicCpuInterfaceRegisters {
+    icc_sre_el1: u64,
+    icc_ctlr_el1: u64,
+    icc_pmr_el1: u64,
+    icc_bpr1_el1: u64,
+    icc_ap1r_el1: [u64; MAX_NUMBER_OF_ICC_AP1R],
+    icc_igrpen1_el1: u64,
+}
+
+impl GicCpuInterfaceRegisters {
+    /// Get the number of ICC_AP1R<n>_EL1 from the number of priority bits
+    fn get_number_of_active_priority_registers(icc_ctlr_el1: u64) -> usize {
+        match ((icc_ctlr_el1 & cpu::ICC_CTLR_EL1_PRI_BITS)
+            >> cpu::ICC_CTLR_EL1_PRI_BITS_BITS_OFFSET)
+            + 1
+        {
+            0..=5 => 1,
+            6 => 2,
+            _ => 4,
+        }
+    }
+
+    fn save(&mut self) {
+        self.icc_sre_el1 = read_system_register!("S3_0_C12_C12_5");
+        self.icc_ctlr_el1 = read_system_register!("S3_0_C12_C12_4");
+        self.icc_pmr_el1 = cpu::get_icc_pmr_el1();
+        self.icc_bpr1_el1 = cpu::get_icc_bpr1_el1();
+        for (i, e) in self.icc_ap1r_el1.iter_mut().enumerate().take(
+            Self::get_number_of_active_priority_registers(self.icc_ctlr_el1),
+        ) {
+            *e = read_indexed_system_register!("S3_0_C12_C9_", i, "", [0, 1, 2, 3]);
+        }
+        self.icc_igrpen1_el1 = cpu::get_icc_igrpen1_el1();
+    }
+
+    fn restore(&self) {
+        write_system_register!("S3_0_C12_C12_5", self.icc_sre_el1);
+        cpu::isb();
+        write_system_register!("S3_0_C12_C12_4", self.icc_ctlr_el1);
+        cpu::set_icc_pmr_el1(self.icc_pmr_el1);
+        cpu::set_icc_bpr1_el1(self.icc_bpr1_el1);
+        for (i, e) in self.icc_ap1r_el1.iter().enumerate().take(
+            Self::get_number_of_active_priority_registers(read_system_register!("S3_0_C12_C12_4")),
+        ) {
+            write_indexed_system_register!("S3_0_C12_C9_", i, "", *e, [0, 1, 2, 3]);
+        }
+        cpu::isb();
+        cpu::set_icc_igrpen1_el1(self.icc_igrpen1_el1);
+    }
+}
+
+#[repr(C)]
+struct DebugRegisters {
+    mdscr_el1: u64,
+    number_of_breakpoints: u64,
+    number_of_watchpoints: u64,
+    dbgbcr_el1: [u64; MAX_NUMBER_OF_BREAKPOINTS],
+    dbgbvr_el1: [u64; MAX_NUMBER_OF_BREAKPOINTS],
+    dbgwcr_el1: [u64; MAX_NUMBER_OF_WATCHPOINTS],
+    dbgwvr_el1: [u64; MAX_NUMBER_OF_WATCHPOINTS],
+}
+
+impl DebugRegisters {
+    /// Get (the number of breakpoints, the number of watchpoints) from ID_AA64DFR0_EL1
+    fn get_number_of_breakpoints_and_watchpoints() -> (usize, usize) {
+        let id_aa64dfr0_el1 = cpu::get_id_aa64dfr0_el1();
+        (
+            (((id_aa64dfr0_el1 & cpu::ID_AA64DFR0_EL1_BRPS)
+                >> cpu::ID_AA64DFR0_EL1_BRPS_BITS_OFFSET)
+                + 1) as usize,
+            (((id_aa64dfr0_el1 & cpu::ID_AA64DFR0_EL1_WRPS)
+                >> cpu::ID_AA64DFR0_EL1_WRPS_BITS_OFFSET)
+                + 1) as usize,
+        )
+    }
+
+    fn save(&mut self) {
+        let (number_of_breakpoints, number_of_watchpoints) =
+            Self::get_number_of_breakpoints_and_watchpoints();
+        self.mdscr_el1 = read_system_register!("mdscr_el1");
+        self.number_of_breakpoints = number_of_breakpoints as u64;
+        self.number_of_watchpoints = number_of_watchpoints as u64;
+        for i in 0..number_of_breakpoints {
+            self.dbgbcr_el1[i] = read_indexed_system_register!("dbgbcr", i, "_el1");
+            self.dbgbvr_el1[i] = read_indexed_system_register!("dbgbvr", i, "_el1");
+        }
+        for i in 0..number_of_watchpoints {
+            self.dbgwcr_el1[i] = read_indexed_system_register!("dbgwcr", i, "_el1");
+            self.dbgwvr_el1[i] = read_indexed_system_register!("dbgwvr", i, "_el1");
+        }
+    }
+
+    fn restore(&self) {
+        let (number_of_breakpoints, number_of_watchpoints) =
+            Self::get_number_of_breakpoints_and_watchpoints();
+        for i in 0..number_of_breakpoints.min(self.number_of_breakpoints as usize) {
+            write_indexed_system_register!("dbgbvr", i, "_el1", self.dbgbvr_el1[i]);
+            write_indexed_system_register!("dbgbcr", i, "_el1", self.dbgbcr_el1[i]);
+        }
+        for i in 0..number_of_watchpoints.min(self.number_of_watchpoints as usize) {
+            write_indexed_system_register!("dbgwvr", i, "_el1", self.dbgwvr_el1[i]);
+            write_indexed_system_register!("dbgwcr", i, "_el1", self.dbgwcr_el1[i]);
+        }
+        write_system_register!("mdscr_el1", self.mdscr_el1);
+    }
+}
+
+#[repr(C)]
+struct PointerAuthenticationKeys {
+    apiakeylo_el1: u64,
+    apiakeyhi_el1: u64,
+    apibkeylo_el1: u64,
+    apibkeyhi_el1: u64,
+    apdakeylo_el1: u64,
+    apdakeyhi_el1: u64,
+    apdbkeylo_el1: u64,
+    apdbkeyhi_el1: u64,
+    apgakeylo_el1: u64,
+    apgakeyhi_el1: u64,
+}
+
+impl PointerAuthenticationKeys {
+    fn save(&mut self) {
+        self.apiakeylo_el1 = read_system_register!("S3_0_C2_C1_0");
+        self.apiakeyhi_el1 = read_system_register!("S3_0_C2_C1_1");
+        self.apibkeylo_el1 = read_system_register!("S3_0_C2_C1_2");
+        self.apibkeyhi_el1 = read_system_register!("S3_0_C2_C1_3");
+        self.apdakeylo_el1 = read_system_register!("S3_0_C2_C2_0");
+        self.apdakeyhi_el1 = read_system_register!("S3_0_C2_C2_1");
+        self.apdbkeylo_el1 = read_system_register!("S3_0_C2_C2_2");
+        self.apdbkeyhi_el1 = read_system_register!("S3_0_C2_C2_3");
+        self.apgakeylo_el1 = read_system_register!("S3_0_C2_C3_0");
+        self.apgakeyhi_el1 = read_system_register!("S3_0_C2_C3_1");
+    }
+
+    fn restore(&self) {
+        write_system_register!("S3_0_C2_C1_0", self.apiakeylo_el1);
+        write_system_register!("S3_0_C2_C1_1", self.apiakeyhi_el1);
+        write_system_register!("S3_0_C2_C1_2", self.apibkeylo_el1);
+        write_system_register!("S3_0_C2_C1_3", self.apibkeyhi_el1);
+        write_system_register!("S3_0_C2_C2_0", self.apdakeylo_el1);
+        write_system_register!("S3_0_C2_C2_1", self.apdakeyhi_el1);
+        write_system_register!("S3_0_C2_C2_2", self.apdbkeylo_el1);
+        write_system_register!("S3_0_C2_C2_3", self.apdbkeyhi_el1);
+        write_system_register!("S3_0_C2_C3_0", self.apgakeylo_el1);
+        write_system_register!("S3_0_C2_C3_1", self.apgakeyhi_el1);
+    }
+}
+
+/// The SIMD&FP registers
+#[repr(C, align(16))]
+struct SimdRegisters {
+    v: [u128; 32],
+    fpsr: u64,
+    fpcr: u64,
+}
+
+impl SimdRegisters {
+    fn save(&mut self) {
+        unsafe {
+            asm!("
+            stp  q0,  q1, [{v}, #( 0 * 32)]
+            stp  q2,  q3, [{v}, #( 1 * 32)]
+            stp  q4,  q5, [{v}, #( 2 * 32)]
+            stp  q6,  q7, [{v}, #( 3 * 32)]
+            stp  q8,  q9, [{v}, #( 4 * 32)]
+            stp q10, q11, [{v}, #( 5 * 32)]
+            stp q12, q13, [{v}, #( 6 * 32)]
+            stp q14, q15, [{v}, #( 7 * 32)]
+            stp q16, q17, [{v}, #( 8 * 32)]
+            stp q18, q19, [{v}, #( 9 * 32)]
+            stp q20, q21, [{v}, #(10 * 32)]
+            stp q22, q23, [{v}, #(11 * 32)]
+            stp q24, q25, [{v}, #(12 * 32)]
+            stp q26, q27, [{v}, #(13 * 32)]
+            stp q28, q29, [{v}, #(14 * 32)]
+            stp q30, q31, [{v}, #(15 * 32)]",
+            v = in(reg) self.v.as_mut_ptr())
+        };
+        self.fpsr = read_system_register!("fpsr");
+        self.fpcr = read_system_register!("fpcr");
+    }
+}
+
+/// The SVE registers
+///
+/// Z registers are stored with the stride of `vector_length`.
+#[repr(C)]
+struct SveRegisters {
+    zcr_el1: u64,
+    /// The vector length at EL2 in bytes when saved
+    vector_length: u64,
+    z: [u8; MAX_SVE_VECTOR_LENGTH * 32],
+    /// P0 ~ P15 and FFR
+    p: [u8; (MAX_SVE_VECTOR_LENGTH / 8) * (NUMBER_OF_SVE_PREDICATE_REGISTERS + 1)],
+}
+
+/// Allow the access to SVE registers at EL2 while `f` is running
+fn with_sve_access<F: FnOnce()>(f: F) {
+    let cptr_el2 = cpu::get_cptr_el2();
+    if (cptr_el2 & cpu::CPTR_EL2_TZ_WITHOUT_E2H) != 0 {
+        cpu::set_cptr_el2(cptr_el2 & !cpu::CPTR_EL2_TZ_WITHOUT_E2H);
+        cpu::isb();
+    }
+    f();
+    cpu::set_cptr_el2(cptr_el2);
+    cpu::isb();
+}
+
+fn get_sve_vector_length() -> usize {
+    let vector_length: usize;
+    unsafe {
+        asm!(".arch_extension sve
+              rdvl {:x}, #1", out(reg) vector_length)
+    };
+    vector_length
+}
+
+impl SveRegisters {
+    fn save(&mut self) {
+        self.vector_length = 0;
+        with_sve_access(|| {
+            let vector_length = get_sve_vector_length();
+            if vector_length > MAX_SVE_VECTOR_LENGTH {
+                println!("Unsupported SVE vector length: {}", vector_length);
+                return;
+            }
+            self.zcr_el1 = read_system_register!("S3_0_C1_C2_0");
+            self.vector_length = vector_length as u64;
+            unsafe {
+                asm!(
+                ".arch_extension sve
+                str  z0, [{z}, #0, mul vl]
+                str  z1, [{z}, #1, mul vl]
+                str  z2, [{z}, #2, mul vl]
+                str  z3, [{z}, #3, mul vl]
+                str  z4, [{z}, #4, mul vl]
+                str  z5, [{z}, #5, mul vl]
+                str  z6, [{z}, #6, mul vl]
+                str  z7, [{z}, #7, mul vl]
+                str  z8, [{z}, #8, mul vl]
+                str  z9, [{z}, #9, mul vl]
+                str z10, [{z}, #10, mul vl]
+                str z11, [{z}, #11, mul vl]
+                str z12, [{z}, #12, mul vl]
+                str z13, [{z}, #13, mul vl]
+                str z14, [{z}, #14, mul vl]
+                str z15, [{z}, #15, mul vl]
+                str z16, [{z}, #16, mul vl]
+                str z17, [{z}, #17, mul vl]
+                str z18, [{z}, #18, mul vl]
+                str z19, [{z}, #19, mul vl]
+                str z20, [{z}, #20, mul vl]
+                str z21, [{z}, #21, mul vl]
+                str z22, [{z}, #22, mul vl]
+                str z23, [{z}, #23, mul vl]
+                str z24, [{z}, #24, mul vl]
+                str z25, [{z}, #25, mul vl]
+                str z26, [{z}, #26, mul vl]
+                str z27, [{z}, #27, mul vl]
+                str z28, [{z}, #28, mul vl]
+                str z29, [{z}, #29, mul vl]
+                str z30, [{z}, #30, mul vl]
+                str z31, [{z}, #31, mul vl]
+                str  p0, [{p}, #0, mul vl]
+                str  p1, [{p}, #1, mul vl]
+                str  p2, [{p}, #2, mul vl]
+                str  p3, [{p}, #3, mul vl]
+                str  p4, [{p}, #4, mul vl]
+                str  p5, [{p}, #5, mul vl]
+                str  p6, [{p}, #6, mul vl]
+                str  p7, [{p}, #7, mul vl]
+                str  p8, [{p}, #8, mul vl]
+                str  p9, [{p}, #9, mul vl]
+                str p10, [{p}, #10, mul vl]
+                str p11, [{p}, #11, mul vl]
+                str p12, [{p}, #12, mul vl]
+                str p13, [{p}, #13, mul vl]
+                str p14, [{p}, #14, mul vl]
+                str p15, [{p}, #15, mul vl]
+                rdffr p0.b
+                str  p0, [{p}, #16, mul vl]
+                ldr  p0, [{p}, #0, mul vl]",
+                z = in(reg) self.z.as_mut_ptr(),
+                p = in(reg) self.p.as_mut_ptr())
+            };
+        });
+    }
+
+    /// Restore SVE registers
+    ///
+    /// # Result
+    /// If the vector length is changed from the saved one, returns false without restoring.
+    fn restore(&self) -> bool {
+        let mut is_restored = false;
+        with_sve_access(|| {
+            if self.vector_length == 0 || get_sve_vector_length() != self.vector_length as usize {
+                return;
+            }
+            write_system_register!("S3_0_C1_C2_0", self.zcr_el1);
+            unsafe {
+                asm!(
+                ".arch_extension sve
+                ldr  p0, [{p}, #16, mul vl]
+                wrffr p0.b
+                ldr  p0, [{p}, #0, mul vl]
+                ldr  p1, [{p}, #1, mul vl]
+                ldr  p2, [{p}, #2, mul vl]
+                ldr  p3, [{p}, #3, mul vl]
+                ldr  p4, [{p}, #4, mul vl]
+                ldr  p5, [{p}, #5, mul vl]
+                ldr  p6, [{p}, #6, mul vl]
+                ldr  p7, [{p}, #7, mul vl]
+                ldr  p8, [{p}, #8, mul vl]
+                ldr  p9, [{p}, #9, mul vl]
+                ldr p10, [{p}, #10, mul vl]
+                ldr p11, [{p}, #11, mul vl]
+                ldr p12, [{p}, #12, mul vl]
+                ldr p13, [{p}, #13, mul vl]
+                ldr p14, [{p}, #14, mul vl]
+                ldr p15, [{p}, #15, mul vl]
+                ldr  z0, [{z}, #0, mul vl]
+                ldr  z1, [{z}, #1, mul vl]
+                ldr  z2, [{z}, #2, mul vl]
+                ldr  z3, [{z}, #3, mul vl]
+                ldr  z4, [{z}, #4, mul vl]
+                ldr  z5, [{z}, #5, mul vl]
+                ldr  z6, [{z}, #6, mul vl]
+                ldr  z7, [{z}, #7, mul vl]
+                ldr  z8, [{z}, #8, mul vl]
+                ldr  z9, [{z}, #9, mul vl]
+                ldr z10, [{z}, #10, mul vl]
+                ldr z11, [{z}, #11, mul vl]
+                ldr z12, [{z}, #12, mul vl]
+                ldr z13, [{z}, #13, mul vl]
+                ldr z14, [{z}, #14, mul vl]
+                ldr z15, [{z}, #15, mul vl]
+                ldr z16, [{z}, #16, mul vl]
+                ldr z17, [{z}, #17, mul vl]
+                ldr z18, [{z}, #18, mul vl]
+                ldr z19, [{z}, #19, mul vl]
+                ldr z20, [{z}, #20, mul vl]
+                ldr z21, [{z}, #21, mul vl]
+                ldr z22, [{z}, #22, mul vl]
+                ldr z23, [{z}, #23, mul vl]
+                ldr z24, [{z}, #24, mul vl]
+                ldr z25, [{z}, #25, mul vl]
+                ldr z26, [{z}, #26, mul vl]
+                ldr z27, [{z}, #27, mul vl]
+                ldr z28, [{z}, #28, mul vl]
+                ldr z29, [{z}, #29, mul vl]
+                ldr z30, [{z}, #30, mul vl]
+                ldr z31, [{z}, #31, mul vl]",
+                z = in(reg) self.z.as_ptr(),
+                p = in(reg) self.p.as_ptr())
+            };
+            is_restored = true;
+        });
+        is_restored
+    }
+}
+
+/// The system registers and the SIMD&FP/SVE registers of the snapshot
+///
+/// This is stored into the snapshot file as it is,
+/// therefore [`common::snapshot_file::SNAPSHOT_FILE_VERSION`] must be changed when modified.
+///
+/// All fields are integers, therefore zero-filled memory is a valid instance.
+#[repr(C)]
+pub struct SavedRegisters {
+    /// The combination of `SAVED_FEATURE_*`
+    saved_features: u64,
+    cpacr_el1: u64,
+    ttbr0_el1: u64,
+    ttbr1_el1: u64,
+    tcr_el1: u64,
+    mair_el1: u64,
+    amair_el1: u64,
+    sctlr_el1: u64,
+    vbar_el1: u64,
+    contextidr_el1: u64,
+    tpidr_el0: u64,
+    tpidrro_el0: u64,
+    tpidr_el1: u64,
+    csselr_el1: u64,
+    esr_el1: u64,
+    far_el1: u64,
+    afsr0_el1: u64,
+    afsr1_el1: u64,
+    par_el1: u64,
+    elr_el1: u64,
+    spsr_el1: u64,
+    sp_el0: u64,
+    sp_el1: u64,
+    cntkctl_el1: u64,
+    cntp_ctl_el0: u64,
+    cntp_cval_el0: u64,
+    cntv_ctl_el0: u64,
+    cntv_cval_el0: u64,
+    spsr_el2: u64,
+    elr_el2: u64,
+    debug: DebugRegisters,
+    gic: GicCpuInterfaceRegisters,
+    pointer_authentication: PointerAuthenticationKeys,
+    simd: SimdRegisters,
+    sve: SveRegisters,
+}
+
+impl SavedRegisters {
+    /// Save the context of EL1/EL0
+    ///
+    /// This saves into `self` directly because this structure is too large to be on the stack.
+    /// SIMD&FP/SVE registers are read directly because the hypervisor is built for the softfloat target.
+    pub fn save(&mut self) {
+        let features = get_available_features();
+        self.saved_features = features;
+        self.cpacr_el1 = cpu::get_cpacr_el1();
+        self.ttbr0_el1 = cpu::get_ttbr0_el1();
+        self.ttbr1_el1 = read_system_register!("ttbr1_el1");
+        self.tcr_el1 = cpu::get_tcr_el1();
+        self.mair_el1 = cpu::get_mair_el1();
+        self.amair_el1 = read_system_register!("amair_el1");
+        self.sctlr_el1 = cpu::get_sctlr_el1();
+        self.vbar_el1 = cpu::get_vbar_el1();
+        self.contextidr_el1 = read_system_register!("contextidr_el1");
+        self.tpidr_el0 = read_system_register!("tpidr_el0");
+        self.tpidrro_el0 = read_system_register!("tpidrro_el0");
+        self.tpidr_el1 = read_system_register!("tpidr_el1");
+        self.csselr_el1 = read_system_register!("csselr_el1");
+        self.esr_el1 = read_system_register!("esr_el1");
+        self.far_el1 = read_system_register!("far_el1");
+        self.afsr0_el1 = read_system_register!("afsr0_el1");
+        self.afsr1_el1 = read_system_register!("afsr1_el1");
+        self.par_el1 = read_system_register!("par_el1");
+        self.elr_el1 = read_system_register!("elr_el1");
+        self.spsr_el1 = read_system_register!("spsr_el1");
+        self.sp_el0 = read_system_register!("sp_el0");
+        self.sp_el1 = cpu::get_sp_el1();
+        self.cntkctl_el1 = read_system_register!("cntkctl_el1");
+        self.cntp_ctl_el0 = read_system_register!("cntp_ctl_el0");
+        self.cntp_cval_el0 = read_system_register!("cntp_cval_el0");
+        self.cntv_ctl_el0 = read_system_register!("cntv_ctl_el0");
+        self.cntv_cval_el0 = read_system_register!("cntv_cval_el0");
+        self.spsr_el2 = cpu::get_spsr_el2();
+        self.elr_el2 = cpu::get_elr_el2();
+        self.debug.save();
+        if (features & SAVED_FEATURE_GIC_CPU_INTERFACE) != 0 {
+            self.gic.save();
+        }
+        if (features & SAVED_FEATURE_POINTER_AUTHENTICATION) != 0 {
+            self.pointer_authentication.save();
+        }
+        if (features & SAVED_FEATURE_FP) != 0 {
+            self.simd.save();
+        }
+        if (features & SAVED_FEATURE_SVE) != 0 {
+            self.sve.save();
+        }
+    }
+
+    /// Restore the context of EL1/EL0
+    ///
+    /// The GIC must be initialized before calling this function.
+    pub fn restore(&self) {
+        let features = get_available_features() & self.saved_features;
+        if features != self.saved_features {
+            println!(
+                "Some saved features are not available(Saved: {:#X}, Available: {:#X})",
+                self.saved_features, features
+            );
+        }
+        cpu::set_cpacr_el1(self.cpacr_el1);
+        cpu::set_ttbr0_el1(self.ttbr0_el1);
+        write_system_register!("ttbr1_el1", self.ttbr1_el1);
+        cpu::set_tcr_el1(self.tcr_el1);
+        cpu::set_mair_el1(self.mair_el1);
+        write_system_register!("amair_el1", self.amair_el1);
+        cpu::set_sctlr_el1(self.sctlr_el1);
+        cpu::set_vbar_el1(self.vbar_el1);
+        write_system_register!("contextidr_el1", self.contextidr_el1);
+        write_system_register!("tpidr_el0", self.tpidr_el0);
+        write_system_register!("tpidrro_el0", self.tpidrro_el0);
+        write_system_register!("tpidr_el1", self.tpidr_el1);
+        write_system_register!("csselr_el1", self.csselr_el1);
+        write_system_register!("esr_el1", self.esr_el1);
+        write_system_register!("far_el1", self.far_el1);
+        write_system_register!("afsr0_el1", self.afsr0_el1);
+        write_system_register!("afsr1_el1", self.afsr1_el1);
+        write_system_register!("par_el1", self.par_el1);
+        write_system_register!("elr_el1", self.elr_el1);
+        write_system_register!("spsr_el1", self.spsr_el1);
+        write_system_register!("sp_el0", self.sp_el0);
+        cpu::set_sp_el1(self.sp_el1);
+        write_system_register!("cntkctl_el1", self.cntkctl_el1);
+        write_system_register!("cntp_cval_el0", self.cntp_cval_el0);
+        cpu::set_cntp_ctl_el0(self.cntp_ctl_el0);
+        write_system_register!("cntv_cval_el0", self.cntv_cval_el0);
+        write_system_register!("cntv_ctl_el0", self.cntv_ctl_el0);
+        cpu::set_spsr_el2(self.spsr_el2);
+        cpu::set_elr_el2(self.elr_el2);
+        self.debug.restore();
+        if (features & SAVED_FEATURE_GIC_CPU_INTERFACE) != 0 {
+            self.gic.restore();
+        }
+        if (features & SAVED_FEATURE_POINTER_AUTHENTICATION) != 0 {
+            self.pointer_authentication.restore();
+        }
+        self.restore_simd_registers(features);
+        cpu::isb();
+    }
+
+    /// Restore SIMD&FP/SVE registers
+    fn restore_simd_registers(&self, features: u64) {
+        if (features & SAVED_FEATURE_FP) == 0 {
+            return;
+        }
+        if (features & SAVED_FEATURE_SVE) == 0 || !self.sve.restore() {
+            unsafe {
+                asm!("
+                ldp  q0,  q1, [{v}, #( 0 * 32)]
+                ldp  q2,  q3, [{v}, #( 1 * 32)]
+                ldp  q4,  q5, [{v}, #( 2 * 32)]
+                ldp  q6,  q7, [{v}, #( 3 * 32)]
+                ldp  q8,  q9, [{v}, #( 4 * 32)]
+                ldp q10, q11, [{v}, #( 5 * 32)]
+                ldp q12, q13, [{v}, #( 6 * 32)]
+                ldp q14, q15, [{v}, #( 7 * 32)]
+                ldp q16, q17, [{v}, #( 8 * 32)]
+                ldp q18, q19, [{v}, #( 9 * 32)]
+                ldp q20, q21, [{v}, #(10 * 32)]
+                ldp q22, q23, [{v}, #(11 * 32)]
+                ldp q24, q25, [{v}, #(12 * 32)]
+                ldp q26, q27, [{v}, #(13 * 32)]
+                ldp q28, q29, [{v}, #(14 * 32)]
+                ldp q30, q31, [{v}, #(15 * 32)]",
+                v = in(reg) self.simd.v.as_ptr())
+            };
+        }
+        unsafe {
+            asm!("msr fpsr, {:x}
+                  msr fpcr, {:x}", in(reg) self.simd.fpsr, in(reg) self.simd.fpcr)
+        };
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Dirty Page Tracking for Fast Restore
+//!
+//! After the memory becomes same as the snapshot, the saved regions are write-protected
+//! and the written pages are recorded. The restore process copies back only the recorded pages.
+//!
+//! If FEAT_HAFDBS is available, the hardware sets the dirty state of stage 2 descriptors
+//! (DBM bit and S2AP), otherwise the write accesses are trapped and
+//! the pages are recorded into the bitmap one by one.
+//! In the hardware mode, the block descriptors are split into the page descriptors before
+//! they become clean, because one write access makes the whole block dirty.
+//!
+//! The stage 2 table is shared with SMMU, therefore DMA writes into the clean pages would be
+//! blocked by SMMU. This feature cannot be enabled with `smmu`.
+//! Without SMMU, DMA writes do not pass the stage 2 table and they are never recorded.
+//! Therefore, the tracking is disabled and all pages are restored if any PCI device which may
+//! be a DMA master is found. The DMA masters which are not PCI devices are not detected,
+//! do not enable this feature on the machines with them.
+//!
+
+use super::snapshot::Snapshot;
+
+use crate::guest_breakpoint::restore_write_protection;
+use crate::paging::{
+    add_memory_access_trap, remove_memory_access_trap, split_stage2_block_descriptors,
+    walk_stage2_leaf_descriptors,
+};
+use crate::pci::is_dma_master_present;
+use crate::{allocate_memory, free_memory};
+
+use common::cpu::{
+    flush_tlb_el1, get_id_aa64mmfr1_el1, get_vtcr_el2, set_vtcr_el2, ID_AA64MMFR1_EL1_HAFDBS,
+    VTCR_EL2_HA, VTCR_EL2_HD,
+};
+use common::paging::{PAGE_DESCRIPTORS_DBM, PAGE_DESCRIPTORS_S2AP_WRITE};
+use common::spin_flag::SpinLockFlag;
+use common::{PAGE_MASK, PAGE_SHIFT, PAGE_SIZE};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static DIRTY_PAGE_LOCK: SpinLockFlag = SpinLockFlag::new();
+/// The snapshot which the tracked regions are same as, except the dirty pages
+static mut TRACKED_SNAPSHOT: Option<&'static Snapshot> = None;
+/// The bitmap of the dirty pages, the pages are numbered through all regions of [`TRACKED_SNAPSHOT`]
+static mut DIRTY_PAGE_BITMAP: Option<&mut [u64]> = None;
+static IS_HARDWARE_DIRTY_STATE_ENABLED: AtomicBool = AtomicBool::new(false);
+/// False if the tracking is not available, in that case all pages are restored
+static IS_DIRTY_PAGE_TRACKING_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// ID_AA64MMFR1_EL1.HAFDBS value which means the hardware updates the Access flag and dirty state
+const HAFDBS_ACCESS_FLAG_AND_DIRTY_STATE: u64 = 0b0010;
+
+const fn size_to_pages(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) >> PAGE_SHIFT
+}
+
+/// Enable the hardware management of the dirty state if available
+///
+/// This must be called by BSP before APs are started because APs copy VTCR_EL2 of BSP.
+/// This must be called after [`crate::pci::init_pci`] to detect the DMA masters.
+pub fn init_dirty_page_tracking() {
+    if !cfg!(feature = "fast_restore_dirty_page_tracking") {
+        return;
+    }
+    if is_dma_master_present() {
+        println!("Dirty Page Tracking: Disabled(DMA masters are found)");
+        return;
+    }
+    IS_DIRTY_PAGE_TRACKING_AVAILABLE.store(true, Ordering::Relaxed);
+    if (get_id_aa64mmfr1_el1() & ID_AA64MMFR1_EL1_HAFDBS) >= HAFDBS_ACCESS_FLAG_AND_DIRTY_STATE {
+        set_vtcr_el2(get_vtcr_el2() | VTCR_EL2_HA | VTCR_EL2_HD);
+        flush_tlb_el1();
+        IS_HARDWARE_DIRTY_STATE_ENABLED.store(true, Ordering::Relaxed);
+        println!("Dirty Page Tracking: Hardware");
+    } else {
+        println!("Dirty Page Tracking: Software");
+    }
+}
+
+/// Check if the dirty pages of `snapshot` are tracked
+///
+/// If true, restoring only dirty pages makes the memory same as `snapshot`.
+pub fn is_tracking(snapshot: &Snapshot) -> bool {
+    unsafe { TRACKED_SNAPSHOT }
+        .map(|s| core::ptr::eq(s, snapshot))
+        .unwrap_or(false)
+}
+
+/// Check if the write access fault should be handled by [`handle_write_fault`]
+#[inline(always)]
+pub fn is_software_tracking_enabled() -> bool {
+    return unsafe { TRACKED_SNAPSHOT.is_some() }
+        && !IS_HARDWARE_DIRTY_STATE_ENABLED.load(Ordering::Relaxed);
+}
+
+/// Start tracking the dirty pages of the regions of `snapshot`
+///
+/// The memory must be same as `snapshot` when this function is called.
+/// If other snapshot is tracked, its tracking is stopped.
+/// If the bitmap cannot be allocated, the tracking is not started and
+/// the next restore will copy back all pages.
+pub fn start_dirty_page_tracking(snapshot: &'static Snapshot) {
+    if !cfg!(feature = "fast_restore_dirty_page_tracking")
+        || !IS_DIRTY_PAGE_TRACKING_AVAILABLE.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    stop_dirty_page_tracking();
+
+    let number_of_pages: usize = snapshot.get_memory_areas().map(|(_, p)| p).sum();
+    let number_of_words = (number_of_pages + u64::BITS as usize - 1) / u64::BITS as usize;
+    let bitmap_pages = size_to_pages(number_of_words * core::mem::size_of::<u64>());
+    let bitmap_address = match allocate_memory(bitmap_pages.max(1), None) {
+        Ok(a) => a,
+        Err(err) => {
+            println!("Failed to allocate the dirty page bitmap: {:?}", err);
+            return;
+        }
+    };
+    let bitmap =
+        unsafe { core::slice::from_raw_parts_mut(bitmap_address as *mut u64, number_of_words) };
+    bitmap.fill(0);
+
+    DIRTY_PAGE_LOCK.lock();
+    unsafe {
+        DIRTY_PAGE_BITMAP = Some(bitmap);
+        TRACKED_SNAPSHOT = Some(snapshot);
+    }
+    for (start_address, number_of_pages) in snapshot.get_memory_areas() {
+        write_protect_area(start_address, number_of_pages << PAGE_SHIFT);
+    }
+    flush_tlb_el1();
+    DIRTY_PAGE_LOCK.unlock();
+    pr_debug!("Start tracking {} pages", number_of_pages);
+}
+
+/// Stop tracking and make the tracked regions writable
+pub fn stop_dirty_page_tracking() {
+    DIRTY_PAGE_LOCK.lock();
+    let Some(snapshot) = (unsafe { TRACKED_SNAPSHOT.take() }) else {
+        DIRTY_PAGE_LOCK.unlock();
+        return;
+    };
+    for (start_address, number_of_pages) in snapshot.get_memory_areas() {
+        let size = number_of_pages << PAGE_SHIFT;
+        if IS_HARDWARE_DIRTY_STATE_ENABLED.load(Ordering::Relaxed) {
+            walk_stage2_leaf_descriptors(start_address, size, &mut |d, _, _| {
+                if (*d & PAGE_DESCRIPTORS_DBM) != 0 {
+                    *d = (*d & !PAGE_DESCRIPTORS_DBM) | PAGE_DESCRIPTORS_S2AP_WRITE;
+                }
+            });
+        } else {
+            remove_memory_access_trap(start_address, size).expect("Failed to remove memory trap");
+            restore_write_protection(start_address, size);
+        }
+    }
+    flush_tlb_el1();
+    if let Some(bitmap) = unsafe { DIRTY_PAGE_BITMAP.take() } {
+        let bitmap_pages = size_to_pages(core::mem::size_of_val(bitmap)).max(1);
+        if let Err(err) = free_memory(bitmap.as_ptr() as usize, bitmap_pages) {
+            println!("Failed to free the dirty page bitmap: {:?}", err);
+        }
+    }
+    DIRTY_PAGE_LOCK.unlock();
+}
+
+/// Write-protect the area to detect the next write access
+///
+/// In the hardware mode, only the writable descriptors become the clean state, and
+/// the descriptors which trap the write access for other purposes are not changed.
+/// The block descriptors are split into the page descriptors to record the dirty state per page,
+/// if the page tables cannot be allocated, the remaining blocks are recorded as a whole.
+fn write_protect_area(start_address: usize, size: usize) {
+    if IS_HARDWARE_DIRTY_STATE_ENABLED.load(Ordering::Relaxed) {
+        if split_stage2_block_descriptors(start_address, size).is_err() {
+            println!(
+                "Failed to split the block descriptors({:#X} ~ {:#X}), they are tracked per block.",
+                start_address,
+                start_address + size
+            );
+        }
+        walk_stage2_leaf_descriptors(start_address, size, &mut |d, _, _| {
+            if (*d & (PAGE_DESCRIPTORS_DBM | PAGE_DESCRIPTORS_S2AP_WRITE)) != 0 {
+                *d = (*d | PAGE_DESCRIPTORS_DBM) & !PAGE_DESCRIPTORS_S2AP_WRITE;
+            }
+        });
+    } else {
+        add_memory_access_trap(start_address, size, true, false)
+            .expect("Failed to add memory trap");
+    }
+}
+
+/// Get the index of the page in [`DIRTY_PAGE_BITMAP`]
+fn get_page_index(address: usize) -> Option<usize> {
+    let mut base_index = 0;
+    for (start_address, number_of_pages) in unsafe { TRACKED_SNAPSHOT }?.get_memory_areas() {
+        if (start_address..(start_address + (number_of_pages << PAGE_SHIFT))).contains(&address) {
+            return Some(base_index + ((address - start_address) >> PAGE_SHIFT));
+        }
+        base_index += number_of_pages;
+    }
+    return None;
+}
+
+fn set_dirty_bit(index: usize) -> bool {
+    let Some(bitmap) = (unsafe { DIRTY_PAGE_BITMAP.as_mut() }) else {
+        return false;
+    };
+    let word = &mut bitmap[index / u64::BITS as usize];
+    let bit = 1 << (index % u64::BITS as usize);
+    let is_already_set = (*word & bit) != 0;
+    *word |= bit;
+    return !is_already_set;
+}
+
+/// Record the written page and remove its trap
+///
+/// # Arguments
+/// * `fault_address` - The intermediate physical address of the write access
+///
+/// # Result
+/// If the address is not in the tracked regions, or already recorded, returns false.
+/// In that case, the abort must be handled by the other handlers.
+pub fn handle_write_fault(fault_address: usize) -> bool {
+    let page_address = fault_address & PAGE_MASK;
+    DIRTY_PAGE_LOCK.lock();
+    let is_newly_dirty = get_page_index(page_address)
+        .map(set_dirty_bit)
+        .unwrap_or(false);
+    if is_newly_dirty {
+        remove_memory_access_trap(page_address, PAGE_SIZE).expect("Failed to remove memory trap");
+        restore_write_protection(page_address, PAGE_SIZE);
+    }
+    DIRTY_PAGE_LOCK.unlock();
+    return is_newly_dirty;
+}
+
+/// Write-protect the page again if it is tracked by software and not recorded yet
+///
+/// This is called when the trap of the page was removed for other purposes.
+/// In the hardware mode, the page without the clean state is already regarded as dirty.
+pub fn restore_write_trap(page_address: usize) {
+    DIRTY_PAGE_LOCK.lock();
+    if is_software_tracking_enabled() && !is_page_dirty(page_address) {
+        add_memory_access_trap(page_address, PAGE_SIZE, true, false)
+            .expect("Failed to add memory trap");
+    }
+    DIRTY_PAGE_LOCK.unlock();
+}
+
+/// Record the pages written by the hypervisor
+///
+/// The write accesses from EL2 are neither trapped nor recorded into stage 2 descriptors,
+/// therefore the pages must be recorded explicitly.
+pub fn mark_dirty_pages(address: usize, size: usize) {
+    DIRTY_PAGE_LOCK.lock();
+    for page_address in ((address & PAGE_MASK)..(address + size)).step_by(PAGE_SIZE) {
+        let is_newly_dirty = get_page_index(page_address)
+            .map(set_dirty_bit)
+            .unwrap_or(false);
+        if is_newly_dirty && !IS_HARDWARE_DIRTY_STATE_ENABLED.load(Ordering::Relaxed) {
+            remove_memory_access_trap(page_address, PAGE_SIZE)
+                .expect("Failed to remove memory trap");
+            restore_write_protection(page_address, PAGE_SIZE);
+        }
+    }
+    DIRTY_PAGE_LOCK.unlock();
+}
+
+/// Gather the dirty state from stage 2 descriptors into the bitmap
+///
+/// This is called by BSP in the restore process after the guest is stopped.
+/// The pages which are not in the clean state are regarded as dirty.
+///
+/// # Result
+/// Returns the number of dirty pages
+pub fn collect_dirty_pages() -> usize {
+    let Some(snapshot) = (unsafe { TRACKED_SNAPSHOT }) else {
+        return 0;
+    };
+    if IS_HARDWARE_DIRTY_STATE_ENABLED.load(Ordering::Relaxed) {
+        let mut base_index = 0;
+        for (start_address, number_of_pages) in snapshot.get_memory_areas() {
+            let end_address = start_address + (number_of_pages << PAGE_SHIFT);
+            walk_stage2_leaf_descriptors(
+                start_address,
+                number_of_pages << PAGE_SHIFT,
+                &mut |d, descriptor_address, descriptor_size| {
+                    if (*d & (PAGE_DESCRIPTORS_DBM | PAGE_DESCRIPTORS_S2AP_WRITE))
+                        == PAGE_DESCRIPTORS_DBM
+                    {
+                        return;
+                    }
+                    let first = descriptor_address.max(start_address);
+                    let last = (descriptor_address + descriptor_size).min(end_address);
+                    for address in (first..last).step_by(PAGE_SIZE) {
+                        set_dirty_bit(base_index + ((address - start_address) >> PAGE_SHIFT));
+                    }
+                },
+            );
+            base_index += number_of_pages;
+        }
+    }
+    return unsafe { DIRTY_PAGE_BITMAP.as_ref() }
+        .map(|b| b.iter().map(|w| w.count_ones() as usize).sum())
+        .unwrap_or(0);
+}
+
+/// Check if the page was written after the tracking started
+///
+/// The pages outside of the tracked regions are regarded as dirty.
+pub fn is_page_dirty(address: usize) -> bool {
+    let (Some(index), Some(bitmap)) = (get_page_index(address), unsafe { DIRTY_PAGE_BITMAP.as_ref() }) else {
+        return true;
+    };
+    return (bitmap[index / u64::BITS as usize] & (1 << (index % u64::BITS as usize))) != 0;
+}
+
+/// Write-protect the dirty pages again and clear the bitmap
+///
+/// This is called by BSP after the dirty pages are restored and the stage 2 table is re-enabled.
+pub fn rearm_dirty_page_tracking() {
+    let Some(snapshot) = (unsafe { TRACKED_SNAPSHOT }) else {
+        return;
+    };
+    DIRTY_PAGE_LOCK.lock();
+    if IS_HARDWARE_DIRTY_STATE_ENABLED.load(Ordering::Relaxed) {
+        for (start_address, number_of_pages) in snapshot.get_memory_areas() {
+            write_protect_area(start_address, number_of_pages << PAGE_SHIFT);
+        }
+    } else {
+        for (start_address, number_of_pages) in snapshot.get_memory_areas() {
+            let mut dirty_area_start: Option<usize> = None;
+            for i in 0..=number_of_pages {
+                let address = start_address + (i << PAGE_SHIFT);
+                let is_dirty = i < number_of_pages && is_page_dirty(address);
+                match (dirty_area_start, is_dirty) {
+                    (None, true) => dirty_area_start = Some(address),
+                    (Some(s), false) => {
+                        write_protect_area(s, address - s);
+                        dirty_area_start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    if let Some(bitmap) = unsafe { DIRTY_PAGE_BITMAP.as_mut() } {
+        bitmap.fill(0);
+    }
+    flush_tlb_el1();
+    DIRTY_PAGE_LOCK.unlock();
+}
@@ -0,0 +1,757 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Snapshot Storage for Fast Restore
+//!
+//! Each snapshot holds the compressed memory and the registers to resume the guest.
+//! Snapshots are linked in the list allocated from the memory pool,
+//! therefore the number of snapshots is limited only by the pool size.
+//! A snapshot can be exported into/imported from the snapshot file format defined in
+//! [`common::snapshot_file`].
+//!
+
+use super::compression;
+use super::context::SavedRegisters;
+
+use crate::gic::{free_gic_state, GicState};
+use crate::paging::map_address;
+use crate::{allocate_memory, free_memory, StoredRegisters};
+
+use common::crc32::{calculate_crc32, update_crc32};
+use common::snapshot_file::{
+    self, SnapshotFileChunk, SnapshotFileHeader, SnapshotFileRegion, SNAPSHOT_FILE_MAGIC,
+    SNAPSHOT_FILE_VERSION,
+};
+use common::spin_flag::SpinLockFlag;
+use common::{PAGE_SHIFT, PAGE_SIZE};
+
+use core::cmp::{max, min};
+use core::mem::{align_of, size_of};
+use core::ptr::{copy_nonoverlapping, write_bytes};
+
+pub const SNAPSHOT_NAME_LENGTH: usize = 16;
+pub type SnapshotName = [u8; SNAPSHOT_NAME_LENGTH];
+
+/// The name of the snapshot taken just after ExitBootServices
+pub const BOOT_SNAPSHOT_NAME: SnapshotName = *b"boot\0\0\0\0\0\0\0\0\0\0\0\0";
+
+/// The number of pages compressed at once, BSP and APs restore chunks in parallel
+pub const SNAPSHOT_CHUNK_PAGES: usize = 512;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SnapshotError {
+    AlreadyExists,
+    NotFound,
+    NotEnoughMemory,
+    InvalidImage,
+}
+
+/// The compressed data of [`SNAPSHOT_CHUNK_PAGES`] pages
+#[derive(Clone, Copy)]
+struct SavedChunk {
+    address: usize,
+    size: usize,
+    /// CRC-32 of the compressed data
+    crc32: u32,
+}
+
+#[derive(Clone, Copy)]
+struct SavedRegion {
+    memory_start: usize,
+    number_of_pages: usize,
+    /// The address of [`SavedChunk`] array
+    chunk_table: usize,
+}
+
+impl SavedRegion {
+    fn get_number_of_chunks(&self) -> usize {
+        (self.number_of_pages + SNAPSHOT_CHUNK_PAGES - 1) / SNAPSHOT_CHUNK_PAGES
+    }
+
+    fn get_chunk_table(&self) -> &'static mut [SavedChunk] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.chunk_table as *mut SavedChunk,
+                self.get_number_of_chunks(),
+            )
+        }
+    }
+}
+
+pub struct Snapshot {
+    pub name: SnapshotName,
+    pub system_registers: SavedRegisters,
+    pub registers: StoredRegisters,
+    /// The sum of compressed data size
+    pub size: usize,
+    /// The state of GIC to restore, if None, GIC is reset into the state at ExitBootServices
+    pub gic_state: Option<GicState>,
+    regions: usize,
+    number_of_regions: usize,
+    /// The address of the snapshot file image if the snapshot is imported, otherwise 0
+    ///
+    /// The chunks of the imported snapshot point into the image.
+    image_address: usize,
+    image_pages: usize,
+    next: usize,
+}
+
+static SNAPSHOT_LIST_LOCK: SpinLockFlag = SpinLockFlag::new();
+static mut SNAPSHOT_LIST_HEAD: usize = 0;
+
+const fn size_to_pages(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) >> PAGE_SHIFT
+}
+
+fn free_memory_or_print_error(address: usize, pages: usize) {
+    if let Err(err) = free_memory(address, pages) {
+        println!("Failed to free the memory({:#X}): {:?}", address, err);
+    }
+}
+
+impl Snapshot {
+    fn get_regions(&self) -> &'static mut [SavedRegion] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.regions as *mut SavedRegion,
+                self.number_of_regions,
+            )
+        }
+    }
+
+    /// Free all memory of the snapshot including itself
+    ///
+    /// The snapshot must be removed from the list.
+    fn free(&mut self) {
+        for r in self.get_regions() {
+            if r.chunk_table == 0 {
+                continue;
+            }
+            if self.image_address == 0 {
+                for c in r.get_chunk_table() {
+                    if c.address != 0 {
+                        free_memory_or_print_error(c.address, size_to_pages(c.size));
+                    }
+                }
+            }
+            free_memory_or_print_error(
+                r.chunk_table,
+                size_to_pages(r.get_number_of_chunks() * size_of::<SavedChunk>()),
+            );
+        }
+        if self.regions != 0 {
+            free_memory_or_print_error(
+                self.regions,
+                size_to_pages(self.number_of_regions * size_of::<SavedRegion>()),
+            );
+        }
+        if self.image_address != 0 {
+            free_memory_or_print_error(self.image_address, self.image_pages);
+        }
+        if let Some(gic_state) = self.gic_state.take() {
+            free_gic_state(gic_state);
+        }
+        free_memory_or_print_error(self as *mut _ as usize, size_to_pages(size_of::<Self>()));
+    }
+
+    /// Save the memory with compression
+    ///
+    /// Each region is divided into the chunks of [`SNAPSHOT_CHUNK_PAGES`], and each chunk is
+    /// compressed independently to restore in parallel.
+    ///
+    /// # Result
+    /// Returns (original size, number of zero pages)
+    fn save_memory<I: Iterator<Item = (usize, usize)>>(
+        &mut self,
+        areas: I,
+    ) -> Result<(usize, usize), SnapshotError> {
+        let buffer_pages =
+            size_to_pages(compression::get_max_compressed_size(SNAPSHOT_CHUNK_PAGES));
+        let buffer_address =
+            allocate_memory(buffer_pages, None).or(Err(SnapshotError::NotEnoughMemory))?;
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(buffer_address as *mut u8, buffer_pages << PAGE_SHIFT)
+        };
+        let mut original_size = 0usize;
+        let mut number_of_zero_pages = 0usize;
+
+        let result = (|| {
+            for (r, (memory_start, number_of_pages)) in self.get_regions().iter_mut().zip(areas) {
+                *r = SavedRegion {
+                    memory_start,
+                    number_of_pages,
+                    chunk_table: 0,
+                };
+                let chunk_table_pages =
+                    size_to_pages(r.get_number_of_chunks() * size_of::<SavedChunk>());
+                r.chunk_table = allocate_memory(chunk_table_pages, None)
+                    .or(Err(SnapshotError::NotEnoughMemory))?;
+                let chunk_table = r.get_chunk_table();
+                for c in chunk_table.iter_mut() {
+                    c.address = 0;
+                }
+
+                for (i, c) in chunk_table.iter_mut().enumerate() {
+                    let first_page = i * SNAPSHOT_CHUNK_PAGES;
+                    let number_of_pages = SNAPSHOT_CHUNK_PAGES.min(number_of_pages - first_page);
+                    let source = unsafe {
+                        core::slice::from_raw_parts(
+                            (memory_start + (first_page << PAGE_SHIFT)) as *const u8,
+                            number_of_pages << PAGE_SHIFT,
+                        )
+                    };
+                    let (size, zero_pages) = compression::compress_pages(source, buffer);
+                    let address = allocate_memory(size_to_pages(size), None)
+                        .or(Err(SnapshotError::NotEnoughMemory))?;
+                    unsafe {
+                        copy_nonoverlapping(buffer_address as *const u8, address as *mut u8, size)
+                    };
+                    *c = SavedChunk {
+                        address,
+                        size,
+                        crc32: calculate_crc32(&buffer[..size]),
+                    };
+
+                    original_size += number_of_pages << PAGE_SHIFT;
+                    self.size += size;
+                    number_of_zero_pages += zero_pages;
+                }
+            }
+            Ok(())
+        })();
+
+        free_memory_or_print_error(buffer_address, buffer_pages);
+        result.and(Ok((original_size, number_of_zero_pages)))
+    }
+
+    /// Get the saved memory areas as (start address, number of pages)
+    pub fn get_memory_areas(&self) -> impl Iterator<Item = (usize, usize)> + Clone {
+        self.get_regions()
+            .iter()
+            .map(|r| (r.memory_start, r.number_of_pages))
+    }
+
+    /// Map the regions to restore and count the chunks to restore
+    ///
+    /// This must be called by BSP before [`Self::restore_chunk`] because
+    /// [`map_address`] is not thread-safe.
+    ///
+    /// # Result
+    /// Returns the number of chunks to restore
+    pub fn prepare_restore(&self) -> usize {
+        let mut number_of_chunks = 0;
+        for r in self.get_regions().iter() {
+            //if cpu::convert_virtual_address_to_physical_address_el2_write(e.memory_start).is_err() {
+            map_address(
+                r.memory_start,
+                r.memory_start,
+                r.number_of_pages << PAGE_SHIFT,
+                true,
+                true,
+                false,
+                false,
+            )
+            .expect("Failed to map memory");
+            //}
+            number_of_chunks += r.get_number_of_chunks();
+        }
+        return number_of_chunks;
+    }
+
+    /// Restore one chunk
+    ///
+    /// The chunks are numbered through all regions.
+    ///
+    /// # Arguments
+    /// * `chunk` - The index of the chunk, must be less than the result of [`Self::prepare_restore`]
+    /// * `should_skip_corrupted_chunk` - If true, the chunk which has wrong CRC-32 is not restored
+    /// * `page_filter` - If Some, only the pages whose address is accepted by the filter are restored
+    ///
+    /// # Result
+    /// If the saved data is corrupted, returns false
+    pub fn restore_chunk(
+        &self,
+        chunk: usize,
+        should_skip_corrupted_chunk: bool,
+        page_filter: Option<&dyn Fn(usize) -> bool>,
+    ) -> bool {
+        let mut chunk_base = 0;
+        for r in self.get_regions().iter() {
+            let number_of_region_chunks = r.get_number_of_chunks();
+            if chunk >= chunk_base + number_of_region_chunks {
+                chunk_base += number_of_region_chunks;
+                continue;
+            }
+            let first_page = (chunk - chunk_base) * SNAPSHOT_CHUNK_PAGES;
+            let number_of_pages = SNAPSHOT_CHUNK_PAGES.min(r.number_of_pages - first_page);
+            let saved_chunk = &r.get_chunk_table()[chunk - chunk_base];
+            let restore_address = r.memory_start + (first_page << PAGE_SHIFT);
+            let page_filter_by_index =
+                page_filter.map(|f| move |index: usize| f(restore_address + (index << PAGE_SHIFT)));
+            if let Some(f) = &page_filter_by_index {
+                if !(0..number_of_pages).any(f) {
+                    return true;
+                }
+            }
+            pr_debug!(
+                "Restore {:#X} from {:#X}({} Pages)",
+                restore_address,
+                saved_chunk.address,
+                number_of_pages
+            );
+            let (source, destination) = unsafe {
+                (
+                    core::slice::from_raw_parts(saved_chunk.address as *const u8, saved_chunk.size),
+                    core::slice::from_raw_parts_mut(
+                        restore_address as *mut u8,
+                        number_of_pages << PAGE_SHIFT,
+                    ),
+                )
+            };
+            let mut is_intact = true;
+            if calculate_crc32(source) != saved_chunk.crc32 {
+                println!(
+                    "The saved data of {:#X} ~ {:#X} is corrupted.",
+                    restore_address,
+                    restore_address + (number_of_pages << PAGE_SHIFT)
+                );
+                if should_skip_corrupted_chunk {
+                    return false;
+                }
+                is_intact = false;
+            }
+            if compression::decompress_pages(
+                source,
+                destination,
+                page_filter_by_index
+                    .as_ref()
+                    .map(|f| f as &dyn Fn(usize) -> bool),
+            )
+            .is_err()
+            {
+                println!(
+                    "Failed to decompress the saved data of {:#X}",
+                    restore_address
+                );
+                is_intact = false;
+            }
+            return is_intact;
+        }
+        unreachable!()
+    }
+}
+
+/// Allocate the snapshot and its region table
+///
+/// The snapshot is filled by zero, and the registers must be set by the caller.
+/// The chunk table of each region is initialized to 0.
+fn allocate_snapshot(
+    name: SnapshotName,
+    number_of_regions: usize,
+) -> Result<&'static mut Snapshot, SnapshotError> {
+    if find_snapshot(&name).is_some() {
+        return Err(SnapshotError::AlreadyExists);
+    }
+    let snapshot_address = allocate_memory(size_to_pages(size_of::<Snapshot>()), None)
+        .or(Err(SnapshotError::NotEnoughMemory))?;
+    /* All fields of Snapshot except gic_state are integers, the zero-filled memory is valid */
+    unsafe { write_bytes(snapshot_address as *mut u8, 0, size_of::<Snapshot>()) };
+    let snapshot = unsafe { &mut *(snapshot_address as *mut Snapshot) };
+    unsafe { core::ptr::write(&mut snapshot.gic_state, None) };
+    snapshot.name = name;
+    match allocate_memory(
+        size_to_pages(number_of_regions * size_of::<SavedRegion>()),
+        None,
+    ) {
+        Ok(address) => {
+            snapshot.regions = address;
+            snapshot.number_of_regions = number_of_regions;
+            for r in snapshot.get_regions() {
+                r.chunk_table = 0;
+            }
+        }
+        Err(_) => {
+            snapshot.free();
+            return Err(SnapshotError::NotEnoughMemory);
+        }
+    }
+    return Ok(snapshot);
+}
+
+fn add_snapshot_into_list(snapshot: &'static mut Snapshot) -> &'static Snapshot {
+    SNAPSHOT_LIST_LOCK.lock();
+    snapshot.next = unsafe { SNAPSHOT_LIST_HEAD };
+    unsafe { SNAPSHOT_LIST_HEAD = snapshot as *mut Snapshot as usize };
+    SNAPSHOT_LIST_LOCK.unlock();
+    return snapshot;
+}
+
+/// Take a snapshot and add it into the list
+///
+/// # Arguments
+/// * `name` - The name of the snapshot, must be unique
+/// * `areas` - The memory areas to save, the items are (start address, number of pages)
+/// * `registers` - The general registers to restore,
+///   the system registers and SIMD&FP registers are saved from the current CPU
+/// * `gic_state` - The state of GIC owned by the snapshot, see [`Snapshot::gic_state`]
+///
+/// # Result
+/// If the pool does not have enough memory, all memory allocated for this snapshot
+/// including `gic_state` is freed and returns Err(SnapshotError::NotEnoughMemory).
+pub fn take_snapshot<I: Iterator<Item = (usize, usize)> + Clone>(
+    name: SnapshotName,
+    areas: I,
+    registers: StoredRegisters,
+    gic_state: Option<GicState>,
+) -> Result<&'static Snapshot, SnapshotError> {
+    let snapshot = match allocate_snapshot(name, areas.clone().count()) {
+        Ok(s) => s,
+        Err(e) => {
+            if let Some(gic_state) = gic_state {
+                free_gic_state(gic_state);
+            }
+            return Err(e);
+        }
+    };
+    snapshot.system_registers.save();
+    snapshot.registers = registers;
+    snapshot.gic_state = gic_state;
+
+    let (original_size, number_of_zero_pages) = match snapshot.save_memory(areas) {
+        Ok(r) => r,
+        Err(e) => {
+            snapshot.free();
+            return Err(e);
+        }
+    };
+    println!(
+        "Saved {} KiB into {} KiB(Compression Ratio: {}%, Zero Pages: {})",
+        original_size >> 10,
+        snapshot.size >> 10,
+        if original_size == 0 {
+            0
+        } else {
+            (snapshot.size * 100) / original_size
+        },
+        number_of_zero_pages
+    );
+    return Ok(add_snapshot_into_list(snapshot));
+}
+
+/// The size of the context in the snapshot file
+const SNAPSHOT_FILE_CONTEXT_SIZE: usize =
+    size_of::<SavedRegisters>() + size_of::<StoredRegisters>();
+
+/// Import the snapshot file image and add it into the list
+///
+/// The compressed data is not copied, the chunks point into the image.
+/// The image is owned by the snapshot and freed with it.
+///
+/// # Arguments
+/// * `name` - The name of the snapshot, must be unique
+/// * `image_address` - The address of the image allocated by [`allocate_memory`]
+/// * `image_size` - The size of the image
+///
+/// # Result
+/// If the image is broken or made by another version, returns Err(SnapshotError::InvalidImage).
+/// On failure, the image is freed.
+pub fn import_snapshot(
+    name: SnapshotName,
+    image_address: usize,
+    image_size: usize,
+) -> Result<&'static Snapshot, SnapshotError> {
+    let image_pages = size_to_pages(image_size);
+    let image = unsafe { core::slice::from_raw_parts(image_address as *const u8, image_size) };
+    let header = match snapshot_file::validate_snapshot_image(image)
+        .and_then(|_| snapshot_file::get_header(image))
+    {
+        Ok(h)
+            if h.chunk_pages as usize == SNAPSHOT_CHUNK_PAGES
+                && h.context_size as usize == SNAPSHOT_FILE_CONTEXT_SIZE =>
+        {
+            h
+        }
+        _ => {
+            free_memory_or_print_error(image_address, image_pages);
+            return Err(SnapshotError::InvalidImage);
+        }
+    };
+    let snapshot = match allocate_snapshot(name, header.number_of_regions as usize) {
+        Ok(s) => s,
+        Err(e) => {
+            free_memory_or_print_error(image_address, image_pages);
+            return Err(e);
+        }
+    };
+    let context = snapshot_file::get_context(image).as_ptr();
+    unsafe {
+        copy_nonoverlapping(
+            context,
+            &mut snapshot.system_registers as *mut _ as *mut u8,
+            size_of::<SavedRegisters>(),
+        );
+        copy_nonoverlapping(
+            context.add(size_of::<SavedRegisters>()),
+            &mut snapshot.registers as *mut _ as *mut u8,
+            size_of::<StoredRegisters>(),
+        );
+    }
+    snapshot.image_address = image_address;
+    snapshot.image_pages = image_pages;
+
+    for (r, file_region) in snapshot
+        .get_regions()
+        .iter_mut()
+        .zip(snapshot_file::get_regions(image))
+    {
+        r.memory_start = file_region.memory_start as usize;
+        r.number_of_pages = file_region.number_of_pages as usize;
+        r.chunk_table = match allocate_memory(
+            size_to_pages(r.get_number_of_chunks() * size_of::<SavedChunk>()),
+            None,
+        ) {
+            Ok(a) => a,
+            Err(_) => {
+                snapshot.free();
+                return Err(SnapshotError::NotEnoughMemory);
+            }
+        };
+        for (c, file_chunk) in r
+            .get_chunk_table()
+            .iter_mut()
+            .zip(snapshot_file::get_chunks(image, file_region))
+        {
+            *c = SavedChunk {
+                address: image_address + file_chunk.data_offset as usize,
+                size: file_chunk.data_size as usize,
+                crc32: file_chunk.crc32,
+            };
+            snapshot.size += c.size;
+        }
+    }
+    return Ok(add_snapshot_into_list(snapshot));
+}
+
+impl Snapshot {
+    /// Calculate the layout of the snapshot file
+    ///
+    /// # Result
+    /// Returns (the offset of the first chunk table, the offset of the first chunk data)
+    fn get_file_layout(&self) -> (usize, usize) {
+        let context_offset = size_of::<SnapshotFileHeader>()
+            + self.number_of_regions * size_of::<SnapshotFileRegion>();
+        let chunk_table_offset =
+            (context_offset + SNAPSHOT_FILE_CONTEXT_SIZE + align_of::<SnapshotFileChunk>() - 1)
+                & !(align_of::<SnapshotFileChunk>() - 1);
+        let number_of_chunks: usize = self
+            .get_regions()
+            .iter()
+            .map(|r| r.get_number_of_chunks())
+            .sum();
+        (
+            chunk_table_offset,
+            chunk_table_offset + number_of_chunks * size_of::<SnapshotFileChunk>(),
+        )
+    }
+
+    /// Get the size of the snapshot file made by [`Self::export`]
+    pub fn get_file_size(&self) -> usize {
+        self.get_file_layout().1 + self.size
+    }
+
+    /// Write a part of the snapshot file
+    ///
+    /// The file is generated from `offset` without any buffer in the memory pool,
+    /// therefore the guest can read it by a small buffer repeatedly.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset in the snapshot file to start writing
+    /// * `buffer` - The destination of the bytes from `offset`
+    ///
+    /// # Result
+    /// Returns the number of written bytes, it is smaller than `buffer.len()` at the end of the file
+    pub fn export(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let file_size = self.get_file_size();
+        if offset >= file_size {
+            return 0;
+        }
+        let length = min(buffer.len(), file_size - offset);
+        let buffer = &mut buffer[..length];
+        let region_table_offset = size_of::<SnapshotFileHeader>();
+        let context_offset =
+            region_table_offset + self.number_of_regions * size_of::<SnapshotFileRegion>();
+        let (first_chunk_table_offset, first_data_offset) = self.get_file_layout();
+
+        /* Copy the part of `bytes` located at `bytes_offset` of the file into `buffer` */
+        let mut write = |bytes_offset: usize, bytes: &[u8]| {
+            let start = max(bytes_offset, offset);
+            let end = min(bytes_offset + bytes.len(), offset + length);
+            if start < end {
+                buffer[(start - offset)..(end - offset)]
+                    .copy_from_slice(&bytes[(start - bytes_offset)..(end - bytes_offset)]);
+            }
+        };
+
+        let system_registers = unsafe {
+            core::slice::from_raw_parts(
+                &self.system_registers as *const _ as *const u8,
+                size_of::<SavedRegisters>(),
+            )
+        };
+        let registers = unsafe {
+            core::slice::from_raw_parts(
+                &self.registers as *const _ as *const u8,
+                size_of::<StoredRegisters>(),
+            )
+        };
+
+        if offset < first_chunk_table_offset {
+            let mut header = SnapshotFileHeader {
+                magic: SNAPSHOT_FILE_MAGIC,
+                version: SNAPSHOT_FILE_VERSION,
+                header_size: size_of::<SnapshotFileHeader>() as u32,
+                file_size: file_size as u64,
+                chunk_pages: SNAPSHOT_CHUNK_PAGES as u64,
+                number_of_regions: self.number_of_regions as u64,
+                region_table_offset: region_table_offset as u64,
+                context_offset: context_offset as u64,
+                context_size: SNAPSHOT_FILE_CONTEXT_SIZE as u64,
+                crc32: 0,
+                reserved: 0,
+            };
+            /* Same as snapshot_file::calculate_header_crc32 */
+            let mut crc = calculate_crc32(unsafe {
+                core::slice::from_raw_parts(
+                    &header as *const _ as *const u8,
+                    size_of::<SnapshotFileHeader>(),
+                )
+            });
+            let mut chunk_table_offset = first_chunk_table_offset;
+            for (i, r) in self.get_regions().iter().enumerate() {
+                let file_region = SnapshotFileRegion {
+                    memory_start: r.memory_start as u64,
+                    number_of_pages: r.number_of_pages as u64,
+                    chunk_table_offset: chunk_table_offset as u64,
+                };
+                let file_region = unsafe {
+                    core::slice::from_raw_parts(
+                        &file_region as *const _ as *const u8,
+                        size_of::<SnapshotFileRegion>(),
+                    )
+                };
+                crc = update_crc32(crc, file_region);
+                write(
+                    region_table_offset + i * size_of::<SnapshotFileRegion>(),
+                    file_region,
+                );
+                chunk_table_offset += r.get_number_of_chunks() * size_of::<SnapshotFileChunk>();
+            }
+            let crc = update_crc32(crc, system_registers);
+            header.crc32 = update_crc32(crc, registers);
+            write(0, unsafe {
+                core::slice::from_raw_parts(
+                    &header as *const _ as *const u8,
+                    size_of::<SnapshotFileHeader>(),
+                )
+            });
+            write(context_offset, system_registers);
+            write(context_offset + size_of::<SavedRegisters>(), registers);
+            /* The padding before the chunk table */
+            write(
+                context_offset + SNAPSHOT_FILE_CONTEXT_SIZE,
+                &[0u8; align_of::<SnapshotFileChunk>()]
+                    [..(first_chunk_table_offset - context_offset - SNAPSHOT_FILE_CONTEXT_SIZE)],
+            );
+        }
+
+        let mut chunk_table_offset = first_chunk_table_offset;
+        let mut data_offset = first_data_offset;
+        for r in self.get_regions().iter() {
+            for c in r.get_chunk_table().iter() {
+                if chunk_table_offset >= offset + length && data_offset >= offset + length {
+                    return length;
+                }
+                let chunk = SnapshotFileChunk {
+                    data_offset: data_offset as u64,
+                    data_size: c.size as u64,
+                    crc32: c.crc32,
+                    reserved: 0,
+                };
+                write(chunk_table_offset, unsafe {
+                    core::slice::from_raw_parts(
+                        &chunk as *const _ as *const u8,
+                        size_of::<SnapshotFileChunk>(),
+                    )
+                });
+                write(data_offset, unsafe {
+                    core::slice::from_raw_parts(c.address as *const u8, c.size)
+                });
+                chunk_table_offset += size_of::<SnapshotFileChunk>();
+                data_offset += c.size;
+            }
+        }
+        return length;
+    }
+}
+
+/// Find the snapshot by name
+///
+/// The reference is valid until [`delete_snapshot`] is called for the snapshot.
+pub fn find_snapshot(name: &SnapshotName) -> Option<&'static Snapshot> {
+    let mut index = 0;
+    while let Some(s) = get_snapshot_by_index(index) {
+        if s.name == *name {
+            return Some(s);
+        }
+        index += 1;
+    }
+    return None;
+}
+
+/// Get the `index`-th snapshot of the list
+///
+/// The newer snapshot has the smaller index.
+/// The reference is valid until [`delete_snapshot`] is called for the snapshot.
+pub fn get_snapshot_by_index(index: usize) -> Option<&'static Snapshot> {
+    SNAPSHOT_LIST_LOCK.lock();
+    let mut address = unsafe { SNAPSHOT_LIST_HEAD };
+    for _ in 0..index {
+        if address == 0 {
+            break;
+        }
+        address = unsafe { (*(address as *const Snapshot)).next };
+    }
+    SNAPSHOT_LIST_LOCK.unlock();
+    if address == 0 {
+        None
+    } else {
+        Some(unsafe { &*(address as *const Snapshot) })
+    }
+}
+
+/// Remove the snapshot from the list and free its memory
+///
+/// The references returned by [`find_snapshot`] and [`get_snapshot_by_index`] become dangling.
+/// Therefore, this must be called only when no other CPU uses the snapshot
+/// (e.g. the guest is running only on BSP).
+pub fn delete_snapshot(name: &SnapshotName) -> Result<(), SnapshotError> {
+    SNAPSHOT_LIST_LOCK.lock();
+    let mut link = unsafe { &mut SNAPSHOT_LIST_HEAD };
+    while *link != 0 {
+        let snapshot = unsafe { &mut *(*link as *mut Snapshot) };
+        if snapshot.name == *name {
+            *link = snapshot.next;
+            SNAPSHOT_LIST_LOCK.unlock();
+            snapshot.free();
+            return Ok(());
+        }
+        link = &mut snapshot.next;
+    }
+    SNAPSHOT_LIST_LOCK.unlock();
+    return Err(SnapshotError::NotFound);
+}
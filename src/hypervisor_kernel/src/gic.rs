@@ -4,118 +4,542 @@
 // This software is released under the MIT License.
 // http://opensource.org/licenses/mit-license.php
 
+//!
+//! GIC Save and Restore for Fast Restore
+//!
+//! The state of GIC left by the firmware is saved by [`save_gic`], and [`restore_gic`] resets
+//! GIC into that state: the pending and active states are cleared, and the configuration of
+//! the distributor, the redistributors, and ITSs is written back.
+//! The pending states of LPIs are kept in the memory, they are restored with the guest memory.
+//!
+//! The snapshots taken by the guest before ExitBootServices have their own GIC state
+//! made by [`save_gic_state`] because the firmware may change GIC until ExitBootServices.
+//!
+
 use crate::memory_hook::{
     add_memory_load_hook_handler, add_memory_store_hook_handler, remove_memory_load_hook_handler,
     remove_memory_store_hook_handler, LoadAccessHandlerEntry, LoadHookResult,
     StoreAccessHandlerEntry, StoreHookResult,
 };
 use crate::paging::{add_memory_access_trap, map_address, remove_memory_access_trap};
-use crate::StoredRegisters;
+use crate::{allocate_memory, free_memory, StoredRegisters};
 
 use common::acpi::{get_acpi_table, madt::MADT};
 use common::paging::{page_align_up, stage2_page_align_up};
-use common::PAGE_SIZE;
+use common::{PAGE_SHIFT, PAGE_SIZE};
 
+use core::mem::size_of;
 use core::ptr::{read_volatile, write_volatile};
 
 const GICR_MAP_SIZE: usize = 0x1000;
+/// The size of each frame(RD_base, SGI_base, VLPI_base) of the redistributor
+const GICR_FRAME_SIZE: usize = 0x10000;
+const GICR_SGI_BASE: usize = GICR_FRAME_SIZE;
+const GICR_VLPI_BASE: usize = GICR_FRAME_SIZE * 2;
 
 const GICR_CTLR: usize = 0x0000;
 const GICR_CTLR_RWP: u32 = 1 << 3;
 const GICR_CTLR_ENABLE_LPIS: u32 = 1;
 
+const GICR_TYPER: usize = 0x0008;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+
 const GICR_WAKER: usize = 0x0014;
 const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
 
 const GICR_PROPBASER: usize = 0x0070;
 const GICR_PROPBASER_PTZ: u64 = 1 << 62;
+const GICR_PENDBASER: usize = 0x0078;
+
+/* Offsets from SGI_base */
+const GICR_IGROUPR0: usize = 0x0080;
+const GICR_ISENABLER0: usize = 0x0100;
+const GICR_ICENABLER0: usize = 0x0180;
+const GICR_ICPENDR0: usize = 0x0280;
+const GICR_ICACTIVER0: usize = 0x0380;
+const GICR_IPRIORITYR: usize = 0x0400;
+const GICR_ICFGR1: usize = 0x0C04;
+const GICR_IGRPMODR0: usize = 0x0D00;
+
+/* Offsets from VLPI_base */
+const GICR_VPROPBASER: usize = 0x0070;
+const GICR_VPENDBASER: usize = 0x0078;
+const GICR_VPENDBASER_VALID: u64 = 1 << 63;
+const GICR_VPENDBASER_DIRTY: u64 = 1 << 60;
 
 const GICD_CTLR: usize = 0x00;
+const GICD_CTLR_ENABLE_GROUPS: u32 = 0b111;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_TYPER: usize = 0x04;
+const GICD_TYPER_IT_LINES_NUMBER: u32 = 0b11111;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_ICPENDR: usize = 0x0280;
+const GICD_ICACTIVER: usize = 0x0380;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0C00;
+const GICD_IGRPMODR: usize = 0x0D00;
+const GICD_IROUTER: usize = 0x6000;
 
 const GITS_CTLR: usize = 0x00;
 const GITS_CTLR_ENABLED: u32 = 0x01;
 const GITS_CTLR_QUIESCENT: u32 = 1 << 31;
+const GITS_CBASER: usize = 0x80;
+const GITS_CWRITER: usize = 0x88;
+const GITS_BASER: usize = 0x100;
+const NUMBER_OF_GITS_BASER: usize = 8;
 
-pub fn restore_gic(acpi_address: usize) {
-    // TODO: Discovery Base Address
-    if let Ok(table) = get_acpi_table(acpi_address, b"APIC") {
-        let table = unsafe { &*(table as *const MADT) };
+/// The maximum number of 32 interrupt blocks of the distributor(GICD_TYPER.ITLinesNumber + 1)
+const MAX_NUMBER_OF_INTERRUPT_BLOCKS: usize = 32;
+/// INTID 1020 ~ 1023 are special INTIDs
+const MAX_NUMBER_OF_INTERRUPTS: usize = 1020;
 
-        for e in table.get_gic_its_list() {
-            map_address(e, e, PAGE_SIZE, true, true, false, true).expect("Failed to map ITS");
-            unsafe { write_volatile((e + GITS_CTLR) as *mut u32, 0) };
-            while unsafe { read_volatile((e + GITS_CTLR) as *const u32) & GITS_CTLR_ENABLED } != 0 {
-                core::hint::spin_loop();
-            }
-            unsafe { write_volatile((e + GITS_CTLR) as *mut u32, GITS_CTLR_QUIESCENT) };
-        }
+struct DistributorState {
+    base_address: usize,
+    number_of_interrupt_blocks: usize,
+    ctlr: u32,
+    group: [u32; MAX_NUMBER_OF_INTERRUPT_BLOCKS],
+    group_modifier: [u32; MAX_NUMBER_OF_INTERRUPT_BLOCKS],
+    enable: [u32; MAX_NUMBER_OF_INTERRUPT_BLOCKS],
+    priority: [u32; MAX_NUMBER_OF_INTERRUPT_BLOCKS * 8],
+    configuration: [u32; MAX_NUMBER_OF_INTERRUPT_BLOCKS * 2],
+    router: [u64; MAX_NUMBER_OF_INTERRUPTS],
+}
+
+/// The state of the redistributor, SGIs and PPIs are only saved
+struct RedistributorState {
+    base_address: usize,
+    is_vlpi_supported: bool,
+    ctlr: u32,
+    waker: u32,
+    propbaser: u64,
+    pendbaser: u64,
+    vpropbaser: u64,
+    group: u32,
+    group_modifier: u32,
+    enable: u32,
+    priority: [u32; 8],
+    configuration: u32,
+}
+
+struct ItsState {
+    base_address: usize,
+    ctlr: u32,
+    cbaser: u64,
+    baser: [u64; NUMBER_OF_GITS_BASER],
+}
+
+pub struct GicState {
+    distributor: &'static mut DistributorState,
+    redistributors: &'static mut [RedistributorState],
+    its_list: &'static mut [ItsState],
+}
+
+static mut SAVED_GIC_STATE: Option<GicState> = None;
 
-        if let Some(distributor) = table.get_gic_distributor_address() {
-            if distributor != 0 {
-                unsafe { write_volatile((distributor + GICD_CTLR) as *mut u32, 0) };
+#[inline(always)]
+fn read_32(address: usize) -> u32 {
+    unsafe { read_volatile(address as *const u32) }
+}
+
+#[inline(always)]
+fn write_32(address: usize, data: u32) {
+    unsafe { write_volatile(address as *mut u32, data) }
+}
+
+#[inline(always)]
+fn read_64(address: usize) -> u64 {
+    unsafe { read_volatile(address as *const u64) }
+}
+
+#[inline(always)]
+fn write_64(address: usize, data: u64) {
+    unsafe { write_volatile(address as *mut u64, data) }
+}
+
+fn wait_register_write(ctlr_address: usize, rwp_bit: u32) {
+    while (read_32(ctlr_address) & rwp_bit) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Allocate the zero-filled array which is never freed
+///
+/// All fields of `T` must be valid with zero.
+fn allocate_array<T>(length: usize) -> &'static mut [T] {
+    let pages = page_align_up((length * size_of::<T>()).max(1)) >> PAGE_SHIFT;
+    let address = allocate_memory(pages, None).expect("Failed to allocate memory for GIC state");
+    unsafe {
+        core::ptr::write_bytes(address as *mut u8, 0, pages << PAGE_SHIFT);
+        core::slice::from_raw_parts_mut(address as *mut T, length)
+    }
+}
+
+/// Map the frames of the redistributor into EL2 and read GICR_TYPER
+fn map_redistributor(redistributor_base: usize) -> u64 {
+    map_address(
+        redistributor_base,
+        redistributor_base,
+        GICR_FRAME_SIZE * 2,
+        true,
+        true,
+        false,
+        true,
+    )
+    .expect("Failed to map GIC Redistributor");
+    let typer = read_64(redistributor_base + GICR_TYPER);
+    if (typer & GICR_TYPER_VLPIS) != 0 {
+        map_address(
+            redistributor_base + GICR_VLPI_BASE,
+            redistributor_base + GICR_VLPI_BASE,
+            GICR_FRAME_SIZE * 2,
+            true,
+            true,
+            false,
+            true,
+        )
+        .expect("Failed to map GIC Redistributor");
+    }
+    return typer;
+}
+
+/// Call `f` with RD_base and GICR_TYPER of each redistributor
+///
+/// The redistributors are discovered from GICC structures and GICR structures of MADT.
+fn for_each_redistributor(table: &MADT, mut f: impl FnMut(usize, u64)) {
+    for e in table.get_gic_list() {
+        let redistributor_base = e.gicr_base_address as usize;
+        if redistributor_base != 0 {
+            f(redistributor_base, map_redistributor(redistributor_base));
+        }
+    }
+    for (range_base, range_length) in table.get_gic_redistributor_list() {
+        let mut redistributor_base = range_base;
+        while redistributor_base < range_base + range_length {
+            let typer = map_redistributor(redistributor_base);
+            f(redistributor_base, typer);
+            if (typer & GICR_TYPER_LAST) != 0 {
+                break;
             }
-        } else {
-            println!("DistributorBase is zero");
+            redistributor_base += if (typer & GICR_TYPER_VLPIS) != 0 {
+                GICR_FRAME_SIZE * 4
+            } else {
+                GICR_FRAME_SIZE * 2
+            };
         }
+    }
+}
 
-        for e in table.get_gic_list() {
-            let redistributor_base = e.gicr_base_address as usize;
-            if redistributor_base == 0 {
-                todo!()
-            }
+/// Free the array allocated by [`allocate_array`]
+fn free_array<T>(array: &mut [T]) {
+    let pages = page_align_up((array.len() * size_of::<T>()).max(1)) >> PAGE_SHIFT;
+    if let Err(err) = free_memory(array.as_mut_ptr() as usize, pages) {
+        println!("Failed to free the GIC state: {:?}", err);
+    }
+}
+
+fn allocate_gic_state(table: &MADT) -> GicState {
+    let mut number_of_redistributors = 0;
+    for_each_redistributor(table, |_, _| number_of_redistributors += 1);
+    let number_of_its = table.get_gic_its_list().count();
+    GicState {
+        distributor: &mut allocate_array::<DistributorState>(1)[0],
+        redistributors: allocate_array(number_of_redistributors),
+        its_list: allocate_array(number_of_its),
+    }
+}
+
+/// Save the state of GIC
+///
+/// This should be called when the firmware hands over GIC to the OS(ExitBootServices).
+/// [`restore_gic`] resets GIC into the last saved state.
+///
+/// # Arguments
+/// * `acpi_address` - The address of RSDP
+pub fn save_gic(acpi_address: usize) {
+    let Ok(table) = get_acpi_table(acpi_address, b"APIC") else {
+        println!("MADT is not found, GIC will not be restored.");
+        return;
+    };
+    let table = unsafe { &*(table as *const MADT) };
+    let state = unsafe { SAVED_GIC_STATE.get_or_insert_with(|| allocate_gic_state(table)) };
+    save_gic_into(table, state);
+}
+
+/// Save the current state of GIC into the new [`GicState`]
+///
+/// The state must be freed by [`free_gic_state`].
+///
+/// # Arguments
+/// * `acpi_address` - The address of RSDP
+///
+/// # Result
+/// If MADT is not found, returns None
+pub fn save_gic_state(acpi_address: usize) -> Option<GicState> {
+    let table = unsafe { &*(get_acpi_table(acpi_address, b"APIC").ok()? as *const MADT) };
+    let mut state = allocate_gic_state(table);
+    save_gic_into(table, &mut state);
+    return Some(state);
+}
+
+/// Free the state made by [`save_gic_state`]
+pub fn free_gic_state(state: GicState) {
+    free_array(core::slice::from_mut(state.distributor));
+    free_array(state.redistributors);
+    free_array(state.its_list);
+}
+
+fn save_gic_into(table: &MADT, state: &mut GicState) {
+    for (e, its) in table.get_gic_its_list().zip(state.its_list.iter_mut()) {
+        map_address(e, e, PAGE_SIZE, true, true, false, true).expect("Failed to map ITS");
+        its.base_address = e;
+        save_its(its);
+    }
+
+    match table.get_gic_distributor_address() {
+        Some(distributor) if distributor != 0 => {
             map_address(
-                redistributor_base,
-                redistributor_base,
-                page_align_up(GICR_MAP_SIZE),
+                distributor,
+                distributor,
+                page_align_up(GICD_IROUTER + MAX_NUMBER_OF_INTERRUPTS * size_of::<u64>()),
                 true,
                 true,
                 false,
                 true,
             )
-            .expect("Failed to map GIC Redistributor");
+            .expect("Failed to map GIC Distributor");
+            state.distributor.base_address = distributor;
+            save_distributor(state.distributor);
+        }
+        _ => println!("DistributorBase is zero"),
+    }
 
-            let ctrl = (redistributor_base + GICR_CTLR) as *mut u32;
-            while unsafe { read_volatile(ctrl) & GICR_CTLR_RWP } != 0 {
-                core::hint::spin_loop();
-            }
-            unsafe { write_volatile(ctrl, 0) };
-            while unsafe { read_volatile(ctrl) & GICR_CTLR_RWP } != 0 {
-                core::hint::spin_loop();
-            }
-            if (unsafe { read_volatile(ctrl) } & GICR_CTLR_ENABLE_LPIS) != 0 {
-                pr_debug!(
-                    "GICR_CTLR::EnableLPIs became RES1(this behavior is IMPLEMENTATION DEFINED).\
-                     Therefore, add trap to mask this bit until EL1 writes this bit 1."
-                );
-                add_memory_load_hook_handler(LoadAccessHandlerEntry::new(
-                    redistributor_base,
-                    stage2_page_align_up(GICR_MAP_SIZE),
-                    gic_redistributor_fast_restore_load_handler,
-                ))
-                .expect("Failed to add load handler");
-                add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
-                    redistributor_base,
-                    stage2_page_align_up(GICR_MAP_SIZE),
-                    gic_redistributor_fast_restore_store_handler,
-                ))
-                .expect("Failed to add store handler");
-                add_memory_access_trap(
-                    redistributor_base,
-                    stage2_page_align_up(GICR_MAP_SIZE),
-                    false,
-                    false,
-                )
-                .expect("Failed to trap GIC Register");
-            }
+    let mut redistributors = state.redistributors.iter_mut();
+    for_each_redistributor(table, |redistributor_base, typer| {
+        let Some(redistributor) = redistributors.next() else {
+            panic!("The number of GIC Redistributors is changed");
+        };
+        redistributor.base_address = redistributor_base;
+        redistributor.is_vlpi_supported = (typer & GICR_TYPER_VLPIS) != 0;
+        save_redistributor(redistributor);
+    });
+}
 
-            unsafe {
-                write_volatile(
-                    (redistributor_base + GICR_WAKER) as *mut u32,
-                    GICR_WAKER_PROCESSOR_SLEEP,
-                )
-            };
+fn save_distributor(state: &mut DistributorState) {
+    let base = state.base_address;
+    state.number_of_interrupt_blocks =
+        ((read_32(base + GICD_TYPER) & GICD_TYPER_IT_LINES_NUMBER) + 1) as usize;
+    state.ctlr = read_32(base + GICD_CTLR);
+    /* The registers for SGIs and PPIs(the first block) are in the redistributors */
+    for n in 1..state.number_of_interrupt_blocks {
+        let offset = n * size_of::<u32>();
+        state.group[n] = read_32(base + GICD_IGROUPR + offset);
+        state.group_modifier[n] = read_32(base + GICD_IGRPMODR + offset);
+        state.enable[n] = read_32(base + GICD_ISENABLER + offset);
+    }
+    for n in 8..(state.number_of_interrupt_blocks * 8) {
+        state.priority[n] = read_32(base + GICD_IPRIORITYR + n * size_of::<u32>());
+    }
+    for n in 2..(state.number_of_interrupt_blocks * 2) {
+        state.configuration[n] = read_32(base + GICD_ICFGR + n * size_of::<u32>());
+    }
+    for n in 32..(state.number_of_interrupt_blocks * 32).min(MAX_NUMBER_OF_INTERRUPTS) {
+        state.router[n] = read_64(base + GICD_IROUTER + n * size_of::<u64>());
+    }
+}
+
+fn save_redistributor(state: &mut RedistributorState) {
+    let rd_base = state.base_address;
+    let sgi_base = rd_base + GICR_SGI_BASE;
+    state.ctlr = read_32(rd_base + GICR_CTLR);
+    state.waker = read_32(rd_base + GICR_WAKER);
+    state.propbaser = read_64(rd_base + GICR_PROPBASER);
+    state.pendbaser = read_64(rd_base + GICR_PENDBASER);
+    if state.is_vlpi_supported {
+        state.vpropbaser = read_64(rd_base + GICR_VLPI_BASE + GICR_VPROPBASER);
+    }
+    state.group = read_32(sgi_base + GICR_IGROUPR0);
+    state.group_modifier = read_32(sgi_base + GICR_IGRPMODR0);
+    state.enable = read_32(sgi_base + GICR_ISENABLER0);
+    for (n, p) in state.priority.iter_mut().enumerate() {
+        *p = read_32(sgi_base + GICR_IPRIORITYR + n * size_of::<u32>());
+    }
+    state.configuration = read_32(sgi_base + GICR_ICFGR1);
+}
+
+fn save_its(state: &mut ItsState) {
+    let base = state.base_address;
+    state.ctlr = read_32(base + GITS_CTLR);
+    state.cbaser = read_64(base + GITS_CBASER);
+    for (n, b) in state.baser.iter_mut().enumerate() {
+        *b = read_64(base + GITS_BASER + n * size_of::<u64>());
+    }
+}
+
+/// Reset GIC into the state saved by [`save_gic`]
+///
+/// This must be called after all other CPUs are stopped.
+/// The CPU interface is restored with the system registers.
+pub fn restore_gic() {
+    let Some(state) = (unsafe { SAVED_GIC_STATE.as_ref() }) else {
+        println!("GIC state is not saved.");
+        return;
+    };
+    restore_gic_state(state);
+}
+
+/// Reset GIC into `state`
+///
+/// This must be called after all other CPUs are stopped.
+pub fn restore_gic_state(state: &GicState) {
+    for its in state.its_list.iter() {
+        restore_its(its);
+    }
+    if state.distributor.base_address != 0 {
+        restore_distributor(state.distributor);
+    }
+    for redistributor in state.redistributors.iter() {
+        restore_redistributor(redistributor);
+    }
+}
+
+fn restore_its(state: &ItsState) {
+    let base = state.base_address;
+    write_32(
+        base + GITS_CTLR,
+        read_32(base + GITS_CTLR) & !GITS_CTLR_ENABLED,
+    );
+    while (read_32(base + GITS_CTLR) & GITS_CTLR_QUIESCENT) == 0 {
+        core::hint::spin_loop();
+    }
+    /* Writing GITS_CBASER resets GITS_CREADR to zero */
+    write_64(base + GITS_CBASER, state.cbaser);
+    write_64(base + GITS_CWRITER, 0);
+    for (n, b) in state.baser.iter().enumerate() {
+        write_64(base + GITS_BASER + n * size_of::<u64>(), *b);
+    }
+    write_32(base + GITS_CTLR, state.ctlr & GITS_CTLR_ENABLED);
+}
+
+fn restore_distributor(state: &DistributorState) {
+    let base = state.base_address;
+    let ctlr = base + GICD_CTLR;
+    write_32(
+        ctlr,
+        read_32(ctlr) & !(GICD_CTLR_ENABLE_GROUPS | GICD_CTLR_RWP),
+    );
+    wait_register_write(ctlr, GICD_CTLR_RWP);
+
+    for n in 1..state.number_of_interrupt_blocks {
+        write_32(base + GICD_ICENABLER + n * size_of::<u32>(), u32::MAX);
+    }
+    wait_register_write(ctlr, GICD_CTLR_RWP);
+    for n in 1..state.number_of_interrupt_blocks {
+        let offset = n * size_of::<u32>();
+        write_32(base + GICD_ICPENDR + offset, u32::MAX);
+        write_32(base + GICD_ICACTIVER + offset, u32::MAX);
+        write_32(base + GICD_IGROUPR + offset, state.group[n]);
+        write_32(base + GICD_IGRPMODR + offset, state.group_modifier[n]);
+    }
+    for n in 8..(state.number_of_interrupt_blocks * 8) {
+        write_32(
+            base + GICD_IPRIORITYR + n * size_of::<u32>(),
+            state.priority[n],
+        );
+    }
+    for n in 2..(state.number_of_interrupt_blocks * 2) {
+        write_32(
+            base + GICD_ICFGR + n * size_of::<u32>(),
+            state.configuration[n],
+        );
+    }
+    for n in 32..(state.number_of_interrupt_blocks * 32).min(MAX_NUMBER_OF_INTERRUPTS) {
+        write_64(base + GICD_IROUTER + n * size_of::<u64>(), state.router[n]);
+    }
+    for n in 1..state.number_of_interrupt_blocks {
+        write_32(
+            base + GICD_ISENABLER + n * size_of::<u32>(),
+            state.enable[n],
+        );
+    }
+
+    write_32(ctlr, state.ctlr & !GICD_CTLR_RWP);
+    wait_register_write(ctlr, GICD_CTLR_RWP);
+}
+
+fn restore_redistributor(state: &RedistributorState) {
+    let redistributor_base = state.base_address;
+    let sgi_base = redistributor_base + GICR_SGI_BASE;
+    let ctrl = redistributor_base + GICR_CTLR;
+
+    /* SGIs and PPIs */
+    write_32(sgi_base + GICR_ICENABLER0, u32::MAX);
+    wait_register_write(ctrl, GICR_CTLR_RWP);
+    write_32(sgi_base + GICR_ICPENDR0, u32::MAX);
+    write_32(sgi_base + GICR_ICACTIVER0, u32::MAX);
+    write_32(sgi_base + GICR_IGROUPR0, state.group);
+    write_32(sgi_base + GICR_IGRPMODR0, state.group_modifier);
+    for (n, p) in state.priority.iter().enumerate() {
+        write_32(sgi_base + GICR_IPRIORITYR + n * size_of::<u32>(), *p);
+    }
+    write_32(sgi_base + GICR_ICFGR1, state.configuration);
+    write_32(sgi_base + GICR_ISENABLER0, state.enable);
+
+    /* GICv4 vPE */
+    if state.is_vlpi_supported {
+        let vpendbaser = redistributor_base + GICR_VLPI_BASE + GICR_VPENDBASER;
+        write_64(vpendbaser, read_64(vpendbaser) & !GICR_VPENDBASER_VALID);
+        while (read_64(vpendbaser) & GICR_VPENDBASER_DIRTY) != 0 {
+            core::hint::spin_loop();
         }
+        write_64(
+            redistributor_base + GICR_VLPI_BASE + GICR_VPROPBASER,
+            state.vpropbaser,
+        );
     }
+
+    /* LPIs */
+    wait_register_write(ctrl, GICR_CTLR_RWP);
+    write_32(ctrl, 0);
+    wait_register_write(ctrl, GICR_CTLR_RWP);
+    if (read_32(ctrl) & GICR_CTLR_ENABLE_LPIS) != 0 {
+        pr_debug!(
+            "GICR_CTLR::EnableLPIs became RES1(this behavior is IMPLEMENTATION DEFINED).\
+             Therefore, add trap to mask this bit until EL1 writes this bit 1."
+        );
+        add_memory_load_hook_handler(LoadAccessHandlerEntry::new(
+            redistributor_base,
+            stage2_page_align_up(GICR_MAP_SIZE),
+            gic_redistributor_fast_restore_load_handler,
+        ))
+        .expect("Failed to add load handler");
+        add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+            redistributor_base,
+            stage2_page_align_up(GICR_MAP_SIZE),
+            gic_redistributor_fast_restore_store_handler,
+        ))
+        .expect("Failed to add store handler");
+        add_memory_access_trap(
+            redistributor_base,
+            stage2_page_align_up(GICR_MAP_SIZE),
+            false,
+            false,
+        )
+        .expect("Failed to trap GIC Register");
+    } else {
+        write_64(redistributor_base + GICR_PROPBASER, state.propbaser);
+        write_64(redistributor_base + GICR_PENDBASER, state.pendbaser);
+        write_32(ctrl, state.ctlr & !GICR_CTLR_RWP);
+        wait_register_write(ctrl, GICR_CTLR_RWP);
+    }
+
+    let waker = redistributor_base + GICR_WAKER;
+    write_32(
+        waker,
+        (read_32(waker) & !GICR_WAKER_PROCESSOR_SLEEP) | (state.waker & GICR_WAKER_PROCESSOR_SLEEP),
+    );
 }
 
 fn gic_redistributor_fast_restore_load_handler(
@@ -124,6 +548,7 @@ fn gic_redistributor_fast_restore_load_handler(
     _access_size: u8,
     _is_64bit_register: bool,
     _is_sign_extend_required: bool,
+    _context: usize,
 ) -> Result<LoadHookResult, ()> {
     let offset = accessing_address & (GICR_MAP_SIZE - 1);
     match offset {
@@ -131,7 +556,7 @@ fn gic_redistributor_fast_restore_load_handler(
             (unsafe { read_volatile(accessing_address as *const u32) } & !GICR_CTLR_ENABLE_LPIS)
                 as u64,
         )),
-        _ => Ok(LoadHookResult::PassThrough),
+        _ => Ok(LoadHookResult::Continue),
     }
 }
 
@@ -140,6 +565,7 @@ fn gic_redistributor_fast_restore_store_handler(
     _stored_registers: &mut StoredRegisters,
     _access_size: u8,
     data: u64,
+    _context: usize,
 ) -> Result<StoreHookResult, ()> {
     let base = accessing_address & !(GICR_MAP_SIZE - 1);
     let offset = accessing_address & (GICR_MAP_SIZE - 1);
@@ -169,6 +595,6 @@ fn gic_redistributor_fast_restore_store_handler(
             assert_eq!(original_data & !GICR_PROPBASER_PTZ, data);
             Ok(StoreHookResult::Cancel)
         }
-        _ => Ok(StoreHookResult::PassThrough),
+        _ => Ok(StoreHookResult::Continue),
     }
 }
@@ -0,0 +1,339 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Guest Breakpoint
+//!
+//! Replace an instruction of the guest with HVC to call the handler when the guest executes it.
+//! The breakpoints are one-shot: when the guest hits the breakpoint, the original instruction is
+//! written back and the guest executes it after the handler. The handler can add the breakpoint again.
+//!
+//! The pages containing breakpoints are write-protected by stage 2 to detect the guest overwriting
+//! the patched instruction (e.g. loading another image). In that case, the breakpoint is discarded
+//! without writing back the original instruction.
+//! If the store access into the page cannot be emulated, all breakpoints in the page are discarded
+//! with writing back the original instructions, and the guest retries the access without the trap.
+//!
+
+use crate::memory_hook::{
+    add_memory_store_hook_handler, remove_memory_store_hook_handler, StoreAccessHandlerEntry,
+    StoreHookResult,
+};
+use crate::paging::{add_memory_access_trap, map_address, remove_memory_access_trap};
+use crate::StoredRegisters;
+
+use common::cpu::{self, AA64_INSTRUCTION_SIZE};
+use common::spin_flag::SpinLockFlag;
+use common::{PAGE_MASK, PAGE_SIZE};
+
+/// The immediate value of HVC written into the breakpoints
+pub const HVC_GUEST_BREAKPOINT: u16 = 0xFFF0;
+const HVC_INSTRUCTION: u32 = 0b11010100000 << 21 | (HVC_GUEST_BREAKPOINT as u32) << 5 | 0b00010;
+const MAX_NUMBER_OF_BREAKPOINTS: usize = 32;
+
+/// The handler called when the guest hits the breakpoint
+///
+/// `address` is the intermediate physical address of the breakpoint and
+/// `context` is the value passed to [`add_breakpoint`].
+/// When the handler is called, the breakpoint is already removed and
+/// ELR_EL2 points the original instruction.
+pub type BreakpointHandler =
+    fn(stored_registers: &mut StoredRegisters, address: usize, context: usize);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: usize,
+    original_instruction: u32,
+    handler: BreakpointHandler,
+    context: usize,
+}
+
+static BREAKPOINT_LOCK: SpinLockFlag = SpinLockFlag::new();
+static mut BREAKPOINT_LIST: [Option<Breakpoint>; MAX_NUMBER_OF_BREAKPOINTS] =
+    [None; MAX_NUMBER_OF_BREAKPOINTS];
+
+/// Check if any breakpoint is in the page, the lock must be acquired
+fn is_page_patched(page_address: usize) -> bool {
+    unsafe { BREAKPOINT_LIST.iter() }
+        .flatten()
+        .any(|b| (b.address & PAGE_MASK) == page_address)
+}
+
+/// Write `instruction` into `address` and make it visible to the instruction fetch
+fn write_instruction(address: usize, instruction: u32) {
+    unsafe { core::ptr::write_volatile(address as *mut u32, instruction) };
+    cpu::clean_data_cache_to_pou(address);
+    cpu::dsb();
+    cpu::invalidate_instruction_cache(address);
+    cpu::dsb();
+    cpu::isb();
+}
+
+fn read_instruction(address: usize) -> u32 {
+    unsafe { core::ptr::read_volatile(address as *const u32) }
+}
+
+/// Write-protect the page and register the store handler to detect overwriting
+fn protect_page(page_address: usize) -> Result<(), ()> {
+    let entry = StoreAccessHandlerEntry::new(page_address, PAGE_SIZE, breakpoint_store_handler);
+    add_memory_store_hook_handler(entry)?;
+    if add_memory_access_trap(page_address, PAGE_SIZE, true, false).is_err() {
+        let _ = remove_memory_store_hook_handler(entry);
+        return Err(());
+    }
+    return Ok(());
+}
+
+/// Remove the write protection if no breakpoint remains in the page, the lock must be acquired
+///
+/// # Result
+/// If the protection is removed, returns true.
+/// In that case, [`restore_other_memory_trap`] must be called after releasing the lock.
+fn unprotect_page_if_unused(page_address: usize) -> bool {
+    if is_page_patched(page_address) {
+        return false;
+    }
+    if let Err(e) = remove_memory_access_trap(page_address, PAGE_SIZE) {
+        println!("Failed to remove memory trap: {:?}", e);
+    }
+    if let Err(e) = remove_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+        page_address,
+        PAGE_SIZE,
+        breakpoint_store_handler,
+    )) {
+        println!("Failed to remove the store handler: {:?}", e);
+    }
+    return true;
+}
+
+/// Re-add the memory trap which was shared with the write protection of breakpoints
+fn restore_other_memory_trap(_page_address: usize) {
+    #[cfg(feature = "fast_restore")]
+    crate::fast_restore::restore_memory_trap(_page_address);
+}
+
+/// Add the breakpoint into the guest
+///
+/// The page of `address` is mapped into EL2 if it is not mapped.
+///
+/// # Arguments
+/// * `address` - The intermediate physical address of the instruction to hook
+/// * `handler` - The handler called when the guest executes the instruction
+/// * `context` - The opaque value passed to `handler`
+///
+/// # Result
+/// If the breakpoint is added, returns Ok(()).
+/// If `address` is not aligned, the breakpoint already exists at `address`,
+/// or the breakpoint list is full, returns Err(()).
+pub fn add_breakpoint(
+    address: usize,
+    handler: BreakpointHandler,
+    context: usize,
+) -> Result<(), ()> {
+    if (address & (AA64_INSTRUCTION_SIZE - 1)) != 0 {
+        println!("Breakpoint address({:#X}) is not aligned.", address);
+        return Err(());
+    }
+    let page_address = address & PAGE_MASK;
+    if cpu::convert_virtual_address_to_physical_address_el2_write(page_address).is_err() {
+        map_address(
+            page_address,
+            page_address,
+            PAGE_SIZE,
+            true,
+            true,
+            false,
+            false,
+        )?;
+    }
+
+    BREAKPOINT_LOCK.lock();
+    if unsafe { BREAKPOINT_LIST.iter() }
+        .flatten()
+        .any(|b| b.address == address)
+    {
+        BREAKPOINT_LOCK.unlock();
+        println!("The breakpoint({:#X}) already exists.", address);
+        return Err(());
+    }
+    let Some(index) = unsafe { BREAKPOINT_LIST.iter() }.position(|b| b.is_none()) else {
+        BREAKPOINT_LOCK.unlock();
+        println!("No breakpoint is available.");
+        return Err(());
+    };
+    if !is_page_patched(page_address) && protect_page(page_address).is_err() {
+        BREAKPOINT_LOCK.unlock();
+        println!("Failed to write-protect the page({:#X}).", page_address);
+        return Err(());
+    }
+    unsafe {
+        BREAKPOINT_LIST[index] = Some(Breakpoint {
+            address,
+            original_instruction: read_instruction(address),
+            handler,
+            context,
+        })
+    };
+    write_instruction(address, HVC_INSTRUCTION);
+    BREAKPOINT_LOCK.unlock();
+    return Ok(());
+}
+
+/// Remove the breakpoint and write back the original instruction
+///
+/// If the guest overwrote the breakpoint, the original instruction is not written back.
+///
+/// # Result
+/// If the breakpoint does not exist at `address`, returns Err(())
+#[allow(dead_code)]
+pub fn remove_breakpoint(address: usize) -> Result<(), ()> {
+    BREAKPOINT_LOCK.lock();
+    let Some(entry) = unsafe { BREAKPOINT_LIST.iter_mut() }
+        .find(|b| b.map(|b| b.address == address).unwrap_or(false)) else {
+        BREAKPOINT_LOCK.unlock();
+        return Err(());
+    };
+    let breakpoint = entry.take().unwrap();
+    if read_instruction(address) == HVC_INSTRUCTION {
+        write_instruction(address, breakpoint.original_instruction);
+    }
+    let is_unprotected = unprotect_page_if_unused(address & PAGE_MASK);
+    BREAKPOINT_LOCK.unlock();
+    if is_unprotected {
+        restore_other_memory_trap(address & PAGE_MASK);
+    }
+    return Ok(());
+}
+
+/// Discard the breakpoints in the page and remove its write protection
+///
+/// This is called when the store access into the page cannot be emulated.
+/// The original instructions are written back, and the guest can retry the access without the trap.
+///
+/// # Arguments
+/// * `page_address` - The intermediate physical address of the page
+///
+/// # Result
+/// If any breakpoint was in the page, returns true
+pub fn discard_breakpoints_in_page(page_address: usize) -> bool {
+    BREAKPOINT_LOCK.lock();
+    let mut is_discarded = false;
+    for entry in unsafe { BREAKPOINT_LIST.iter_mut() }.filter(|b| {
+        b.map(|b| (b.address & PAGE_MASK) == page_address)
+            .unwrap_or(false)
+    }) {
+        let breakpoint = entry.take().unwrap();
+        println!(
+            "The store access into the page of the breakpoint({:#X}) cannot be emulated, it is discarded.",
+            breakpoint.address
+        );
+        if read_instruction(breakpoint.address) == HVC_INSTRUCTION {
+            write_instruction(breakpoint.address, breakpoint.original_instruction);
+        }
+        is_discarded = true;
+    }
+    let is_unprotected = is_discarded && unprotect_page_if_unused(page_address);
+    BREAKPOINT_LOCK.unlock();
+    if is_unprotected {
+        restore_other_memory_trap(page_address);
+    }
+    return is_discarded;
+}
+
+/// Write-protect the pages containing breakpoints again
+///
+/// This must be called after removing the memory trap of (`address` ~ (`address` + `size`))
+/// for other purposes, otherwise overwriting the breakpoints will not be detected.
+pub fn restore_write_protection(address: usize, size: usize) {
+    BREAKPOINT_LOCK.lock();
+    for b in unsafe { BREAKPOINT_LIST.iter() }
+        .flatten()
+        .filter(|b| (address..(address + size)).contains(&b.address))
+    {
+        if let Err(e) = add_memory_access_trap(b.address & PAGE_MASK, PAGE_SIZE, true, false) {
+            println!("Failed to add memory trap: {:?}", e);
+        }
+    }
+    BREAKPOINT_LOCK.unlock();
+}
+
+/// Handle HVC of [`HVC_GUEST_BREAKPOINT`]
+///
+/// If the HVC is not a breakpoint, it is treated as an unknown hypervisor call.
+///
+/// # Arguments
+/// * `stored_registers` - The registers of the guest
+/// * `elr` - ELR_EL2, the next address of the HVC instruction
+pub fn breakpoint_handler(stored_registers: &mut StoredRegisters, elr: u64) {
+    let hvc_address = elr as usize - AA64_INSTRUCTION_SIZE;
+    let Ok(address) =
+        cpu::convert_virtual_address_to_intermediate_physical_address_el1_read(hvc_address) else {
+        println!("Hypervisor Call: {:#X}", HVC_GUEST_BREAKPOINT);
+        return;
+    };
+
+    BREAKPOINT_LOCK.lock();
+    let Some(entry) = unsafe { BREAKPOINT_LIST.iter_mut() }
+        .find(|b| b.map(|b| b.address == address).unwrap_or(false)) else {
+        /* The page is not mapped into EL2 if no breakpoint has been added into it */
+        let is_removed = cpu::convert_virtual_address_to_physical_address_el2_read(address)
+            .is_ok()
+            && read_instruction(address) != HVC_INSTRUCTION;
+        BREAKPOINT_LOCK.unlock();
+        if is_removed {
+            /* Another CPU hit the same breakpoint and removed it */
+            cpu::set_elr_el2(hvc_address as u64);
+        } else {
+            println!("Hypervisor Call: {:#X}", HVC_GUEST_BREAKPOINT);
+        }
+        return;
+    };
+    let breakpoint = entry.take().unwrap();
+    write_instruction(address, breakpoint.original_instruction);
+    let is_unprotected = unprotect_page_if_unused(address & PAGE_MASK);
+    BREAKPOINT_LOCK.unlock();
+    if is_unprotected {
+        restore_other_memory_trap(address & PAGE_MASK);
+    }
+
+    cpu::set_elr_el2(hvc_address as u64);
+    (breakpoint.handler)(stored_registers, address, breakpoint.context);
+}
+
+/// Discard the breakpoints overwritten by the guest
+fn breakpoint_store_handler(
+    accessing_memory_address: usize,
+    _stored_registers: &mut StoredRegisters,
+    access_size: u8,
+    _data: u64,
+    _context: usize,
+) -> Result<StoreHookResult, ()> {
+    let access_end = accessing_memory_address + (1 << access_size);
+    BREAKPOINT_LOCK.lock();
+    let mut is_overwritten = false;
+    for entry in unsafe { BREAKPOINT_LIST.iter_mut() } {
+        if let Some(b) = entry {
+            if b.address < access_end
+                && accessing_memory_address < b.address + AA64_INSTRUCTION_SIZE
+            {
+                println!(
+                    "The guest overwrote the breakpoint({:#X}), it is discarded.",
+                    b.address
+                );
+                *entry = None;
+                is_overwritten = true;
+            }
+        }
+    }
+    let page_address = accessing_memory_address & PAGE_MASK;
+    let is_unprotected = is_overwritten && unprotect_page_if_unused(page_address);
+    BREAKPOINT_LOCK.unlock();
+    if is_unprotected {
+        restore_other_memory_trap(page_address);
+    }
+    return Ok(StoreHookResult::Continue);
+}
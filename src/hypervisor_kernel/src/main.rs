@@ -12,6 +12,10 @@
 #![feature(naked_functions)]
 #![feature(panic_info_message)]
 
+/* The stage 2 table is shared with SMMU, the write protection for tracking would block DMA */
+#[cfg(all(feature = "fast_restore_dirty_page_tracking", feature = "smmu"))]
+compile_error!("`fast_restore_dirty_page_tracking` cannot be enabled with `smmu`");
+
 #[macro_use]
 mod serial_port;
 mod acpi_protect;
@@ -19,17 +23,19 @@ mod drivers;
 mod emulation;
 mod fast_restore;
 mod gic;
+mod guest_breakpoint;
 mod memory_hook;
 mod multi_core;
 mod paging;
 mod panic;
 mod pci;
 mod psci;
+mod smccc;
 mod smmu;
 
 use common::cpu::{
-    advance_elr_el2, get_elr_el2, get_esr_el2, get_far_el2, get_hpfar_el2, get_mpidr_el1,
-    get_spsr_el2, secure_monitor_call,
+    advance_elr_el2, convert_hpfar_el2_to_ipa, get_elr_el2, get_esr_el2, get_far_el2,
+    get_hpfar_el2, get_mpidr_el1, get_spsr_el2,
 };
 use common::spin_flag::SpinLockFlag;
 use common::{
@@ -42,6 +48,7 @@ use core::mem::MaybeUninit;
 
 const EC_HVC: u8 = 0b010110;
 const EC_SMC_AA64: u8 = 0b010111;
+const EC_INSTRUCTION_ABORT: u8 = 0b100000;
 const EC_DATA_ABORT: u8 = 0b100100;
 
 static mut MEMORY_ALLOCATOR: (SpinLockFlag, MaybeUninit<MemoryAllocator>) =
@@ -109,6 +116,13 @@ fn hypervisor_main(system_information: &mut SystemInformation) {
         ACPI_RSDP = system_information.acpi_rsdp_address;
     }
 
+    smccc::init_smccc();
+    if let Some(rsdp_address) = unsafe { ACPI_RSDP } {
+        multi_core::init_cpu_list_by_madt(rsdp_address);
+    } else if let Some(cpu_list) = system_information.dtb_cpu_list {
+        multi_core::init_cpu_list_by_dtb(unsafe { &*cpu_list });
+    }
+
     if let Some(ecam_info) = &system_information.ecam_info {
         pci::init_pci(ecam_info.address, ecam_info.start_bus, ecam_info.end_bus);
     }
@@ -131,8 +145,23 @@ fn hypervisor_main(system_information: &mut SystemInformation) {
     {
         /* Fast Restore Initialization */
         fast_restore::add_memory_save_list(system_information.memory_save_list);
-        fast_restore::add_trap_to_exit_boot_service(system_information.exit_boot_service_address);
+        if let Some(rsdp_address) = unsafe { ACPI_RSDP } {
+            /* Saved again when ExitBootServices is called */
+            gic::save_gic(rsdp_address);
+        }
+        let is_snapshot_imported = system_information
+            .snapshot_image
+            .map(|(address, size)| fast_restore::import_boot_snapshot(address, size))
+            .unwrap_or(false);
+        if !is_snapshot_imported {
+            /* The bootloader continues the normal boot */
+            system_information.snapshot_image = None;
+            fast_restore::add_trap_to_exit_boot_service(
+                system_information.exit_boot_service_address,
+            );
+        }
         fast_restore::create_memory_trap_for_save_memory();
+        fast_restore::init_dirty_page_tracking();
     }
 
     unsafe { BSP_MPIDR = get_mpidr_el1() };
@@ -178,6 +207,10 @@ fn show_kernel_info() {
     print_is_feature_enabled!("i210");
     print_is_feature_enabled!("mt27800");
     print_is_feature_enabled!("fast_restore");
+    print_is_feature_enabled!("fast_restore_ignore_corruption");
+    print_is_feature_enabled!("fast_restore_dirty_page_tracking");
+    print_is_feature_enabled!("fast_restore_real_system_off");
+    print_is_feature_enabled!("fast_restore_real_system_reset");
     print_is_feature_enabled!("acpi_table_protection");
     print_is_feature_enabled!("contiguous_bit");
     print_is_feature_enabled!("a64fx");
@@ -244,22 +277,33 @@ extern "C" fn synchronous_exception_handler(regs: &mut StoredRegisters) {
 
     /* FastRestore Hook */
     #[cfg(feature = "fast_restore")]
+    fast_restore::perform_restore_if_needed();
+
+    /* The stage 2 descriptor was being replaced by other CPU, retry the access */
+    if (ec == EC_DATA_ABORT || ec == EC_INSTRUCTION_ABORT)
+        && paging::is_stage2_translation_fault_resolved(esr_el2, hpfar_el2)
     {
-        fast_restore::perform_restore_if_needed();
-        if fast_restore::check_memory_access_for_memory_save_list(ec, far_el2) {
-            return;
-        }
+        pr_debug!("Retry the access after the stage 2 descriptor is replaced.");
+        return;
+    }
+
+    #[cfg(feature = "fast_restore")]
+    if fast_restore::check_memory_access_for_memory_save_list(ec, esr_el2, far_el2, hpfar_el2) {
+        return;
     }
 
     match ec {
         EC_HVC => match (esr_el2 & bitmask!(15, 0)) as u16 {
-            fast_restore::HVC_EXIT_BOOT_SERVICE_TRAP => {
-                #[cfg(feature = "fast_restore")]
-                fast_restore::exit_boot_service_trap_main(regs, elr_el2);
+            guest_breakpoint::HVC_GUEST_BREAKPOINT => {
+                guest_breakpoint::breakpoint_handler(regs, elr_el2);
             }
-            fast_restore::HVC_AFTER_EXIT_BOOT_SERVICE_TRAP => {
+            fast_restore::HVC_SNAPSHOT_CALL => {
                 #[cfg(feature = "fast_restore")]
-                fast_restore::after_exit_boot_service_trap_main(regs, elr_el2);
+                fast_restore::snapshot_call_main(regs);
+            }
+            0 => {
+                pr_debug!("Hypervisor Call: {:#X}", regs.x0);
+                smccc::handle_smccc_call(smccc::SmcccConduit::Hvc, 0, regs);
             }
             hvc_number => {
                 println!("Hypervisor Call: {:#X}", hvc_number);
@@ -271,41 +315,19 @@ extern "C" fn synchronous_exception_handler(regs: &mut StoredRegisters) {
             let smc_number = esr_el2 & bitmask!(15, 0);
             pr_debug!("SecureMonitor Call: {:#X}", smc_number);
             pr_debug!("Registers: {:#X?}", regs);
-            if smc_number == 0 {
-                if let Ok(psci_function_id) = psci::PsciFunctionId::try_from(regs.x0) {
-                    psci::handle_psci_call(psci_function_id, regs);
-                } else {
-                    pr_debug!("Unknown Secure Monitor Call: {:#X}", regs.x0);
-                    secure_monitor_call(
-                        &mut regs.x0,
-                        &mut regs.x1,
-                        &mut regs.x2,
-                        &mut regs.x3,
-                        &mut regs.x4,
-                        &mut regs.x5,
-                        &mut regs.x6,
-                        &mut regs.x7,
-                        &mut regs.x8,
-                        &mut regs.x9,
-                        &mut regs.x10,
-                        &mut regs.x11,
-                        &mut regs.x12,
-                        &mut regs.x13,
-                        &mut regs.x14,
-                        &mut regs.x15,
-                        &mut regs.x16,
-                        &mut regs.x17,
-                    );
-                }
-            } else {
-                handler_panic!(regs, "SMC {:#X} is not implemented.", smc_number);
-            }
+            smccc::handle_smccc_call(smccc::SmcccConduit::Smc, smc_number as u16, regs);
         }
         EC_DATA_ABORT => {
             pr_debug!("Data Abort");
             if let Err(e) =
                 emulation::data_abort_handler(regs, esr_el2, elr_el2, far_el2, hpfar_el2, spsr_el2)
             {
+                /* Retry the store access without the write protection of breakpoints */
+                if guest_breakpoint::discard_breakpoints_in_page(convert_hpfar_el2_to_ipa(
+                    hpfar_el2,
+                )) {
+                    return;
+                }
                 handler_panic!(regs, "Failed to emulate the instruction: {:?}", e);
             }
         }
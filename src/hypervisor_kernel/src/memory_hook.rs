@@ -8,26 +8,49 @@
 //!
 //! Memory Hook Handler
 //!
+//! Handler entries are kept in the address-sorted tables allocated from the memory pool.
+//! Each table is also used as an implicit balanced binary tree (the middle entry of each range
+//! is the root of the range) augmented with the largest end address of each subtree,
+//! therefore the entries containing an address are found in O(log N + the number of them).
+//! Multiple handlers can hook the same address. They are called in descending order of
+//! their priorities (if the priorities are same, in the registered order) until one of them
+//! returns the result other than `Continue`.
+//!
+
+use crate::{allocate_memory, free_memory, StoredRegisters};
 
-use crate::StoredRegisters;
+use common::spin_flag::SpinLockFlag;
+use common::PAGE_SHIFT;
+
+use core::mem::size_of;
 
 pub enum LoadHookResult {
     PassThrough,
     Data(u64),
+    /// Call the next handler, if no handler remains, this is treated as `PassThrough`
+    Continue,
 }
 
 pub enum StoreHookResult {
     PassThrough,
     AlternativeData(u64),
     Cancel,
+    /// Call the next handler, if no handler remains, this is treated as `PassThrough`
+    Continue,
 }
 
+pub type MemoryHookPriority = u8;
+
+pub const MEMORY_HOOK_PRIORITY_HIGHEST: MemoryHookPriority = MemoryHookPriority::MAX;
+pub const MEMORY_HOOK_PRIORITY_DEFAULT: MemoryHookPriority = 0x80;
+
 pub type LoadAccessHandler = fn(
     accessing_memory_address: usize,
     stored_registers: &mut StoredRegisters,
     access_size: u8,
     is_64bit_register: bool,
     is_sign_extend_required: bool,
+    context: usize,
 ) -> Result<LoadHookResult, ()>;
 
 pub type StoreAccessHandler = fn(
@@ -35,6 +58,7 @@ pub type StoreAccessHandler = fn(
     stored_registers: &mut StoredRegisters,
     access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()>;
 
 #[derive(Clone, Copy)]
@@ -42,7 +66,8 @@ pub struct LoadAccessHandlerEntry {
     target_address: usize,
     range: usize,
     handler: LoadAccessHandler,
-    //busy_flag: AtomicBool
+    context: usize,
+    priority: MemoryHookPriority,
 }
 
 #[derive(Clone, Copy)]
@@ -50,15 +75,31 @@ pub struct StoreAccessHandlerEntry {
     target_address: usize,
     range: usize,
     handler: StoreAccessHandler,
-    //busy_flag: AtomicBool
+    context: usize,
+    priority: MemoryHookPriority,
 }
 
 impl LoadAccessHandlerEntry {
     pub const fn new(target_address: usize, range: usize, handler: LoadAccessHandler) -> Self {
+        Self::new_with_context(target_address, range, handler, 0)
+    }
+
+    /// Create the entry with the opaque value passed to the handler
+    ///
+    /// `context` is not touched by memory_hook, it is used to identify the device instance
+    /// when one handler serves multiple devices. (e.g. the address of the device state)
+    pub const fn new_with_context(
+        target_address: usize,
+        range: usize,
+        handler: LoadAccessHandler,
+        context: usize,
+    ) -> Self {
         Self {
             target_address,
             range,
             handler,
+            context,
+            priority: MEMORY_HOOK_PRIORITY_DEFAULT,
         }
     }
 
@@ -69,14 +110,42 @@ impl LoadAccessHandlerEntry {
     pub fn set_target_address(&mut self, address: usize) {
         self.target_address = address;
     }
+
+    pub fn set_context(&mut self, context: usize) {
+        self.context = context;
+    }
+
+    /// Set the priority of the handler
+    ///
+    /// The handler which has the higher priority is called earlier.
+    /// The default priority is [`MEMORY_HOOK_PRIORITY_DEFAULT`].
+    #[allow(dead_code)]
+    pub fn set_priority(&mut self, priority: MemoryHookPriority) {
+        self.priority = priority;
+    }
 }
 
 impl StoreAccessHandlerEntry {
     pub const fn new(target_address: usize, range: usize, handler: StoreAccessHandler) -> Self {
+        Self::new_with_context(target_address, range, handler, 0)
+    }
+
+    /// Create the entry with the opaque value passed to the handler
+    ///
+    /// `context` is not touched by memory_hook, it is used to identify the device instance
+    /// when one handler serves multiple devices. (e.g. the address of the device state)
+    pub const fn new_with_context(
+        target_address: usize,
+        range: usize,
+        handler: StoreAccessHandler,
+        context: usize,
+    ) -> Self {
         Self {
             target_address,
             range,
             handler,
+            context,
+            priority: MEMORY_HOOK_PRIORITY_DEFAULT,
         }
     }
 
@@ -87,61 +156,324 @@ impl StoreAccessHandlerEntry {
     pub fn set_target_address(&mut self, address: usize) {
         self.target_address = address;
     }
+
+    pub fn set_context(&mut self, context: usize) {
+        self.context = context;
+    }
+
+    /// Set the priority of the handler
+    ///
+    /// The handler which has the higher priority is called earlier.
+    /// The default priority is [`MEMORY_HOOK_PRIORITY_DEFAULT`].
+    pub fn set_priority(&mut self, priority: MemoryHookPriority) {
+        self.priority = priority;
+    }
 }
 
-pub static mut LOAD_HANDLER_LIST: [Option<LoadAccessHandlerEntry>; 256] = [None; 256];
-static mut NUM_OF_LOAD_HANDLER_ENABLED_ENTRIES: usize = 0;
-pub static mut STORE_HANDLER_LIST: [Option<StoreAccessHandlerEntry>; 256] = [None; 256];
-static mut NUM_OF_STORE_HANDLER_ENABLED_ENTRIES: usize = 0;
+trait HandlerEntry: Copy {
+    fn get_range(&self) -> (usize, usize);
+    fn get_priority(&self) -> MemoryHookPriority;
+    /// Check if the handler and the context are same, the range is not compared
+    fn is_same_handler(&self, other: &Self) -> bool;
+}
 
-pub fn add_memory_load_hook_handler(entry: LoadAccessHandlerEntry) -> Result<(), ()> {
-    for e in unsafe { &mut LOAD_HANDLER_LIST } {
-        if e.is_none() {
-            *e = Some(entry);
-            unsafe { NUM_OF_LOAD_HANDLER_ENABLED_ENTRIES += 1 };
-            return Ok(());
-        }
+impl HandlerEntry for LoadAccessHandlerEntry {
+    fn get_range(&self) -> (usize, usize) {
+        (self.target_address, self.range)
+    }
+
+    fn get_priority(&self) -> MemoryHookPriority {
+        self.priority
+    }
+
+    fn is_same_handler(&self, other: &Self) -> bool {
+        self.handler as usize == other.handler as usize && self.context == other.context
     }
-    return Err(());
 }
 
-pub fn add_memory_store_hook_handler(entry: StoreAccessHandlerEntry) -> Result<(), ()> {
-    for e in unsafe { &mut STORE_HANDLER_LIST } {
-        if e.is_none() {
-            *e = Some(entry);
-            unsafe { NUM_OF_STORE_HANDLER_ENABLED_ENTRIES += 1 };
-            return Ok(());
+impl HandlerEntry for StoreAccessHandlerEntry {
+    fn get_range(&self) -> (usize, usize) {
+        (self.target_address, self.range)
+    }
+
+    fn get_priority(&self) -> MemoryHookPriority {
+        self.priority
+    }
+
+    fn is_same_handler(&self, other: &Self) -> bool {
+        self.handler as usize == other.handler as usize && self.context == other.context
+    }
+}
+
+#[derive(Clone, Copy)]
+struct HandlerTableEntry<T: HandlerEntry> {
+    entry: T,
+    /// Registration order, used to decide the order of the handlers with same priority
+    sequence: usize,
+    /// The largest end address of the entries in the implicit subtree rooted at this entry
+    subtree_end_address: usize,
+}
+
+impl<T: HandlerEntry> HandlerTableEntry<T> {
+    /// The key to decide the calling order, the entry having the larger key is called earlier
+    fn get_order_key(&self) -> (MemoryHookPriority, usize) {
+        (self.entry.get_priority(), usize::MAX - self.sequence)
+    }
+
+    fn get_start_address(&self) -> usize {
+        self.entry.get_range().0
+    }
+
+    fn get_end_address(&self) -> usize {
+        let (target_address, range) = self.entry.get_range();
+        target_address + range
+    }
+}
+
+/// Update [`HandlerTableEntry::subtree_end_address`] of `entries`
+///
+/// # Result
+/// Returns the largest end address of `entries`, or 0 if `entries` is empty.
+fn update_subtree_end_address<T: HandlerEntry>(entries: &mut [HandlerTableEntry<T>]) -> usize {
+    if entries.is_empty() {
+        return 0;
+    }
+    let middle = entries.len() / 2;
+    let (left, right) = entries.split_at_mut(middle);
+    let (root, right) = right.split_first_mut().unwrap();
+    let end_address = root
+        .get_end_address()
+        .max(update_subtree_end_address(left))
+        .max(update_subtree_end_address(right));
+    root.subtree_end_address = end_address;
+    return end_address;
+}
+
+/// Search the entry containing `address` with the largest order key less than `previous_key`
+///
+/// `result` is updated when a better entry is found in `entries`.
+fn search_next_entry<'a, T: HandlerEntry>(
+    entries: &'a [HandlerTableEntry<T>],
+    address: usize,
+    previous_key: Option<(MemoryHookPriority, usize)>,
+    result: &mut Option<&'a HandlerTableEntry<T>>,
+) {
+    if entries.is_empty() {
+        return;
+    }
+    let middle = entries.len() / 2;
+    let root = &entries[middle];
+    if root.subtree_end_address <= address {
+        /* No entry in this subtree contains `address` */
+        return;
+    }
+    search_next_entry(&entries[..middle], address, previous_key, result);
+    if root.get_start_address() > address {
+        /* The entries on the right start after `address` */
+        return;
+    }
+    if root.get_end_address() > address {
+        let key = root.get_order_key();
+        if previous_key.map(|p| key < p).unwrap_or(true)
+            && result.map(|r| r.get_order_key() < key).unwrap_or(true)
+        {
+            *result = Some(root);
         }
     }
-    return Err(());
+    search_next_entry(&entries[(middle + 1)..], address, previous_key, result);
 }
 
-pub fn remove_memory_load_hook_handler(entry: LoadAccessHandlerEntry) -> Result<(), ()> {
-    for e in unsafe { &mut LOAD_HANDLER_LIST } {
-        if let Some(t_e) = e {
-            if t_e.target_address == entry.target_address && t_e.range == entry.range {
-                *e = None;
-                unsafe { NUM_OF_LOAD_HANDLER_ENABLED_ENTRIES -= 1 };
-                return Ok(());
+/// Address-sorted handler table
+///
+/// The entries are stored in the pages allocated by [`allocate_memory`],
+/// and the table is reallocated with doubled size when it becomes full.
+struct HandlerTable<T: HandlerEntry> {
+    lock: SpinLockFlag,
+    table_address: usize,
+    number_of_entries: usize,
+    number_of_pages: usize,
+    next_sequence: usize,
+    _phantom: core::marker::PhantomData<T>,
+}
+
+impl<T: HandlerEntry> HandlerTable<T> {
+    const fn new() -> Self {
+        Self {
+            lock: SpinLockFlag::new(),
+            table_address: 0,
+            number_of_entries: 0,
+            number_of_pages: 0,
+            next_sequence: 0,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    fn get_entries(&self) -> &[HandlerTableEntry<T>] {
+        if self.table_address == 0 {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(
+                self.table_address as *const HandlerTableEntry<T>,
+                self.number_of_entries,
+            )
+        }
+    }
+
+    fn get_entries_mut(&mut self) -> &mut [HandlerTableEntry<T>] {
+        if self.table_address == 0 {
+            return &mut [];
+        }
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.table_address as *mut HandlerTableEntry<T>,
+                self.number_of_entries,
+            )
+        }
+    }
+
+    fn get_max_number_of_entries(&self) -> usize {
+        (self.number_of_pages << PAGE_SHIFT) / size_of::<HandlerTableEntry<T>>()
+    }
+
+    /// Expand the table to store one more entry
+    ///
+    /// The lock must be acquired.
+    fn expand(&mut self) -> Result<(), ()> {
+        let new_number_of_pages = if self.number_of_pages == 0 {
+            1
+        } else {
+            self.number_of_pages << 1
+        };
+        let new_table_address = match allocate_memory(new_number_of_pages, None) {
+            Ok(a) => a,
+            Err(e) => {
+                println!(
+                    "Failed to allocate memory for the memory hook table: {:?}",
+                    e
+                );
+                return Err(());
+            }
+        };
+        if self.table_address != 0 {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.table_address as *const HandlerTableEntry<T>,
+                    new_table_address as *mut HandlerTableEntry<T>,
+                    self.number_of_entries,
+                )
+            };
+            if let Err(e) = free_memory(self.table_address, self.number_of_pages) {
+                println!("Failed to free the old memory hook table: {:?}", e);
             }
         }
+        self.table_address = new_table_address;
+        self.number_of_pages = new_number_of_pages;
+        return Ok(());
     }
-    return Err(());
-}
 
-pub fn remove_memory_store_hook_handler(entry: StoreAccessHandlerEntry) -> Result<(), ()> {
-    for e in unsafe { &mut STORE_HANDLER_LIST } {
-        if let Some(t_e) = e {
-            if t_e.target_address == entry.target_address && t_e.range == entry.range {
-                *e = None;
-                unsafe { NUM_OF_STORE_HANDLER_ENABLED_ENTRIES -= 1 };
+    fn add(&mut self, entry: T) -> Result<(), ()> {
+        let (target_address, range) = entry.get_range();
+        if range == 0 {
+            return Err(());
+        }
+        self.lock.lock();
+        if self.number_of_entries == self.get_max_number_of_entries() && self.expand().is_err() {
+            self.lock.unlock();
+            return Err(());
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.number_of_entries += 1;
+        let entries = self.get_entries_mut();
+        let last_index = entries.len() - 1;
+        let index =
+            entries[..last_index].partition_point(|e| e.get_start_address() <= target_address);
+        entries.copy_within(index..last_index, index + 1);
+        entries[index] = HandlerTableEntry {
+            entry,
+            sequence,
+            subtree_end_address: 0,
+        };
+        update_subtree_end_address(entries);
+        self.lock.unlock();
+        return Ok(());
+    }
+
+    /// Remove the entry which has the same range, handler, and context as `entry`
+    fn remove(&mut self, entry: T) -> Result<(), ()> {
+        let (target_address, range) = entry.get_range();
+        self.lock.lock();
+        let entries = self.get_entries_mut();
+        let mut index = entries.partition_point(|e| e.get_start_address() < target_address);
+        while index < entries.len() && entries[index].get_start_address() == target_address {
+            if entries[index].entry.get_range().1 == range
+                && entries[index].entry.is_same_handler(&entry)
+            {
+                entries.copy_within((index + 1).., index);
+                self.number_of_entries -= 1;
+                update_subtree_end_address(self.get_entries_mut());
+                self.lock.unlock();
                 return Ok(());
             }
+            index += 1;
         }
+        self.lock.unlock();
+        return Err(());
+    }
+
+    /// Find the entry to call next
+    ///
+    /// This returns the entry which contains `address` and should be called just after
+    /// the entry having `previous_key`. (If `previous_key` is None, the first entry)
+    /// The entry is copied to allow the handler to modify the table.
+    fn find_next(
+        &self,
+        address: usize,
+        previous_key: Option<(MemoryHookPriority, usize)>,
+    ) -> Option<(T, (MemoryHookPriority, usize))> {
+        self.lock.lock();
+        let mut result: Option<&HandlerTableEntry<T>> = None;
+        search_next_entry(self.get_entries(), address, previous_key, &mut result);
+        let result = result.map(|e| (e.entry, e.get_order_key()));
+        self.lock.unlock();
+        return result;
     }
-    return Err(());
 }
 
+static mut LOAD_HANDLER_TABLE: HandlerTable<LoadAccessHandlerEntry> = HandlerTable::new();
+static mut STORE_HANDLER_TABLE: HandlerTable<StoreAccessHandlerEntry> = HandlerTable::new();
+
+/// Register the load handler
+///
+/// # Result
+/// If memory allocation failed or the range of `entry` is empty, this returns Err(()).
+pub fn add_memory_load_hook_handler(entry: LoadAccessHandlerEntry) -> Result<(), ()> {
+    unsafe { LOAD_HANDLER_TABLE.add(entry) }
+}
+
+/// Register the store handler
+///
+/// # Result
+/// If memory allocation failed or the range of `entry` is empty, this returns Err(()).
+pub fn add_memory_store_hook_handler(entry: StoreAccessHandlerEntry) -> Result<(), ()> {
+    unsafe { STORE_HANDLER_TABLE.add(entry) }
+}
+
+/// Remove the load handler registered with same target address, range, handler, and context as `entry`
+pub fn remove_memory_load_hook_handler(entry: LoadAccessHandlerEntry) -> Result<(), ()> {
+    unsafe { LOAD_HANDLER_TABLE.remove(entry) }
+}
+
+/// Remove the store handler registered with same target address, range, handler, and context as `entry`
+pub fn remove_memory_store_hook_handler(entry: StoreAccessHandlerEntry) -> Result<(), ()> {
+    unsafe { STORE_HANDLER_TABLE.remove(entry) }
+}
+
+/// Call the load handlers hooking `accessing_memory_address`
+///
+/// # Result
+/// This never returns `LoadHookResult::Continue`.
 pub fn memory_load_hook_handler(
     accessing_memory_address: usize,
     stored_registers: &mut StoredRegisters,
@@ -149,52 +481,48 @@ pub fn memory_load_hook_handler(
     is_64bit_register: bool,
     is_sign_extend_required: bool,
 ) -> Result<LoadHookResult, ()> {
-    let mut num_of_check_entries = 0;
-    for e in unsafe { &LOAD_HANDLER_LIST } {
-        if let Some(handler_entry) = e {
-            if (handler_entry.target_address..(handler_entry.target_address + handler_entry.range))
-                .contains(&accessing_memory_address)
-            {
-                return (handler_entry.handler)(
-                    accessing_memory_address,
-                    stored_registers,
-                    access_size,
-                    is_64bit_register,
-                    is_sign_extend_required,
-                );
-            }
-            num_of_check_entries += 1;
-            if num_of_check_entries == unsafe { NUM_OF_LOAD_HANDLER_ENABLED_ENTRIES } {
-                break;
-            }
+    let mut previous_key = None;
+    while let Some((handler_entry, key)) =
+        unsafe { LOAD_HANDLER_TABLE.find_next(accessing_memory_address, previous_key) }
+    {
+        match (handler_entry.handler)(
+            accessing_memory_address,
+            stored_registers,
+            access_size,
+            is_64bit_register,
+            is_sign_extend_required,
+            handler_entry.context,
+        )? {
+            LoadHookResult::Continue => previous_key = Some(key),
+            r => return Ok(r),
         }
     }
     return Ok(LoadHookResult::PassThrough);
 }
 
+/// Call the store handlers hooking `accessing_memory_address`
+///
+/// # Result
+/// This never returns `StoreHookResult::Continue`.
 pub fn memory_store_hook_handler(
     accessing_memory_address: usize,
     stored_registers: &mut StoredRegisters,
     access_size: u8,
     data: u64,
 ) -> Result<StoreHookResult, ()> {
-    let mut num_of_check_entries = 0;
-    for e in unsafe { &STORE_HANDLER_LIST } {
-        if let Some(handler_entry) = e {
-            if (handler_entry.target_address..(handler_entry.target_address + handler_entry.range))
-                .contains(&accessing_memory_address)
-            {
-                return (handler_entry.handler)(
-                    accessing_memory_address,
-                    stored_registers,
-                    access_size,
-                    data,
-                );
-            }
-            num_of_check_entries += 1;
-            if num_of_check_entries == unsafe { NUM_OF_STORE_HANDLER_ENABLED_ENTRIES } {
-                break;
-            }
+    let mut previous_key = None;
+    while let Some((handler_entry, key)) =
+        unsafe { STORE_HANDLER_TABLE.find_next(accessing_memory_address, previous_key) }
+    {
+        match (handler_entry.handler)(
+            accessing_memory_address,
+            stored_registers,
+            access_size,
+            data,
+            handler_entry.context,
+        )? {
+            StoreHookResult::Continue => previous_key = Some(key),
+            r => return Ok(r),
         }
     }
     return Ok(StoreHookResult::PassThrough);
@@ -8,17 +8,81 @@
 //!
 //! MultiCore Handling Functions
 //!
+//! The power state of each CPU is tracked by the CPU list made from GICC structures of MADT,
+//! or from the cpu nodes of DTB if ACPI is not available.
+//! If neither is available, the list is empty and the requests are answered by the firmware.
+//!
 
 use crate::psci::{call_psci_function, PsciFunctionId, PsciReturnCode};
 use crate::{allocate_memory, free_memory, StoredRegisters};
 
+use common::acpi::{get_acpi_table, madt::MADT};
+use common::cpu::{CTR_EL0_DMINLINE, CTR_EL0_DMINLINE_BITS_OFFSET};
+use common::paging::page_align_up;
 use common::{cpu, PAGE_SHIFT, STACK_PAGES};
 
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
-pub static NUMBER_OF_RUNNING_AP: AtomicU64 = AtomicU64::new(0);
+static NUMBER_OF_RUNNING_AP: AtomicU64 = AtomicU64::new(0);
 pub static STACK_TO_FREE_LATER: AtomicUsize = AtomicUsize::new(0);
 
+/// Aff3, Aff2, Aff1, and Aff0 of MPIDR_EL1
+const MPIDR_AFFINITY_MASK: u64 = 0xFF_00FF_FFFF;
+const MAX_AFFINITY_LEVEL: u64 = 3;
+
+/// The power state of the CPU
+///
+/// On, Off, and OnPending have the same values as the result of AFFINITY_INFO.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CpuState {
+    On = 0,
+    Off = 1,
+    OnPending = 2,
+    /// CPU_OFF is called, but the firmware may not have completed it yet
+    OffPending = 3,
+}
+
+impl From<u8> for CpuState {
+    fn from(s: u8) -> Self {
+        match s {
+            0 => CpuState::On,
+            1 => CpuState::Off,
+            2 => CpuState::OnPending,
+            _ => CpuState::OffPending,
+        }
+    }
+}
+
+pub struct CpuEntry {
+    mpidr: u64,
+    state: AtomicU8,
+}
+
+impl CpuEntry {
+    pub fn get_state(&self) -> CpuState {
+        CpuState::from(self.state.load(Ordering::Acquire))
+    }
+
+    /// Change the state from `current` to `new`
+    ///
+    /// # Result
+    /// If the state was not `current`, returns Err(the actual state)
+    fn change_state(&self, current: CpuState, new: CpuState) -> Result<(), CpuState> {
+        self.state
+            .compare_exchange(
+                current as u8,
+                new as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map(|_| ())
+            .map_err(CpuState::from)
+    }
+}
+
+static mut CPU_LIST: &[CpuEntry] = &[];
+
 #[repr(C, align(16))]
 #[derive(Debug)]
 struct HypervisorRegisters {
@@ -35,6 +99,8 @@ struct HypervisorRegisters {
     vbar_el2: u64,
     el1_entry_point: u64,
     el1_context_id: u64,
+    /// The real address of [`CpuEntry::state`] of the booting CPU, set to On by [`cpu_boot`]
+    cpu_state_address: u64,
     complete_flag: AtomicU64,
 }
 
@@ -52,10 +118,197 @@ static mut REGISTER_BUFFER: HypervisorRegisters = HypervisorRegisters {
     vbar_el2: 0,
     el1_entry_point: 0,
     el1_context_id: 0,
+    cpu_state_address: 0,
     complete_flag: AtomicU64::new(1),
 };
 
+/// Make the CPU list from MADT
+///
+/// This must be called before the guest starts the APs.
+///
+/// # Arguments
+/// * `acpi_address` - The address of RSDP
+pub fn init_cpu_list_by_madt(acpi_address: usize) {
+    let Ok(table) = get_acpi_table(acpi_address, b"APIC") else {
+        println!("MADT is not found, the CPU states will not be tracked.");
+        return;
+    };
+    let table = unsafe { &*(table as *const MADT) };
+    init_cpu_list(table.get_gic_list().map(|e| e.mpidr));
+}
+
+/// Make the CPU list from the list made by hypervisor_bootloader from DTB
+///
+/// This must be called before the guest starts the APs.
+///
+/// # Arguments
+/// * `mpidr_list` - [`common::SystemInformation::dtb_cpu_list`]
+pub fn init_cpu_list_by_dtb(mpidr_list: &[u64]) {
+    init_cpu_list(mpidr_list.iter().copied());
+}
+
+/// Make the CPU list
+///
+/// The current CPU is registered as On and the others are registered as Off.
+///
+/// # Arguments
+/// * `mpidr_list` - MPIDR_EL1 of all CPUs
+fn init_cpu_list(mpidr_list: impl Iterator<Item = u64> + Clone) {
+    let number_of_cpus = mpidr_list.clone().count();
+    let pages =
+        page_align_up((number_of_cpus * core::mem::size_of::<CpuEntry>()).max(1)) >> PAGE_SHIFT;
+    let address = allocate_memory(pages, None).expect("Failed to allocate memory for CPU list");
+    let current_mpidr = cpu::get_mpidr_el1() & MPIDR_AFFINITY_MASK;
+
+    for (i, mpidr) in mpidr_list.enumerate() {
+        let mpidr = mpidr & MPIDR_AFFINITY_MASK;
+        let state = if mpidr == current_mpidr {
+            CpuState::On
+        } else {
+            CpuState::Off
+        };
+        unsafe {
+            core::ptr::write(
+                (address as *mut CpuEntry).add(i),
+                CpuEntry {
+                    mpidr,
+                    state: AtomicU8::new(state as u8),
+                },
+            )
+        };
+    }
+    unsafe { CPU_LIST = core::slice::from_raw_parts(address as *const CpuEntry, number_of_cpus) };
+    pr_debug!("Number of CPUs: {}", number_of_cpus);
+}
+
+/// Get the list of CPUs enabled in MADT or DTB
+///
+/// If neither MADT nor DTB is available, the list is empty.
+pub fn get_cpu_list() -> &'static [CpuEntry] {
+    unsafe { CPU_LIST }
+}
+
+fn find_cpu(mpidr: u64) -> Option<&'static CpuEntry> {
+    get_cpu_list()
+        .iter()
+        .find(|c| c.mpidr == (mpidr & MPIDR_AFFINITY_MASK))
+}
+
+/// Get the real address of the state of `mpidr` for [`HypervisorRegisters::cpu_state_address`]
+fn get_cpu_state_real_address(mpidr: u64) -> u64 {
+    find_cpu(mpidr)
+        .map(|c| {
+            cpu::convert_virtual_address_to_physical_address_el2_read(&c.state as *const _ as usize)
+                .expect("Failed to convert virtual address to real address") as u64
+        })
+        .unwrap_or(0)
+}
+
+/// Check if any CPU except the current one is running
+///
+/// The CPUs which called CPU_OFF are treated as stopped.
+pub fn is_any_ap_running() -> bool {
+    let cpu_list = get_cpu_list();
+    if cpu_list.is_empty() {
+        return NUMBER_OF_RUNNING_AP.load(Ordering::Relaxed) != 0;
+    }
+    let current_mpidr = cpu::get_mpidr_el1() & MPIDR_AFFINITY_MASK;
+    cpu_list.iter().any(|c| {
+        c.mpidr != current_mpidr
+            && (c.get_state() == CpuState::On || c.get_state() == CpuState::OnPending)
+    })
+}
+
+/// Answer AFFINITY_INFO from the CPU list
+///
+/// The state of the CPUs in [`CpuState::OffPending`] is confirmed by the firmware.
+///
+/// # Arguments
+/// * `target_affinity` - The affinity passed by the guest
+/// * `lowest_affinity_level` - The affinity level passed by the guest
+///
+/// # Result
+/// If the CPU list is not available, returns None and the call must be forwarded to the firmware.
+/// Otherwise, returns the value to set into x0 of the guest.
+pub fn get_affinity_info(target_affinity: u64, lowest_affinity_level: u64) -> Option<u64> {
+    let cpu_list = get_cpu_list();
+    if cpu_list.is_empty() {
+        return None;
+    }
+    if lowest_affinity_level > MAX_AFFINITY_LEVEL {
+        return Some(PsciReturnCode::InvalidParameters as i64 as u64);
+    }
+    /* Aff3 is placed at bits[39:32] */
+    let ignored_bits = if lowest_affinity_level == MAX_AFFINITY_LEVEL {
+        0xFF_FFFF
+    } else {
+        (1u64 << (lowest_affinity_level * 8)) - 1
+    };
+    let mask = MPIDR_AFFINITY_MASK & !ignored_bits;
+
+    let mut result: Option<CpuState> = None;
+    for c in cpu_list
+        .iter()
+        .filter(|c| (c.mpidr & mask) == (target_affinity & mask))
+    {
+        let mut state = c.get_state();
+        if state == CpuState::OffPending {
+            let firmware_state =
+                call_psci_function(PsciFunctionId::AffinityInfo, c.mpidr, 0, 0) as i32;
+            if firmware_state == CpuState::Off as i32
+                && c.change_state(CpuState::OffPending, CpuState::Off).is_ok()
+            {
+                state = CpuState::Off;
+            } else {
+                state = c.get_state();
+            }
+        }
+        result = match (result, state) {
+            (_, CpuState::On | CpuState::OffPending) | (Some(CpuState::On), _) => {
+                Some(CpuState::On)
+            }
+            (_, CpuState::OnPending) | (Some(CpuState::OnPending), _) => Some(CpuState::OnPending),
+            _ => Some(CpuState::Off),
+        };
+    }
+    Some(match result {
+        Some(s) => s as i64 as u64,
+        None => PsciReturnCode::InvalidParameters as i64 as u64,
+    })
+}
+
 pub fn setup_new_cpu(regs: &mut StoredRegisters) {
+    let target_cpu = find_cpu(regs.x1);
+    let mut previous_state = CpuState::Off;
+    if let Some(target_cpu) = target_cpu {
+        /* Mark as OnPending to prevent other CPUs from calling CPU_ON for the same CPU */
+        loop {
+            match target_cpu.get_state() {
+                CpuState::On => {
+                    regs.x0 = PsciReturnCode::AlreadyOn as i64 as u64;
+                    return;
+                }
+                CpuState::OnPending => {
+                    regs.x0 = PsciReturnCode::OnPending as i64 as u64;
+                    return;
+                }
+                s => {
+                    if target_cpu.change_state(s, CpuState::OnPending).is_ok() {
+                        previous_state = s;
+                        break;
+                    }
+                }
+            }
+        }
+    } else if !get_cpu_list().is_empty() {
+        println!(
+            "The cpu (MPIDR: {:#X}) is not found in the CPU list.",
+            regs.x1
+        );
+        regs.x0 = PsciReturnCode::InvalidParameters as i64 as u64;
+        return;
+    }
+
     let stack_address = (allocate_memory(STACK_PAGES, Some(STACK_PAGES))
         .expect("Failed to allocate stack")
         + (STACK_PAGES << PAGE_SHIFT)) as u64;
@@ -69,6 +322,7 @@ pub fn setup_new_cpu(regs: &mut StoredRegisters) {
     let vtcr_el2 = cpu::get_vtcr_el2();
     let sctlr_el2 = cpu::get_sctlr_el2();
     let vbar_el2 = cpu::get_vbar_el2();
+    let cpu_state_address = get_cpu_state_real_address(regs.x1);
 
     /* Aquire REGISTER_BUFFER's lock */
     loop {
@@ -103,6 +357,7 @@ pub fn setup_new_cpu(regs: &mut StoredRegisters) {
         REGISTER_BUFFER.vbar_el2 = vbar_el2;
         REGISTER_BUFFER.el1_entry_point = regs.x2;
         REGISTER_BUFFER.el1_context_id = regs.x3;
+        REGISTER_BUFFER.cpu_state_address = cpu_state_address;
     }
 
     let hypervisor_registers_real_address =
@@ -130,6 +385,9 @@ pub fn setup_new_cpu(regs: &mut StoredRegisters) {
             println!("Failed to free memory: {:?}", err);
         }
         unsafe { REGISTER_BUFFER.complete_flag.store(1, Ordering::Release) };
+        if let Some(target_cpu) = target_cpu {
+            let _ = target_cpu.change_state(CpuState::OnPending, previous_state);
+        }
         println!(
             "Failed to power on the cpu (MPIDR: {:#X}): {:?}",
             regs.x1,
@@ -176,10 +434,97 @@ pub fn power_off_cpu() -> i32 {
     }
 
     NUMBER_OF_RUNNING_AP.fetch_sub(1, Ordering::SeqCst);
+    if let Some(c) = find_cpu(cpu::get_mpidr_el1()) {
+        c.state.store(CpuState::OffPending as u8, Ordering::Release);
+    }
     call_psci_function(PsciFunctionId::CpuOff, 0, 0, 0) as i32
 }
 
+/// Suspend the current CPU with the resume entry point of the hypervisor
+///
+/// CPU_SUSPEND and CPU_DEFAULT_SUSPEND may power down the CPU, and then the firmware resumes it
+/// from the given entry point at EL2 with the system registers of EL2 reset.
+/// Therefore, this passes [`cpu_boot`] as the entry point instead of the guest's one,
+/// and it re-establishes the registers of the hypervisor before jumping to the guest's entry point.
+/// For the standby states, the entry point is ignored and the call returns normally.
+///
+/// # Arguments
+/// * `function_id` - [`PsciFunctionId::CpuSuspend`] or [`PsciFunctionId::CpuDefaultSuspend`]
+/// * `regs` - The registers of the guest stored by the exception vector, x0 is set to the result
+pub fn suspend_cpu(function_id: PsciFunctionId, regs: &mut StoredRegisters) {
+    let (el1_entry_point, el1_context_id) = if function_id == PsciFunctionId::CpuSuspend {
+        (regs.x2, regs.x3)
+    } else {
+        (regs.x1, regs.x2)
+    };
+    /* Resume with the stack pointer at the exception entry, the handlers' frames are discarded */
+    let stack_address =
+        (regs as *const _ as usize + core::mem::size_of::<StoredRegisters>()) as u64;
+
+    /* Each CPU has its own buffer on its stack, it is kept while the CPU is suspended */
+    let register_buffer = HypervisorRegisters {
+        stack_address,
+        cnthctl_el2: cpu::get_cnthctl_el2(),
+        cptr_el2: cpu::get_cptr_el2(),
+        hcr_el2: cpu::get_hcr_el2(),
+        vttbr_el2: cpu::get_vttbr_el2(),
+        ttbr0_el2: cpu::get_ttbr0_el2(),
+        mair_el2: cpu::get_mair_el2(),
+        tcr_el2: cpu::get_tcr_el2(),
+        vtcr_el2: cpu::get_vtcr_el2(),
+        sctlr_el2: cpu::get_sctlr_el2(),
+        vbar_el2: cpu::get_vbar_el2(),
+        el1_entry_point,
+        el1_context_id,
+        cpu_state_address: get_cpu_state_real_address(cpu::get_mpidr_el1()),
+        complete_flag: AtomicU64::new(0),
+    };
+
+    /* cpu_boot reads the buffer with the MMU disabled */
+    let buffer_address = &register_buffer as *const _ as usize;
+    let cache_line_size =
+        4usize << ((cpu::get_ctr_el0() & CTR_EL0_DMINLINE) >> CTR_EL0_DMINLINE_BITS_OFFSET);
+    let mut line_address = buffer_address & !(cache_line_size - 1);
+    while line_address < buffer_address + core::mem::size_of::<HypervisorRegisters>() {
+        cpu::clean_data_cache(line_address);
+        line_address += cache_line_size;
+    }
+    cpu::dsb();
+
+    let hypervisor_registers_real_address =
+        cpu::convert_virtual_address_to_physical_address_el2_read(buffer_address)
+            .expect("Failed to convert virtual address to real address") as u64;
+    let cpu_boot_address_real_address =
+        cpu::convert_virtual_address_to_physical_address_el2_read(cpu_boot as *const fn() as usize)
+            .expect("Failed to convert virtual address to real address") as u64;
+
+    regs.x0 = if function_id == PsciFunctionId::CpuSuspend {
+        call_psci_function(
+            function_id,
+            regs.x1,
+            cpu_boot_address_real_address,
+            hypervisor_registers_real_address,
+        )
+    } else {
+        call_psci_function(
+            function_id,
+            cpu_boot_address_real_address,
+            hypervisor_registers_real_address,
+            0,
+        )
+    };
+    /* Reached only if the CPU was not powered down */
+    if regs.x0 as i32 != PsciReturnCode::Success as i32 {
+        pr_debug!(
+            "Failed to suspend the cpu (MPIDR: {:#X}): {:?}",
+            cpu::get_mpidr_el1(),
+            PsciReturnCode::try_from(regs.x0 as i32)
+        );
+    }
+}
+
 /* cpu_boot must use position-relative code */
+/* cpu_boot is also used as the resume entry point of CPU_SUSPEND, see suspend_cpu */
 #[naked]
 extern "C" fn cpu_boot() {
     unsafe {
@@ -224,8 +569,8 @@ extern "C" fn cpu_boot() {
                     ldp x9,  x10, [x0, 16 * 4]
                     ldp x11, x12, [x0, 16 * 5]
                     ldp x13, x14, [x0, 16 * 6]
-                    mov x14, x0
-                    add x14, x14, 8 * 13
+                    mov x15, x0
+                    add x15, x15, 8 * 14
 
                     mov sp, x1         
                     msr cnthctl_el2, x2
@@ -245,11 +590,16 @@ extern "C" fn cpu_boot() {
                     mov x0, x13
                     dsb sy
                     isb
-                    str x14, [x14]
+                    cbz x14, 4f
+                    mov x16, {CPU_STATE_ON}
+                    stlrb w16, [x14]
+4:
+                    str x15, [x15]
                     isb
                     eret
                     ",  MAX_ZCR_EL2_LEN = const cpu::MAX_ZCR_EL2_LEN,
                         A64FX = const cfg!(feature = "a64fx") as u64,
+                        CPU_STATE_ON = const CpuState::On as u8,
                         options(noreturn))
     }
 }
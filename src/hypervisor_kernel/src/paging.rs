@@ -15,82 +15,10 @@ use common::cpu::*;
 use common::paging::*;
 use common::{PAGE_SHIFT, PAGE_SIZE, STAGE_2_PAGE_SHIFT, STAGE_2_PAGE_SIZE};
 
-fn _remake_stage2_page_table(
-    table_address: usize,
-    physical_address: &mut usize,
-    table_level: i8,
-    number_of_tables: usize,
-    vtcr_el2_t0sz: u8,
-) -> Result<(), ()> {
-    let page_table = unsafe {
-        core::slice::from_raw_parts_mut(
-            table_address as *mut u64,
-            (PAGE_TABLE_SIZE * number_of_tables) / core::mem::size_of::<u64>(),
-        )
-    };
-    let shift_level = table_level_to_table_shift(STAGE_2_PAGE_SHIFT, table_level);
-    if table_level >= 1 {
-        for e in page_table {
-            let attribute = create_attributes_for_stage_2(
-                (1 << MEMORY_PERMISSION_EXECUTABLE_BIT)
-                    | (1 << MEMORY_PERMISSION_WRITABLE_BIT)
-                    | (1 << MEMORY_PERMISSION_READABLE_BIT),
-                false,
-                false,
-                true,
-            );
-            *e = (*physical_address as u64) | attribute;
-            *physical_address += 1 << shift_level;
-        }
-    } else {
-        for e in page_table {
-            let next_table_address =
-                allocate_page_table_for_stage_2(table_level + 1, vtcr_el2_t0sz, false, 1)?;
-            _remake_stage2_page_table(
-                next_table_address,
-                physical_address,
-                table_level + 1,
-                1,
-                vtcr_el2_t0sz,
-            )?;
-            *e = (next_table_address as u64) | 0b11;
-        }
-    }
-    return Ok(());
-}
-
-pub fn remake_stage2_page_table() -> Result<usize, ()> {
-    let vtcr_el2 = get_vtcr_el2();
-    let vtcr_el2_sl0 = ((vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET) as u8;
-    let vtcr_el2_sl2 = ((vtcr_el2 & VTCR_EL2_SL2) >> VTCR_EL2_SL2_BIT_OFFSET) as u8;
-    let vtcr_el2_t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
-    let initial_look_up_level: i8 = match (vtcr_el2_sl0, vtcr_el2_sl2) {
-        (0b01u8, 0b0u8) => 1,
-        (0b10u8, 0b0u8) => 0,
-        (0b00u8, 0b1u8) => -1,
-        _ => unreachable!(),
-    };
-    let number_of_tables =
-        calculate_number_of_concatenated_page_tables(vtcr_el2_t0sz, initial_look_up_level) as usize;
-
-    let mut physical_address = 0;
-    let table_address = allocate_page_table_for_stage_2(
-        initial_look_up_level,
-        vtcr_el2_t0sz,
-        true,
-        number_of_tables as u8,
-    )?;
-
-    _remake_stage2_page_table(
-        table_address,
-        &mut physical_address,
-        initial_look_up_level,
-        number_of_tables,
-        vtcr_el2_t0sz,
-    )?;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-    return Ok(table_address);
-}
+/// The number of the stage 2 descriptors invalidated temporarily by [`replace_stage2_descriptor`]
+static NUMBER_OF_BREAKING_DESCRIPTORS: AtomicUsize = AtomicUsize::new(0);
 
 /// Map physical address recursively
 ///
@@ -420,7 +348,8 @@ fn map_address_recursive_stage2(
                 table_level
             );
 
-            let old_descriptor = core::mem::replace(
+            let old_descriptor = *target_descriptor;
+            replace_stage2_descriptor(
                 target_descriptor,
                 *physical_address as u64
                     | create_attributes_for_stage_2(permission, is_dummy_page, is_unmap, true),
@@ -429,7 +358,7 @@ fn map_address_recursive_stage2(
             if is_descriptor_table_or_level_3_descriptor(old_descriptor) {
                 let old_table = extract_output_address(old_descriptor, STAGE_2_PAGE_SHIFT);
                 pr_debug!("PageTable:({:#X}) will be deleted.", old_table);
-                let _ = free_memory(old_table, 1);
+                free_stage2_table_tree(old_table, table_level + 1);
             }
 
             *physical_address += 1 << shift_level;
@@ -500,7 +429,24 @@ fn map_address_recursive_stage2(
                 is_dummy_page,
             )?;
             if let Some(d) = created_entry {
-                *target_descriptor = d;
+                replace_stage2_descriptor(target_descriptor, d);
+            }
+            if table_level >= 1 {
+                let next_table_address =
+                    extract_output_address(*target_descriptor, STAGE_2_PAGE_SHIFT);
+                if let Some(block_descriptor) =
+                    try_to_merge_stage2_table(next_table_address, table_level + 1)
+                {
+                    pr_debug!(
+                        "Merge the table({:#X}) into the block descriptor({:#b})",
+                        next_table_address,
+                        block_descriptor
+                    );
+                    replace_stage2_descriptor(target_descriptor, block_descriptor);
+                    if let Err(err) = free_memory(next_table_address, 1) {
+                        println!("Failed to free the page table: {:?}", err);
+                    }
+                }
             }
         }
         table_index += 1;
@@ -508,6 +454,316 @@ fn map_address_recursive_stage2(
     return Ok(());
 }
 
+/// Replace the stage 2 descriptor by break-before-make
+///
+/// If `descriptor` is valid, it is invalidated and the TLB entries of the guest are flushed
+/// before writing `new_descriptor`. Therefore, the CPUs never walk the old table after this
+/// function returns, and the table pointed by the old descriptor can be freed.
+///
+/// # Arguments
+/// * `descriptor` - The descriptor in the current stage 2 table
+/// * `new_descriptor` - The descriptor to write
+fn replace_stage2_descriptor(descriptor: &mut u64, new_descriptor: u64) {
+    if (*descriptor & 0b01) != 0 {
+        NUMBER_OF_BREAKING_DESCRIPTORS.fetch_add(1, Ordering::AcqRel);
+        unsafe { core::ptr::write_volatile(descriptor, 0) };
+        flush_tlb_vmalls12e1is();
+        unsafe { core::ptr::write_volatile(descriptor, new_descriptor) };
+        dsb();
+        NUMBER_OF_BREAKING_DESCRIPTORS.fetch_sub(1, Ordering::AcqRel);
+        return;
+    }
+    unsafe { core::ptr::write_volatile(descriptor, new_descriptor) };
+}
+
+/// Check if the stage 2 translation fault was caused by [`replace_stage2_descriptor`]
+///
+/// The other CPUs may access the area while its descriptor is invalidated temporarily.
+/// This function waits until all descriptors being replaced are written, and then
+/// checks if the fault address is mapped. If it is mapped, the guest can retry the access.
+///
+/// # Arguments
+/// * `esr_el2` - ESR_EL2 of the data abort or the instruction abort from EL1/EL0
+/// * `hpfar_el2` - HPFAR_EL2 of the abort
+///
+/// # Result
+/// If the abort is the stage 2 translation fault and the address is mapped now, returns true
+pub fn is_stage2_translation_fault_resolved(esr_el2: u64, hpfar_el2: u64) -> bool {
+    if (esr_el2 & ESR_EL2_ISS_FSC & !0b11) != ESR_EL2_ISS_FSC_TRANSLATION_FAULT {
+        return false;
+    }
+    while NUMBER_OF_BREAKING_DESCRIPTORS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    let fault_address = convert_hpfar_el2_to_ipa(hpfar_el2);
+    let mut is_mapped = false;
+    walk_stage2_leaf_descriptors(fault_address, STAGE_2_PAGE_SIZE, &mut |_, _, _| {
+        is_mapped = true
+    });
+    return is_mapped;
+}
+
+/// Make the stage 2 table which maps the same area as the block descriptor by the page descriptors
+///
+/// # Arguments
+/// * `block_descriptor` - The block descriptor to convert
+/// * `table_level` - The tree level of `block_descriptor`, must be 1 or 2
+///
+/// # Result
+/// If succeeded, returns Ok(table_descriptor), otherwise Err(())
+fn convert_stage2_block_to_page_table(block_descriptor: u64, table_level: i8) -> Result<u64, ()> {
+    let shift_level = table_level_to_table_shift(STAGE_2_PAGE_SHIFT, table_level);
+    let mut block_physical_address = extract_output_address(block_descriptor, STAGE_2_PAGE_SHIFT);
+    let descriptor_attribute =
+        (block_descriptor ^ (block_physical_address as u64)) & !PAGE_DESCRIPTORS_CONTIGUOUS;
+    let table_address = allocate_page_table_for_stage_2(table_level, 0, false, 1)?;
+    let table = unsafe {
+        &mut *(table_address as *mut [u64; PAGE_TABLE_SIZE / core::mem::size_of::<u64>()])
+    };
+    for i in 0..table.len() {
+        let descriptor = if table_level + 1 == 3 {
+            (block_physical_address as u64) | ((descriptor_attribute | 0b11) & !PAGE_DESCRIPTORS_NT)
+        } else {
+            match convert_stage2_block_to_page_table(
+                (block_physical_address as u64) | descriptor_attribute,
+                table_level + 1,
+            ) {
+                Ok(d) => d,
+                Err(_) => {
+                    for d in &table[0..i] {
+                        free_stage2_table_tree(
+                            extract_output_address(*d, STAGE_2_PAGE_SHIFT),
+                            table_level + 2,
+                        );
+                    }
+                    if let Err(err) = free_memory(table_address, 1) {
+                        println!("Failed to free the page table: {:?}", err);
+                    }
+                    return Err(());
+                }
+            }
+        };
+        table[i] = descriptor;
+        block_physical_address += 1 << (shift_level - 9);
+    }
+    return Ok(table_address as u64 | 0b11);
+}
+
+/// Split the stage 2 block descriptors in the area into the page descriptors
+///
+/// The page descriptors have the same attributes as the block descriptor.
+/// The block descriptors which cover outside of the area are split entirely.
+/// The tables may be merged into the block descriptors again by [`add_memory_access_trap`]
+/// and [`remove_memory_access_trap`] if all page descriptors have the same attributes.
+///
+/// # Arguments
+/// * `address` - The intermediate physical address of the area
+/// * `size` - The size of the area
+///
+/// # Result
+/// If the page tables cannot be allocated, returns Err(()) and the remaining blocks are not split
+pub fn split_stage2_block_descriptors(address: usize, size: usize) -> Result<(), ()> {
+    let mut result = Ok(());
+    walk_stage2_leaf_descriptors(address, size, &mut |d, _, descriptor_size| {
+        if descriptor_size == STAGE_2_PAGE_SIZE || result.is_err() {
+            return;
+        }
+        let table_level =
+            3 - ((descriptor_size.trailing_zeros() as usize - STAGE_2_PAGE_SHIFT) / 9) as i8;
+        match convert_stage2_block_to_page_table(*d, table_level) {
+            Ok(table_descriptor) => replace_stage2_descriptor(d, table_descriptor),
+            Err(_) => result = Err(()),
+        }
+    });
+    return result;
+}
+
+/// Check if the stage 2 table can be replaced with one block descriptor
+///
+/// The table can be merged when all entries map the contiguous physical address
+/// with the same attributes, and the first address is aligned with the size of the block.
+/// The contiguous bit is ignored.
+///
+/// # Arguments
+/// * `table_address` - The address of the table to check, it must not be concatenated
+/// * `table_level` - The tree level of `table_address`, must be 2 or 3
+///
+/// # Result
+/// If the table can be merged, returns Some(block_descriptor), otherwise None
+fn try_to_merge_stage2_table(table_address: usize, table_level: i8) -> Option<u64> {
+    let table =
+        unsafe { &*(table_address as *const [u64; PAGE_TABLE_SIZE / core::mem::size_of::<u64>()]) };
+    let shift_level = table_level_to_table_shift(STAGE_2_PAGE_SHIFT, table_level);
+    let first_descriptor = table[0] & !PAGE_DESCRIPTORS_CONTIGUOUS;
+    let expected_type = if table_level == 3 { 0b11 } else { 0b01 };
+    if first_descriptor & 0b11 != expected_type {
+        return None;
+    }
+    let base_address = extract_output_address(first_descriptor, STAGE_2_PAGE_SHIFT);
+    if (base_address & ((1usize << (shift_level + 9)) - 1)) != 0 {
+        return None;
+    }
+    let attribute = first_descriptor ^ (base_address as u64);
+
+    for (i, e) in table.iter().enumerate() {
+        if (*e & !PAGE_DESCRIPTORS_CONTIGUOUS)
+            != ((base_address + (i << shift_level)) as u64) | attribute
+        {
+            return None;
+        }
+    }
+    return Some((base_address as u64) | (attribute & !0b11) | 0b01);
+}
+
+/// Walk the stage 2 table tree
+///
+/// `f` is called with (table_address, table_level) for each table under `table_address`
+/// including itself. The children are visited before the parent, so `f` can free the table.
+///
+/// # Arguments
+/// * `table_address` - The address of the table to start walking
+/// * `table_level` - The tree level of `table_address`
+/// * `concatenated_tables` - The number of concatenated tables at `table_address`
+/// * `f` - The function to call for each table
+fn walk_stage2_table_tree(
+    table_address: usize,
+    table_level: i8,
+    concatenated_tables: usize,
+    f: &mut dyn FnMut(usize, i8),
+) {
+    if table_level < 3 {
+        let table = unsafe {
+            core::slice::from_raw_parts(
+                table_address as *const u64,
+                (PAGE_TABLE_SIZE * concatenated_tables) / core::mem::size_of::<u64>(),
+            )
+        };
+        for e in table {
+            if is_descriptor_table_or_level_3_descriptor(*e) {
+                walk_stage2_table_tree(
+                    extract_output_address(*e, STAGE_2_PAGE_SHIFT),
+                    table_level + 1,
+                    1,
+                    f,
+                );
+            }
+        }
+    }
+    f(table_address, table_level);
+}
+
+/// Free the stage 2 table and all tables under it
+///
+/// The table must not be referred from the active stage 2 table tree.
+///
+/// # Arguments
+/// * `table_address` - The address of the table to free, it must not be concatenated
+/// * `table_level` - The tree level of `table_address`
+pub fn free_stage2_table_tree(table_address: usize, table_level: i8) {
+    walk_stage2_table_tree(table_address, table_level, 1, &mut |address, _| {
+        if let Err(err) = free_memory(address, 1) {
+            println!("Failed to free the page table({:#X}): {:?}", address, err);
+        }
+    });
+}
+
+/// Get the initial lookup level of stage 2 from VTCR_EL2.SL0
+///
+/// The value of SL0 is interpreted for the 4KiB granule ([`STAGE_2_PAGE_SHIFT`] == 12).
+fn get_stage2_initial_look_up_level(vtcr_el2: u64) -> i8 {
+    match (vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET {
+        0b00 => 2,
+        0b01 => 1,
+        0b10 => 0,
+        0b11 => 3,
+        _ => unreachable!(),
+    }
+}
+
+/// Count the pages used by the current stage 2 table tree
+///
+/// # Result
+/// Returns the number of live table pages including concatenated top level tables
+pub fn get_number_of_stage2_table_pages() -> usize {
+    let vtcr_el2 = get_vtcr_el2();
+    let vtcr_el2_t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
+    let initial_look_up_level = get_stage2_initial_look_up_level(vtcr_el2);
+    let concatenated_tables =
+        calculate_number_of_concatenated_page_tables(vtcr_el2_t0sz, initial_look_up_level) as usize;
+    let mut number_of_pages = 0;
+
+    walk_stage2_table_tree(
+        TTBR::new(get_vttbr_el2()).get_base_address(),
+        initial_look_up_level,
+        concatenated_tables,
+        &mut |_, level| {
+            number_of_pages += if level == initial_look_up_level {
+                concatenated_tables
+            } else {
+                1
+            }
+        },
+    );
+    return number_of_pages;
+}
+
+/// Call `f` for each leaf descriptor of the current stage 2 table covering the area
+///
+/// `f` is called with (descriptor, the start address of descriptor, the size of descriptor).
+/// The descriptor may be a block descriptor which covers outside of the area.
+/// The valid bit of the non-zero initial lookup level entries is ignored because
+/// [`crate::fast_restore`] disables them temporarily in the restore process.
+///
+/// # Arguments
+/// * `address` - The intermediate physical address of the area
+/// * `size` - The size of the area
+/// * `f` - The function to call for each valid leaf descriptor
+pub fn walk_stage2_leaf_descriptors(
+    address: usize,
+    size: usize,
+    f: &mut dyn FnMut(&mut u64, usize, usize),
+) {
+    let vtcr_el2 = get_vtcr_el2();
+    let vtcr_el2_t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
+    let initial_look_up_level = get_stage2_initial_look_up_level(vtcr_el2);
+    let initial_concatenated_tables =
+        calculate_number_of_concatenated_page_tables(vtcr_el2_t0sz, initial_look_up_level) as usize;
+    let end_address = address + size;
+    let mut current_address = address;
+
+    while current_address < end_address {
+        let mut table_address = TTBR::new(get_vttbr_el2()).get_base_address();
+        let mut table_level = initial_look_up_level;
+        let mut concatenated_tables = initial_concatenated_tables;
+        loop {
+            let shift_level = table_level_to_table_shift(STAGE_2_PAGE_SHIFT, table_level);
+            let table_index = (current_address >> shift_level) & (0x200 * concatenated_tables - 1);
+            let descriptor = unsafe { &mut *(table_address as *mut u64).add(table_index) };
+            let descriptor_type = if table_level == initial_look_up_level && *descriptor != 0 {
+                (*descriptor | 1) & 0b11
+            } else {
+                *descriptor & 0b11
+            };
+            let descriptor_size = 1usize << shift_level;
+            let descriptor_address = current_address & !(descriptor_size - 1);
+
+            if table_level < 3 && descriptor_type == 0b11 {
+                table_address = extract_output_address(*descriptor, STAGE_2_PAGE_SHIFT);
+                table_level += 1;
+                concatenated_tables = 1;
+                continue;
+            }
+            if (table_level < 3 && descriptor_type == 0b01)
+                || (table_level == 3 && descriptor_type == 0b11)
+            {
+                f(descriptor, descriptor_address, descriptor_size);
+            }
+            current_address = descriptor_address + descriptor_size;
+            break;
+        }
+    }
+}
+
 /// Set up to trap memory access from EL1/EL0
 ///
 /// This will modify the stage2 page table to trap the access of (`address` ~ (`address` + `size`))
@@ -516,6 +772,8 @@ fn map_address_recursive_stage2(
 /// This function should be called after calling [`crate::memory_hook::add_memory_load_hook_handler`]
 /// and/or [`crate::memory_hook::add_memory_store_hook_handler`].
 ///
+/// If the area is a part of a block descriptor, the block is split into the finer table.
+///
 /// # Arguments
 /// * `address` - The physical address to trap
 /// * `size` - The trap size
@@ -544,15 +802,8 @@ pub fn add_memory_access_trap(
     }
     let mut num_of_needed_pages = size >> STAGE_2_PAGE_SHIFT;
     let vtcr_el2 = get_vtcr_el2();
-    let vtcr_el2_sl0 = ((vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET) as u8;
     let vtcr_el2_t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
-    let initial_look_up_level: i8 = match vtcr_el2_sl0 {
-        0b00 => 2,
-        0b01 => 1,
-        0b10 => 0,
-        0b11 => 3,
-        _ => unreachable!(),
-    };
+    let initial_look_up_level = get_stage2_initial_look_up_level(vtcr_el2);
 
     assert!(address < (1 << (64 - vtcr_el2_t0sz)));
 
@@ -585,6 +836,9 @@ pub fn add_memory_access_trap(
 /// This function should be called before calling [`crate::memory_hook::remove_memory_load_hook_handler`]
 /// and/or [`crate::memory_hook::remove_memory_store_hook_handler`].
 ///
+/// The tables which become representable by one block descriptor are merged into the block,
+/// and the memory of the tables is freed.
+///
 /// # Arguments
 /// * `address` - The physical address to remove trapping
 /// * `size` - The trap size
@@ -598,15 +852,8 @@ pub fn remove_memory_access_trap(mut address: usize, size: usize) -> Result<(),
     }
     let mut num_of_needed_pages = size >> STAGE_2_PAGE_SHIFT;
     let vtcr_el2 = get_vtcr_el2();
-    let vtcr_el2_sl0 = ((vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET) as u8;
     let vtcr_el2_t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
-    let initial_look_up_level: i8 = match vtcr_el2_sl0 {
-        0b00 => 2,
-        0b01 => 1,
-        0b10 => 0,
-        0b11 => 3,
-        _ => unreachable!(),
-    };
+    let initial_look_up_level = get_stage2_initial_look_up_level(vtcr_el2);
 
     assert!(address < (1 << (64 - vtcr_el2_t0sz)));
     let mut physical_address = address;
@@ -11,6 +11,33 @@
 
 use crate::drivers;
 
+const HEADER_TYPE_OFFSET: usize = 0x0E;
+const HEADER_TYPE_MASK: u32 = 0x7F;
+const HEADER_TYPE_ENDPOINT: u32 = 0x00;
+const BASE_CLASS_OFFSET: usize = 0x0B;
+const BASE_CLASS_BRIDGE: u32 = 0x06;
+
+/// True if any PCI device which may perform DMA is found by [`init_pci`]
+static mut IS_DMA_MASTER_PRESENT: bool = false;
+
+/// Check if any PCI device which may perform DMA is found
+///
+/// The endpoints except the bridges(including the host bridges) are regarded as DMA masters.
+pub fn is_dma_master_present() -> bool {
+    unsafe { IS_DMA_MASTER_PRESENT }
+}
+
+fn check_dma_master(ecam_address: usize, bus: u8, device: u8, function: u8) {
+    let header_type =
+        get_configuration_space_data(ecam_address, bus, device, function, HEADER_TYPE_OFFSET, 1)
+            & HEADER_TYPE_MASK;
+    let base_class =
+        get_configuration_space_data(ecam_address, bus, device, function, BASE_CLASS_OFFSET, 1);
+    if header_type == HEADER_TYPE_ENDPOINT && base_class != BASE_CLASS_BRIDGE {
+        unsafe { IS_DMA_MASTER_PRESENT = true };
+    }
+}
+
 pub fn init_pci(ecam_address: usize, start_bus_number: u8, end_bus_number: u8) {
     for bus in start_bus_number..=end_bus_number {
         for device in 0..32 {
@@ -18,6 +45,7 @@ pub fn init_pci(ecam_address: usize, start_bus_number: u8, end_bus_number: u8) {
             if vendor_id == 0xffff {
                 continue;
             }
+            check_dma_master(ecam_address, bus, device, 0);
             let device_id = get_configuration_space_data(ecam_address, bus, device, 0, 2, 2) as u16;
 
             println!(
@@ -40,6 +68,7 @@ pub fn init_pci(ecam_address: usize, start_bus_number: u8, end_bus_number: u8) {
                 if vendor_id == 0xffff {
                     continue;
                 }
+                check_dma_master(ecam_address, bus, device, function);
                 println!(
                     "  {:X}:{:X}:{:X} VenderId: {:#X}",
                     bus, device, function, vendor_id
@@ -11,7 +11,8 @@
 //! Supported Version: ~2.0
 
 use crate::fast_restore::enter_restore_process;
-use crate::multi_core::{power_off_cpu, setup_new_cpu};
+use crate::multi_core::{get_affinity_info, power_off_cpu, setup_new_cpu, suspend_cpu};
+use crate::smccc::{forward_smc_call, SMCCC_VERSION};
 use crate::{handler_panic, StoredRegisters};
 
 use common::cpu::{get_mpidr_el1, secure_monitor_call};
@@ -115,6 +116,50 @@ impl TryFrom<i32> for PsciReturnCode {
     }
 }
 
+/// The action for the requests of the guest to power off or reset the system
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SystemPowerAction {
+    /// Forward the request to the firmware to power off or reset the machine really
+    Forward,
+    /// Restore the boot snapshot by [`enter_restore_process`] instead of the request
+    FastRestore,
+}
+
+/// SYSTEM_RESET2's reset type of the architectural warm reset
+const SYSTEM_RESET2_WARM_RESET: u32 = 0;
+/// SYSTEM_RESET2's reset types with this bit are vendor-specific
+const SYSTEM_RESET2_VENDOR_SPECIFIC: u32 = 1 << 31;
+
+/// The action of SYSTEM_OFF
+///
+/// Enable `fast_restore_real_system_off` to keep the way to power off the machine from the guest.
+const SYSTEM_OFF_ACTION: SystemPowerAction =
+    if cfg!(feature = "fast_restore") && !cfg!(feature = "fast_restore_real_system_off") {
+        SystemPowerAction::FastRestore
+    } else {
+        SystemPowerAction::Forward
+    };
+
+/// The action of SYSTEM_RESET, it is the cold reset
+///
+/// Enable `fast_restore_real_system_reset` to keep the way to reset the machine from the guest.
+const SYSTEM_RESET_ACTION: SystemPowerAction =
+    if cfg!(feature = "fast_restore") && !cfg!(feature = "fast_restore_real_system_reset") {
+        SystemPowerAction::FastRestore
+    } else {
+        SystemPowerAction::Forward
+    };
+
+/// The action of SYSTEM_RESET2 with [`SYSTEM_RESET2_WARM_RESET`]
+const SYSTEM_WARM_RESET_ACTION: SystemPowerAction = if cfg!(feature = "fast_restore") {
+    SystemPowerAction::FastRestore
+} else {
+    SystemPowerAction::Forward
+};
+
+/// The action of SYSTEM_RESET2 with the vendor-specific reset types
+const VENDOR_SPECIFIC_RESET_ACTION: SystemPowerAction = SystemPowerAction::Forward;
+
 pub fn handle_psci_call(function_id: PsciFunctionId, stored_registers: &mut StoredRegisters) {
     pr_debug!("PSCI Function Call: {:?}", function_id);
 
@@ -129,35 +174,120 @@ pub fn handle_psci_call(function_id: PsciFunctionId, stored_registers: &mut Stor
             get_mpidr_el1(),
             PsciReturnCode::try_from(result)
         );
+    } else if function_id == PsciFunctionId::AffinityInfo {
+        match get_affinity_info(stored_registers.x1, stored_registers.x2) {
+            Some(result) => stored_registers.x0 = result,
+            None => forward_smc_call(stored_registers),
+        }
+    } else if function_id == PsciFunctionId::CpuSuspend
+        || function_id == PsciFunctionId::CpuDefaultSuspend
+    {
+        suspend_cpu(function_id, stored_registers);
+    } else if function_id == PsciFunctionId::PsciFeatures {
+        stored_registers.x0 = get_psci_feature(stored_registers.x1 as u32);
+    } else if !is_psci_function_supported(function_id) {
+        pr_debug!("{:?} is not supported.", function_id);
+        stored_registers.x0 = PsciReturnCode::NotSupported as i64 as u64;
+    } else if function_id == PsciFunctionId::SystemOff
+        || function_id == PsciFunctionId::SystemReset
+        || function_id == PsciFunctionId::SystemReset2
+    {
+        handle_system_power_request(function_id, stored_registers);
     } else {
-        #[cfg(feature = "fast_restore")]
-        if function_id == PsciFunctionId::SystemOff
-            || function_id == PsciFunctionId::SystemReset
-            || function_id == PsciFunctionId::SystemReset2
-        {
+        forward_smc_call(stored_registers);
+    }
+}
+
+/// Handle SYSTEM_OFF, SYSTEM_RESET, and SYSTEM_RESET2 by the action of each request type
+///
+/// If the action is [`SystemPowerAction::FastRestore`], this function does not return.
+fn handle_system_power_request(
+    function_id: PsciFunctionId,
+    stored_registers: &mut StoredRegisters,
+) {
+    let action = match function_id {
+        PsciFunctionId::SystemOff => SYSTEM_OFF_ACTION,
+        PsciFunctionId::SystemReset => SYSTEM_RESET_ACTION,
+        _ => {
+            let reset_type = stored_registers.x1 as u32;
+            if reset_type == SYSTEM_RESET2_WARM_RESET {
+                SYSTEM_WARM_RESET_ACTION
+            } else if (reset_type & SYSTEM_RESET2_VENDOR_SPECIFIC) != 0 {
+                VENDOR_SPECIFIC_RESET_ACTION
+            } else {
+                pr_debug!("Unknown architectural reset type: {:#X}", reset_type);
+                stored_registers.x0 = PsciReturnCode::InvalidParameters as i64 as u64;
+                return;
+            }
+        }
+    };
+    pr_debug!("{:?}: {:?}", function_id, action);
+
+    match action {
+        SystemPowerAction::FastRestore => {
             println!("Trap power_off/reboot");
             enter_restore_process();
         }
-        secure_monitor_call(
-            &mut stored_registers.x0,
-            &mut stored_registers.x1,
-            &mut stored_registers.x2,
-            &mut stored_registers.x3,
-            &mut stored_registers.x4,
-            &mut stored_registers.x5,
-            &mut stored_registers.x6,
-            &mut stored_registers.x7,
-            &mut stored_registers.x8,
-            &mut stored_registers.x9,
-            &mut stored_registers.x10,
-            &mut stored_registers.x11,
-            &mut stored_registers.x12,
-            &mut stored_registers.x13,
-            &mut stored_registers.x14,
-            &mut stored_registers.x15,
-            &mut stored_registers.x16,
-            &mut stored_registers.x17,
-        );
+        SystemPowerAction::Forward => {
+            println!("Perform {:?} of the firmware", function_id);
+            forward_smc_call(stored_registers);
+            if function_id == PsciFunctionId::SystemReset2
+                && stored_registers.x0 as i32 == PsciReturnCode::NotSupported as i32
+                && SYSTEM_WARM_RESET_ACTION == SystemPowerAction::FastRestore
+            {
+                /* SYSTEM_RESET2 is reported as supported even if the firmware does not */
+                stored_registers.x0 = PsciReturnCode::InvalidParameters as i64 as u64;
+            }
+        }
+    }
+}
+
+/// Check if the hypervisor handles or forwards `function_id`
+///
+/// CPU_FREEZE and SYSTEM_SUSPEND are not supported because the firmware resumes the system
+/// without the state of the hypervisor (e.g. the configuration of SMMU) on them.
+fn is_psci_function_supported(function_id: PsciFunctionId) -> bool {
+    function_id != PsciFunctionId::CpuFreeze && function_id != PsciFunctionId::SystemSuspend
+}
+
+/// Answer PSCI_FEATURES based on the functions supported by the hypervisor
+///
+/// The supported functions are queried to the firmware because it must also implement them.
+///
+/// # Arguments
+/// * `queried_function_id` - The function ID passed by the guest
+///
+/// # Result
+/// Returns the value to set into x0 of the guest
+fn get_psci_feature(queried_function_id: u32) -> u64 {
+    if queried_function_id == SMCCC_VERSION {
+        /* SMCCC_VERSION is emulated by crate::smccc */
+        return PsciReturnCode::Success as i64 as u64;
+    }
+    match PsciFunctionId::try_from(queried_function_id as u64) {
+        Ok(PsciFunctionId::SystemReset2)
+            if SYSTEM_WARM_RESET_ACTION == SystemPowerAction::FastRestore =>
+        {
+            /* The warm reset is handled by the hypervisor */
+            PsciReturnCode::Success as i64 as u64
+        }
+        Ok(f) if is_psci_function_supported(f) => {
+            call_psci_function(PsciFunctionId::PsciFeatures, f as u64, 0, 0)
+        }
+        _ => PsciReturnCode::NotSupported as i64 as u64,
+    }
+}
+
+/// The emulation handler of PSCI for [`crate::smccc`]
+///
+/// The unknown PSCI functions (including SMC32 variants of the functions taking an address)
+/// return `NOT_SUPPORTED` to prevent the guest from passing its entry point to EL3 directly.
+pub fn psci_smc_handler(function_id: u32, stored_registers: &mut StoredRegisters) {
+    if let Ok(psci_function_id) = PsciFunctionId::try_from(function_id as u64) {
+        handle_psci_call(psci_function_id, stored_registers);
+    } else {
+        pr_debug!("Unknown PSCI Call: {:#X}", function_id);
+        stored_registers.x0 = PsciReturnCode::NotSupported as i64 as u64;
     }
 }
 
@@ -0,0 +1,407 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! SMC Calling Convention
+//!
+//! The SMCs from the guest are filtered by [`SMC_POLICY_TABLE`] to prevent the guest OS from
+//! operating the firmware. Each call is forwarded to EL3, denied, or emulated by the hypervisor
+//! depending on the first entry matching its function ID.
+//! The denied calls return `NOT_SUPPORTED` and are recorded into the log.
+//!
+//! The Arm Architecture calls are handled by the hypervisor to keep the answer of
+//! `SMCCC_ARCH_FEATURES` consistent with the calls actually forwarded to EL3.
+//! HVC #0 is also accepted as the conduit of SMCCC.
+//!
+
+use crate::psci::{call_psci_function, psci_smc_handler, PsciFunctionId};
+use crate::StoredRegisters;
+
+use common::cpu::{get_elr_el2, get_mpidr_el1, secure_monitor_call, AA64_INSTRUCTION_SIZE};
+
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+pub const SMCCC_RETURN_NOT_SUPPORTED: i64 = -1;
+
+const SMCCC_FAST_CALL: u32 = 1 << 31;
+const SMCCC_SMC64: u32 = 1 << 30;
+const SMCCC_OWNER_BITS_OFFSET: u32 = 24;
+const SMCCC_OWNER: u32 = 0x3F << SMCCC_OWNER_BITS_OFFSET;
+/// Bits\[23:16\] of the function ID of fast calls must be zero
+const SMCCC_FAST_CALL_RESERVED: u32 = 0xFF << 16;
+const SMCCC_FUNCTION_NUMBER: u32 = 0xFFFF;
+
+/* Arm Architecture Calls */
+pub const SMCCC_VERSION: u32 = 0x8000_0000;
+const SMCCC_ARCH_FEATURES: u32 = 0x8000_0001;
+const SMCCC_ARCH_SOC_ID: u32 = 0x8000_0002;
+const SMCCC_ARCH_WORKAROUND_1: u32 = 0x8000_8000;
+const SMCCC_ARCH_WORKAROUND_2: u32 = 0x8000_7FFF;
+const SMCCC_ARCH_WORKAROUND_3: u32 = 0x8000_3FFF;
+
+const SMCCC_VERSION_1_0: u32 = 0x10000;
+const SMCCC_VERSION_1_1: u32 = 0x10001;
+const SMCCC_VERSION_1_2: u32 = 0x10002;
+
+/* Owning Entity Number */
+const OWNER_ARM_ARCHITECTURE: u8 = 0;
+const OWNER_CPU_SERVICE: u8 = 1;
+const OWNER_SIP: u8 = 2;
+const OWNER_OEM: u8 = 3;
+const OWNER_STANDARD_SECURE: u8 = 4;
+const OWNER_STANDARD_HYPERVISOR: u8 = 5;
+const OWNER_VENDOR_HYPERVISOR: u8 = 6;
+const OWNER_TRUSTED_APPLICATION_START: u8 = 48;
+const OWNER_TRUSTED_OS_START: u8 = 50;
+
+/// SMCCC version implemented by the firmware, detected by [`init_smccc`]
+static FIRMWARE_SMCCC_VERSION: AtomicU32 = AtomicU32::new(SMCCC_VERSION_1_0);
+
+/// The number of function IDs whose denied calls are counted separately
+const NUMBER_OF_AUDITED_FUNCTION_IDS: usize = 32;
+const AUDIT_COUNT_OFFSET: u64 = 32;
+
+/// Denied function IDs and their counts, (count << AUDIT_COUNT_OFFSET) | function_id
+///
+/// The entry is empty if the count is zero.
+static DENIED_CALL_COUNTS: [AtomicU64; NUMBER_OF_AUDITED_FUNCTION_IDS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_ENTRY: AtomicU64 = AtomicU64::new(0);
+    [EMPTY_ENTRY; NUMBER_OF_AUDITED_FUNCTION_IDS]
+};
+/// The count of the denied calls which are not recorded in [`DENIED_CALL_COUNTS`]
+static DENIED_CALL_OTHER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SmcccConduit {
+    Smc,
+    Hvc,
+}
+
+pub type SmcEmulationHandler = fn(function_id: u32, stored_registers: &mut StoredRegisters);
+
+pub enum SmcPolicy {
+    /// Forward the call to EL3
+    Allow,
+    /// Return `NOT_SUPPORTED` without calling EL3
+    Deny,
+    /// Call the handler in the hypervisor instead of EL3
+    Emulate(SmcEmulationHandler),
+}
+
+struct SmcPolicyEntry {
+    owners: RangeInclusive<u8>,
+    function_numbers: RangeInclusive<u16>,
+    policy: SmcPolicy,
+}
+
+impl SmcPolicyEntry {
+    const fn new(
+        owners: RangeInclusive<u8>,
+        function_numbers: RangeInclusive<u16>,
+        policy: SmcPolicy,
+    ) -> Self {
+        Self {
+            owners,
+            function_numbers,
+            policy,
+        }
+    }
+}
+
+/// The policy table of fast calls, both of SMC32 and SMC64 are matched
+///
+/// The calls not matching any entry are handled by [`DEFAULT_SMC_POLICY`].
+static SMC_POLICY_TABLE: [SmcPolicyEntry; 4] = [
+    /* SMCCC_VERSION, SMCCC_ARCH_FEATURES, SMCCC_ARCH_SOC_ID, and the workarounds */
+    SmcPolicyEntry::new(
+        OWNER_ARM_ARCHITECTURE..=OWNER_ARM_ARCHITECTURE,
+        0x0000..=0xFFFF,
+        SmcPolicy::Emulate(arch_call_handler),
+    ),
+    /* PSCI */
+    SmcPolicyEntry::new(
+        OWNER_STANDARD_SECURE..=OWNER_STANDARD_SECURE,
+        0x0000..=0x001F,
+        SmcPolicy::Emulate(psci_smc_handler),
+    ),
+    /* SDEI */
+    SmcPolicyEntry::new(
+        OWNER_STANDARD_SECURE..=OWNER_STANDARD_SECURE,
+        0x0020..=0x003F,
+        SmcPolicy::Allow,
+    ),
+    /* TRNG */
+    SmcPolicyEntry::new(
+        OWNER_STANDARD_SECURE..=OWNER_STANDARD_SECURE,
+        0x0050..=0x005F,
+        SmcPolicy::Allow,
+    ),
+];
+
+/// The policy of the fast calls not in [`SMC_POLICY_TABLE`] (e.g. SiP, OEM, Trusted OS)
+const DEFAULT_SMC_POLICY: SmcPolicy = SmcPolicy::Deny;
+
+/// The policy of the yielding calls, they are used only for Trusted OS
+const YIELDING_CALL_POLICY: SmcPolicy = SmcPolicy::Deny;
+
+fn get_smc_policy(function_id: u32) -> &'static SmcPolicy {
+    if (function_id & SMCCC_FAST_CALL) == 0 {
+        return &YIELDING_CALL_POLICY;
+    }
+    if (function_id & SMCCC_FAST_CALL_RESERVED) != 0 {
+        return &SmcPolicy::Deny;
+    }
+    let owner = ((function_id & SMCCC_OWNER) >> SMCCC_OWNER_BITS_OFFSET) as u8;
+    let function_number = (function_id & SMCCC_FUNCTION_NUMBER) as u16;
+    SMC_POLICY_TABLE
+        .iter()
+        .find(|e| e.owners.contains(&owner) && e.function_numbers.contains(&function_number))
+        .map(|e| &e.policy)
+        .unwrap_or(&DEFAULT_SMC_POLICY)
+}
+
+fn get_owner_name(function_id: u32) -> &'static str {
+    if (function_id & SMCCC_FAST_CALL) == 0 {
+        return "Yielding Call";
+    }
+    match ((function_id & SMCCC_OWNER) >> SMCCC_OWNER_BITS_OFFSET) as u8 {
+        OWNER_ARM_ARCHITECTURE => "Arm Architecture",
+        OWNER_CPU_SERVICE => "CPU Service",
+        OWNER_SIP => "SiP",
+        OWNER_OEM => "OEM",
+        OWNER_STANDARD_SECURE => "Standard Secure",
+        OWNER_STANDARD_HYPERVISOR => "Standard Hypervisor",
+        OWNER_VENDOR_HYPERVISOR => "Vendor Hypervisor",
+        OWNER_TRUSTED_APPLICATION_START..=49 => "Trusted Application",
+        OWNER_TRUSTED_OS_START..=63 => "Trusted OS",
+        _ => "Reserved",
+    }
+}
+
+/// Count the denied call of `function_id`
+///
+/// # Result
+/// Returns the number of the denied calls of `function_id` including this call.
+/// If `function_id` cannot be recorded, returns the number of the unrecorded denied calls.
+fn count_denied_call(function_id: u32) -> u64 {
+    let initial_entry = (1 << AUDIT_COUNT_OFFSET) | (function_id as u64);
+    for e in &DENIED_CALL_COUNTS {
+        let mut current = e.load(Ordering::Relaxed);
+        if current == 0 {
+            match e.compare_exchange(0, initial_entry, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return 1,
+                Err(c) => current = c,
+            }
+        }
+        if (current as u32) == function_id {
+            return (e.fetch_add(1 << AUDIT_COUNT_OFFSET, Ordering::Relaxed) >> AUDIT_COUNT_OFFSET)
+                + 1;
+        }
+    }
+    return DENIED_CALL_OTHER_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+}
+
+/// Record the denied call into the log
+///
+/// To prevent the guest from stalling CPUs by the log output,
+/// the call is logged only when the number of the denied calls of the function ID is a power of two.
+fn audit_denied_call(conduit: SmcccConduit, function_id: u32, immediate: u16) {
+    let count = count_denied_call(function_id);
+    if !count.is_power_of_two() {
+        return;
+    }
+    println!(
+        "{:?} is denied({} times): FunctionID: {:#X}({}, {}), Immediate: {:#X}, MPIDR: {:#X}, PC: {:#X}",
+        conduit,
+        count,
+        function_id,
+        get_owner_name(function_id),
+        if (function_id & SMCCC_SMC64) != 0 {
+            "SMC64"
+        } else {
+            "SMC32"
+        },
+        immediate,
+        get_mpidr_el1(),
+        get_elr_el2() as usize - AA64_INSTRUCTION_SIZE
+    );
+}
+
+/// Call SMC with one argument from the hypervisor
+fn call_smc(function_id: u32, mut argument: u64) -> u64 {
+    let mut x0 = function_id as u64;
+    secure_monitor_call(
+        &mut x0,
+        &mut argument,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+        &mut 0,
+    );
+    return x0;
+}
+
+/// Detect SMCCC version of the firmware
+///
+/// SMCCC v1.0 firmware may not implement `SMCCC_VERSION`,
+/// therefore `PSCI_FEATURES` is used to check if it is available.
+pub fn init_smccc() {
+    let psci_features_result =
+        call_psci_function(PsciFunctionId::PsciFeatures, SMCCC_VERSION as u64, 0, 0) as i32;
+    let smccc_version_result = if psci_features_result < 0 {
+        SMCCC_RETURN_NOT_SUPPORTED as i32
+    } else {
+        call_smc(SMCCC_VERSION, 0) as i32
+    };
+    let version = if smccc_version_result < 0 {
+        SMCCC_VERSION_1_0
+    } else {
+        smccc_version_result as u32
+    };
+    FIRMWARE_SMCCC_VERSION.store(version, Ordering::Relaxed);
+    println!(
+        "SMCCC Version of the firmware: {}.{}",
+        version >> 16,
+        version & 0xFFFF
+    );
+}
+
+/// Get SMCCC version answered to the guest
+///
+/// The hypervisor implements `SMCCC_ARCH_FEATURES` by itself, therefore v1.1 is always available.
+/// v1.2 is available if the firmware supports it because `SMCCC_ARCH_SOC_ID` is forwarded to EL3.
+fn get_smccc_version() -> u32 {
+    if FIRMWARE_SMCCC_VERSION.load(Ordering::Relaxed) >= SMCCC_VERSION_1_2 {
+        SMCCC_VERSION_1_2
+    } else {
+        SMCCC_VERSION_1_1
+    }
+}
+
+/// Get the result of `SMCCC_ARCH_FEATURES` for `function_id`
+///
+/// The queries of the calls forwarded to EL3 are also forwarded, the other calls are not supported.
+fn get_arch_feature(function_id: u32) -> u64 {
+    let firmware_version = FIRMWARE_SMCCC_VERSION.load(Ordering::Relaxed);
+    match function_id {
+        SMCCC_VERSION | SMCCC_ARCH_FEATURES => 0,
+        SMCCC_ARCH_SOC_ID if firmware_version >= SMCCC_VERSION_1_2 => {
+            call_smc(SMCCC_ARCH_FEATURES, function_id as u64)
+        }
+        SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_2 | SMCCC_ARCH_WORKAROUND_3
+            if firmware_version >= SMCCC_VERSION_1_1 =>
+        {
+            call_smc(SMCCC_ARCH_FEATURES, function_id as u64)
+        }
+        _ => SMCCC_RETURN_NOT_SUPPORTED as u64,
+    }
+}
+
+/// The emulation handler of Arm Architecture Calls
+fn arch_call_handler(function_id: u32, stored_registers: &mut StoredRegisters) {
+    match function_id {
+        SMCCC_VERSION => stored_registers.x0 = get_smccc_version() as u64,
+        SMCCC_ARCH_FEATURES => {
+            stored_registers.x0 = get_arch_feature(stored_registers.x1 as u32);
+        }
+        SMCCC_ARCH_SOC_ID
+        | SMCCC_ARCH_WORKAROUND_1
+        | SMCCC_ARCH_WORKAROUND_2
+        | SMCCC_ARCH_WORKAROUND_3 => {
+            if (get_arch_feature(function_id) as i32) < 0 {
+                stored_registers.x0 = SMCCC_RETURN_NOT_SUPPORTED as u64;
+            } else {
+                forward_smc_call(stored_registers);
+            }
+        }
+        _ => {
+            pr_debug!("Unknown Arm Architecture Call: {:#X}", function_id);
+            stored_registers.x0 = SMCCC_RETURN_NOT_SUPPORTED as u64;
+        }
+    }
+}
+
+/// Forward the SMC of the guest to EL3
+///
+/// The guest is told SMCCC v1.1 or later by [`get_smccc_version`], and expects x4 ~ x17 to be
+/// preserved. The firmware of SMCCC v1.0 may corrupt them, therefore they are restored after the call.
+pub fn forward_smc_call(stored_registers: &mut StoredRegisters) {
+    let saved_registers = if FIRMWARE_SMCCC_VERSION.load(Ordering::Relaxed) < SMCCC_VERSION_1_1 {
+        Some(stored_registers.clone())
+    } else {
+        None
+    };
+    secure_monitor_call(
+        &mut stored_registers.x0,
+        &mut stored_registers.x1,
+        &mut stored_registers.x2,
+        &mut stored_registers.x3,
+        &mut stored_registers.x4,
+        &mut stored_registers.x5,
+        &mut stored_registers.x6,
+        &mut stored_registers.x7,
+        &mut stored_registers.x8,
+        &mut stored_registers.x9,
+        &mut stored_registers.x10,
+        &mut stored_registers.x11,
+        &mut stored_registers.x12,
+        &mut stored_registers.x13,
+        &mut stored_registers.x14,
+        &mut stored_registers.x15,
+        &mut stored_registers.x16,
+        &mut stored_registers.x17,
+    );
+    if let Some(s) = saved_registers {
+        let r = stored_registers;
+        (r.x4, r.x5, r.x6, r.x7, r.x8, r.x9, r.x10) = (s.x4, s.x5, s.x6, s.x7, s.x8, s.x9, s.x10);
+        (r.x11, r.x12, r.x13, r.x14, r.x15, r.x16, r.x17) =
+            (s.x11, s.x12, s.x13, s.x14, s.x15, s.x16, s.x17);
+    }
+}
+
+/// Handle SMCCC call from the guest by [`SMC_POLICY_TABLE`]
+///
+/// ELR_EL2 must point the next instruction of SMC/HVC.
+///
+/// # Arguments
+/// * `conduit` - The instruction used for the call, the allowed calls are forwarded by SMC anyway
+/// * `immediate` - The immediate value of SMC/HVC, SMCCC uses only zero
+/// * `stored_registers` - The registers of the guest, x0 is the function ID
+pub fn handle_smccc_call(
+    conduit: SmcccConduit,
+    immediate: u16,
+    stored_registers: &mut StoredRegisters,
+) {
+    let function_id = stored_registers.x0 as u32;
+    let policy = if immediate != 0 {
+        &SmcPolicy::Deny
+    } else {
+        get_smc_policy(function_id)
+    };
+    match policy {
+        SmcPolicy::Allow => forward_smc_call(stored_registers),
+        SmcPolicy::Deny => {
+            audit_denied_call(conduit, function_id, immediate);
+            stored_registers.x0 = SMCCC_RETURN_NOT_SUPPORTED as u64;
+        }
+        SmcPolicy::Emulate(handler) => handler(function_id, stored_registers),
+    }
+}
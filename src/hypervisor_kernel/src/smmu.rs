@@ -11,32 +11,30 @@
 
 use crate::memory_hook::*;
 use crate::paging::{add_memory_access_trap, map_address, remove_memory_access_trap};
-use crate::{emulation, StoredRegisters};
+use crate::{allocate_memory, emulation, StoredRegisters};
 
 use common::cpu::{dsb, get_vtcr_el2, get_vttbr_el2};
 use common::paging::{page_align_up, stage2_page_align_up};
 use common::smmu::*;
-use common::{bitmask, STAGE_2_PAGE_MASK, STAGE_2_PAGE_SIZE};
+use common::{bitmask, PAGE_SIZE, STAGE_2_PAGE_MASK, STAGE_2_PAGE_SIZE};
 
 use core::mem::size_of;
 
-static mut SMMU_BASE_ADDRESS: usize = 0;
-
-fn read_smmu_register<T>(offset: usize) -> T {
+fn read_smmu_register<T>(smmu: &SmmuDevice, offset: usize) -> T {
     assert!(offset < SMMU_MEMORY_MAP_SIZE);
     dsb();
-    unsafe { core::ptr::read_volatile((SMMU_BASE_ADDRESS + offset) as *const T) }
+    unsafe { core::ptr::read_volatile((smmu.base_address + offset) as *const T) }
 }
 
-fn write_smmu_register<T>(offset: usize, data: T) {
+fn write_smmu_register<T>(smmu: &SmmuDevice, offset: usize, data: T) {
     assert!(offset < SMMU_MEMORY_MAP_SIZE);
-    unsafe { core::ptr::write_volatile((SMMU_BASE_ADDRESS + offset) as *mut T, data) }
+    unsafe { core::ptr::write_volatile((smmu.base_address + offset) as *mut T, data) }
     dsb();
 }
 
 /// # ATTENTION
 /// If you add the member of SmmuSavedRegisters,
-/// please modify [`backup_default_smmu_settings`] and [`restore_smmu_status`]
+/// please modify [`backup_default_smmu_settings`] and [`restore_default_smmu_status`]
 struct SmmuSavedRegisters {
     cr0: u32,
     cr1: u32,
@@ -67,8 +65,23 @@ impl SmmuSavedRegisters {
     }
 }
 
-static mut DEFAULT_SMMU_STATUS: SmmuSavedRegisters = SmmuSavedRegisters::new();
-static mut CURRENT_SMMU_STATUS: SmmuSavedRegisters = SmmuSavedRegisters::new();
+/// The state of each SMMU
+///
+/// The address of this struct is passed to the memory hook handlers as the context.
+struct SmmuDevice {
+    base_address: usize,
+    default_status: SmmuSavedRegisters,
+    current_status: SmmuSavedRegisters,
+    /// The address of the next [`SmmuDevice`] to restore, or 0
+    next: usize,
+}
+
+fn get_smmu_device(context: usize) -> &'static mut SmmuDevice {
+    unsafe { &mut *(context as *mut SmmuDevice) }
+}
+
+/// The address of the first [`SmmuDevice`], the devices are linked by [`SmmuDevice::next`]
+static mut SMMU_DEVICE_LIST: usize = 0;
 
 /// Set up SMMU registers, and mapping of it.
 ///
@@ -83,9 +96,20 @@ static mut CURRENT_SMMU_STATUS: SmmuSavedRegisters = SmmuSavedRegisters::new();
 /// * `iort_address` - The address of IORT(Optional)
 pub fn init_smmu(smmu_registers_base_address: usize, _iort_address: Option<usize>) {
     /* smmu_registers_base_address must be mapped, accessible, and enabled. */
-    unsafe { SMMU_BASE_ADDRESS = smmu_registers_base_address };
+    assert!(size_of::<SmmuDevice>() <= PAGE_SIZE);
+    let context = allocate_memory(1, None).expect("Failed to allocate memory for SMMU");
+    unsafe {
+        *(context as *mut SmmuDevice) = SmmuDevice {
+            base_address: smmu_registers_base_address,
+            default_status: SmmuSavedRegisters::new(),
+            current_status: SmmuSavedRegisters::new(),
+            next: SMMU_DEVICE_LIST,
+        };
+        SMMU_DEVICE_LIST = context;
+    }
+    let smmu = get_smmu_device(context);
 
-    backup_default_smmu_settings();
+    backup_default_smmu_settings(smmu);
 
     add_memory_access_trap(
         smmu_registers_base_address,
@@ -95,35 +119,37 @@ pub fn init_smmu(smmu_registers_base_address: usize, _iort_address: Option<usize
     )
     .expect("Failed to trap the memory access to SMMU");
 
-    add_memory_load_hook_handler(LoadAccessHandlerEntry::new(
+    add_memory_load_hook_handler(LoadAccessHandlerEntry::new_with_context(
         smmu_registers_base_address,
         SMMU_MEMORY_MAP_SIZE,
         smmu_registers_load_handler,
+        context,
     ))
     .expect("Failed to add the load handler");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         smmu_registers_base_address,
         SMMU_MEMORY_MAP_SIZE,
         smmu_registers_store_handler,
+        context,
     ))
     .expect("Failed to add the store handler");
 }
 
-fn backup_default_smmu_settings() {
+fn backup_default_smmu_settings(smmu: &mut SmmuDevice) {
     let default_smmu_settings = SmmuSavedRegisters {
-        cr0: read_smmu_register(SMMU_CR0),
-        cr1: read_smmu_register(SMMU_CR1),
-        cr2: read_smmu_register(SMMU_CR2),
-        gbpa: read_smmu_register(SMMU_GBPA),
-        agbpa: read_smmu_register(SMMU_AGBPA),
-        irq_ctrl: read_smmu_register(SMMU_IRQ_CTRL),
-        gerrorn: read_smmu_register(SMMU_GERRORN),
-        strtab_base: read_smmu_register(SMMU_STRTAB_BASE),
-        strtab_base_cfg: read_smmu_register(SMMU_STRTAB_BASE_CFG),
-        gatos_ctrl: read_smmu_register(SMMU_GATOS_CTRL),
+        cr0: read_smmu_register(smmu, SMMU_CR0),
+        cr1: read_smmu_register(smmu, SMMU_CR1),
+        cr2: read_smmu_register(smmu, SMMU_CR2),
+        gbpa: read_smmu_register(smmu, SMMU_GBPA),
+        agbpa: read_smmu_register(smmu, SMMU_AGBPA),
+        irq_ctrl: read_smmu_register(smmu, SMMU_IRQ_CTRL),
+        gerrorn: read_smmu_register(smmu, SMMU_GERRORN),
+        strtab_base: read_smmu_register(smmu, SMMU_STRTAB_BASE),
+        strtab_base_cfg: read_smmu_register(smmu, SMMU_STRTAB_BASE_CFG),
+        gatos_ctrl: read_smmu_register(smmu, SMMU_GATOS_CTRL),
     };
 
-    unsafe { DEFAULT_SMMU_STATUS = default_smmu_settings };
+    smmu.default_status = default_smmu_settings;
 }
 
 fn smmu_registers_load_handler(
@@ -132,12 +158,14 @@ fn smmu_registers_load_handler(
     _access_size: u8,
     _is_64bit_register: bool,
     _is_sign_extend_required: bool,
+    context: usize,
 ) -> Result<LoadHookResult, ()> {
-    let register_offset = accessing_memory_address - unsafe { SMMU_BASE_ADDRESS };
+    let smmu = get_smmu_device(context);
+    let register_offset = accessing_memory_address - smmu.base_address;
     pr_debug!("SMMU Load Access Handler: Offset: {:#X}", register_offset);
     match register_offset {
         SMMU_IDR0 => Ok(LoadHookResult::Data(
-            (read_smmu_register::<u32>(SMMU_IDR0)
+            (read_smmu_register::<u32>(smmu, SMMU_IDR0)
                 & (!(SMMU_IDR0_S2P
                     | SMMU_IDR0_HYP
                     | SMMU_IDR0_CD2L
@@ -145,26 +173,14 @@ fn smmu_registers_load_handler(
                     | SMMU_IDR0_VATOS))) as u64,
         )),
         SMMU_IDR2 => Ok(LoadHookResult::Data(0)),
-        SMMU_CR0 | SMMU_CR0ACK => Ok(LoadHookResult::Data(
-            unsafe { CURRENT_SMMU_STATUS.cr0 } as u64
-        )),
-        SMMU_CR1 => Ok(LoadHookResult::Data(
-            unsafe { CURRENT_SMMU_STATUS.cr1 } as u64
+        SMMU_CR0 | SMMU_CR0ACK => Ok(LoadHookResult::Data(smmu.current_status.cr0 as u64)),
+        SMMU_CR1 => Ok(LoadHookResult::Data(smmu.current_status.cr1 as u64)),
+        SMMU_CR2 => Ok(LoadHookResult::Data(smmu.current_status.cr2 as u64)),
+        SMMU_STRTAB_BASE => Ok(LoadHookResult::Data(smmu.current_status.strtab_base)),
+        SMMU_STRTAB_BASE_HIGH => Ok(LoadHookResult::Data(smmu.current_status.strtab_base >> 32)),
+        SMMU_STRTAB_BASE_CFG => Ok(LoadHookResult::Data(
+            smmu.current_status.strtab_base_cfg as u64,
         )),
-        SMMU_CR2 => Ok(LoadHookResult::Data(
-            unsafe { CURRENT_SMMU_STATUS.cr2 } as u64
-        )),
-        SMMU_STRTAB_BASE => Ok(LoadHookResult::Data(unsafe {
-            CURRENT_SMMU_STATUS.strtab_base
-        })),
-        SMMU_STRTAB_BASE_HIGH => Ok(LoadHookResult::Data(
-            unsafe { CURRENT_SMMU_STATUS.strtab_base } >> 32,
-        )),
-        SMMU_STRTAB_BASE_CFG => {
-            Ok(LoadHookResult::Data(
-                unsafe { CURRENT_SMMU_STATUS.strtab_base_cfg } as u64,
-            ))
-        }
         _ => Ok(LoadHookResult::PassThrough),
     }
 }
@@ -174,8 +190,10 @@ fn smmu_registers_store_handler(
     _stored_registers: &mut StoredRegisters,
     access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
-    let register_offset = accessing_memory_address - unsafe { SMMU_BASE_ADDRESS };
+    let smmu = get_smmu_device(context);
+    let register_offset = accessing_memory_address - smmu.base_address;
     pr_debug!(
         "SMMU Store Access Handler: Offset: {:#X}, Data: {:#X}",
         register_offset,
@@ -203,7 +221,7 @@ fn smmu_registers_store_handler(
 
     match register_offset {
         SMMU_CR0 => {
-            let old_smmu_en = (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_SMMUEN) != 0;
+            let old_smmu_en = (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) != 0;
             let new_smmu_en = ((data as u32) & SMMU_CR0_SMMUEN) != 0;
             pr_debug!(
                 "SMMU_CR0: {:#X}(SMMUEN: {} => {})",
@@ -212,14 +230,16 @@ fn smmu_registers_store_handler(
                 new_smmu_en
             );
             if old_smmu_en == new_smmu_en {
-                unsafe { CURRENT_SMMU_STATUS.cr0 = data as u32 };
-                if (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_EVENTQEN) == 0
+                smmu.current_status.cr0 = data as u32;
+                if (smmu.current_status.cr0 & SMMU_CR0_EVENTQEN) == 0
                     && ((data as u32) & SMMU_CR0_EVENTQEN) != 0
                 {
                     let mask = SMMU_CR1_QUEUE_IC | SMMU_CR1_QUEUE_OC | SMMU_CR1_QUEUE_SH;
                     write_smmu_register(
+                        smmu,
                         SMMU_CR1,
-                        ((data as u32) & mask) | (read_smmu_register::<u32>(SMMU_CR1) & !mask),
+                        ((data as u32) & mask)
+                            | (read_smmu_register::<u32>(smmu, SMMU_CR1) & !mask),
                     );
                 }
                 return Ok(StoreHookResult::AlternativeData(
@@ -228,34 +248,34 @@ fn smmu_registers_store_handler(
             }
             if !new_smmu_en {
                 /*Check SMMU_GBPA Status*/
-                while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+                while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
                     core::hint::spin_loop();
                 }
-                if (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_ABORT) != 0 {
+                if (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_ABORT) != 0 {
                     /* Disable SMMUEN */
-                    disable_smmu(old_smmu_en, true);
-                    unsafe { CURRENT_SMMU_STATUS.cr0 = data as u32 };
+                    disable_smmu(smmu, old_smmu_en, true);
+                    smmu.current_status.cr0 = data as u32;
                     return Ok(StoreHookResult::PassThrough);
                 }
-                set_default_smmu_settings(old_smmu_en, true, Some(data as u32));
+                set_default_smmu_settings(smmu, old_smmu_en, true, Some(data as u32));
             } else {
-                apply_current_smmu_settings(Some(data as u32));
+                apply_current_smmu_settings(smmu, Some(data as u32));
             }
             /* Set CR0 (SMMU_CR0ACK will return the new value) */
-            unsafe { CURRENT_SMMU_STATUS.cr0 = data as u32 };
+            smmu.current_status.cr0 = data as u32;
             Ok(StoreHookResult::Cancel)
         }
         SMMU_GBPA => {
             let data = data as u32;
             if (data & SMMU_GBPA_UPDATE) != 0 {
                 if (data & SMMU_GBPA_ABORT) == 0
-                    && ((read_smmu_register::<u32>(SMMU_CR0) & SMMU_CR0_SMMUEN) == 0)
+                    && ((read_smmu_register::<u32>(smmu, SMMU_CR0) & SMMU_CR0_SMMUEN) == 0)
                 {
                     /* When Abort will be disabled and SMMUEN is disabled, all translations will be bypassed.
                     To avoid it, we must set default smmu settings */
-                    set_default_smmu_settings(false, false, None);
+                    set_default_smmu_settings(smmu, false, false, None);
                 } else if (data & SMMU_GBPA_ABORT) != 0
-                    && ((read_smmu_register::<u32>(SMMU_CR0) & SMMU_CR0_SMMUEN) != 0)
+                    && ((read_smmu_register::<u32>(smmu, SMMU_CR0) & SMMU_CR0_SMMUEN) != 0)
                 {
                     /*
                       When Abort will be enabled and SMMUEN is enabled, all translations will not be bypassed.
@@ -263,53 +283,49 @@ fn smmu_registers_store_handler(
                     */
 
                     /* To avoid bypass translation while disabling smmu, write abort at first. */
-                    write_smmu_register(SMMU_GBPA, SMMU_GBPA_UPDATE | SMMU_GBPA_ABORT);
-                    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+                    write_smmu_register(smmu, SMMU_GBPA, SMMU_GBPA_UPDATE | SMMU_GBPA_ABORT);
+                    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
                         core::hint::spin_loop();
                     }
-                    disable_smmu(false, false);
+                    disable_smmu(smmu, false, false);
                 }
             }
             Ok(StoreHookResult::PassThrough)
         }
         SMMU_CR1 => {
-            if (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_SMMUEN) == 0 {
-                unsafe { CURRENT_SMMU_STATUS.cr1 = data as u32 }
+            if (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) == 0 {
+                smmu.current_status.cr1 = data as u32
             }
             Ok(StoreHookResult::Cancel)
         }
         SMMU_CR2 => {
-            if (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_SMMUEN) == 0 {
-                unsafe { CURRENT_SMMU_STATUS.cr2 = (data as u32) & !SMMU_CR2_E2H }
+            if (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) == 0 {
+                smmu.current_status.cr2 = (data as u32) & !SMMU_CR2_E2H
             }
             Ok(StoreHookResult::Cancel)
         }
         SMMU_STRTAB_BASE => {
-            if (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_SMMUEN) == 0 {
+            if (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) == 0 {
                 if access_size != 0b11 {
                     /* Store lower 32bit */
-                    unsafe {
-                        CURRENT_SMMU_STATUS.strtab_base =
-                            (CURRENT_SMMU_STATUS.strtab_base & !(u32::MAX as u64)) | data
-                    };
+                    smmu.current_status.strtab_base =
+                        (smmu.current_status.strtab_base & !(u32::MAX as u64)) | data;
                 } else {
-                    unsafe { CURRENT_SMMU_STATUS.strtab_base = data };
+                    smmu.current_status.strtab_base = data;
                 }
             }
             Ok(StoreHookResult::Cancel)
         }
         SMMU_STRTAB_BASE_HIGH => {
-            if (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_SMMUEN) == 0 {
-                unsafe {
-                    CURRENT_SMMU_STATUS.strtab_base =
-                        (data << 32) | (CURRENT_SMMU_STATUS.strtab_base & u32::MAX as u64)
-                }
+            if (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) == 0 {
+                smmu.current_status.strtab_base =
+                    (data << 32) | (smmu.current_status.strtab_base & u32::MAX as u64);
             }
             Ok(StoreHookResult::Cancel)
         }
         SMMU_STRTAB_BASE_CFG => {
-            if (unsafe { CURRENT_SMMU_STATUS.cr0 } & SMMU_CR0_SMMUEN) == 0 {
-                unsafe { CURRENT_SMMU_STATUS.strtab_base_cfg = data as u32 }
+            if (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) == 0 {
+                smmu.current_status.strtab_base_cfg = data as u32
             }
             Ok(StoreHookResult::Cancel)
         }
@@ -317,13 +333,13 @@ fn smmu_registers_store_handler(
     }
 }
 
-fn remove_current_stream_table_traps() {
+fn remove_current_stream_table_traps(smmu: &SmmuDevice) {
     assert_ne!(
-        unsafe { CURRENT_SMMU_STATUS.strtab_base } & SMMU_STRTAB_BASE_ADDRESS,
-        unsafe { DEFAULT_SMMU_STATUS.strtab_base } & SMMU_STRTAB_BASE_ADDRESS
+        smmu.current_status.strtab_base & SMMU_STRTAB_BASE_ADDRESS,
+        smmu.default_status.strtab_base & SMMU_STRTAB_BASE_ADDRESS
     );
 
-    let smmu_status = unsafe { &CURRENT_SMMU_STATUS };
+    let smmu_status = &smmu.current_status;
     let split = (smmu_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_SPLIT)
         >> SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET;
     let log2_size = (smmu_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_LOG2SIZE)
@@ -335,18 +351,21 @@ fn remove_current_stream_table_traps() {
         split
     };
     let level1_table_size = get_level1_table_size(log2_size, split);
+    let context = smmu as *const SmmuDevice as usize;
 
     remove_memory_access_trap(table_base_address, stage2_page_align_up(level1_table_size))
         .expect("Failed to remove trap of SMMU table");
-    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         table_base_address,
         level1_table_size,
         level1_table_store_handler,
+        context,
     ))
     .expect("Failed to remove store handler");
 
     for i in 0..(level1_table_size / size_of::<u64>()) {
         remove_trap_of_level1_entry(
+            context,
             unsafe { *((table_base_address + (i * size_of::<u64>())) as *const u64) },
             split,
         );
@@ -355,7 +374,7 @@ fn remove_current_stream_table_traps() {
     .expect("Failed to unmap address");*/
 }
 
-fn remove_trap_of_level1_entry(entry: u64, split: u32) {
+fn remove_trap_of_level1_entry(context: usize, entry: u64, split: u32) {
     let span = entry & bitmask!(4, 0);
     if span == 0 || span > 12 {
         return;
@@ -368,141 +387,159 @@ fn remove_trap_of_level1_entry(entry: u64, split: u32) {
         stage2_page_align_up(level2_table_size),
     )
     .expect("Failed to remove trap of SMMU table");
-    remove_memory_load_hook_handler(LoadAccessHandlerEntry::new(
+    remove_memory_load_hook_handler(LoadAccessHandlerEntry::new_with_context(
         level2_table_address,
         level2_table_size,
         level2_table_load_handler,
+        context,
     ))
     .expect("Failed to remove load handler");
-    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    remove_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         level2_table_address,
         level2_table_size,
         level2_table_store_handler,
+        context,
     ))
     .expect("Failed to remove store handler");
     /*unmap_address(level2_table_address, page_align_up(level2_table_size))
     .expect("Failed to unmap address");*/
 }
 
-fn disable_smmu(should_remove_current_trap: bool, should_apply_current_smmu_settings: bool) {
+fn disable_smmu(
+    smmu: &SmmuDevice,
+    should_remove_current_trap: bool,
+    should_apply_current_smmu_settings: bool,
+) {
     if should_apply_current_smmu_settings {
-        write_smmu_register(SMMU_CR1, unsafe { CURRENT_SMMU_STATUS.cr1 });
-        write_smmu_register(SMMU_CR2, unsafe { CURRENT_SMMU_STATUS.cr2 });
+        write_smmu_register(smmu, SMMU_CR1, smmu.current_status.cr1);
+        write_smmu_register(smmu, SMMU_CR2, smmu.current_status.cr2);
     }
     write_smmu_register(
+        smmu,
         SMMU_CR0,
-        read_smmu_register::<u32>(SMMU_CR0) & !SMMU_CR0_SMMUEN,
+        read_smmu_register::<u32>(smmu, SMMU_CR0) & !SMMU_CR0_SMMUEN,
     );
 
-    while (read_smmu_register::<u32>(SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
         core::hint::spin_loop();
     }
 
     if should_remove_current_trap {
-        remove_current_stream_table_traps();
+        remove_current_stream_table_traps(smmu);
     }
 }
 
 fn set_default_smmu_settings(
+    smmu: &SmmuDevice,
     should_remove_current_trap: bool,
     should_apply_current_smmu_settings: bool,
     new_smmu_cr0: Option<u32>,
 ) {
     /* To avoid bypass translation while disabling smmu, write abort at first. */
-    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
         core::hint::spin_loop();
     }
-    let default_gbpa: u32 = read_smmu_register(SMMU_GBPA);
-    write_smmu_register(SMMU_GBPA, SMMU_GBPA_UPDATE | SMMU_GBPA_ABORT);
-    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+    let default_gbpa: u32 = read_smmu_register(smmu, SMMU_GBPA);
+    write_smmu_register(smmu, SMMU_GBPA, SMMU_GBPA_UPDATE | SMMU_GBPA_ABORT);
+    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
         core::hint::spin_loop();
     }
 
     /* Disable SMMUEN */
     write_smmu_register(
+        smmu,
         SMMU_CR0,
-        read_smmu_register::<u32>(SMMU_CR0) & !SMMU_CR0_SMMUEN,
+        read_smmu_register::<u32>(smmu, SMMU_CR0) & !SMMU_CR0_SMMUEN,
     );
-    while (read_smmu_register::<u32>(SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
         core::hint::spin_loop();
     }
 
     /* Set default value */
-    write_smmu_register(SMMU_STRTAB_BASE_CFG, unsafe {
-        DEFAULT_SMMU_STATUS.strtab_base_cfg
-    });
-    write_smmu_register(SMMU_STRTAB_BASE, unsafe { DEFAULT_SMMU_STATUS.strtab_base });
+    write_smmu_register(
+        smmu,
+        SMMU_STRTAB_BASE_CFG,
+        smmu.default_status.strtab_base_cfg,
+    );
+    write_smmu_register(smmu, SMMU_STRTAB_BASE, smmu.default_status.strtab_base);
 
     if should_apply_current_smmu_settings {
-        write_smmu_register(SMMU_CR1, unsafe { CURRENT_SMMU_STATUS.cr1 });
-        write_smmu_register(SMMU_CR2, unsafe { CURRENT_SMMU_STATUS.cr2 });
+        write_smmu_register(smmu, SMMU_CR1, smmu.current_status.cr1);
+        write_smmu_register(smmu, SMMU_CR2, smmu.current_status.cr2);
         write_smmu_register(
+            smmu,
             SMMU_CR0,
-            new_smmu_cr0.unwrap_or(unsafe { CURRENT_SMMU_STATUS.cr0 }),
+            new_smmu_cr0.unwrap_or(smmu.current_status.cr0),
         );
     }
 
     /* Enable SMMUEN */
     write_smmu_register(
+        smmu,
         SMMU_CR0,
-        read_smmu_register::<u32>(SMMU_CR0) | SMMU_CR0_SMMUEN,
+        read_smmu_register::<u32>(smmu, SMMU_CR0) | SMMU_CR0_SMMUEN,
     );
-    while (read_smmu_register::<u32>(SMMU_CR0ACK) & SMMU_CR0_SMMUEN) == 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_CR0ACK) & SMMU_CR0_SMMUEN) == 0 {
         core::hint::spin_loop();
     }
 
     /* Restore GBPA */
-    write_smmu_register(SMMU_GBPA, default_gbpa | SMMU_GBPA_UPDATE);
+    write_smmu_register(smmu, SMMU_GBPA, default_gbpa | SMMU_GBPA_UPDATE);
 
     if should_remove_current_trap {
-        remove_current_stream_table_traps()
+        remove_current_stream_table_traps(smmu)
     }
 }
 
-fn apply_current_smmu_settings(new_smmu_cr0: Option<u32>) {
+fn apply_current_smmu_settings(smmu: &mut SmmuDevice, new_smmu_cr0: Option<u32>) {
     /* To avoid bypass translation while disabling smmu, write abort at first. */
-    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
         core::hint::spin_loop();
     }
-    let default_gbpa: u32 = read_smmu_register(SMMU_GBPA);
-    write_smmu_register(SMMU_GBPA, SMMU_GBPA_UPDATE | SMMU_GBPA_ABORT);
-    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+    let default_gbpa: u32 = read_smmu_register(smmu, SMMU_GBPA);
+    write_smmu_register(smmu, SMMU_GBPA, SMMU_GBPA_UPDATE | SMMU_GBPA_ABORT);
+    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
         core::hint::spin_loop();
     }
 
     /* Disable SMMUEN */
     write_smmu_register(
+        smmu,
         SMMU_CR0,
-        read_smmu_register::<u32>(SMMU_CR0) & !SMMU_CR0_SMMUEN,
+        read_smmu_register::<u32>(smmu, SMMU_CR0) & !SMMU_CR0_SMMUEN,
     );
-    while (read_smmu_register::<u32>(SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
         core::hint::spin_loop();
     }
 
     /* Set default value */
-    write_smmu_register(SMMU_STRTAB_BASE_CFG, unsafe {
-        CURRENT_SMMU_STATUS.strtab_base_cfg
-    });
-    write_smmu_register(SMMU_STRTAB_BASE, unsafe { CURRENT_SMMU_STATUS.strtab_base });
-    write_smmu_register(SMMU_CR1, unsafe { CURRENT_SMMU_STATUS.cr1 });
-    write_smmu_register(SMMU_CR2, unsafe { CURRENT_SMMU_STATUS.cr2 });
+    write_smmu_register(
+        smmu,
+        SMMU_STRTAB_BASE_CFG,
+        smmu.current_status.strtab_base_cfg,
+    );
+    write_smmu_register(smmu, SMMU_STRTAB_BASE, smmu.current_status.strtab_base);
+    write_smmu_register(smmu, SMMU_CR1, smmu.current_status.cr1);
+    write_smmu_register(smmu, SMMU_CR2, smmu.current_status.cr2);
     /* Analysis new settings */
-    add_trap_of_current_stream_table();
+    add_trap_of_current_stream_table(smmu);
 
     write_smmu_register(
+        smmu,
         SMMU_CR0,
-        new_smmu_cr0.unwrap_or(unsafe { CURRENT_SMMU_STATUS.cr0 }),
+        new_smmu_cr0.unwrap_or(smmu.current_status.cr0),
     );
-    while (read_smmu_register::<u32>(SMMU_CR0ACK) & SMMU_CR0_SMMUEN) == 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_CR0ACK) & SMMU_CR0_SMMUEN) == 0 {
         core::hint::spin_loop();
     }
 
     /* Restore GBPA */
-    write_smmu_register(SMMU_GBPA, default_gbpa | SMMU_GBPA_UPDATE);
+    write_smmu_register(smmu, SMMU_GBPA, default_gbpa | SMMU_GBPA_UPDATE);
 }
 
-fn add_trap_of_current_stream_table() {
-    let smmu_status = unsafe { &CURRENT_SMMU_STATUS };
+fn add_trap_of_current_stream_table(smmu: &mut SmmuDevice) {
+    let context = smmu as *const SmmuDevice as usize;
+    let smmu_status = &mut smmu.current_status;
     let fmt = (smmu_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_FMT)
         >> SMMU_STRTAB_BASE_CFG_FMT_BITS_OFFSET;
     let split = (smmu_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_SPLIT)
@@ -525,11 +562,8 @@ fn add_trap_of_current_stream_table() {
 
     let split = if split != 6 && split != 8 && split != 10 {
         println!("SMMU Split is invalid, behave as 6");
-        unsafe {
-            CURRENT_SMMU_STATUS.strtab_base_cfg = (CURRENT_SMMU_STATUS.strtab_base_cfg
-                & !SMMU_STRTAB_BASE_CFG_SPLIT)
-                | (6 << SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET)
-        };
+        smmu_status.strtab_base_cfg = (smmu_status.strtab_base_cfg & !SMMU_STRTAB_BASE_CFG_SPLIT)
+            | (6 << SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET);
         6
     } else {
         split
@@ -554,10 +588,11 @@ fn add_trap_of_current_stream_table() {
         false,
     )
     .expect("Failed to map address");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         level1_table_address,
         level1_table_size,
         level1_table_store_handler,
+        context,
     ))
     .expect("Failed to add store handler");
     add_memory_access_trap(level1_table_address, aligned_level1_table_size, true, false)
@@ -565,6 +600,7 @@ fn add_trap_of_current_stream_table() {
 
     for i in 0..(level1_table_size / size_of::<u64>()) {
         process_level1_table_entry(
+            context,
             unsafe { *((level1_table_address + (i * size_of::<u64>())) as *const u64) },
             (i << split) as u32,
             split,
@@ -572,7 +608,7 @@ fn add_trap_of_current_stream_table() {
     }
 }
 
-fn process_level1_table_entry(entry: u64, base_id: u32, split: u32) {
+fn process_level1_table_entry(context: usize, entry: u64, base_id: u32, split: u32) {
     let span = entry & bitmask!(4, 0);
     if span == 0 || span > 12 {
         pr_debug!(
@@ -607,16 +643,18 @@ fn process_level1_table_entry(entry: u64, base_id: u32, split: u32) {
         false,
     )
     .expect("Failed to map address");
-    add_memory_load_hook_handler(LoadAccessHandlerEntry::new(
+    add_memory_load_hook_handler(LoadAccessHandlerEntry::new_with_context(
         table_address,
         table_size,
         level2_table_load_handler,
+        context,
     ))
     .expect("Failed to add load handler");
-    add_memory_store_hook_handler(StoreAccessHandlerEntry::new(
+    add_memory_store_hook_handler(StoreAccessHandlerEntry::new_with_context(
         table_address,
         table_size,
         level2_table_store_handler,
+        context,
     ))
     .expect("Failed to add store handler");
     add_memory_access_trap(
@@ -661,20 +699,26 @@ fn level1_table_store_handler(
     _stored_registers: &mut StoredRegisters,
     access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let smmu = get_smmu_device(context);
     assert_eq!(STAGE_2_PAGE_SIZE, 0x1000);
     if access_size != 0b11 {
         panic!("unsupported Access Size: {:#b}", access_size);
     }
     let id = (accessing_address
-        - (unsafe { CURRENT_SMMU_STATUS.strtab_base } & SMMU_STRTAB_BASE_ADDRESS) as usize)
+        - (smmu.current_status.strtab_base & SMMU_STRTAB_BASE_ADDRESS) as usize)
         >> 3;
     pr_debug!("Level1 table ID: {}", id);
-    let smmu_split = (unsafe { CURRENT_SMMU_STATUS.strtab_base_cfg } & SMMU_STRTAB_BASE_CFG_SPLIT)
+    let smmu_split = (smmu.current_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_SPLIT)
         >> SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET;
 
-    remove_trap_of_level1_entry(unsafe { *(accessing_address as *mut u64) }, smmu_split);
-    process_level1_table_entry(data, (id << smmu_split) as u32, smmu_split);
+    remove_trap_of_level1_entry(
+        context,
+        unsafe { *(accessing_address as *mut u64) },
+        smmu_split,
+    );
+    process_level1_table_entry(context, data, (id << smmu_split) as u32, smmu_split);
 
     Ok(StoreHookResult::PassThrough)
 }
@@ -685,6 +729,7 @@ fn level2_table_load_handler(
     access_size: u8,
     _is_64bit_register: bool,
     _is_sign_extend_required: bool,
+    _context: usize,
 ) -> Result<LoadHookResult, ()> {
     let ste_base = accessing_address & !(size_of::<StreamTableEntry>() - 1);
     let ste_offset = accessing_address - ste_base;
@@ -698,21 +743,23 @@ fn level2_table_store_handler(
     _stored_registers: &mut StoredRegisters,
     access_size: u8,
     data: u64,
+    context: usize,
 ) -> Result<StoreHookResult, ()> {
+    let smmu = get_smmu_device(context);
     let ste_base_address = accessing_address & !(size_of::<StreamTableEntry>() - 1);
     let ste_offset = accessing_address - ste_base_address;
     let ste_offset_per_ste_base_type = ste_offset / size_of::<SteArrayBaseType>();
 
     let stream_id = get_stream_id(
         accessing_address,
-        (unsafe { CURRENT_SMMU_STATUS.strtab_base } & SMMU_STRTAB_BASE_ADDRESS) as usize,
+        (smmu.current_status.strtab_base & SMMU_STRTAB_BASE_ADDRESS) as usize,
         get_level1_table_size(
-            (unsafe { CURRENT_SMMU_STATUS.strtab_base_cfg } & SMMU_STRTAB_BASE_CFG_LOG2SIZE)
+            (smmu.current_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_LOG2SIZE)
                 >> SMMU_STRTAB_BASE_CFG_LOG2SIZE_BITS_OFFSET,
-            (unsafe { CURRENT_SMMU_STATUS.strtab_base_cfg } & SMMU_STRTAB_BASE_CFG_SPLIT)
+            (smmu.current_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_SPLIT)
                 >> SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET,
         ),
-        (unsafe { CURRENT_SMMU_STATUS.strtab_base_cfg } & SMMU_STRTAB_BASE_CFG_SPLIT)
+        (smmu.current_status.strtab_base_cfg & SMMU_STRTAB_BASE_CFG_SPLIT)
             >> SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET,
     );
     assert_eq!(STE_V_INDEX, 0);
@@ -758,47 +805,70 @@ fn get_stream_id(
     panic!("Not Found");
 }
 
+/// Restore the status of all SMMUs into the one saved on initialization
 pub fn restore_smmu_status() {
-    let default_smmu_status = unsafe { &DEFAULT_SMMU_STATUS };
+    let mut context = unsafe { SMMU_DEVICE_LIST };
+    while context != 0 {
+        let smmu = get_smmu_device(context);
+        restore_default_smmu_status(smmu);
+        context = smmu.next;
+    }
+}
+
+fn restore_default_smmu_status(smmu: &mut SmmuDevice) {
+    let default_smmu_status = &smmu.default_status;
     /* Restore GBPA */
-    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
         core::hint::spin_loop();
     }
-    write_smmu_register(SMMU_GBPA, default_smmu_status.gbpa | SMMU_GBPA_UPDATE);
-    while (read_smmu_register::<u32>(SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
+    write_smmu_register(smmu, SMMU_GBPA, default_smmu_status.gbpa | SMMU_GBPA_UPDATE);
+    while (read_smmu_register::<u32>(smmu, SMMU_GBPA) & SMMU_GBPA_UPDATE) != 0 {
         core::hint::spin_loop();
     }
 
-    write_smmu_register(SMMU_CR0, 0u32);
-    while (read_smmu_register::<u32>(SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
+    write_smmu_register(smmu, SMMU_CR0, 0u32);
+    while (read_smmu_register::<u32>(smmu, SMMU_CR0ACK) & SMMU_CR0_SMMUEN) != 0 {
         core::hint::spin_loop();
     }
 
     /* Restore SMMU settings */
-    let default_smmu_status = unsafe { &DEFAULT_SMMU_STATUS };
-    write_smmu_register(SMMU_CR1, default_smmu_status.cr1);
-    write_smmu_register(SMMU_CR2, default_smmu_status.cr2);
-    write_smmu_register(SMMU_AGBPA, default_smmu_status.agbpa);
-    write_smmu_register(SMMU_IRQ_CTRL, default_smmu_status.irq_ctrl);
-    write_smmu_register(SMMU_GERRORN, default_smmu_status.gerrorn);
-    write_smmu_register(SMMU_STRTAB_BASE, default_smmu_status.strtab_base);
-    write_smmu_register(SMMU_STRTAB_BASE_CFG, default_smmu_status.strtab_base_cfg);
-    write_smmu_register(SMMU_GATOS_CTRL, default_smmu_status.gatos_ctrl);
-
-    write_smmu_register(SMMU_GBPA, default_smmu_status.gbpa | SMMU_GBPA_UPDATE);
-    write_smmu_register(SMMU_CR0, default_smmu_status.cr0);
-
-    if unsafe { CURRENT_SMMU_STATUS.cr0 & SMMU_CR0_SMMUEN } != 0 {
-        remove_current_stream_table_traps();
-    }
-    unsafe { CURRENT_SMMU_STATUS = SmmuSavedRegisters::new() };
+    write_smmu_register(smmu, SMMU_CR1, default_smmu_status.cr1);
+    write_smmu_register(smmu, SMMU_CR2, default_smmu_status.cr2);
+    write_smmu_register(smmu, SMMU_AGBPA, default_smmu_status.agbpa);
+    write_smmu_register(smmu, SMMU_IRQ_CTRL, default_smmu_status.irq_ctrl);
+    write_smmu_register(smmu, SMMU_GERRORN, default_smmu_status.gerrorn);
+    write_smmu_register(smmu, SMMU_STRTAB_BASE, default_smmu_status.strtab_base);
+    write_smmu_register(
+        smmu,
+        SMMU_STRTAB_BASE_CFG,
+        default_smmu_status.strtab_base_cfg,
+    );
+    write_smmu_register(smmu, SMMU_GATOS_CTRL, default_smmu_status.gatos_ctrl);
+
+    write_smmu_register(smmu, SMMU_GBPA, default_smmu_status.gbpa | SMMU_GBPA_UPDATE);
+    write_smmu_register(smmu, SMMU_CR0, default_smmu_status.cr0);
+
+    if (smmu.current_status.cr0 & SMMU_CR0_SMMUEN) != 0 {
+        remove_current_stream_table_traps(smmu);
+    }
+    smmu.current_status = SmmuSavedRegisters::new();
 }
 
 #[allow(dead_code)]
 pub fn dump_stream_table() {
+    let mut context = unsafe { SMMU_DEVICE_LIST };
+    while context != 0 {
+        let smmu = get_smmu_device(context);
+        dump_stream_table_of_smmu(smmu);
+        context = smmu.next;
+    }
+}
+
+#[allow(dead_code)]
+fn dump_stream_table_of_smmu(smmu: &SmmuDevice) {
     let table_base_address =
-        (read_smmu_register::<u64>(SMMU_STRTAB_BASE) & SMMU_STRTAB_BASE_ADDRESS) as usize;
-    let strtab_base_cfg = read_smmu_register::<u32>(SMMU_STRTAB_BASE_CFG);
+        (read_smmu_register::<u64>(smmu, SMMU_STRTAB_BASE) & SMMU_STRTAB_BASE_ADDRESS) as usize;
+    let strtab_base_cfg = read_smmu_register::<u32>(smmu, SMMU_STRTAB_BASE_CFG);
     let split =
         (strtab_base_cfg & SMMU_STRTAB_BASE_CFG_SPLIT) >> SMMU_STRTAB_BASE_CFG_SPLIT_BITS_OFFSET;
     let log2size = (strtab_base_cfg & SMMU_STRTAB_BASE_CFG_LOG2SIZE)
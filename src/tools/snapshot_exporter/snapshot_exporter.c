@@ -0,0 +1,159 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+/*
+ * Snapshot Exporter
+ *
+ * The reference implementation of the Export operation of the snapshot call (hvc #0xFFF2).
+ * This Linux kernel module creates /proc/hypervisor_snapshot, and reading it returns
+ * the snapshot file made by the hypervisor.
+ * The file is read through a small physically contiguous buffer by the offset of the Export.
+ *
+ * Usage:
+ *   insmod snapshot_exporter.ko [snapshot_name=boot] [buffer_order=8]
+ *   cat /proc/hypervisor_snapshot > /boot/efi/EFI/BOOT/hypervisor_snapshot
+ *   rmmod snapshot_exporter
+ */
+
+#include <linux/gfp.h>
+#include <linux/io.h>
+#include <linux/minmax.h>
+#include <linux/module.h>
+#include <linux/mutex.h>
+#include <linux/proc_fs.h>
+#include <linux/string.h>
+#include <linux/uaccess.h>
+#include <asm/unaligned.h>
+
+#define PROC_FILE_NAME "hypervisor_snapshot"
+#define SNAPSHOT_NAME_LENGTH 16
+
+/* See SnapshotOperation and SnapshotCallStatus of hypervisor_kernel/src/fast_restore.rs */
+#define SNAPSHOT_OPERATION_EXPORT 4
+#define SNAPSHOT_CALL_STATUS_SUCCESS 0
+#define SNAPSHOT_CALL_STATUS_NOT_SUPPORTED (-1)
+#define SNAPSHOT_CALL_STATUS_DENIED (-3)
+#define SNAPSHOT_CALL_STATUS_NOT_FOUND (-4)
+
+static char *snapshot_name = "boot";
+module_param(snapshot_name, charp, 0400);
+MODULE_PARM_DESC(snapshot_name, "The name of the snapshot to export (default: boot)");
+
+static unsigned int buffer_order = 8;
+module_param(buffer_order, uint, 0400);
+MODULE_PARM_DESC(buffer_order, "The buffer size is (PAGE_SIZE << buffer_order) (default: 8)");
+
+static u64 snapshot_name_low;
+static u64 snapshot_name_high;
+static void *export_buffer;
+static DEFINE_MUTEX(export_buffer_lock);
+
+/*
+ * Call the Export operation
+ *
+ * The hypervisor writes the snapshot file from `offset` into export_buffer.
+ * The file size is stored into `file_size`, and the number of written bytes is returned by `written_size`.
+ */
+static long snapshot_export(u64 offset, u64 *file_size, u64 *written_size)
+{
+	register u64 x0 asm("x0") = SNAPSHOT_OPERATION_EXPORT;
+	register u64 x1 asm("x1") = snapshot_name_low;
+	register u64 x2 asm("x2") = snapshot_name_high;
+	register u64 x3 asm("x3") = virt_to_phys(export_buffer);
+	register u64 x4 asm("x4") = PAGE_SIZE << buffer_order;
+	register u64 x5 asm("x5") = offset;
+
+	asm volatile("hvc #0xFFF2"
+		     : "+r"(x0), "+r"(x1), "+r"(x2), "+r"(x3), "+r"(x4), "+r"(x5)
+		     :
+		     : "memory");
+	*file_size = x1;
+	*written_size = x2;
+	return (long)x0;
+}
+
+static ssize_t snapshot_exporter_read(struct file *file, char __user *user_buffer, size_t count,
+				      loff_t *position)
+{
+	u64 file_size;
+	u64 written_size;
+	ssize_t result;
+	long status;
+
+	if (*position < 0)
+		return -EINVAL;
+
+	mutex_lock(&export_buffer_lock);
+	status = snapshot_export(*position, &file_size, &written_size);
+	switch (status) {
+	case SNAPSHOT_CALL_STATUS_SUCCESS:
+		written_size = min_t(u64, written_size, count);
+		if (copy_to_user(user_buffer, export_buffer, written_size)) {
+			result = -EFAULT;
+		} else {
+			*position += written_size;
+			result = written_size;
+		}
+		break;
+	case SNAPSHOT_CALL_STATUS_NOT_FOUND:
+		result = -ENOENT;
+		break;
+	case SNAPSHOT_CALL_STATUS_DENIED:
+		/* The boot memory is saved on demand, it happens only before ExitBootServices */
+		result = -EBUSY;
+		break;
+	case SNAPSHOT_CALL_STATUS_NOT_SUPPORTED:
+		result = -EOPNOTSUPP;
+		break;
+	default:
+		result = -EIO;
+		break;
+	}
+	mutex_unlock(&export_buffer_lock);
+	return result;
+}
+
+static const struct proc_ops snapshot_exporter_proc_ops = {
+	.proc_read = snapshot_exporter_read,
+	.proc_lseek = default_llseek,
+};
+
+static int __init snapshot_exporter_init(void)
+{
+	u8 name[SNAPSHOT_NAME_LENGTH] = { 0 };
+
+	if (strlen(snapshot_name) > SNAPSHOT_NAME_LENGTH) {
+		pr_err("snapshot_exporter: The snapshot name is too long\n");
+		return -EINVAL;
+	}
+	memcpy(name, snapshot_name, strlen(snapshot_name));
+	snapshot_name_low = get_unaligned_le64(&name[0]);
+	snapshot_name_high = get_unaligned_le64(&name[8]);
+
+	export_buffer = (void *)__get_free_pages(GFP_KERNEL, buffer_order);
+	if (!export_buffer) {
+		pr_err("snapshot_exporter: Failed to allocate the buffer\n");
+		return -ENOMEM;
+	}
+	if (!proc_create(PROC_FILE_NAME, 0400, NULL, &snapshot_exporter_proc_ops)) {
+		free_pages((unsigned long)export_buffer, buffer_order);
+		return -ENOMEM;
+	}
+	return 0;
+}
+
+static void __exit snapshot_exporter_exit(void)
+{
+	remove_proc_entry(PROC_FILE_NAME, NULL);
+	free_pages((unsigned long)export_buffer, buffer_order);
+}
+
+module_init(snapshot_exporter_init);
+module_exit(snapshot_exporter_exit);
+
+MODULE_LICENSE("Dual MIT/GPL");
+MODULE_DESCRIPTION("Export the snapshot of fast restore of MilvusVisor");